@@ -0,0 +1,98 @@
+; src/ap_trampoline.asm
+
+; Application processors start here in real mode once they are sent a startup IPI
+; The code is copied to 0x8000 so every address is computed relative to that rather than where it was linked
+
+global ap_trampoline_start
+global ap_trampoline_end
+global ap_trampoline_parameters
+
+%define ABSOLUTE(label) (0x8000 + (label - ap_trampoline_start))
+
+section .text
+bits 16
+ap_trampoline_start:
+    cli
+    cld
+
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    lgdt [ABSOLUTE(trampoline_gdt.pointer)]
+
+    ; Enable protected mode
+    mov eax, cr0
+    or eax, 1 << 0
+    mov cr0, eax
+
+    jmp dword 0x18:ABSOLUTE(protected_mode)
+
+bits 32
+protected_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    ; Enable PAE paging along with SSE like boot.asm does
+    mov eax, cr4
+    or eax, 1 << 5 | 1 << 9 | 1 << 10
+    mov cr4, eax
+
+    ; P4 of the kernel (which identity maps this page)
+    mov eax, [ABSOLUTE(ap_trampoline_parameters)]
+    mov cr3, eax
+
+    ; Set long mode and no execute enable bits in EFER MSR
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, 1 << 8 | 1 << 11
+    wrmsr
+
+    ; Clear EM then enable paging along with write protect and MP
+    mov eax, cr0
+    and eax, 0xFFFFFFFB
+    or eax, 1 << 31 | 1 << 16 | 1 << 1
+    mov cr0, eax
+
+    jmp 0x08:ABSOLUTE(long_mode)
+
+bits 64
+long_mode:
+    mov ax, 0x10
+    mov ss, ax
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [ABSOLUTE(ap_trampoline_parameters) + 8] ; Kernel stack
+    mov rdi, [ABSOLUTE(ap_trampoline_parameters) + 24] ; Index of the CPU
+    mov rax, [ABSOLUTE(ap_trampoline_parameters) + 16] ; ap_main
+    call rax
+
+    hlt
+
+; Replaced by the GDT of the CPU in ap_main
+align 8
+trampoline_gdt:
+    dq 0 ; null entry
+    dq 0x00af9a000000ffff ; kernel code segment
+    dq 0x00cf92000000ffff ; data segment
+    dq 0x00cf9a000000ffff ; 32 bit code segment
+
+.pointer:
+    dw $ - trampoline_gdt - 1
+    dd ABSOLUTE(trampoline_gdt)
+
+; Filled in for each CPU before it is started (the layout matches TrampolineParameters)
+align 8
+ap_trampoline_parameters:
+    dq 0 ; P4
+    dq 0 ; Stack
+    dq 0 ; Entry
+    dq 0 ; CPU
+
+ap_trampoline_end:
@@ -113,15 +113,15 @@ enable_paging:
     or eax, 1 << 5,
     mov cr4, eax
 
-    ; Set long mode bit in EFER MSR
+    ; Set long mode and no execute enable bits in EFER MSR
     mov ecx, 0xC0000080
     rdmsr
-    or eax, 1 << 8
+    or eax, 1 << 8 | 1 << 11
     wrmsr
 
-    ; Enable paging
+    ; Enable paging along with write protect so the kernel also faults on copy on write pages
     mov eax, cr0
-    or eax, 1 << 31 | 1 << 0
+    or eax, 1 << 31 | 1 << 16 | 1 << 0
     mov cr0, eax
 
     ret
\ No newline at end of file
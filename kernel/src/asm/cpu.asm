@@ -26,6 +26,19 @@ inpw_raw:
   in ax, dx
   ret
 
+global outpl_raw
+outpl_raw:
+  mov dx, di ; Address (16 Bit)
+  mov eax, esi ; Value (32 Bit)
+  out dx, eax
+  ret
+
+global inpl_raw
+inpl_raw:
+  mov dx, di ; Address (16 Bit)
+  in eax, dx
+  ret
+
 ; Load IDT
 global flush_idt    
 flush_idt:
@@ -0,0 +1,387 @@
+/*
+    AHCI (Advanced Host Controller Interface) controllers connect SATA disks and are found on the PCI bus (class 0x01, subclass 0x06)
+    The registers of the controller (HBA memory) are memory mapped at BAR 5 and hold up to 32 ports with one disk each
+    Commands are sent as FISes (frame information structures) within a command table which the controller reads from memory itself (DMA)
+    Each port has a list of 32 command slots (only the first is used here) along with an area where the disk writes the FISes it sends back
+    +-------------------+---------------------+-----------------------------------------------+
+    |   Command list    |    Received FIS     |                 Command table                 |
+    | (1024 byte align) |  (256 byte align)   | Command FIS | ATAPI command | PRDT (regions)  |
+    +-------------------+---------------------+-----------------------------------------------+
+*/
+
+use crate::dev::pci;
+use crate::fs::block::{BlockDevice, SECTOR_SIZE};
+use crate::memory::page_frame_allocator::PAGE_FRAME_ALLOCATOR;
+use crate::memory::paging::{self, PAGE_SIZE};
+use crate::print_serial;
+
+const CLASS_MASS_STORAGE: u8 = 0x01;
+const SUBCLASS_SATA: u8 = 0x06;
+const ABAR_INDEX: u8 = 5;
+const ABAR_PAGES: usize = 2; // Generic registers and 32 ports of 0x80 bytes
+
+// Generic host control registers
+const REGISTER_GLOBAL_CONTROL: usize = 0x04;
+const REGISTER_PORTS_IMPLEMENTED: usize = 0x0C;
+
+const GLOBAL_AHCI_ENABLE: u32 = 1 << 31;
+
+const PORT_COUNT: usize = 32;
+const PORTS_OFFSET: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+
+// Port registers
+const PORT_COMMAND_LIST: usize = 0x00;
+const PORT_COMMAND_LIST_UPPER: usize = 0x04;
+const PORT_FIS: usize = 0x08;
+const PORT_FIS_UPPER: usize = 0x0C;
+const PORT_INTERRUPT_STATUS: usize = 0x10;
+const PORT_COMMAND: usize = 0x18;
+const PORT_TASK_FILE: usize = 0x20;
+const PORT_SIGNATURE: usize = 0x24;
+const PORT_SATA_STATUS: usize = 0x28;
+const PORT_SATA_ERROR: usize = 0x30;
+const PORT_COMMAND_ISSUE: usize = 0x38;
+
+const COMMAND_START: u32 = 1 << 0;
+const COMMAND_FIS_RECEIVE: u32 = 1 << 4;
+const COMMAND_FIS_RUNNING: u32 = 1 << 14;
+const COMMAND_LIST_RUNNING: u32 = 1 << 15;
+
+const TASK_FILE_ERROR: u32 = 1 << 0;
+const TASK_FILE_DATA_REQUEST: u32 = 1 << 3;
+const TASK_FILE_BUSY: u32 = 1 << 7;
+
+const INTERRUPT_TASK_FILE_ERROR: u32 = 1 << 30;
+
+const SIGNATURE_SATA: u32 = 0x00000101;
+const DEVICE_PRESENT: u32 = 0x3; // Detection bits of the SATA status
+const INTERFACE_ACTIVE: u32 = 0x1; // Power management bits of the SATA status
+
+// Layout of the frame given to each port
+const FIS_OFFSET: usize = 1024;
+const COMMAND_TABLE_OFFSET: usize = 2048;
+const PRDT_OFFSET: usize = 0x80; // Within the command table
+
+const FIS_TYPE_HOST_TO_DEVICE: u8 = 0x27;
+const FIS_IS_COMMAND: u8 = 1 << 7;
+const FIS_LBA_MODE: u8 = 1 << 6;
+
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
+const ATA_IDENTIFY: u8 = 0xEC;
+
+const IDENTIFY_LBA48_SECTORS: usize = 100; // Word index
+
+const SECTORS_PER_TRANSFER: usize = PAGE_SIZE / SECTOR_SIZE;
+const TIMEOUT: usize = 1_000_000;
+
+// Describes the command in a slot of the command list
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct CommandHeader {
+    flags: u16, // Length of the command FIS in dwords (bits 0-4) and whether it writes to the disk (bit 6)
+    prdt_length: u16,
+    bytes_transferred: u32,
+    command_table: u32,
+    command_table_upper: u32,
+    reserved: [u32; 4],
+}
+
+// Register host to device FIS which carries an ATA command
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct HostToDeviceFis {
+    fis_type: u8,
+    flags: u8,
+    command: u8,
+    feature_low: u8,
+    lba_low: [u8; 3],
+    device: u8,
+    lba_high: [u8; 3],
+    feature_high: u8,
+    count: u16,
+    icc: u8,
+    control: u8,
+    reserved: u32,
+}
+
+// Physical region descriptor (a region of memory which is read or written)
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct PrdtEntry {
+    data_base: u32,
+    data_base_upper: u32,
+    reserved: u32,
+    byte_count: u32, // One less than the number of bytes (bits 0-21)
+}
+
+pub struct AhciPort {
+    registers: usize, // Virtual address of the registers of the port
+    memory: usize,    // Frame holding the command list, received FISes and command table
+    buffer: usize,    // Frame which the disk transfers sectors to and from
+    sectors: usize,
+}
+
+impl AhciPort {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.registers + register) as *const u32) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.registers + register) as *mut u32, value) }
+    }
+
+    fn wait_while(&self, register: usize, bits: u32) -> Result<(), &'static str> {
+        for _ in 0..TIMEOUT {
+            if self.read(register) & bits == 0 {
+                return Ok(());
+            }
+        }
+
+        Err("AHCI port timed out")
+    }
+
+    // Only a disk which has finished linking with the controller can be used
+    fn is_sata_disk(&self) -> bool {
+        let status = self.read(PORT_SATA_STATUS);
+        let detection = status & 0xF;
+        let power = (status >> 8) & 0xF;
+
+        detection == DEVICE_PRESENT
+            && power == INTERFACE_ACTIVE
+            && self.read(PORT_SIGNATURE) == SIGNATURE_SATA
+    }
+
+    /*
+        The controller must be stopped before the command list and FIS area are moved to memory the kernel owns
+        Frames from the page frame allocator are identity mapped so their address is also their physical address
+    */
+    fn rebase(&mut self) -> Result<(), &'static str> {
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) & !COMMAND_START);
+        self.wait_while(PORT_COMMAND, COMMAND_LIST_RUNNING)?;
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) & !COMMAND_FIS_RECEIVE);
+        self.wait_while(PORT_COMMAND, COMMAND_FIS_RUNNING)?;
+
+        let frames = PAGE_FRAME_ALLOCATOR.lock().alloc_page_frames(2) as usize;
+        PAGE_FRAME_ALLOCATOR.free();
+
+        self.memory = frames;
+        self.buffer = frames + PAGE_SIZE;
+
+        unsafe {
+            core::ptr::write_bytes(self.memory as *mut u8, 0, PAGE_SIZE);
+        }
+
+        self.write(PORT_COMMAND_LIST, self.memory as u32);
+        self.write(PORT_COMMAND_LIST_UPPER, (self.memory >> 32) as u32);
+        self.write(PORT_FIS, (self.memory + FIS_OFFSET) as u32);
+        self.write(PORT_FIS_UPPER, ((self.memory + FIS_OFFSET) >> 32) as u32);
+
+        // Both are cleared by writing ones
+        self.write(PORT_SATA_ERROR, u32::MAX);
+        self.write(PORT_INTERRUPT_STATUS, u32::MAX);
+
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) | COMMAND_FIS_RECEIVE);
+        self.write(PORT_COMMAND, self.read(PORT_COMMAND) | COMMAND_START);
+
+        Ok(())
+    }
+
+    /*
+        Sends an ATA command in the first command slot and waits for the disk to complete it
+        Any sectors are transferred through the buffer frame of the port
+    */
+    fn issue(
+        &self,
+        command: u8,
+        lba: usize,
+        count: usize,
+        is_write: bool,
+    ) -> Result<(), &'static str> {
+        self.wait_while(PORT_TASK_FILE, TASK_FILE_BUSY | TASK_FILE_DATA_REQUEST)?;
+        self.write(PORT_INTERRUPT_STATUS, u32::MAX);
+
+        let bytes = count * SECTOR_SIZE;
+        let table = self.memory + COMMAND_TABLE_OFFSET;
+
+        let header = CommandHeader {
+            flags: (core::mem::size_of::<HostToDeviceFis>() / 4) as u16 | (is_write as u16) << 6,
+            prdt_length: (bytes > 0) as u16,
+            bytes_transferred: 0,
+            command_table: table as u32,
+            command_table_upper: (table >> 32) as u32,
+            reserved: [0; 4],
+        };
+
+        let fis = HostToDeviceFis {
+            fis_type: FIS_TYPE_HOST_TO_DEVICE,
+            flags: FIS_IS_COMMAND,
+            command,
+            feature_low: 0,
+            lba_low: [lba as u8, (lba >> 8) as u8, (lba >> 16) as u8],
+            device: FIS_LBA_MODE,
+            lba_high: [(lba >> 24) as u8, (lba >> 32) as u8, (lba >> 40) as u8],
+            feature_high: 0,
+            count: count as u16,
+            icc: 0,
+            control: 0,
+            reserved: 0,
+        };
+
+        let region = PrdtEntry {
+            data_base: self.buffer as u32,
+            data_base_upper: (self.buffer >> 32) as u32,
+            reserved: 0,
+            byte_count: (bytes as u32).wrapping_sub(1),
+        };
+
+        unsafe {
+            core::ptr::write_volatile(self.memory as *mut CommandHeader, header);
+            core::ptr::write_volatile(table as *mut HostToDeviceFis, fis);
+            core::ptr::write_volatile((table + PRDT_OFFSET) as *mut PrdtEntry, region);
+        }
+
+        self.write(PORT_COMMAND_ISSUE, 1);
+
+        for _ in 0..TIMEOUT {
+            if self.read(PORT_INTERRUPT_STATUS) & INTERRUPT_TASK_FILE_ERROR != 0 {
+                return Err("AHCI disk reported an error");
+            }
+
+            if self.read(PORT_COMMAND_ISSUE) & 1 == 0 {
+                return match self.read(PORT_TASK_FILE) & TASK_FILE_ERROR {
+                    0 => Ok(()),
+                    _ => Err("AHCI disk reported an error"),
+                };
+            }
+        }
+
+        Err("AHCI port timed out")
+    }
+
+    fn identify(&mut self) -> Result<(), &'static str> {
+        self.issue(ATA_IDENTIFY, 0, 1, false)?;
+
+        let identity = self.buffer as *const u16;
+        self.sectors = (0..4).fold(0, |sectors, i| unsafe {
+            sectors | (*identity.add(IDENTIFY_LBA48_SECTORS + i) as usize) << (16 * i)
+        });
+
+        Ok(())
+    }
+}
+
+impl BlockDevice for AhciPort {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn capacity(&self) -> usize {
+        self.sectors
+    }
+
+    fn read_sectors(
+        &mut self,
+        mut sector: usize,
+        count: usize,
+        mut buffer: *mut u8,
+    ) -> Result<(), &'static str> {
+        let mut sectors_left = count;
+
+        while sectors_left > 0 {
+            let sectors = sectors_left.min(SECTORS_PER_TRANSFER);
+            self.issue(ATA_READ_DMA_EXT, sector, sectors, false)?;
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    self.buffer as *const u8,
+                    buffer,
+                    sectors * SECTOR_SIZE,
+                );
+                buffer = buffer.add(sectors * SECTOR_SIZE);
+            }
+
+            sector += sectors;
+            sectors_left -= sectors;
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(
+        &mut self,
+        mut sector: usize,
+        count: usize,
+        mut buffer: *const u8,
+    ) -> Result<(), &'static str> {
+        let mut sectors_left = count;
+
+        while sectors_left > 0 {
+            let sectors = sectors_left.min(SECTORS_PER_TRANSFER);
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    buffer,
+                    self.buffer as *mut u8,
+                    sectors * SECTOR_SIZE,
+                );
+                buffer = buffer.add(sectors * SECTOR_SIZE);
+            }
+
+            self.issue(ATA_WRITE_DMA_EXT, sector, sectors, true)?;
+
+            sector += sectors;
+            sectors_left -= sectors;
+        }
+
+        self.issue(ATA_FLUSH_CACHE_EXT, 0, 0, false)
+    }
+}
+
+// Returns the first SATA disk attached to the first AHCI controller
+pub fn find_disk() -> Option<AhciPort> {
+    let controller = pci::find_device(CLASS_MASS_STORAGE, SUBCLASS_SATA)?;
+    controller.enable_bus_mastering();
+
+    let abar = paging::map_device_memory(controller.memory_bar(ABAR_INDEX)?, ABAR_PAGES);
+
+    unsafe {
+        let global_control = (abar + REGISTER_GLOBAL_CONTROL) as *mut u32;
+        core::ptr::write_volatile(
+            global_control,
+            core::ptr::read_volatile(global_control) | GLOBAL_AHCI_ENABLE,
+        );
+    }
+
+    let implemented =
+        unsafe { core::ptr::read_volatile((abar + REGISTER_PORTS_IMPLEMENTED) as *const u32) };
+
+    for i in (0..PORT_COUNT).filter(|i| implemented & (1 << i) != 0) {
+        let mut port = AhciPort {
+            registers: abar + PORTS_OFFSET + i * PORT_SIZE,
+            memory: 0,
+            buffer: 0,
+            sectors: 0,
+        };
+
+        if !port.is_sata_disk() {
+            continue;
+        }
+
+        if let Err(error) = port.rebase().and_then(|_| port.identify()) {
+            print_serial!("AHCI: Port {} could not be used: {}\n", i, error);
+            continue;
+        }
+
+        print_serial!(
+            "AHCI: Found disk on port {} with {} sectors\n",
+            i,
+            port.sectors
+        );
+        return Some(port);
+    }
+
+    None
+}
@@ -0,0 +1,253 @@
+/*
+    ATA (IDE) disks are controlled through a set of ports per channel, the primary channel uses 0x1F0-0x1F7 along with 0x3F6
+    PIO (programmed IO) mode moves every word of a sector through the data port rather than the disk writing to memory itself
+    Sectors are addressed by LBA (logical block address) where LBA28 reaches the first 128GB of a disk
+    The disk is polled (its interrupts are disabled) as a sector is ready within microseconds
+*/
+
+use crate::fs::block::{BlockDevice, SECTOR_SIZE};
+use crate::print_serial;
+use crate::utils::ports::{inb, inpw, outb, outpw};
+
+const PRIMARY_IO_BASE: u16 = 0x1F0;
+const PRIMARY_CONTROL_BASE: u16 = 0x3F6;
+
+// Offsets from the IO base
+const REGISTER_DATA: u16 = 0;
+const REGISTER_SECTOR_COUNT: u16 = 2;
+const REGISTER_LBA_LOW: u16 = 3;
+const REGISTER_LBA_MID: u16 = 4;
+const REGISTER_LBA_HIGH: u16 = 5;
+const REGISTER_DRIVE: u16 = 6;
+const REGISTER_STATUS: u16 = 7; // Read
+const REGISTER_COMMAND: u16 = 7; // Write
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DRIVE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+
+const CONTROL_DISABLE_INTERRUPTS: u8 = 1 << 1;
+
+const DRIVE_MASTER: u8 = 0xA0;
+const DRIVE_LBA: u8 = 1 << 6;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_CACHE_FLUSH: u8 = 0xE7;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+const IDENTIFY_LBA28_SECTORS: usize = 60; // Word index
+const WORDS_PER_SECTOR: usize = SECTOR_SIZE / 2;
+
+const MAX_SECTORS_PER_COMMAND: usize = 255;
+const LBA28_LIMIT: usize = 1 << 28;
+const TIMEOUT: usize = 1_000_000;
+
+pub struct AtaDrive {
+    io_base: u16,
+    control_base: u16,
+    sectors: usize,
+}
+
+impl AtaDrive {
+    // Selecting a drive takes 400ns to apply which reading the status 4 times covers
+    fn select(&self, head: u8) {
+        outb(self.io_base + REGISTER_DRIVE, DRIVE_MASTER | head);
+
+        for _ in 0..4 {
+            inb(self.control_base);
+        }
+    }
+
+    fn wait_until_ready(&self) -> Result<u8, &'static str> {
+        for _ in 0..TIMEOUT {
+            let status = inb(self.io_base + REGISTER_STATUS);
+
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+        }
+
+        Err("ATA drive timed out")
+    }
+
+    // Waits for the drive to be ready to transfer the next sector through the data port
+    fn wait_for_data(&self) -> Result<(), &'static str> {
+        for _ in 0..TIMEOUT {
+            let status = self.wait_until_ready()?;
+
+            if status & (STATUS_ERROR | STATUS_DRIVE_FAULT) != 0 {
+                return Err("ATA drive reported an error");
+            }
+
+            if status & STATUS_DATA_REQUEST != 0 {
+                return Ok(());
+            }
+        }
+
+        Err("ATA drive timed out")
+    }
+
+    fn send_command(&self, command: u8, lba: usize, count: usize) -> Result<(), &'static str> {
+        if lba + count > LBA28_LIMIT {
+            return Err("Sector is out of reach of LBA28");
+        }
+
+        self.select(DRIVE_LBA | ((lba >> 24) & 0x0F) as u8);
+        self.wait_until_ready()?;
+
+        outb(self.io_base + REGISTER_SECTOR_COUNT, count as u8);
+        outb(self.io_base + REGISTER_LBA_LOW, lba as u8);
+        outb(self.io_base + REGISTER_LBA_MID, (lba >> 8) as u8);
+        outb(self.io_base + REGISTER_LBA_HIGH, (lba >> 16) as u8);
+        outb(self.io_base + REGISTER_COMMAND, command);
+
+        Ok(())
+    }
+
+    /*
+        Asks the master drive of the channel to describe itself
+        A status of 0 means nothing is attached and a non zero LBA mid/high means it isn't an ATA disk (eg an ATAPI CD drive)
+    */
+    fn identify(&mut self) -> bool {
+        outb(self.control_base, CONTROL_DISABLE_INTERRUPTS);
+
+        self.select(0);
+        outb(self.io_base + REGISTER_SECTOR_COUNT, 0);
+        outb(self.io_base + REGISTER_LBA_LOW, 0);
+        outb(self.io_base + REGISTER_LBA_MID, 0);
+        outb(self.io_base + REGISTER_LBA_HIGH, 0);
+        outb(self.io_base + REGISTER_COMMAND, COMMAND_IDENTIFY);
+
+        // A floating bus reads as 0xFF
+        let status = inb(self.io_base + REGISTER_STATUS);
+        if status == 0 || status == 0xFF || self.wait_until_ready().is_err() {
+            return false;
+        }
+
+        if inb(self.io_base + REGISTER_LBA_MID) != 0 || inb(self.io_base + REGISTER_LBA_HIGH) != 0 {
+            return false;
+        }
+
+        if self.wait_for_data().is_err() {
+            return false;
+        }
+
+        let mut identity = [0u16; WORDS_PER_SECTOR];
+        for word in identity.iter_mut() {
+            *word = inpw(self.io_base + REGISTER_DATA);
+        }
+
+        self.sectors = identity[IDENTIFY_LBA28_SECTORS] as usize
+            | (identity[IDENTIFY_LBA28_SECTORS + 1] as usize) << 16;
+
+        self.sectors > 0
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn capacity(&self) -> usize {
+        self.sectors
+    }
+
+    fn read_sectors(
+        &mut self,
+        mut sector: usize,
+        count: usize,
+        mut buffer: *mut u8,
+    ) -> Result<(), &'static str> {
+        let mut sectors_left = count;
+
+        while sectors_left > 0 {
+            let sectors = sectors_left.min(MAX_SECTORS_PER_COMMAND);
+            self.send_command(COMMAND_READ_SECTORS, sector, sectors)?;
+
+            for _ in 0..sectors {
+                self.wait_for_data()?;
+
+                let words = buffer as *mut u16;
+                for i in 0..WORDS_PER_SECTOR {
+                    unsafe {
+                        words
+                            .add(i)
+                            .write_unaligned(inpw(self.io_base + REGISTER_DATA))
+                    };
+                }
+
+                buffer = unsafe { buffer.add(SECTOR_SIZE) };
+            }
+
+            sector += sectors;
+            sectors_left -= sectors;
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(
+        &mut self,
+        mut sector: usize,
+        count: usize,
+        mut buffer: *const u8,
+    ) -> Result<(), &'static str> {
+        let mut sectors_left = count;
+
+        while sectors_left > 0 {
+            let sectors = sectors_left.min(MAX_SECTORS_PER_COMMAND);
+            self.send_command(COMMAND_WRITE_SECTORS, sector, sectors)?;
+
+            for _ in 0..sectors {
+                self.wait_for_data()?;
+
+                let words = buffer as *const u16;
+                for i in 0..WORDS_PER_SECTOR {
+                    outpw(self.io_base + REGISTER_DATA, unsafe {
+                        words.add(i).read_unaligned()
+                    });
+                }
+
+                buffer = unsafe { buffer.add(SECTOR_SIZE) };
+            }
+
+            sector += sectors;
+            sectors_left -= sectors;
+        }
+
+        // The drive is busy until the last sector has been taken and only accepts the flush afterwards
+        let status = self.wait_until_ready()?;
+        if status & (STATUS_ERROR | STATUS_DRIVE_FAULT) != 0 {
+            return Err("ATA drive reported an error");
+        }
+
+        // The drive may hold the sectors in its own cache until told to write them out
+        outb(self.io_base + REGISTER_COMMAND, COMMAND_CACHE_FLUSH);
+
+        let status = self.wait_until_ready()?;
+        if status & (STATUS_ERROR | STATUS_DRIVE_FAULT) != 0 {
+            return Err("ATA drive could not flush its cache");
+        }
+
+        Ok(())
+    }
+}
+
+// Returns the master drive of the primary channel if it is an ATA disk
+pub fn find_disk() -> Option<AtaDrive> {
+    let mut drive = AtaDrive {
+        io_base: PRIMARY_IO_BASE,
+        control_base: PRIMARY_CONTROL_BASE,
+        sectors: 0,
+    };
+
+    if !drive.identify() {
+        return None;
+    }
+
+    print_serial!("ATA: Found disk with {} sectors\n", drive.sectors);
+    Some(drive)
+}
@@ -1,5 +1,8 @@
+pub mod ahci;
+pub mod ata;
 pub mod keyboard;
 pub mod mouse;
+mod pci;
 mod ps2;
 
 pub fn init() {
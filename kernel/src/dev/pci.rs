@@ -0,0 +1,127 @@
+/*
+    PCI (Peripheral Component Interconnect) connects devices such as disk controllers to the CPU
+    Every function of a device has 256 bytes of configuration space which identifies it and holds its base address registers (BARs)
+    The configuration space is reached through two ports, the address of a register is written to 0xCF8 and its value is accessed via 0xCFC
+    +-------+----------+------+----------+----------+--------+
+    |  31   |  30-24   | 23-16|  15-11   |   10-8   |  7-0   |
+    +-------+----------+------+----------+----------+--------+
+    | Enable| Reserved |  Bus |  Device  | Function | Offset |
+    +-------+----------+------+----------+----------+--------+
+*/
+
+use crate::utils::ports::{inpl, outpl};
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+const BUS_COUNT: usize = 256;
+const DEVICES_PER_BUS: usize = 32;
+const FUNCTIONS_PER_DEVICE: usize = 8;
+
+const REGISTER_ID: u8 = 0x00;
+const REGISTER_COMMAND: u8 = 0x04;
+const REGISTER_CLASS: u8 = 0x08;
+const REGISTER_HEADER_TYPE: u8 = 0x0C;
+const REGISTER_BAR0: u8 = 0x10;
+
+const NO_DEVICE: u32 = 0xFFFF;
+const MULTIFUNCTION: u32 = 1 << 23;
+
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+const COMMAND_INTERRUPT_DISABLE: u32 = 1 << 10;
+
+const BAR_IO_SPACE: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    bus: u8,
+    device: u8,
+    function: u8,
+}
+
+impl PciDevice {
+    pub fn read(&self, offset: u8) -> u32 {
+        outpl(CONFIG_ADDRESS, self.address(offset));
+        inpl(CONFIG_DATA)
+    }
+
+    pub fn write(&self, offset: u8, value: u32) {
+        outpl(CONFIG_ADDRESS, self.address(offset));
+        outpl(CONFIG_DATA, value);
+    }
+
+    // Registers are read 4 bytes at a time so the bottom 2 bits of the offset are ignored
+    fn address(&self, offset: u8) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset as u32 & 0xFC)
+    }
+
+    fn vendor_id(&self) -> u32 {
+        self.read(REGISTER_ID) & 0xFFFF
+    }
+
+    // Class and subclass describe what the device is (eg 0x01, 0x06 is a SATA controller)
+    pub fn class(&self) -> (u8, u8) {
+        let value = self.read(REGISTER_CLASS);
+        ((value >> 24) as u8, (value >> 16) as u8)
+    }
+
+    // Physical address of a memory mapped BAR (the bottom 4 bits hold flags)
+    pub fn memory_bar(&self, index: u8) -> Option<usize> {
+        let value = self.read(REGISTER_BAR0 + index * 4);
+
+        if value & BAR_IO_SPACE != 0 {
+            return None;
+        }
+
+        Some((value & !0xF) as usize)
+    }
+
+    // Lets the device access memory itself (DMA) with its interrupts disabled as it is polled instead
+    pub fn enable_bus_mastering(&self) {
+        let command = self.read(REGISTER_COMMAND);
+        self.write(
+            REGISTER_COMMAND,
+            command | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER | COMMAND_INTERRUPT_DISABLE,
+        );
+    }
+}
+
+/*
+    Checks every function of every device on every bus for the given class
+    Only the first function is checked unless the device reports it has several
+*/
+pub fn find_device(class: u8, subclass: u8) -> Option<PciDevice> {
+    for bus in 0..BUS_COUNT {
+        for device in 0..DEVICES_PER_BUS {
+            let mut pci_device = PciDevice {
+                bus: bus as u8,
+                device: device as u8,
+                function: 0,
+            };
+
+            if pci_device.vendor_id() == NO_DEVICE {
+                continue;
+            }
+
+            let functions = match pci_device.read(REGISTER_HEADER_TYPE) & MULTIFUNCTION {
+                0 => 1,
+                _ => FUNCTIONS_PER_DEVICE,
+            };
+
+            for function in 0..functions {
+                pci_device.function = function as u8;
+
+                if pci_device.vendor_id() != NO_DEVICE && pci_device.class() == (class, subclass) {
+                    return Some(pci_device);
+                }
+            }
+        }
+    }
+
+    None
+}
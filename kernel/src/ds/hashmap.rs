@@ -7,7 +7,12 @@
     Separate chaining is a method in which linked lists are created for items with same hash
 */
 
-use crate::{ds::queue, fs::vfs::File, memory::allocator::kmalloc, print_serial};
+use crate::{
+    ds::queue,
+    fs::vfs::File,
+    memory::allocator::{kfree, kmalloc},
+    print_serial,
+};
 
 use super::{list::ListNode, queue::Queue};
 
@@ -35,6 +40,10 @@ impl<T: Clone> HashItem<T> {
     pub fn new(key: usize, value: T) -> HashItem<T> {
         let addr = kmalloc(core::mem::size_of::<Queue<HashItem<T>>>()) as *mut Queue<HashItem<T>>;
 
+        unsafe {
+            core::ptr::write(addr, Queue::new());
+        }
+
         HashItem {
             key,
             value,
@@ -147,6 +156,39 @@ impl<T: Copy> HashMap<T> {
         }
     }
 
+    // Calls func on every key value pair
+    pub fn for_each<F>(&self, mut func: F)
+    where
+        F: FnMut(usize, T),
+    {
+        for item in self.items.iter() {
+            if let Some(hashitem) = item {
+                func(hashitem.key, hashitem.value);
+
+                let queue = unsafe { &*hashitem.values };
+                for queued_item in queue.iter() {
+                    func(queued_item.key, queued_item.value);
+                }
+            }
+        }
+    }
+
+    // Frees the memory used by every item (the values themselves are not free'd)
+    pub fn free(&mut self) {
+        for i in 0..CAPACITY {
+            if let Some(hashitem) = self.items[i] {
+                let queue = unsafe { &mut *hashitem.values };
+
+                while let Some(queued_item) = queue.dequeue() {
+                    kfree(queued_item.values as *mut usize);
+                }
+
+                kfree(hashitem.values as *mut usize);
+                self.items[i] = None;
+            }
+        }
+    }
+
     fn hash(&self, key: usize) -> usize {
         key % CAPACITY
     }
@@ -12,12 +12,16 @@ use core::cmp::Ordering;
 #[derive(Clone, Copy)]
 pub struct PriorityWrapper<T> {
     priority: usize,
+    order: usize, // Elements of equal priority are dequeued in the order they were enqueued
     pub value: T,
 }
 
 impl<T> Ord for PriorityWrapper<T> {
     fn cmp(&self, other: &Self) -> Ordering {
-        other.priority.cmp(&self.priority)
+        other
+            .priority
+            .cmp(&self.priority)
+            .then(other.order.cmp(&self.order))
     }
 }
 
@@ -31,14 +35,15 @@ impl<T> Eq for PriorityWrapper<T> {}
 
 impl<T> PartialEq for PriorityWrapper<T> {
     fn eq(&self, other: &Self) -> bool {
-        self.priority == other.priority
+        self.priority == other.priority && self.order == other.order
     }
 }
 
 impl<T> PriorityWrapper<T> {
-    pub fn new(payload: T, priority: usize) -> PriorityWrapper<T> {
+    pub fn new(payload: T, priority: usize, order: usize) -> PriorityWrapper<T> {
         PriorityWrapper {
             priority,
+            order,
             value: payload,
         }
     }
@@ -50,12 +55,14 @@ impl<T> PriorityWrapper<T> {
 */
 pub struct PriorityQueue<T: 'static> {
     pub nodes: DynamicArray<PriorityWrapper<T>>,
+    counter: usize,
 }
 
 impl<T: Clone> PriorityQueue<T> {
     pub const fn new() -> PriorityQueue<T> {
         PriorityQueue {
             nodes: DynamicArray::new(),
+            counter: 0,
         }
     }
 
@@ -64,13 +71,17 @@ impl<T: Clone> PriorityQueue<T> {
     }
 
     pub fn enqueue(&mut self, payload: T, priority: usize) {
-        let priority_wrapped_node = PriorityWrapper::new(payload, priority);
+        let priority_wrapped_node = PriorityWrapper::new(payload, priority, self.counter);
+        self.counter += 1;
         self.nodes.push(priority_wrapped_node);
         self.swim();
     }
 
     pub fn swim(&mut self) {
-        let mut index = self.nodes.length() - 1;
+        self.swim_from(self.nodes.length() - 1);
+    }
+
+    fn swim_from(&mut self, mut index: usize) {
         while index > 0 {
             let parent = (index - 1) / 2;
             if self.nodes.get_mut(index) > self.nodes.get_mut(parent) {
@@ -94,6 +105,24 @@ impl<T: Clone> PriorityQueue<T> {
         return Some(wrapper.value);
     }
 
+    // Removes the element at any index whilst keeping the heap ordered
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        if index >= self.nodes.length() {
+            return None;
+        }
+
+        let last = self.nodes.length() - 1;
+        self.nodes.swap(index, last);
+        let wrapper = self.nodes.pop().expect("Priority Queue is empty");
+
+        if index < last {
+            self.sink(index);
+            self.swim_from(index);
+        }
+
+        return Some(wrapper.value);
+    }
+
     pub fn sink(&mut self, mut index: usize) {
         let len = self.nodes.length();
         let mut left = 2 * index + 1;
@@ -106,7 +135,7 @@ impl<T: Clone> PriorityQueue<T> {
                 left
             };
 
-            if self.nodes.get_mut(largest) > self.nodes.get_mut(0) {
+            if self.nodes.get_mut(largest) > self.nodes.get_mut(index) {
                 self.nodes.swap(largest, index);
                 index = largest;
                 left = 2 * index + 1;
@@ -116,6 +145,21 @@ impl<T: Clone> PriorityQueue<T> {
         }
     }
 
+    // Recalculates the priority of every element (eg after they have been aged) and restores the heap order
+    pub fn reprioritise<F>(&mut self, priority: F)
+    where
+        F: Fn(&T) -> usize,
+    {
+        for i in 0..self.nodes.length() {
+            let node = self.nodes.get_mut(i).expect("Priority Queue is empty");
+            node.priority = priority(&node.value);
+        }
+
+        for i in (0..self.nodes.length() / 2).rev() {
+            self.sink(i);
+        }
+    }
+
     pub fn peek(&mut self) -> &mut T {
         let node =
             self.nodes.get_mut(0).expect("Priority Queue is empty") as *mut PriorityWrapper<T>;
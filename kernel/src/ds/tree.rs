@@ -44,6 +44,13 @@ impl<T: core::fmt::Debug> TreeNode<T> {
         self.children.push(child);
     }
 
+    // The removed child can be added to another node
+    pub fn remove_child(&mut self, index: usize) -> Option<TreeNode<T>> {
+        let mut child = self.children.remove(index)?;
+        child.has_parent = false;
+        Some(child)
+    }
+
     pub fn traverse<F>(&self, func: &F)
     where
         F: Fn(&TreeNode<T>),
@@ -30,16 +30,16 @@ impl<T> DynamicArray<T> {
         if self.length >= self.capacity {
             let new_capacity = self.capacity * 2;
 
-            let new_data = kmalloc(DynamicArray::<T>::calculate_capacity(DEFAULT_SIZE)) as *mut T;
+            let new_data = kmalloc(DynamicArray::<T>::calculate_capacity(new_capacity)) as *mut T;
 
             unsafe {
-                core::ptr::copy(new_data, self.data, self.length);
+                core::ptr::copy(self.data, new_data, self.length);
             }
 
             kfree(self.data as *mut usize);
 
             self.data = new_data;
-            self.capacity = 0;
+            self.capacity = new_capacity;
         }
 
         unsafe {
@@ -91,6 +91,24 @@ impl<T> DynamicArray<T> {
         }
     }
 
+    // Removes the element at an index and shifts the following elements down
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        if index >= self.length {
+            return None;
+        }
+
+        unsafe {
+            let element = core::ptr::read(self.data.add(index));
+            core::ptr::copy(
+                self.data.add(index + 1),
+                self.data.add(index),
+                self.length - index - 1,
+            );
+            self.length -= 1;
+            Some(element)
+        }
+    }
+
     fn calculate_capacity(size: usize) -> usize {
         core::mem::size_of::<T>() * size
     }
@@ -0,0 +1,135 @@
+/*
+    Block devices (eg disks) are read and written a whole sector at a time rather than a byte at a time
+    The filesystem is mounted on a single block device, a disk if one is attached and otherwise the copy of the filesystem grub loaded into memory
+*/
+
+use core::mem::size_of;
+
+use crate::memory::allocator::kmalloc;
+use crate::print_serial;
+use crate::utils::spinlock::Lock;
+
+pub const SECTOR_SIZE: usize = 512;
+
+pub trait BlockDevice {
+    fn sector_size(&self) -> usize;
+    fn capacity(&self) -> usize; // In sectors
+
+    fn read_sectors(
+        &mut self,
+        sector: usize,
+        count: usize,
+        buffer: *mut u8,
+    ) -> Result<(), &'static str>;
+    fn write_sectors(
+        &mut self,
+        sector: usize,
+        count: usize,
+        buffer: *const u8,
+    ) -> Result<(), &'static str>;
+}
+
+// Filesystem image loaded by grub (changes are lost on reboot)
+pub struct RamDisk {
+    start_addr: usize,
+    size: usize, // In bytes
+}
+
+impl RamDisk {
+    pub fn new(start_addr: usize, size: usize) -> RamDisk {
+        RamDisk { start_addr, size }
+    }
+
+    fn check_range(&self, sector: usize, count: usize) -> Result<usize, &'static str> {
+        if (sector + count) * SECTOR_SIZE > self.size {
+            return Err("Sector is past the end of the ram disk");
+        }
+
+        Ok(self.start_addr + sector * SECTOR_SIZE)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn capacity(&self) -> usize {
+        self.size / SECTOR_SIZE
+    }
+
+    fn read_sectors(
+        &mut self,
+        sector: usize,
+        count: usize,
+        buffer: *mut u8,
+    ) -> Result<(), &'static str> {
+        let addr = self.check_range(sector, count)?;
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(addr as *const u8, buffer, count * SECTOR_SIZE);
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(
+        &mut self,
+        sector: usize,
+        count: usize,
+        buffer: *const u8,
+    ) -> Result<(), &'static str> {
+        let addr = self.check_range(sector, count)?;
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(buffer, addr as *mut u8, count * SECTOR_SIZE);
+        }
+
+        Ok(())
+    }
+}
+
+// Device the filesystem is mounted on
+pub static DISK: Lock<Option<&'static mut dyn BlockDevice>> = Lock::new(None);
+
+// Moves the device onto the kernel heap so it lives as long as the kernel
+pub fn mount<T: BlockDevice + 'static>(device: T) {
+    let device_ptr = kmalloc(size_of::<T>()) as *mut T;
+
+    unsafe {
+        core::ptr::write(device_ptr, device);
+        *DISK.lock() = Some(&mut *device_ptr);
+    }
+    DISK.free();
+}
+
+// Errors from the device (eg a timeout) are logged and passed up to the syscall which caused the access
+pub fn read_sectors(sector: usize, count: usize, buffer: *mut u8) -> Result<(), &'static str> {
+    let result = DISK
+        .lock()
+        .as_mut()
+        .expect("Error: No disk mounted")
+        .read_sectors(sector, count, buffer);
+    DISK.free();
+
+    if let Err(error) = result {
+        print_serial!("Error: Failed to read sector {}: {}\n", sector, error);
+    }
+
+    result
+}
+
+pub fn write_sectors(sector: usize, count: usize, buffer: *const u8) -> Result<(), &'static str> {
+    let result = DISK
+        .lock()
+        .as_mut()
+        .expect("Error: No disk mounted")
+        .write_sectors(sector, count, buffer);
+    DISK.free();
+
+    if let Err(error) = result {
+        print_serial!("Error: Failed to write sector {}: {}\n", sector, error);
+    }
+
+    result
+}
@@ -0,0 +1,302 @@
+/*
+    The buffer cache keeps recently used sectors of the disk in memory so the filesystem rarely has to wait for the disk
+    Writes only change the cached copy (marking it dirty) and are written back when the buffer is evicted, synced or by the flush thread
+    Once every buffer is in use the least recently used one is evicted
+    Buffers are found by hashing their sector into a bucket which chains together the buffers of that bucket
+*/
+
+use core::mem::size_of;
+
+use crate::fs::block::{self, SECTOR_SIZE};
+use crate::interrupts::pit::{self, PIT};
+use crate::memory::allocator::kmalloc;
+use crate::memory::page_frame_allocator::{self, PAGE_FRAME_ALLOCATOR};
+use crate::multitask::wait_queue;
+use crate::print_serial;
+use crate::utils::spinlock::Lock;
+
+// The cache takes this fraction of the memory which is free once the filesystem is mounted
+const MEMORY_FRACTION: usize = 16;
+const MIN_BUFFERS: usize = 64;
+const MAX_BUFFERS: usize = 8192; // 4MB of sectors
+
+const FLUSH_INTERVAL_SECONDS: usize = 5;
+
+#[derive(Debug, Clone, Copy)]
+struct Buffer {
+    sector: usize,
+    data: *mut u8,
+    last_used: usize,
+    is_valid: bool,
+    is_dirty: bool,
+    next: Option<usize>, // Next buffer within the same bucket
+}
+
+pub struct BufferCache {
+    buffers: *mut Buffer,
+    buckets: *mut Option<usize>, // Index of the first buffer in each bucket
+    count: usize,
+    clock: usize, // Increases on every access to order the buffers by when they were last used
+}
+
+impl BufferCache {
+    pub const fn new() -> BufferCache {
+        BufferCache {
+            buffers: core::ptr::null_mut(),
+            buckets: core::ptr::null_mut(),
+            count: 0,
+            clock: 0,
+        }
+    }
+
+    pub fn init(&mut self) {
+        let free_memory = PAGE_FRAME_ALLOCATOR.lock().free_memory();
+        PAGE_FRAME_ALLOCATOR.free();
+
+        self.count = (free_memory / MEMORY_FRACTION / SECTOR_SIZE).clamp(MIN_BUFFERS, MAX_BUFFERS);
+
+        let pages = page_frame_allocator::get_number_of_pages(
+            page_frame_allocator::round_to_nearest_page(self.count * SECTOR_SIZE),
+        );
+        let data = PAGE_FRAME_ALLOCATOR.lock().alloc_page_frames(pages) as *mut u8;
+        PAGE_FRAME_ALLOCATOR.free();
+
+        self.buffers = kmalloc(self.count * size_of::<Buffer>()) as *mut Buffer;
+        self.buckets = kmalloc(self.count * size_of::<Option<usize>>()) as *mut Option<usize>;
+
+        for i in 0..self.count {
+            unsafe {
+                self.buffers.add(i).write(Buffer {
+                    sector: 0,
+                    data: data.add(i * SECTOR_SIZE),
+                    last_used: 0,
+                    is_valid: false,
+                    is_dirty: false,
+                    next: None,
+                });
+                self.buckets.add(i).write(None);
+            }
+        }
+
+        print_serial!("Buffer cache holds {} sectors\n", self.count);
+    }
+
+    fn buffer(&self, index: usize) -> &'static mut Buffer {
+        unsafe { &mut *self.buffers.add(index) }
+    }
+
+    fn bucket(&self, sector: usize) -> &'static mut Option<usize> {
+        unsafe { &mut *self.buckets.add(sector % self.count) }
+    }
+
+    fn find(&self, sector: usize) -> Option<usize> {
+        let mut current = *self.bucket(sector);
+
+        while let Some(index) = current {
+            let buffer = self.buffer(index);
+            if buffer.sector == sector {
+                return Some(index);
+            }
+
+            current = buffer.next;
+        }
+
+        None
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let sector = self.buffer(index).sector;
+        let mut link = self.bucket(sector);
+
+        while let Some(current) = *link {
+            if current == index {
+                *link = self.buffer(index).next;
+                return;
+            }
+
+            link = &mut self.buffer(current).next;
+        }
+    }
+
+    // Unused buffers are taken first and otherwise the least recently used one
+    fn least_recently_used(&self) -> usize {
+        let mut oldest = 0;
+
+        for i in 0..self.count {
+            let buffer = self.buffer(i);
+            if !buffer.is_valid {
+                return i;
+            }
+
+            if buffer.last_used < self.buffer(oldest).last_used {
+                oldest = i;
+            }
+        }
+
+        oldest
+    }
+
+    // Buffers which fail to be written stay dirty so they are tried again later
+    fn write_back(&self, index: usize) -> Result<(), &'static str> {
+        let buffer = self.buffer(index);
+
+        if buffer.is_valid && buffer.is_dirty {
+            block::write_sectors(buffer.sector, 1, buffer.data)?;
+            buffer.is_dirty = false;
+        }
+
+        Ok(())
+    }
+
+    /*
+        Returns the buffer holding the sector, evicting another if it isn't cached
+        The sector is only read from the disk if it is going to be read (not if it is about to be overwritten)
+    */
+    fn get(
+        &mut self,
+        sector: usize,
+        should_read: bool,
+    ) -> Result<&'static mut Buffer, &'static str> {
+        self.clock += 1;
+
+        let index = match self.find(sector) {
+            Some(index) => index,
+            None => {
+                let index = self.least_recently_used();
+                self.write_back(index)?;
+
+                if self.buffer(index).is_valid {
+                    self.unlink(index);
+                }
+
+                let buffer = self.buffer(index);
+                buffer.sector = sector;
+                buffer.is_valid = true;
+                buffer.next = *self.bucket(sector);
+                *self.bucket(sector) = Some(index);
+
+                // A buffer which couldn't be filled mustn't be found later
+                if should_read {
+                    if let Err(error) = block::read_sectors(sector, 1, buffer.data) {
+                        self.unlink(index);
+                        self.buffer(index).is_valid = false;
+                        return Err(error);
+                    }
+                }
+
+                index
+            }
+        };
+
+        let buffer = self.buffer(index);
+        buffer.last_used = self.clock;
+        Ok(buffer)
+    }
+
+    pub fn read(
+        &mut self,
+        sector: usize,
+        count: usize,
+        buffer: *mut u8,
+    ) -> Result<(), &'static str> {
+        for i in 0..count {
+            let cached = self.get(sector + i, true)?;
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    cached.data,
+                    buffer.add(i * SECTOR_SIZE),
+                    SECTOR_SIZE,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn write(
+        &mut self,
+        sector: usize,
+        count: usize,
+        buffer: *const u8,
+    ) -> Result<(), &'static str> {
+        for i in 0..count {
+            let cached = self.get(sector + i, false)?;
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    buffer.add(i * SECTOR_SIZE),
+                    cached.data,
+                    SECTOR_SIZE,
+                );
+            }
+
+            cached.is_dirty = true;
+        }
+
+        Ok(())
+    }
+
+    // Writes back the dirty buffers of the given sectors
+    pub fn flush(&mut self, sector: usize, count: usize) -> Result<(), &'static str> {
+        for i in 0..count {
+            if let Some(index) = self.find(sector + i) {
+                self.write_back(index)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes back every dirty buffer (carrying on past any which fail) and returns the last error
+    pub fn sync(&mut self) -> Result<(), &'static str> {
+        let mut result = Ok(());
+
+        for i in 0..self.count {
+            if let Err(error) = self.write_back(i) {
+                result = Err(error);
+            }
+        }
+
+        result
+    }
+}
+
+pub static BUFFER_CACHE: Lock<BufferCache> = Lock::new(BufferCache::new());
+
+pub fn read_sectors(sector: usize, count: usize, buffer: *mut u8) -> Result<(), &'static str> {
+    let result = BUFFER_CACHE.lock().read(sector, count, buffer);
+    BUFFER_CACHE.free();
+    result
+}
+
+pub fn write_sectors(sector: usize, count: usize, buffer: *const u8) -> Result<(), &'static str> {
+    let result = BUFFER_CACHE.lock().write(sector, count, buffer);
+    BUFFER_CACHE.free();
+    result
+}
+
+pub fn flush_sectors(sector: usize, count: usize) -> Result<(), &'static str> {
+    let result = BUFFER_CACHE.lock().flush(sector, count);
+    BUFFER_CACHE.free();
+    result
+}
+
+pub fn sync() -> Result<(), &'static str> {
+    let result = BUFFER_CACHE.lock().sync();
+    BUFFER_CACHE.free();
+    result
+}
+
+// Kernel thread which writes back dirty buffers every few seconds so little is lost if the machine stops
+pub extern "C" fn flush_thread() -> ! {
+    loop {
+        let ticks = PIT.lock().ticks();
+        PIT.free();
+
+        wait_queue::sleep_until(ticks + FLUSH_INTERVAL_SECONDS * pit::FREQUENCY);
+
+        // Buffers which failed are logged and stay dirty for the next flush
+        let _ = sync();
+    }
+}
@@ -1,14 +1,61 @@
+/*
+    FAT (File Allocation Table) splits the data region of a disk into clusters which hold the contents of files and directories
+    The FAT itself has an entry for every cluster which gives the next cluster of the file (a cluster chain) or marks the end of it
+    The width of an entry gives the type of the filesystem (FAT12, FAT16 or FAT32) which depends on the number of clusters
+    +-----------------+------+-----+--------------------------------+----------------------+
+    | Reserved (boot) | FAT  | FAT | Root directory (FAT12/16 only) | Data region          |
+    +-----------------+------+-----+--------------------------------+----------------------+
+    Every position is stored in sectors of the block device (which may be smaller than the sectors of the filesystem)
+*/
+
 use core::ffi::CStr;
 use core::{fmt::Error, mem::size_of, ptr};
 
-use crate::fs::fat;
+use crate::fs::block::{BlockDevice, SECTOR_SIZE};
+use crate::fs::{cache, fat};
 use crate::utils::string;
-use crate::{memory::allocator::kmalloc, print_serial};
-
-const SECTORS_PER_CLUSTER: usize = 4;
-const BYTES_PER_SECTOR: usize = 512;
-pub const BYTES_PER_CLUSTER: usize = 2048;
-const BYTES_PER_FAT: usize = 10240;
+use crate::{either, memory::allocator::kmalloc, print_serial};
+
+// Filesystems with fewer clusters than these are FAT12 or FAT16
+const FAT12_MAX_CLUSTERS: usize = 4085;
+const FAT16_MAX_CLUSTERS: usize = 65525;
+
+const FIRST_CLUSTER: usize = 2; // Clusters 0 and 1 are reserved
+
+pub const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+pub const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+pub const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+pub const ATTRIBUTE_LONG_NAME: u8 = 0x0F; // Read only, hidden, system and volume id together mark a long entry
+
+// First byte of the name of an entry
+pub const ENTRY_END: u8 = 0x00; // No entries follow
+pub const ENTRY_DELETED: u8 = 0xE5;
+
+pub const SHORT_NAME_LENGTH: usize = 11;
+pub const LONG_NAME_LENGTH: usize = 255;
+pub const CHARACTERS_PER_LONG_ENTRY: usize = 13;
+pub const MAX_LONG_ENTRIES: usize = 20; // Enough for 255 characters
+
+const LONG_ENTRY_LAST: u8 = 0x40; // Set on the order of the final part of the name (which is stored first)
+const LONG_ENTRY_ORDER_MASK: u8 = 0x1F;
+
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+const BOOT_SIGNATURE: u16 = 0xAA55;
+
+// The FSInfo sector of FAT32 keeps a count of free clusters and a hint of where to look for the next one
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x41615252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x61417272;
+const FS_INFO_STRUCT_SIGNATURE_OFFSET: usize = 484;
+const FS_INFO_FREE_COUNT_OFFSET: usize = 488;
+const FS_INFO_NEXT_FREE_OFFSET: usize = 492;
+const FS_INFO_UNKNOWN: u32 = 0xFFFFFFFF;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
 
 // Boot record occupies one sector and is at the start
 #[derive(Debug, Copy, Clone)]
@@ -20,16 +67,17 @@ struct BiosParameterBlock {
     sectors_per_cluster: u8,
     reserved_sector_count: u16,
     table_count: u8,
-    root_entry_count: u16,
-    sector_count_16: u16,
+    root_entry_count: u16, // 0 for FAT32
+    sector_count_16: u16, // 0 if there are more than 65535 sectors (large_sector_count is used instead)
     media_type: u8,
-    table_size_16: u16,     // Number of sectors per FAT
+    table_size_16: u16,     // Number of sectors per FAT (0 for FAT32)
     sectors_per_track: u16, // Number of sectors per track
     head_count: u16,
     hidden_sector_count: u32,
     large_sector_count: u32,
 }
 
+// Follows the BPB on FAT12 and FAT16
 #[derive(Debug, Copy, Clone)]
 #[repr(C, packed)]
 struct ExtendedBootRecord {
@@ -43,6 +91,27 @@ struct ExtendedBootRecord {
     bootable_partition_signature: u16,
 }
 
+// Follows the BPB on FAT32
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+struct ExtendedBootRecord32 {
+    table_size_32: u32, // Number of sectors per FAT
+    extended_flags: u16,
+    version: u16,
+    root_cluster: u32, // First cluster of the root directory
+    fs_info_sector: u16,
+    backup_boot_sector: u16,
+    reserved: [u8; 12],
+    drive_number: u8,
+    nt_flags: u8,
+    signature: u8,
+    serial: u32,
+    volume_label: [u8; 11],
+    system_ud_string: u64,
+    bootcode: [u8; 420],
+    bootable_partition_signature: u16,
+}
+
 // Stores information on where a file's data/folder are stored on disk along with name, size, creation
 #[derive(Debug, Copy, Clone)]
 #[repr(C)]
@@ -51,7 +120,7 @@ pub struct FileEntry {
     pub ext: [u8; 3],
     pub attributes: u8, // Could be LFN, Directory, Archive
     unused: [u8; 8],    // Reserved for windows NT
-    cluster_high: u16,  // Always 0
+    cluster_high: u16,  // Always 0 on FAT12 and FAT16
     time: u16,
     date: u16,
     pub cluster_low: u16,
@@ -86,123 +155,596 @@ impl FileEntry {
             size: 0,
         }
     }
+
+    pub fn cluster(&self) -> usize {
+        (self.cluster_high as usize) << 16 | self.cluster_low as usize
+    }
+
+    pub fn set_cluster(&mut self, cluster: usize) {
+        self.cluster_high = (cluster >> 16) as u16;
+        self.cluster_low = cluster as u16;
+    }
+
+    // Name and extension as they are stored (padded with spaces)
+    pub fn short_name(&self) -> [u8; SHORT_NAME_LENGTH] {
+        let mut short_name = [0; SHORT_NAME_LENGTH];
+        short_name[..8].copy_from_slice(&self.filename);
+        short_name[8..].copy_from_slice(&self.ext);
+        short_name
+    }
+
+    pub fn set_short_name(&mut self, short_name: &[u8; SHORT_NAME_LENGTH]) {
+        self.filename.copy_from_slice(&short_name[..8]);
+        self.ext.copy_from_slice(&short_name[8..]);
+    }
 }
 
-impl BiosParameterBlock {
-    pub fn verify(&self) {
-        assert!(
-            self.jmp[0] == 0xEB && self.jmp[1] == 0x3C && self.jmp[2] == 0x90,
-            "Error: Invalid JMP sequence"
-        );
+impl LongFileEntry {
+    pub fn new(
+        order: usize,
+        is_last: bool,
+        checksum: u8,
+        characters: &[u16; CHARACTERS_PER_LONG_ENTRY],
+    ) -> LongFileEntry {
+        let mut name_start = [0; 5];
+        let mut name_middle = [0; 6];
+        let mut name_end = [0; 2];
+        name_start.copy_from_slice(&characters[..5]);
+        name_middle.copy_from_slice(&characters[5..11]);
+        name_end.copy_from_slice(&characters[11..]);
+
+        LongFileEntry {
+            order: order as u8 | either!(is_last => LONG_ENTRY_LAST; 0),
+            name_start,
+            attribute: ATTRIBUTE_LONG_NAME,
+            long_entry_type: 0,
+            checksum,
+            name_middle,
+            zero: 0,
+            name_end,
+        }
+    }
 
-        assert!(
-            self.bytes_per_sector == BYTES_PER_SECTOR as u16,
-            "Error: Bytes per sector is not 512"
-        );
+    // Position of this part of the name starting from 1
+    pub fn order(&self) -> usize {
+        (self.order & LONG_ENTRY_ORDER_MASK) as usize
+    }
 
-        assert!(
-            self.sectors_per_cluster == SECTORS_PER_CLUSTER as u8,
-            "Error: Sectors per cluster is not 4"
-        );
+    pub fn is_last(&self) -> bool {
+        self.order & LONG_ENTRY_LAST != 0
+    }
 
-        assert!(self.table_count == 2, "Error: Table count is not 2");
+    pub fn checksum(&self) -> u8 {
+        self.checksum
+    }
 
-        assert!(self.table_size_16 == 20, "Error: Table size is not 20");
+    pub fn characters(&self) -> [u16; CHARACTERS_PER_LONG_ENTRY] {
+        let (name_start, name_middle, name_end) =
+            (self.name_start, self.name_middle, self.name_end);
 
-        // field is set if there are more than 65535 sectors in the volume this may cause problems *CHECK*
-        assert!(
-            self.large_sector_count == 0,
-            "Error: Invalid Large Sector Count"
-        );
+        let mut characters = [0; CHARACTERS_PER_LONG_ENTRY];
+        characters[..5].copy_from_slice(&name_start);
+        characters[5..11].copy_from_slice(&name_middle);
+        characters[11..].copy_from_slice(&name_end);
+        characters
     }
 }
 
-impl ExtendedBootRecord {
-    pub fn verify(&self) {
-        assert!(
-            self.signature == 0x29 || self.signature == 0x28,
-            "Error: Invalid Signature"
-        );
+// Every long entry holds this checksum of the short name so entries left behind by a system without long names are spotted
+pub fn short_name_checksum(short_name: &[u8; SHORT_NAME_LENGTH]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
 
-        assert!(
-            self.bootable_partition_signature == 0xAA55,
-            "Error: Invalid Bootable Signature"
-        );
+// Short names only hold uppercase letters, digits and these symbols
+fn is_short_name_character(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/*
+    Converts a name into the 8.3 form of a short entry and returns whether the name can be read back from it unchanged
+    Short names are read back in lowercase so any uppercase letters also need a long name to keep them
+    Characters a short name can't hold become an underscore whilst spaces and extra periods are dropped
+*/
+pub fn convert_to_short_name(name: &str) -> ([u8; SHORT_NAME_LENGTH], bool) {
+    let mut short_name = [b' '; SHORT_NAME_LENGTH];
+
+    let (base, ext) = match name.rfind('.') {
+        Some(index) if index > 0 => (&name[..index], &name[index + 1..]),
+        _ => (name, ""),
+    };
+
+    let mut is_exact = !base.is_empty();
+
+    for (part, start, length) in [(base, 0, 8), (ext, 8, 3)] {
+        let mut position = 0;
+
+        for c in part.chars() {
+            if c == ' ' || c == '.' {
+                is_exact = false;
+                continue;
+            }
+
+            if position == length {
+                is_exact = false;
+                break;
+            }
+
+            if !is_short_name_character(c) || c.is_ascii_uppercase() {
+                is_exact = false;
+            }
+
+            short_name[start + position] =
+                either!(is_short_name_character(c) => c.to_ascii_uppercase() as u8; b'_');
+            position += 1;
+        }
     }
+
+    (short_name, is_exact)
 }
 
-pub fn convert_sector_to_bytes(sector: usize) -> usize {
-    return sector * BYTES_PER_SECTOR;
+// Ends the base of a short name with ~N so it differs from others which started the same (eg LONGFI~1.TXT)
+pub fn add_numeric_tail(short_name: &mut [u8; SHORT_NAME_LENGTH], n: usize) {
+    let mut digits = [0u8; 20];
+    let mut digit_count = 0;
+    let mut value = n;
+
+    loop {
+        digits[digit_count] = b'0' + (value % 10) as u8;
+        digit_count += 1;
+        value /= 10;
+
+        if value == 0 {
+            break;
+        }
+    }
+
+    let base_length = short_name[..8].iter().position(|&c| c == b' ').unwrap_or(8);
+    let start = base_length.min(8 - (digit_count + 1));
+
+    short_name[start] = b'~';
+    for i in 0..digit_count {
+        short_name[start + 1 + i] = digits[digit_count - 1 - i];
+    }
 }
 
-pub fn get_next_cluster(fat_addr: usize, active_cluster: usize) -> Option<(usize)> {
-    let fat_offset = active_cluster * 2;
+impl BiosParameterBlock {
+    pub fn verify(&self) -> Result<(), &'static str> {
+        // Either a short jump followed by a nop or a near jump
+        if !((self.jmp[0] == 0xEB && self.jmp[2] == 0x90) || self.jmp[0] == 0xE9) {
+            return Err("Invalid JMP sequence");
+        }
+
+        let bytes_per_sector = self.bytes_per_sector as usize;
+        if !(bytes_per_sector.is_power_of_two()
+            && bytes_per_sector >= SECTOR_SIZE
+            && bytes_per_sector <= 4096)
+        {
+            return Err("Bytes per sector must be 512, 1024, 2048 or 4096");
+        }
+
+        if !self.sectors_per_cluster.is_power_of_two() {
+            return Err("Sectors per cluster is not a power of 2");
+        }
+
+        if self.table_count == 0 {
+            return Err("There are no FATs");
+        }
 
-    let next_cluster = read_fat(fat_addr, fat_offset) as usize;
+        if self.sector_count_16 == 0 && self.large_sector_count == 0 {
+            return Err("Invalid Sector Count");
+        }
 
-    match next_cluster {
-        0x00 => panic!("Error: Empty cluster?"),
-        0xFFF7 => panic!("Error: Bad cluster when reading cluster!"), // Indicates bad cluster
-        0xFFF8..=0xFFFF => None, // Indicates the whole file has been read
-        _ => Some(next_cluster), // Gives next cluster number
+        Ok(())
     }
 }
 
-pub fn write_fat(fat_addr: usize, cluster: usize, next_cluster: usize) {
-    let fat_offset = cluster * 2;
-    let fat = unsafe { &mut *(fat_addr as *mut [u8; 512]) };
-    fat[fat_offset] = (next_cluster & 0x00FF) as u8;
-    fat[fat_offset + 1] = ((next_cluster & 0xFF00) >> 8) as u8;
+impl ExtendedBootRecord {
+    pub fn verify(&self) -> Result<(), &'static str> {
+        either!(self.signature == 0x29 || self.signature == 0x28 => Ok(()); Err("Invalid Signature"))
+    }
 }
 
-pub fn find_free_cluster(fat_addr: usize) -> Option<usize> {
-    let fat = unsafe { &mut *(fat_addr as *mut [u8; 512]) };
-    for i in 0..(BYTES_PER_FAT / 2) {
-        if ((fat[i + 1] as u16) << 8 | (fat[i] as u16)) == 0 {
-            fat[i] = 0xFF;
-            fat[i + 1] = 0xFF;
-            return Some(i as usize);
+impl ExtendedBootRecord32 {
+    pub fn verify(&self) -> Result<(), &'static str> {
+        if self.signature != 0x29 && self.signature != 0x28 {
+            return Err("Invalid Signature");
         }
+
+        either!(self.version == 0 => Ok(()); Err("Unknown FAT32 version"))
     }
-    None
 }
 
-fn read_fat(fat_addr: usize, fat_offset: usize) -> u16 {
-    let sector_num = fat_offset / 512;
-    let byte_offset = fat_offset % 512;
+// Layout of a FAT filesystem taken from its boot record
+#[derive(Debug, Copy, Clone)]
+pub struct FatVolume {
+    pub fat_type: FatType,
+    pub bytes_per_cluster: usize,
+    sectors_per_cluster: usize,
+    fat_sector: usize, // First sector of the first FAT
+    sectors_per_fat: usize,
+    fat_count: usize,
+    rd_sector: usize, // Fixed root directory of FAT12 and FAT16
+    rd_sectors: usize,
+    ds_sector: usize,        // Cluster 2 starts the data region
+    pub root_cluster: usize, // 0 when the root directory is the fixed region
+    cluster_count: usize,
+    fs_info_sector: Option<usize>,
+    next_free_cluster: usize, // Where the search for a free cluster starts
+}
+
+impl FatVolume {
+    pub const fn new() -> FatVolume {
+        FatVolume {
+            fat_type: FatType::Fat16,
+            bytes_per_cluster: 0,
+            sectors_per_cluster: 0,
+            fat_sector: 0,
+            sectors_per_fat: 0,
+            fat_count: 0,
+            rd_sector: 0,
+            rd_sectors: 0,
+            ds_sector: 0,
+            root_cluster: 0,
+            cluster_count: 0,
+            fs_info_sector: None,
+            next_free_cluster: FIRST_CLUSTER,
+        }
+    }
+
+    // Values at or above these end a cluster chain and the one below marks a bad cluster
+    fn end_of_chain(&self) -> usize {
+        match self.fat_type {
+            FatType::Fat12 => 0xFF8,
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFFFFF8,
+        }
+    }
+
+    /*
+        Returns the byte offset of the entry of a cluster within the FAT along with how many bytes hold it
+        FAT12 entries are 1.5 bytes so 2 bytes are read and the entry is the top or bottom 12 bits of them
+    */
+    fn entry_location(&self, cluster: usize) -> (usize, usize) {
+        match self.fat_type {
+            FatType::Fat12 => (cluster + cluster / 2, 2),
+            FatType::Fat16 => (cluster * 2, 2),
+            FatType::Fat32 => (cluster * 4, 4),
+        }
+    }
+
+    // FAT12 entries may cross into the next sector so up to 2 sectors are read
+    fn read_fat(&self, cluster: usize) -> Result<usize, &'static str> {
+        let (offset, width) = self.entry_location(cluster);
+        let sector = self.fat_sector + offset / SECTOR_SIZE;
+        let byte_offset = offset % SECTOR_SIZE;
+        let sectors = either!(byte_offset + width > SECTOR_SIZE => 2; 1);
+
+        let mut fat = [0u8; 2 * SECTOR_SIZE];
+        cache::read_sectors(sector, sectors, fat.as_mut_ptr())?;
+
+        let mut value = 0;
+        for i in 0..width {
+            value |= (fat[byte_offset + i] as usize) << (8 * i);
+        }
+
+        Ok(match self.fat_type {
+            FatType::Fat12 => either!(cluster % 2 == 1 => value >> 4; value & 0xFFF),
+            FatType::Fat16 => value,
+            FatType::Fat32 => value & 0x0FFFFFFF, // Top 4 bits are reserved
+        })
+    }
+
+    // Every copy of the FAT is kept the same
+    pub fn write_fat(&self, cluster: usize, next_cluster: usize) -> Result<(), &'static str> {
+        let (offset, width) = self.entry_location(cluster);
+        let byte_offset = offset % SECTOR_SIZE;
+        let sectors = either!(byte_offset + width > SECTOR_SIZE => 2; 1);
+
+        for copy in 0..self.fat_count {
+            let sector = self.fat_sector + copy * self.sectors_per_fat + offset / SECTOR_SIZE;
 
-    let fat = unsafe { &*((fat_addr + convert_sector_to_bytes(sector_num)) as *const [u8; 512]) };
-    return ((fat[byte_offset + 1] as u16) << 8) | (fat[byte_offset] as u16);
+            let mut fat = [0u8; 2 * SECTOR_SIZE];
+            cache::read_sectors(sector, sectors, fat.as_mut_ptr())?;
+
+            let mut value = 0;
+            for i in 0..width {
+                value |= (fat[byte_offset + i] as usize) << (8 * i);
+            }
+
+            value = match self.fat_type {
+                FatType::Fat12 if cluster % 2 == 1 => (value & 0x000F) | (next_cluster << 4),
+                FatType::Fat12 => (value & 0xF000) | (next_cluster & 0xFFF),
+                FatType::Fat16 => next_cluster & 0xFFFF,
+                FatType::Fat32 => (value & 0xF0000000) | (next_cluster & 0x0FFFFFFF),
+            };
+
+            for i in 0..width {
+                fat[byte_offset + i] = (value >> (8 * i)) as u8;
+            }
+
+            cache::write_sectors(sector, sectors, fat.as_ptr())?;
+        }
+
+        Ok(())
+    }
+
+    // A chain which runs into a free or bad cluster is corrupt
+    pub fn get_next_cluster(&self, active_cluster: usize) -> Result<Option<usize>, &'static str> {
+        let next_cluster = self.read_fat(active_cluster)?;
+
+        match next_cluster {
+            0x00 => Err("Cluster chain runs into a free cluster"),
+            _ if next_cluster == self.end_of_chain() - 1 => {
+                Err("Cluster chain runs into a bad cluster")
+            }
+            _ if next_cluster >= self.end_of_chain() => Ok(None), // Indicates the whole file has been read
+            _ => Ok(Some(next_cluster)),                          // Gives next cluster number
+        }
+    }
+
+    // Marks the first free cluster as the end of a chain (None if the disk is full)
+    pub fn find_free_cluster(&mut self) -> Result<Option<usize>, &'static str> {
+        let last_cluster = FIRST_CLUSTER + self.cluster_count;
+
+        // Starts from where the last free cluster was found and wraps around
+        let start = self
+            .next_free_cluster
+            .clamp(FIRST_CLUSTER, last_cluster - 1);
+        let clusters = (start..last_cluster).chain(FIRST_CLUSTER..start);
+
+        for cluster in clusters {
+            if self.read_fat(cluster)? != 0 {
+                continue;
+            }
+
+            self.write_fat(cluster, self.end_of_chain())?;
+            self.next_free_cluster = cluster + 1;
+            self.update_fs_info(-1)?;
+
+            return Ok(Some(cluster));
+        }
+
+        Ok(None)
+    }
+
+    // Marks every cluster of a chain as free in every FAT
+    pub fn free_cluster_chain(&mut self, first_cluster: usize) -> Result<(), &'static str> {
+        let mut current_cluster = Some(first_cluster);
+        let mut freed_clusters = 0;
+
+        while let Some(cluster) = current_cluster {
+            current_cluster = self.get_next_cluster(cluster)?;
+            self.write_fat(cluster, 0)?;
+
+            self.next_free_cluster = self.next_free_cluster.min(cluster);
+            freed_clusters += 1;
+        }
+
+        self.update_fs_info(freed_clusters)
+    }
+
+    // Ends a chain at the given cluster and frees the clusters which followed it
+    pub fn truncate_cluster_chain(&mut self, last_cluster: usize) -> Result<(), &'static str> {
+        if let Some(next_cluster) = self.get_next_cluster(last_cluster)? {
+            self.write_fat(last_cluster, self.end_of_chain())?;
+            self.free_cluster_chain(next_cluster)?;
+        }
+
+        Ok(())
+    }
+
+    // Keeps the free cluster count and next free hint of FAT32 up to date
+    fn update_fs_info(&self, change: isize) -> Result<(), &'static str> {
+        let sector = match self.fs_info_sector {
+            Some(sector) => sector,
+            None => return Ok(()),
+        };
+
+        let mut fs_info = [0u8; SECTOR_SIZE];
+        cache::read_sectors(sector, 1, fs_info.as_mut_ptr())?;
+
+        let free_count = read_u32(&fs_info, FS_INFO_FREE_COUNT_OFFSET);
+        if free_count != FS_INFO_UNKNOWN {
+            let free_count = (free_count as isize + change) as u32;
+            fs_info[FS_INFO_FREE_COUNT_OFFSET..FS_INFO_FREE_COUNT_OFFSET + 4]
+                .copy_from_slice(&free_count.to_le_bytes());
+        }
+
+        fs_info[FS_INFO_NEXT_FREE_OFFSET..FS_INFO_NEXT_FREE_OFFSET + 4]
+            .copy_from_slice(&(self.next_free_cluster as u32).to_le_bytes());
+
+        cache::write_sectors(sector, 1, fs_info.as_ptr())
+    }
+
+    pub fn get_sector_from_cluster(&self, cluster_num: usize) -> usize {
+        ((cluster_num - FIRST_CLUSTER) * self.sectors_per_cluster) + self.ds_sector
+    }
+
+    // Buffer must hold bytes_per_cluster bytes
+    pub fn read_cluster(&self, cluster_num: usize, buffer: *mut u8) -> Result<(), &'static str> {
+        let sector = self.get_sector_from_cluster(cluster_num);
+        cache::read_sectors(sector, self.sectors_per_cluster, buffer)
+    }
+
+    pub fn write_cluster(&self, cluster_num: usize, buffer: *const u8) -> Result<(), &'static str> {
+        let sector = self.get_sector_from_cluster(cluster_num);
+        cache::write_sectors(sector, self.sectors_per_cluster, buffer)
+    }
+
+    pub fn flush_cluster(&self, cluster_num: usize) -> Result<(), &'static str> {
+        let sector = self.get_sector_from_cluster(cluster_num);
+        cache::flush_sectors(sector, self.sectors_per_cluster)
+    }
+
+    // Size in bytes of the fixed root directory of FAT12 and FAT16
+    pub fn root_directory_size(&self) -> usize {
+        self.rd_sectors * SECTOR_SIZE
+    }
+
+    pub fn read_root_directory(&self, buffer: *mut u8) -> Result<(), &'static str> {
+        cache::read_sectors(self.rd_sector, self.rd_sectors, buffer)
+    }
+
+    pub fn write_root_directory(&self, buffer: *const u8) -> Result<(), &'static str> {
+        cache::write_sectors(self.rd_sector, self.rd_sectors, buffer)
+    }
+
+    pub fn flush_root_directory(&self) -> Result<(), &'static str> {
+        cache::flush_sectors(self.rd_sector, self.rd_sectors)
+    }
+
+    // Writes back every copy of the FAT along with the FSInfo sector
+    pub fn flush_fats(&self) -> Result<(), &'static str> {
+        cache::flush_sectors(self.fat_sector, self.fat_count * self.sectors_per_fat)?;
+
+        match self.fs_info_sector {
+            Some(sector) => cache::flush_sectors(sector, 1),
+            None => Ok(()),
+        }
+    }
 }
 
-pub fn get_sector_from_cluster(sector_addr: usize, cluster_num: usize) -> *mut u8 {
-    (((cluster_num - 2) * SECTORS_PER_CLUSTER) + sector_addr) as *mut u8
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buffer[offset],
+        buffer[offset + 1],
+        buffer[offset + 2],
+        buffer[offset + 3],
+    ])
 }
 
-pub fn init(start_addr: usize) -> (usize, usize, usize) {
-    let bpb = unsafe { &*(start_addr as *const BiosParameterBlock) };
+/*
+    Works out the layout of the filesystem from the boot record in the first sector of the disk
+    The type of FAT only depends on the number of clusters in the data region
+    Returns why the sector doesn't hold a FAT boot record (eg the disk is partitioned or holds another filesystem)
+*/
+fn read_layout(boot_sector: &[u8; SECTOR_SIZE]) -> Result<(FatVolume, usize), &'static str> {
+    let bpb = unsafe { &*(boot_sector.as_ptr() as *const BiosParameterBlock) };
+    let extended_boot_record = unsafe { boot_sector.as_ptr().add(size_of::<BiosParameterBlock>()) };
+
+    let signature = boot_sector[BOOT_SIGNATURE_OFFSET] as u16
+        | (boot_sector[BOOT_SIGNATURE_OFFSET + 1] as u16) << 8;
+    if signature != BOOT_SIGNATURE {
+        return Err("Invalid Bootable Signature");
+    }
+
+    bpb.verify()?;
 
-    // print_serial!("{:?}\n", bpb);
+    // Sectors of the filesystem are converted into sectors of the block device
+    let scale = bpb.bytes_per_sector as usize / SECTOR_SIZE;
 
-    let ebr = unsafe {
-        &*((start_addr as *mut u8).offset(size_of::<BiosParameterBlock>() as isize)
-            as *const ExtendedBootRecord)
+    let ebr32 = unsafe { &*(extended_boot_record as *const ExtendedBootRecord32) };
+    let sectors_per_fat = match bpb.table_size_16 {
+        0 => ebr32.table_size_32 as usize,
+        table_size => table_size as usize,
     };
 
-    bpb.verify();
-    ebr.verify();
+    let total_sectors = match bpb.sector_count_16 {
+        0 => bpb.large_sector_count as usize,
+        sector_count => sector_count as usize,
+    };
+
+    let rd_sectors = ((bpb.root_entry_count as usize * size_of::<FileEntry>())
+        + (bpb.bytes_per_sector as usize - 1))
+        / bpb.bytes_per_sector as usize;
 
-    let fat_addr = start_addr + convert_sector_to_bytes(bpb.reserved_sector_count as usize);
+    let fat_sector = bpb.reserved_sector_count as usize;
+    let rd_sector = fat_sector + bpb.table_count as usize * sectors_per_fat;
+    let ds_sector = rd_sector + rd_sectors;
 
-    let rd_sector_num: usize = (bpb.reserved_sector_count as usize)
-        + ((bpb.table_count as usize) * bpb.table_size_16 as usize);
+    if ds_sector >= total_sectors {
+        return Err("Data region is past the end of the filesystem");
+    }
 
-    let rd_addr: usize = start_addr + convert_sector_to_bytes(rd_sector_num);
+    let cluster_count = (total_sectors - ds_sector) / bpb.sectors_per_cluster as usize;
+
+    let fat_type = match cluster_count {
+        0..FAT12_MAX_CLUSTERS => FatType::Fat12,
+        FAT12_MAX_CLUSTERS..FAT16_MAX_CLUSTERS => FatType::Fat16,
+        _ => FatType::Fat32,
+    };
 
-    let rd_size: usize = ((((bpb.root_entry_count) * 32) + (bpb.bytes_per_sector - 1))
-        / bpb.bytes_per_sector) as usize;
+    let mut volume = FatVolume {
+        fat_type,
+        bytes_per_cluster: bpb.sectors_per_cluster as usize * bpb.bytes_per_sector as usize,
+        sectors_per_cluster: bpb.sectors_per_cluster as usize * scale,
+        fat_sector: fat_sector * scale,
+        sectors_per_fat: sectors_per_fat * scale,
+        fat_count: bpb.table_count as usize,
+        rd_sector: rd_sector * scale,
+        rd_sectors: rd_sectors * scale,
+        ds_sector: ds_sector * scale,
+        root_cluster: 0,
+        cluster_count,
+        fs_info_sector: None,
+        next_free_cluster: FIRST_CLUSTER,
+    };
+
+    if fat_type == FatType::Fat32 {
+        ebr32.verify()?;
+        volume.root_cluster = ebr32.root_cluster as usize;
+        return Ok((volume, ebr32.fs_info_sector as usize * scale));
+    }
+
+    let ebr = unsafe { &*(extended_boot_record as *const ExtendedBootRecord) };
+    ebr.verify()?;
+
+    Ok((volume, 0))
+}
+
+// Checks a disk starts with a FAT boot record before it is mounted
+pub fn probe<T: BlockDevice>(device: &mut T) -> bool {
+    let mut boot_sector = [0u8; SECTOR_SIZE];
+
+    let result = device
+        .read_sectors(0, 1, boot_sector.as_mut_ptr())
+        .and_then(|_| read_layout(&boot_sector));
+
+    if let Err(error) = result {
+        print_serial!("FAT: Disk does not hold a FAT filesystem: {}\n", error);
+    }
+
+    result.is_ok()
+}
+
+// Reads the layout of the mounted disk along with the FSInfo sector of FAT32
+pub fn init() -> FatVolume {
+    let mut boot_sector = [0u8; SECTOR_SIZE];
+    if let Err(error) = cache::read_sectors(0, 1, boot_sector.as_mut_ptr()) {
+        panic!("Error: Could not read the boot record: {}", error);
+    }
+
+    let (mut volume, fs_info_sector) = match read_layout(&boot_sector) {
+        Ok(layout) => layout,
+        Err(error) => panic!("Error: {}", error),
+    };
+
+    if volume.fat_type == FatType::Fat32 {
+        let mut fs_info = [0u8; SECTOR_SIZE];
+        let is_read = cache::read_sectors(fs_info_sector, 1, fs_info.as_mut_ptr()).is_ok();
+
+        // The FSInfo sector is optional and only trusted if it can be read and both of its signatures match
+        if is_read
+            && read_u32(&fs_info, 0) == FS_INFO_LEAD_SIGNATURE
+            && read_u32(&fs_info, FS_INFO_STRUCT_SIGNATURE_OFFSET) == FS_INFO_STRUCT_SIGNATURE
+        {
+            volume.fs_info_sector = Some(fs_info_sector);
+
+            let next_free = read_u32(&fs_info, FS_INFO_NEXT_FREE_OFFSET);
+            if next_free != FS_INFO_UNKNOWN {
+                volume.next_free_cluster = next_free as usize;
+            }
+        }
+    }
 
-    let ds_addr: usize = convert_sector_to_bytes(rd_size) + rd_addr;
+    print_serial!(
+        "FAT: Mounted {:?} with {} clusters of {} bytes\n",
+        volume.fat_type,
+        volume.cluster_count,
+        volume.bytes_per_cluster
+    );
 
-    return (fat_addr, rd_addr, ds_addr);
+    volume
 }
@@ -1,16 +1,33 @@
+use block::{BlockDevice, RamDisk};
 use vfs::VFS;
 
 use crate::{
+    dev::{ahci, ata},
     memory::{allocator::kmalloc, page_frame_allocator::PAGE_FRAME_ALLOCATOR},
-    print_serial,
+    multitask, print_serial,
 };
 
+pub mod block;
+pub mod cache;
 mod fat;
 pub mod vfs;
 
-pub fn init(start_addr: usize) {
-    let values = fat::init(start_addr);
-    VFS.lock().init(values);
+/*
+    Mounts the filesystem from the first disk found which holds a FAT filesystem (without a partition table)
+    Without one, the copy of the filesystem loaded by grub is used instead
+*/
+pub fn init(ram_disk_addr: usize, ram_disk_size: usize) {
+    if !mount_if_fat(ahci::find_disk()) && !mount_if_fat(ata::find_disk()) {
+        block::mount(RamDisk::new(ram_disk_addr, ram_disk_size));
+    }
+
+    cache::BUFFER_CACHE.lock().init();
+    cache::BUFFER_CACHE.free();
+
+    multitask::spawn_kernel_thread(cache::flush_thread);
+
+    let volume = fat::init();
+    VFS.lock().init(volume);
     VFS.free();
 
     // print_serial!("attempting to find\n");
@@ -23,3 +40,18 @@ pub fn init(start_addr: usize) {
     // VFS.free();
     // print_serial!("{:?}", crate::utils::string::get_string_from_ptr(buffer));
 }
+
+// Returns false if there is no disk or it doesn't start with a FAT boot record
+fn mount_if_fat<T: BlockDevice + 'static>(disk: Option<T>) -> bool {
+    let mut disk = match disk {
+        Some(disk) => disk,
+        None => return false,
+    };
+
+    if !fat::probe(&mut disk) {
+        return false;
+    }
+
+    block::mount(disk);
+    true
+}
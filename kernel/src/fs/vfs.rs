@@ -1,8 +1,10 @@
 use core::intrinsics::size_of;
 use core::panic;
 
-use crate::fs::fat::{self, BYTES_PER_CLUSTER};
+use crate::fs::cache;
+use crate::fs::fat::{self, FatVolume};
 use crate::memory::allocator::{kfree, kmalloc};
+use crate::utils::time::Timespec;
 use crate::utils::wrapping_zero::WrappingSubZero;
 use crate::utils::{self, string};
 use crate::{ds::tree::TreeNode, utils::spinlock::Lock};
@@ -24,6 +26,9 @@ pub struct File {
     offset: usize,
     current_cluster: usize,
     f_type: FileType,
+    directory_cluster: usize, // Directory holding the file's entry (0 is the root directory of FAT12/16)
+    entry_index: usize,       // Position of the file's short entry within its directory
+    is_deleted: bool,         // Descriptors may still be open after the file has been deleted
 }
 
 impl File {
@@ -41,6 +46,9 @@ impl File {
             offset: 0,
             current_cluster,
             f_type,
+            directory_cluster: 0,
+            entry_index: 0,
+            is_deleted: false,
         }
     }
 
@@ -51,268 +59,928 @@ impl File {
     pub fn set_offset(&mut self, offset: usize) {
         self.offset = offset
     }
+
+    pub fn get_name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn get_type(&self) -> FileType {
+        self.f_type
+    }
+}
+
+// Types of file given in the mode of stat
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_REGULAR_FILE: u32 = 0o100000;
+
+const STAT_BLOCK_SIZE: usize = 512; // Blocks are counted in 512 bytes whatever the cluster size
+
+// Same layout as struct stat on x86_64 Linux (FAT keeps no owners, permissions or times which are used here)
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Stat {
+    device: usize,
+    inode: usize, // First cluster
+    links: usize,
+    mode: u32,
+    user_id: u32,
+    group_id: u32,
+    padding: u32,
+    special_device: usize,
+    size: usize,
+    block_size: usize,
+    blocks: usize,
+    access_time: Timespec,
+    modification_time: Timespec,
+    status_change_time: Timespec,
+    reserved: [usize; 3],
 }
 
 pub struct Vfs {
-    fat_addr: usize,
-    rd_addr: usize,
-    ds_addr: usize,
+    volume: FatVolume,
     root: TreeNode<File>,
 }
 
 impl Vfs {
     pub const fn new() -> Vfs {
         Vfs {
-            fat_addr: 0,
-            rd_addr: 0,
-            ds_addr: 0,
+            volume: FatVolume::new(),
             root: TreeNode::new_const(),
         }
     }
 
-    pub fn init(&mut self, values: (usize, usize, usize)) {
-        self.fat_addr = values.0;
-        self.rd_addr = values.1;
-        self.ds_addr = values.2;
+    pub fn init(&mut self, volume: FatVolume) {
+        self.volume = volume;
 
-        self.root = TreeNode::new(File::new("root", 0, FileType::Directory, 0));
+        // On FAT32 the root directory is a cluster chain like any other directory
+        self.root = TreeNode::new(File::new(
+            "root",
+            0,
+            FileType::Directory,
+            self.volume.root_cluster,
+        ));
 
-        let addr = self.rd_addr as *const u8;
         let current_node = &mut self.root.clone();
-        self.build_vfs(addr, current_node);
+        if let Err(error) = self.build_vfs(current_node) {
+            panic!(
+                "Error: Could not read the directories of the filesystem: {}",
+                error
+            );
+        }
         self.root = current_node.clone();
     }
 
-    fn build_vfs(&mut self, mut addr: *const u8, current_node: &mut TreeNode<File>) {
-        const ENTRIES_PER_CLUSTER: usize = BYTES_PER_CLUSTER / size_of::<fat::FileEntry>();
+    /*
+        Reads every entry of a directory into a kmalloc'd buffer and returns it along with its size
+        The root directory of FAT12/16 has a fixed region before the data region (cluster 0) whilst others are a cluster chain
+        Errors from the disk are passed up by this and every other function which reads or writes it
+    */
+    fn read_directory(&self, directory: &File) -> Result<(*mut u8, usize), &'static str> {
+        let size = if directory.current_cluster == 0 {
+            self.volume.root_directory_size()
+        } else {
+            let mut cluster_count = 1;
+            let mut current_cluster = directory.current_cluster;
+            while let Some(cluster) = self.volume.get_next_cluster(current_cluster)? {
+                cluster_count += 1;
+                current_cluster = cluster;
+            }
+
+            cluster_count * self.volume.bytes_per_cluster
+        };
 
-        for i in 0..ENTRIES_PER_CLUSTER {
-            match unsafe { *addr } {
-                0 => return,
-                0xE5 => {
-                    addr = unsafe { addr.add(size_of::<fat::FileEntry>()) };
+        let buffer = kmalloc(size) as *mut u8;
+        let result = if directory.current_cluster == 0 {
+            self.volume.read_root_directory(buffer)
+        } else {
+            self.read_chain(directory.current_cluster, buffer)
+        };
+
+        if let Err(error) = result {
+            kfree(buffer as *mut usize);
+            return Err(error);
+        }
+
+        Ok((buffer, size))
+    }
+
+    fn read_chain(&self, first_cluster: usize, buffer: *mut u8) -> Result<(), &'static str> {
+        let mut current_cluster = Some(first_cluster);
+        let mut cluster_buffer = buffer;
+        while let Some(cluster) = current_cluster {
+            self.volume.read_cluster(cluster, cluster_buffer)?;
+
+            cluster_buffer = unsafe { cluster_buffer.add(self.volume.bytes_per_cluster) };
+            current_cluster = self.volume.get_next_cluster(cluster)?;
+        }
+
+        Ok(())
+    }
+
+    // Writes back a buffer returned by read_directory
+    fn write_directory(&self, directory: &File, buffer: *mut u8) -> Result<(), &'static str> {
+        if directory.current_cluster == 0 {
+            return self.volume.write_root_directory(buffer);
+        }
+
+        let mut current_cluster = Some(directory.current_cluster);
+        let mut cluster_buffer = buffer;
+        while let Some(cluster) = current_cluster {
+            self.volume.write_cluster(cluster, cluster_buffer)?;
+
+            cluster_buffer = unsafe { cluster_buffer.add(self.volume.bytes_per_cluster) };
+            current_cluster = self.volume.get_next_cluster(cluster)?;
+        }
+
+        Ok(())
+    }
+
+    /*
+        Adds a node for every entry of a directory, descending into the directories found
+        The parts of a long name are stored in reverse order before the short entry and are only used if they are
+        complete and hold the checksum of the short name (otherwise they were left behind by a system without long names)
+    */
+    fn build_vfs(&mut self, current_node: &mut TreeNode<File>) -> Result<(), &'static str> {
+        let directory = unsafe { &*current_node.payload };
+        let (buffer, size) = self.read_directory(directory)?;
+        let entries = buffer as *const fat::FileEntry;
+
+        let mut long_name = [0u16; fat::MAX_LONG_ENTRIES * fat::CHARACTERS_PER_LONG_ENTRY];
+        let mut long_name_checksum = None;
+        let mut next_order = 0;
+
+        for i in 0..(size / size_of::<fat::FileEntry>()) {
+            let file_entry = unsafe { &*entries.add(i) };
+
+            match file_entry.filename[0] {
+                fat::ENTRY_END => break,
+                fat::ENTRY_DELETED => {
+                    long_name_checksum = None;
                     continue;
                 }
                 _ => {}
             }
 
-            let file_entry = unsafe { &*(addr as *const fat::FileEntry) };
+            if file_entry.attributes == fat::ATTRIBUTE_LONG_NAME {
+                let long_entry = unsafe { &*(entries.add(i) as *const fat::LongFileEntry) };
+                let order = long_entry.order();
 
-            let filename = string::convert_utf8_to_trimmed_string(&file_entry.filename);
-            let ext = string::convert_utf8_to_trimmed_string(&file_entry.ext);
+                if long_entry.is_last() {
+                    long_name = [0; fat::MAX_LONG_ENTRIES * fat::CHARACTERS_PER_LONG_ENTRY];
+                    long_name_checksum = Some(long_entry.checksum());
+                    next_order = order;
+                }
 
-            let file_type = match file_entry.attributes {
-                0x10 => FileType::Directory,
-                0x20 => FileType::File,
-                _ => break,
-            };
+                // Parts must count down to 1 without the checksum changing
+                if order == 0
+                    || order > fat::MAX_LONG_ENTRIES
+                    || order != next_order
+                    || long_name_checksum != Some(long_entry.checksum())
+                {
+                    long_name_checksum = None;
+                    continue;
+                }
 
-            if filename.starts_with(".") {
-                addr = unsafe { addr.add(size_of::<fat::FileEntry>()) };
+                let start = (order - 1) * fat::CHARACTERS_PER_LONG_ENTRY;
+                long_name[start..start + fat::CHARACTERS_PER_LONG_ENTRY]
+                    .copy_from_slice(&long_entry.characters());
+                next_order -= 1;
                 continue;
             }
 
+            let has_long_name = next_order == 0
+                && long_name_checksum == Some(fat::short_name_checksum(&file_entry.short_name()));
+            long_name_checksum = None;
+
+            // Skips the volume label along with the . and .. entries
+            if file_entry.attributes & fat::ATTRIBUTE_VOLUME_ID != 0
+                || file_entry.filename[0] == b'.'
+            {
+                continue;
+            }
+
+            let file_type = if file_entry.attributes & fat::ATTRIBUTE_DIRECTORY != 0 {
+                FileType::Directory
+            } else {
+                FileType::File
+            };
+
+            let mut name_buffer = [0u8; fat::LONG_NAME_LENGTH * 3];
+            let name = if has_long_name {
+                string::convert_utf16_to_string(&long_name, &mut name_buffer)
+            } else {
+                let filename = string::convert_utf8_to_trimmed_string(&file_entry.filename);
+                let ext = string::convert_utf8_to_trimmed_string(&file_entry.ext);
+                string::concatenate_filename_ext(filename, ext, &mut name_buffer)
+            };
+
+            let name = match name {
+                Ok(name) => name,
+                Err(error) => {
+                    panic!("Error: {}", error);
+                }
+            };
+
             let mut file = File::new(
-                filename,
+                copy_name(name),
                 file_entry.size as usize,
                 file_type,
-                file_entry.cluster_low as usize,
+                file_entry.cluster(),
             );
+            file.directory_cluster = directory.current_cluster;
+            file.entry_index = i;
 
-            match file_type {
-                FileType::Directory => {
-                    current_node.add_child(TreeNode::new(file));
+            // print_serial!("Found file {}\n", file.name);
 
-                    self.build_vfs(
-                        fat::get_sector_from_cluster(self.ds_addr, file.current_cluster),
-                        current_node.children.get_last_mut().unwrap(),
-                    );
+            current_node.add_child(TreeNode::new(file));
+
+            if file_type == FileType::Directory {
+                if let Err(error) = self.build_vfs(current_node.children.get_last_mut().unwrap()) {
+                    kfree(buffer as *mut usize);
+                    return Err(error);
                 }
-                FileType::File => {
-                    unsafe {
-                        for byte in FILE_NAME_BUFFER.iter_mut() {
-                            *byte = 0;
-                        }
-                    }
+            }
+        }
 
-                    let test = kmalloc(32) as *mut u8;
-                    let hest = unsafe { core::slice::from_raw_parts_mut(test, 32) };
+        kfree(buffer as *mut usize);
+        Ok(())
+    }
 
-                    let combined_str = unsafe {
-                        match string::concatenate_filename_ext(filename, ext, hest) {
-                            Ok(result) => result,
-                            Err(error) => {
-                                panic!("Error: {}", error);
-                            }
-                        }
-                    };
+    // Marks the short entry of a file as deleted along with the long entries before it
+    fn delete_file_from_disk(&self, file: &File) -> Result<(), &'static str> {
+        let directory = File::new("", 0, FileType::Directory, file.directory_cluster);
+        let (buffer, _) = self.read_directory(&directory)?;
+        let entries = buffer as *mut fat::FileEntry;
 
-                    file.name = combined_str.clone();
+        let file_entry = unsafe { &mut *entries.add(file.entry_index) };
+        let checksum = fat::short_name_checksum(&file_entry.short_name());
+        file_entry.filename[0] = fat::ENTRY_DELETED;
 
-                    // print_serial!("Found file {}\n", file.name);
+        for i in (0..file.entry_index).rev() {
+            let entry = unsafe { &mut *entries.add(i) };
+            let long_entry = unsafe { &*(entries.add(i) as *const fat::LongFileEntry) };
 
-                    current_node.add_child(TreeNode::new(file));
+            if entry.attributes != fat::ATTRIBUTE_LONG_NAME
+                || entry.filename[0] == fat::ENTRY_DELETED
+                || long_entry.checksum() != checksum
+            {
+                break;
+            }
+
+            entry.filename[0] = fat::ENTRY_DELETED;
+        }
+
+        let result = self.write_directory(&directory, buffer);
+        kfree(buffer as *mut usize);
+        result
+    }
+
+    /*
+        Creates an empty file or directory
+        A file's first cluster is allocated when it is first written to whilst a directory starts with one holding its . and .. entries
+        Returns None if the parent directory doesn't exist, the file already exists, there is no room for its entries or the disk fails
+    */
+    pub fn create_file(&mut self, filepath: &str, f_type: FileType) -> Option<*mut File> {
+        let (parent_path, name) = filepath.trim_end_matches("/").rsplit_once("/")?;
+
+        if name.is_empty() || self.find_path(filepath).is_some() {
+            return None;
+        }
+
+        let parent_node = self.find_node(parent_path)?;
+        let parent = unsafe { &*parent_node.payload };
+
+        if parent.f_type != FileType::Directory {
+            return None;
+        }
+
+        let (attributes, cluster) = match f_type {
+            FileType::Directory => (
+                fat::ATTRIBUTE_DIRECTORY,
+                self.create_directory_cluster(parent).ok()?,
+            ),
+            _ => (fat::ATTRIBUTE_ARCHIVE, 0),
+        };
+
+        let entry_index = match self.write_file_to_disk(name, attributes, cluster, parent) {
+            Ok(entry_index) => entry_index,
+            Err(_) => {
+                if cluster != 0 {
+                    let _ = self.volume.free_cluster_chain(cluster);
                 }
-                _ => {}
+
+                return None;
             }
+        };
+
+        let mut file = File::new(copy_name(name), 0, f_type, cluster);
+        file.directory_cluster = parent.current_cluster;
+        file.entry_index = entry_index;
+
+        parent_node.add_child(TreeNode::new(file));
+        Some(parent_node.children.get_last_mut().unwrap().payload)
+    }
+
+    // Allocates the first cluster of a new directory which holds . (itself) and .. (its parent, 0 for the root directory)
+    fn create_directory_cluster(&mut self, parent: &File) -> Result<usize, &'static str> {
+        let cluster = self.volume.find_free_cluster()?.ok_or("Disk is full")?;
+        let parent_cluster = self.parent_cluster(parent);
 
-            addr = unsafe { addr.add(size_of::<fat::FileEntry>()) };
+        let cluster_buffer = kmalloc(self.volume.bytes_per_cluster) as *mut u8;
+        unsafe {
+            core::ptr::write_bytes(cluster_buffer, 0, self.volume.bytes_per_cluster);
         }
 
-        // Note that the cluster number inside the file is not updated
-        let file = unsafe { &mut *current_node.payload };
-        if let Some(addr) = fat::get_next_cluster(self.fat_addr, file.current_cluster) {
-            self.build_vfs(addr as *const u8, current_node);
+        let entries = cluster_buffer as *mut fat::FileEntry;
+        for (i, (short_name, entry_cluster)) in
+            [(b".          ", cluster), (b"..         ", parent_cluster)]
+                .into_iter()
+                .enumerate()
+        {
+            let mut file_entry = fat::FileEntry::new();
+            file_entry.set_short_name(short_name);
+            file_entry.attributes = fat::ATTRIBUTE_DIRECTORY;
+            file_entry.set_cluster(entry_cluster);
+
+            unsafe {
+                core::ptr::write(entries.add(i), file_entry);
+            }
         }
+
+        let result = self.volume.write_cluster(cluster, cluster_buffer);
+        kfree(cluster_buffer as *mut usize);
+
+        if let Err(error) = result {
+            let _ = self.volume.free_cluster_chain(cluster);
+            return Err(error);
+        }
+
+        Ok(cluster)
     }
 
-    fn delete_file_from_disk(&mut self, file: &File, parent: &File) {
-        let mut addr = fat::get_sector_from_cluster(self.fat_addr, parent.current_cluster);
+    /*
+        Removes a file or an empty directory and frees its cluster chain
+        Open descriptors keep pointing at the removed file so it is left allocated
+    */
+    pub fn delete_file(&mut self, filepath: &str, f_type: FileType) -> bool {
+        let (parent_node, index) = match self.find_parent_node(filepath) {
+            Some(result) => result,
+            None => return false,
+        };
+
+        let node = parent_node.children.get_mut(index).unwrap();
+        let file = unsafe { &mut *node.payload };
+
+        if file.f_type != f_type || !node.children.is_empty() {
+            return false;
+        }
+
+        if self.delete_file_from_disk(file).is_err() {
+            return false;
+        }
 
-        if parent.name == "root" {
-            addr = self.ds_addr as *mut u8;
+        // The entry is already gone so clusters which fail to be freed are only lost space
+        if file.current_cluster != 0 {
+            let _ = self.volume.free_cluster_chain(file.current_cluster);
+            file.current_cluster = 0;
         }
 
-        for i in 0..(BYTES_PER_CLUSTER / size_of::<fat::FileEntry>()) {
-            let file_entry = unsafe { &*(addr as *const fat::FileEntry) };
+        file.size = 0;
+        file.is_deleted = true;
+        parent_node.remove_child(index);
+        true
+    }
 
-            if file_entry.cluster_low == file.current_cluster as u16 {
-                unsafe {
-                    core::ptr::write(addr as *mut u8, 0xE5);
+    /*
+        Moves a file or directory to a new path, replacing a file already there
+        New entries are written before the old ones are deleted so the file isn't lost if the directory is full
+    */
+    pub fn rename(&mut self, old_filepath: &str, new_filepath: &str) -> bool {
+        let (old_parent_path, _) = match old_filepath.trim_end_matches("/").rsplit_once("/") {
+            Some(result) => result,
+            None => return false,
+        };
+
+        let (new_parent_path, new_name) = match new_filepath.trim_end_matches("/").rsplit_once("/")
+        {
+            Some(result) => result,
+            None => return false,
+        };
+
+        let node = match self.find_path(old_filepath) {
+            Some(node) => node,
+            None => return false,
+        };
+        let file = unsafe { &mut *node.payload };
+
+        let new_parent_node = match self.find_node(new_parent_path) {
+            Some(new_parent_node) => new_parent_node,
+            None => return false,
+        };
+        let new_parent = unsafe { &*new_parent_node.payload };
+
+        // A directory can't be moved inside itself
+        if new_name.is_empty()
+            || new_parent.f_type != FileType::Directory
+            || is_in_subtree(&node, new_parent_node.payload)
+        {
+            return false;
+        }
+
+        let is_replacing = match self.find_path(new_filepath) {
+            Some(existing_node) => {
+                if existing_node.payload == node.payload {
+                    return true;
                 }
+
+                let existing_file = unsafe { &*existing_node.payload };
+                if file.f_type != FileType::File || existing_file.f_type != FileType::File {
+                    return false;
+                }
+
+                true
             }
+            None => false,
+        };
+
+        let attributes = match file.f_type {
+            FileType::Directory => fat::ATTRIBUTE_DIRECTORY,
+            _ => fat::ATTRIBUTE_ARCHIVE,
+        };
+        let entry_index =
+            match self.write_file_to_disk(new_name, attributes, file.current_cluster, new_parent) {
+                Ok(entry_index) => entry_index,
+                Err(_) => return false,
+            };
+
+        let mut new_file = *file;
+        new_file.directory_cluster = new_parent.current_cluster;
+        new_file.entry_index = entry_index;
 
-            addr = unsafe { addr.add(size_of::<fat::FileEntry>()) };
+        // The file being replaced is only deleted once the new entry exists so a failed write keeps it
+        // The VFS still has the moved file under its old name so the path finds the file being replaced
+        if is_replacing && !self.delete_file(new_filepath, FileType::File) {
+            let _ = self.delete_file_from_disk(&new_file);
+            return false;
         }
 
-        // Rebuild filesystem
+        // The new entry is removed again so two entries don't share the clusters
+        if self.delete_file_from_disk(file).is_err() {
+            let _ = self.delete_file_from_disk(&new_file);
+            return false;
+        }
 
-        let addr = self.rd_addr as *const u8;
-        let current_node = &mut self.root.clone();
-        self.build_vfs(addr, current_node);
-        self.root = current_node.clone();
+        let old_directory_cluster = file.directory_cluster;
+        file.name = copy_name(new_name);
+        file.directory_cluster = new_parent.current_cluster;
+        file.entry_index = entry_index;
+
+        // The file has moved either way so failing to update its entry is only reported once the nodes are moved
+        let result = if file.f_type == FileType::Directory {
+            if old_directory_cluster != new_parent.current_cluster {
+                self.set_parent_cluster(file, new_parent)
+            } else {
+                Ok(())
+            }
+        } else {
+            self.update_entry(file)
+        };
+
+        // The name has changed so the node is found by its file
+        let old_parent_node = self.find_node(old_parent_path).unwrap();
+        let index = old_parent_node
+            .children
+            .iter()
+            .position(|child| child.payload == node.payload)
+            .unwrap();
+
+        let node = old_parent_node.remove_child(index).unwrap();
+        new_parent_node.add_child(node);
+
+        result.is_ok()
     }
 
-    fn write_file_to_disk(&mut self, file: &File, parent: &File) {
-        let mut addr = fat::get_sector_from_cluster(self.fat_addr, parent.current_cluster);
+    // A .. entry holds 0 rather than the cluster of the root directory (even on FAT32)
+    fn parent_cluster(&self, parent: &File) -> usize {
+        if parent.current_cluster == self.volume.root_cluster {
+            0
+        } else {
+            parent.current_cluster
+        }
+    }
+
+    // Points the .. entry of a directory which has moved at its new parent
+    fn set_parent_cluster(&self, directory: &File, parent: &File) -> Result<(), &'static str> {
+        let parent_cluster = self.parent_cluster(parent);
+
+        let cluster_buffer = kmalloc(self.volume.bytes_per_cluster) as *mut u8;
+        let result = self
+            .volume
+            .read_cluster(directory.current_cluster, cluster_buffer)
+            .and_then(|_| {
+                let entries = cluster_buffer as *mut fat::FileEntry;
+                for i in 0..2 {
+                    let file_entry = unsafe { &mut *entries.add(i) };
+
+                    if file_entry.short_name() == *b"..         " {
+                        file_entry.set_cluster(parent_cluster);
+                    }
+                }
+
+                self.volume
+                    .write_cluster(directory.current_cluster, cluster_buffer)
+            });
+        kfree(cluster_buffer as *mut usize);
+
+        result
+    }
 
-        if parent.name == "root" {
-            addr = self.ds_addr as *mut u8;
+    /*
+        Shortens or lengthens a file, freeing the clusters past the new end or filling the new space with zeros
+    */
+    pub fn truncate_file(&mut self, file: &mut File, length: usize) -> Result<(), &'static str> {
+        if file.f_type == FileType::Directory || file.is_deleted {
+            return Ok(());
         }
 
-        for i in 0..(BYTES_PER_CLUSTER / size_of::<fat::FileEntry>()) {
+        if length > file.size {
+            let zeros = kmalloc(self.volume.bytes_per_cluster) as *mut u8;
             unsafe {
-                if (*addr) == 0 {
-                    // Write new file entry at this address
-                    let new_file_entry = fat::FileEntry::new();
-                    core::ptr::write(addr as *mut fat::FileEntry, new_file_entry);
+                core::ptr::write_bytes(zeros, 0, self.volume.bytes_per_cluster);
+            }
+
+            let mut result = Ok(());
+            while result.is_ok() && file.size < length {
+                let bytes_to_write = (length - file.size).min(self.volume.bytes_per_cluster);
+                let size = file.size;
+                result = self.write_file(file, zeros, bytes_to_write, size);
+            }
+
+            kfree(zeros as *mut usize);
+            return result;
+        }
+
+        if file.current_cluster != 0 {
+            let clusters_kept = length.div_ceil(self.volume.bytes_per_cluster);
+
+            if clusters_kept == 0 {
+                self.volume.free_cluster_chain(file.current_cluster)?;
+                file.current_cluster = 0;
+            } else {
+                let mut last_cluster = file.current_cluster;
+                for _ in 1..clusters_kept {
+                    last_cluster = self
+                        .volume
+                        .get_next_cluster(last_cluster)?
+                        .ok_or("Cluster chain is shorter than the file")?;
                 }
+
+                self.volume.truncate_cluster_chain(last_cluster)?;
             }
+        }
 
-            addr = unsafe { addr.add(size_of::<fat::FileEntry>()) };
+        file.size = length;
+        self.update_entry(file)
+    }
+
+    pub fn stat(&self, file: &File) -> Result<Stat, &'static str> {
+        let mut clusters = 0;
+        let mut current_cluster =
+            either!(file.current_cluster == 0 => None; Some(file.current_cluster));
+        while let Some(cluster) = current_cluster {
+            clusters += 1;
+            current_cluster = self.volume.get_next_cluster(cluster)?;
         }
 
-        let addr = self.rd_addr as *const u8;
-        let current_node = &mut self.root.clone();
-        self.build_vfs(addr, current_node);
-        self.root = current_node.clone();
+        let mode = match file.f_type {
+            FileType::Directory => MODE_DIRECTORY | 0o755,
+            _ => MODE_REGULAR_FILE | 0o644,
+        };
+
+        Ok(Stat {
+            device: 0,
+            inode: file.current_cluster,
+            links: 1,
+            mode,
+            user_id: 0,
+            group_id: 0,
+            padding: 0,
+            special_device: 0,
+            size: file.size,
+            block_size: self.volume.bytes_per_cluster,
+            blocks: clusters * self.volume.bytes_per_cluster / STAT_BLOCK_SIZE,
+            access_time: Timespec {
+                seconds: 0,
+                nanoseconds: 0,
+            },
+            modification_time: Timespec {
+                seconds: 0,
+                nanoseconds: 0,
+            },
+            status_change_time: Timespec {
+                seconds: 0,
+                nanoseconds: 0,
+            },
+            reserved: [0; 3],
+        })
+    }
+
+    // Gives the entry at a position of an open directory so it can be listed an entry at a time
+    pub fn get_child(&self, directory: *const File, index: usize) -> Option<File> {
+        let node = find_node_of_file(&self.root, directory)?;
+        let child = node.children.get_mut(index)?;
+        Some(unsafe { *child.payload })
     }
 
-    pub fn write_file(&self, file: &mut File, mut buffer: *mut u8, length: usize, offset: usize) {
-        if file.f_type == FileType::Directory {
-            return;
+    /*
+        Adds the entries of a new file to a directory and returns the position of its short entry
+        Names which a short entry can't hold exactly get long entries (placed before the short entry) and a short name ending in ~N
+        A directory without enough free entries in a row is given another cluster, except the root directory of FAT12/16 which can't grow
+    */
+    fn write_file_to_disk(
+        &mut self,
+        name: &str,
+        attributes: u8,
+        cluster: usize,
+        parent: &File,
+    ) -> Result<usize, &'static str> {
+        let name_length = name.encode_utf16().count();
+        if name_length > fat::LONG_NAME_LENGTH {
+            return Err("Name is too long");
         }
 
+        loop {
+            let (buffer, size) = self.read_directory(parent)?;
+            let entries = buffer as *mut fat::FileEntry;
+            let entry_count = size / size_of::<fat::FileEntry>();
+
+            let (mut short_name, is_exact) = fat::convert_to_short_name(name);
+            let needs_long_name =
+                !is_exact || is_short_name_taken(&short_name, entries, entry_count);
+
+            let long_entry_count =
+                either!(needs_long_name => name_length.div_ceil(fat::CHARACTERS_PER_LONG_ENTRY); 0);
+
+            if needs_long_name {
+                let mut n = 1;
+                loop {
+                    let mut numbered_name = short_name;
+                    fat::add_numeric_tail(&mut numbered_name, n);
+
+                    if !is_short_name_taken(&numbered_name, entries, entry_count) {
+                        short_name = numbered_name;
+                        break;
+                    }
+
+                    n += 1;
+                }
+            }
+
+            let start = match find_free_entries(entries, entry_count, long_entry_count + 1) {
+                Some(start) => start,
+                None => {
+                    kfree(buffer as *mut usize);
+
+                    self.extend_directory(parent)?;
+                    continue;
+                }
+            };
+
+            // Unused characters after the end of the name are 0xFFFF
+            let mut long_name = [0xFFFFu16; fat::MAX_LONG_ENTRIES * fat::CHARACTERS_PER_LONG_ENTRY];
+            for (i, c) in name.encode_utf16().enumerate() {
+                long_name[i] = c;
+            }
+            if name_length < long_entry_count * fat::CHARACTERS_PER_LONG_ENTRY {
+                long_name[name_length] = 0;
+            }
+
+            let checksum = fat::short_name_checksum(&short_name);
+            for i in 0..long_entry_count {
+                let order = long_entry_count - i;
+                let characters_start = (order - 1) * fat::CHARACTERS_PER_LONG_ENTRY;
+
+                let mut characters = [0u16; fat::CHARACTERS_PER_LONG_ENTRY];
+                characters.copy_from_slice(
+                    &long_name[characters_start..characters_start + fat::CHARACTERS_PER_LONG_ENTRY],
+                );
+
+                let long_entry = fat::LongFileEntry::new(order, i == 0, checksum, &characters);
+                unsafe {
+                    core::ptr::write(
+                        entries.add(start + i) as *mut fat::LongFileEntry,
+                        long_entry,
+                    );
+                }
+            }
+
+            let mut file_entry = fat::FileEntry::new();
+            file_entry.set_short_name(&short_name);
+            file_entry.attributes = attributes;
+            file_entry.set_cluster(cluster);
+
+            let entry_index = start + long_entry_count;
+            unsafe {
+                core::ptr::write(entries.add(entry_index), file_entry);
+            }
+
+            let result = self.write_directory(parent, buffer);
+            kfree(buffer as *mut usize);
+
+            return result.map(|_| entry_index);
+        }
+    }
+
+    // Gives a directory another empty cluster at the end of its cluster chain
+    fn extend_directory(&mut self, directory: &File) -> Result<(), &'static str> {
+        if directory.current_cluster == 0 {
+            return Err("Root directory is full");
+        }
+
+        let mut last_cluster = directory.current_cluster;
+        while let Some(cluster) = self.volume.get_next_cluster(last_cluster)? {
+            last_cluster = cluster;
+        }
+
+        let cluster = self.volume.find_free_cluster()?.ok_or("Disk is full")?;
+
+        // The cluster is zeroed before it joins the chain so the directory never ends in stale entries
+        let cluster_buffer = kmalloc(self.volume.bytes_per_cluster) as *mut u8;
+        unsafe {
+            core::ptr::write_bytes(cluster_buffer, 0, self.volume.bytes_per_cluster);
+        }
+        let result = self.volume.write_cluster(cluster, cluster_buffer);
+        kfree(cluster_buffer as *mut usize);
+
+        if let Err(error) = result.and_then(|_| self.volume.write_fat(last_cluster, cluster)) {
+            let _ = self.volume.free_cluster_chain(cluster);
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    // Writes the size and first cluster of a file back to its entry
+    fn update_entry(&self, file: &File) -> Result<(), &'static str> {
+        let directory = File::new("", 0, FileType::Directory, file.directory_cluster);
+        let (buffer, _) = self.read_directory(&directory)?;
+
+        let file_entry = unsafe { &mut *(buffer as *mut fat::FileEntry).add(file.entry_index) };
+        file_entry.size = file.size as u32;
+        file_entry.set_cluster(file.current_cluster);
+
+        let result = self.write_directory(&directory, buffer);
+        kfree(buffer as *mut usize);
+        result
+    }
+
+    // The size only grows if every cluster was written but a newly allocated first cluster is always recorded
+    pub fn write_file(
+        &mut self,
+        file: &mut File,
+        buffer: *mut u8,
+        length: usize,
+        offset: usize,
+    ) -> Result<(), &'static str> {
+        if file.f_type == FileType::Directory || file.is_deleted {
+            return Ok(());
+        }
+
+        let cluster_buffer = kmalloc(self.volume.bytes_per_cluster) as *mut u8;
+        let result = self.write_clusters(file, buffer, length, offset, cluster_buffer);
+        kfree(cluster_buffer as *mut usize);
+
+        if result.is_ok() {
+            file.size = file.size.max(length + offset);
+        }
+
+        result.and(self.update_entry(file))
+    }
+
+    // Clusters are read whole so writes to part of one read it first
+    fn write_clusters(
+        &mut self,
+        file: &mut File,
+        mut buffer: *mut u8,
+        length: usize,
+        offset: usize,
+        cluster_buffer: *mut u8,
+    ) -> Result<(), &'static str> {
         let mut size_left = length;
-        let mut current_cluster = Some(file.current_cluster);
+        let mut current_cluster =
+            either!(file.current_cluster == 0 => None; Some(file.current_cluster));
         let mut previous_cluster = file.current_cluster;
         let mut offset_left = offset;
 
-        // TODO: Need to update the file_entry thing (could be a property of the file)
-        file.size = file.size.max(length + offset);
-
         while let Some(cluster) = current_cluster {
-            if offset_left < BYTES_PER_CLUSTER {
+            if offset_left < self.volume.bytes_per_cluster {
                 break;
             }
-            offset_left = offset_left.wrapping_sub_zero(BYTES_PER_CLUSTER);
+            offset_left = offset_left.wrapping_sub_zero(self.volume.bytes_per_cluster);
             previous_cluster = cluster;
-            current_cluster = fat::get_next_cluster(self.fat_addr, cluster);
+            current_cluster = self.volume.get_next_cluster(cluster)?;
         }
 
         while size_left > 0 {
-            let cluster_offset = offset_left.min(BYTES_PER_CLUSTER);
-            let bytes_to_copy = size_left.min(BYTES_PER_CLUSTER - cluster_offset);
-
-            if let Some(cluster) = current_cluster {
-                unsafe {
-                    let cluster_addr =
-                        fat::get_sector_from_cluster(self.ds_addr, cluster).add(cluster_offset);
+            let cluster_offset = offset_left.min(self.volume.bytes_per_cluster);
+            let bytes_to_copy = size_left.min(self.volume.bytes_per_cluster - cluster_offset);
 
-                    core::ptr::copy_nonoverlapping(buffer, cluster_addr as *mut u8, bytes_to_copy);
-                    buffer = buffer.add(bytes_to_copy);
+            let cluster = match current_cluster {
+                Some(cluster) => {
+                    self.volume.read_cluster(cluster, cluster_buffer)?;
+                    cluster
                 }
-
-                offset_left = 0; // Reset offset for subsequent clusters
-                size_left = size_left.wrapping_sub(bytes_to_copy);
-                previous_cluster = cluster;
-                current_cluster = fat::get_next_cluster(self.fat_addr, cluster);
-            } else {
-                // Search FAT for unallocated cluster
-                if let Some(next_cluster) = fat::find_free_cluster(self.fat_addr) {
-                    // Write previous cluster entry to point to new cluster
-                    fat::write_fat(self.fat_addr, previous_cluster, next_cluster);
+                None => {
+                    // Search FAT for unallocated cluster
+                    let next_cluster = self.volume.find_free_cluster()?.ok_or("Disk is full")?;
+
+                    // Write previous cluster entry to point to new cluster (an empty file has none)
+                    if previous_cluster == 0 {
+                        file.current_cluster = next_cluster;
+                    } else {
+                        self.volume.write_fat(previous_cluster, next_cluster)?;
+                    }
 
                     unsafe {
-                        let cluster_addr = fat::get_sector_from_cluster(self.ds_addr, next_cluster)
-                            .add(cluster_offset);
-
-                        core::ptr::copy_nonoverlapping(
-                            buffer,
-                            cluster_addr as *mut u8,
-                            bytes_to_copy,
-                        );
-                        buffer = buffer.add(bytes_to_copy);
+                        core::ptr::write_bytes(cluster_buffer, 0, self.volume.bytes_per_cluster);
                     }
-
-                    previous_cluster = next_cluster;
-                    current_cluster = Some(next_cluster);
-                    offset_left = 0; // Reset offset for subsequent clusters
-                    size_left = size_left.wrapping_sub(bytes_to_copy);
+                    next_cluster
                 }
+            };
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    buffer,
+                    cluster_buffer.add(cluster_offset),
+                    bytes_to_copy,
+                );
+                buffer = buffer.add(bytes_to_copy);
             }
+
+            self.volume.write_cluster(cluster, cluster_buffer)?;
+
+            offset_left = 0; // Reset offset for subsequent clusters
             size_left = size_left.wrapping_sub_zero(bytes_to_copy);
+            previous_cluster = cluster;
+            current_cluster = self.volume.get_next_cluster(cluster)?;
         }
+
+        Ok(())
     }
 
-    pub fn read_file(&self, file: &File, mut buffer: *mut u8, length: usize, offset: usize) {
-        if file.f_type == FileType::Directory {
-            return;
+    pub fn read_file(
+        &self,
+        file: &File,
+        buffer: *mut u8,
+        length: usize,
+        offset: usize,
+    ) -> Result<(), &'static str> {
+        if file.f_type == FileType::Directory || file.current_cluster == 0 {
+            return Ok(());
         }
 
+        let cluster_buffer = kmalloc(self.volume.bytes_per_cluster) as *mut u8;
+        let result = self.read_clusters(file, buffer, length, offset, cluster_buffer);
+        kfree(cluster_buffer as *mut usize);
+
+        result
+    }
+
+    fn read_clusters(
+        &self,
+        file: &File,
+        mut buffer: *mut u8,
+        length: usize,
+        offset: usize,
+        cluster_buffer: *mut u8,
+    ) -> Result<(), &'static str> {
         let mut current_cluster = Some(file.current_cluster);
         let mut size_left = length;
         let mut offset_left = offset;
 
         // Skip clusters until we reach the starting cluster of the given offset
         while let Some(cluster) = current_cluster {
-            if offset_left < BYTES_PER_CLUSTER {
+            if offset_left < self.volume.bytes_per_cluster {
                 break;
             }
 
-            offset_left -= BYTES_PER_CLUSTER;
-            current_cluster = fat::get_next_cluster(self.fat_addr, cluster);
+            offset_left -= self.volume.bytes_per_cluster;
+            current_cluster = self.volume.get_next_cluster(cluster)?;
         }
 
         while let Some(cluster) = current_cluster {
-            let cluster_addr = fat::get_sector_from_cluster(self.ds_addr, cluster);
-            let cluster_offset = BYTES_PER_CLUSTER.min(offset_left);
-            let bytes_to_copy = size_left.min(BYTES_PER_CLUSTER - cluster_offset);
+            if size_left == 0 {
+                break;
+            }
+
+            self.volume.read_cluster(cluster, cluster_buffer)?;
+            let cluster_offset = self.volume.bytes_per_cluster.min(offset_left);
+            let bytes_to_copy = size_left.min(self.volume.bytes_per_cluster - cluster_offset);
 
             unsafe {
                 // Copy data from the current cluster starting at the specified offset
                 core::ptr::copy_nonoverlapping(
-                    cluster_addr.add(cluster_offset),
+                    cluster_buffer.add(cluster_offset),
                     buffer,
                     bytes_to_copy,
                 );
@@ -323,38 +991,36 @@ impl Vfs {
             size_left = size_left.wrapping_sub(bytes_to_copy);
             offset_left = 0; // Reset offset for subsequent clusters
 
-            current_cluster = fat::get_next_cluster(self.fat_addr, cluster);
+            current_cluster = self.volume.get_next_cluster(cluster)?;
         }
-    }
-
-    pub fn open_addr(&self, filepath: &str) -> *mut File {
-        let is_absolute = filepath.starts_with("/");
 
-        // print_serial!("file is {}\n", filepath);
-
-        // assert!(is_absolute, "Error: Filename must be absolute");
+        Ok(())
+    }
 
-        // let cleaned_filepath: &str = &filepath[1..filepath.len()];
-        // let mut filepath_components = cleaned_filepath.split("/");
+    // Writes back the cached data of a file along with the FATs which hold its cluster chain
+    pub fn sync_file(&self, file: &File) -> Result<(), &'static str> {
+        self.volume.flush_fats()?;
 
-        // let mut current_node = self.root.clone();
-        // for component in filepath_components {
-        //     if let Some(node) = self.find(component, &current_node) {
-        //         current_node = node;
-        //     } else {
-        //         panic!("Error: File not found");
-        //     }
-        // }
+        if file.current_cluster == 0 {
+            if file.f_type == FileType::Directory {
+                self.volume.flush_root_directory()?;
+            }
 
-        let mut current_node = self.root.clone();
+            return Ok(());
+        }
 
-        if let Some(node) = self.find(filepath, &current_node) {
-            current_node = node;
-        } else {
-            panic!("Error: File not found");
+        let mut current_cluster = Some(file.current_cluster);
+        while let Some(cluster) = current_cluster {
+            self.volume.flush_cluster(cluster)?;
+            current_cluster = self.volume.get_next_cluster(cluster)?;
         }
 
-        current_node.payload
+        Ok(())
+    }
+
+    // Returns None rather than panicking so a missing file can be reported to the process opening it
+    pub fn open_addr(&self, filepath: &str) -> Option<*mut File> {
+        Some(self.find_path(filepath)?.payload)
     }
 
     pub fn open(&self, filepath: &str) -> File {
@@ -362,39 +1028,79 @@ impl Vfs {
 
         assert!(is_absolute, "Error: Filename must be absolute");
 
-        let cleaned_filepath: &str = &filepath[1..filepath.len()];
-        let mut filepath_components = cleaned_filepath.split("/");
-
         // print_serial!("Opening {:?}\n", filepath);
 
+        match self.find_file(filepath) {
+            Some(file) => file,
+            None => panic!("Error: File not found"),
+        }
+    }
+
+    // Same as open but returns None rather than panicking when the file does not exist
+    pub fn find_file(&self, filepath: &str) -> Option<File> {
+        let node = self.find_path(filepath)?;
+        let file = unsafe { &*node.payload };
+        Some(file.clone())
+    }
+
+    // Follows each component of the path from the root (paths without a leading slash are relative to the root)
+    fn find_path(&self, filepath: &str) -> Option<TreeNode<File>> {
+        let cleaned_filepath = filepath.trim_start_matches("/");
+
         let mut current_node = self.root.clone();
-        for component in filepath_components {
-            if let Some(node) = self.find(component, &current_node) {
-                current_node = node;
-            } else {
-                panic!("Error: File not found");
+        for component in cleaned_filepath.split("/") {
+            if component.is_empty() {
+                continue;
             }
+
+            current_node = self.find(component, &current_node)?;
         }
 
-        let file_addr = current_node.payload;
+        Some(current_node)
+    }
+
+    // Same as find_path but gives the node within the tree itself so children can be added to it
+    fn find_node(&mut self, filepath: &str) -> Option<&'static mut TreeNode<File>> {
+        let mut current_node = &mut self.root as *mut TreeNode<File>;
+
+        for component in filepath.trim_start_matches("/").split("/") {
+            if component.is_empty() {
+                continue;
+            }
+
+            let children = unsafe { &(*current_node).children };
+            let index = children.iter().position(|child| {
+                let file = unsafe { &*child.payload };
+                file.name.eq_ignore_ascii_case(component)
+            })?;
+
+            current_node = children.get_mut(index).unwrap();
+        }
 
-        let file = unsafe { &*current_node.payload };
-        return file.clone();
+        Some(unsafe { &mut *current_node })
     }
 
-    fn find(&self, filename: &str, current_node: &TreeNode<File>) -> Option<TreeNode<File>> {
-        // print_serial!("Finding stuff\n");
+    // Gives the node of the directory holding a path along with the position of the path's node within it
+    fn find_parent_node(&mut self, filepath: &str) -> Option<(&'static mut TreeNode<File>, usize)> {
+        let (parent_path, name) = filepath.trim_end_matches("/").rsplit_once("/")?;
+        let parent_node = self.find_node(parent_path)?;
 
-        for child in current_node.children.iter() {
+        let index = parent_node.children.iter().position(|child| {
             let file = unsafe { &*child.payload };
+            file.name.eq_ignore_ascii_case(name)
+        })?;
 
-            // print_serial!("{:?}\n", file);
+        Some((parent_node, index))
+    }
 
-            // if file.name == filename {
-            //     return Some(child.clone());
-            // }
+    // FAT names are case insensitive
+    fn find(&self, filename: &str, current_node: &TreeNode<File>) -> Option<TreeNode<File>> {
+        for child in current_node.children.iter() {
+            let file = unsafe { &*child.payload };
 
-            return Some(child.clone());
+            if file.name.eq_ignore_ascii_case(filename) {
+                return Some(child.clone());
+            }
         }
 
         None
@@ -410,5 +1116,76 @@ impl Vfs {
     }
 }
 
+// Searches the tree for the node holding an open file
+fn find_node_of_file(node: &TreeNode<File>, file: *const File) -> Option<TreeNode<File>> {
+    if node.payload as *const File == file {
+        return Some(node.clone());
+    }
+
+    node.children
+        .iter()
+        .find_map(|child| find_node_of_file(child, file))
+}
+
+fn is_in_subtree(node: &TreeNode<File>, file: *const File) -> bool {
+    find_node_of_file(node, file).is_some()
+}
+
+// The entry is freed along with the directory buffer so the name is copied out
+fn copy_name(name: &str) -> &'static str {
+    let name_buffer = kmalloc(name.len()) as *mut u8;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(name.as_ptr(), name_buffer, name.len());
+        core::str::from_utf8_unchecked(core::slice::from_raw_parts(name_buffer, name.len()))
+    }
+}
+
+// Compares against every short entry in use (long entries have no short name)
+fn is_short_name_taken(
+    short_name: &[u8; fat::SHORT_NAME_LENGTH],
+    entries: *const fat::FileEntry,
+    entry_count: usize,
+) -> bool {
+    for i in 0..entry_count {
+        let file_entry = unsafe { &*entries.add(i) };
+
+        match file_entry.filename[0] {
+            fat::ENTRY_END => break,
+            fat::ENTRY_DELETED => continue,
+            _ => {}
+        }
+
+        if file_entry.attributes != fat::ATTRIBUTE_LONG_NAME
+            && file_entry.short_name() == *short_name
+        {
+            return true;
+        }
+    }
+
+    false
+}
+
+// Returns the first of a run of unused entries (everything after the end marker is unused)
+fn find_free_entries(
+    entries: *const fat::FileEntry,
+    entry_count: usize,
+    count: usize,
+) -> Option<usize> {
+    let mut run = 0;
+
+    for i in 0..entry_count {
+        match unsafe { (*entries.add(i)).filename[0] } {
+            fat::ENTRY_END | fat::ENTRY_DELETED => run += 1,
+            _ => run = 0,
+        }
+
+        if run == count {
+            return Some(i + 1 - count);
+        }
+    }
+
+    None
+}
+
 pub static VFS: Lock<Vfs> = Lock::new(Vfs::new());
-static mut FILE_NAME_BUFFER: [u8; 32] = [0u8; 32];
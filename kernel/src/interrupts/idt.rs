@@ -8,7 +8,7 @@ pub const IDT_MAX_DESCRIPTIONS: usize = 256;
 pub struct IDTEntry {
     isr_low: u16,   // Lower 16 bits of the ISR's address
     kernel_cs: u16, // GDT segment selector that CPU loads before calling ISR
-    ist: u8,        // IST in TSS which CPU will load into RSP (zero currently)
+    ist: u8,        // IST in TSS which CPU will load into RSP (zero keeps the current stack)
     attributes: u8, // Type and attributes
     isr_mid: u16,   // Higher 16 bits of the lower 32 bits of ISR's address
     isr_high: u32,  // Higher 32 bits of ISR's address
@@ -42,6 +42,12 @@ impl IDTEntry {
         IDTEntry::new(GateType::Interrupt, PrivilegeLevel::Ring3, func_addr_raw)
     }
 
+    // Runs the handler on a stack from the interrupt stack table of the TSS
+    pub fn with_ist(mut self, ist_index: u8) -> IDTEntry {
+        self.ist = ist_index;
+        self
+    }
+
     fn generate_flags(data: (GateType, PrivilegeLevel)) -> u8 {
         let mut attributes: u8 = match data.0 {
             GateType::Trap => 0x8F,
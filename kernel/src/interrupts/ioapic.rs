@@ -0,0 +1,164 @@
+/*
+    The IO APIC receives the interrupts of devices (global system interrupts) and sends each to a local APIC as a vector
+    Every input has a 64 bit redirection entry which sets its vector, destination CPU, polarity and trigger mode
+    ISA IRQs are wired to the input with the same number unless the MADT lists an override (eg the PIT is usually on input 2)
+    Registers are accessed indirectly by writing the number of the register to IOREGSEL and then using IOWIN
+*/
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::memory::paging;
+use crate::print_serial;
+use crate::utils::acpi::{MADT, MAX_IO_APICS};
+use crate::utils::spinlock::Lock;
+
+const REGISTER_SELECT: usize = 0x00;
+const REGISTER_WINDOW: usize = 0x10;
+
+const REGISTER_VERSION: u32 = 0x01;
+const REGISTER_REDIRECTION: u32 = 0x10; // Each entry takes 2 registers (low then high half)
+
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+
+// Flags of an interrupt override from the MADT (0 means the ISA default of active high and edge triggered)
+const POLARITY_MASK: usize = 0b11;
+const POLARITY_ACTIVE_LOW: usize = 0b11;
+const TRIGGER_MASK: usize = 0b11 << 2;
+const TRIGGER_LEVEL: usize = 0b11 << 2;
+
+#[derive(Clone, Copy)]
+struct IoApic {
+    registers: usize, // Virtual address
+    gsi_base: usize,
+    inputs: usize,
+}
+
+impl IoApic {
+    fn handles(&self, gsi: usize) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.inputs
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile((self.registers + REGISTER_SELECT) as *mut u32, register);
+            core::ptr::read_volatile((self.registers + REGISTER_WINDOW) as *const u32)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            core::ptr::write_volatile((self.registers + REGISTER_SELECT) as *mut u32, register);
+            core::ptr::write_volatile((self.registers + REGISTER_WINDOW) as *mut u32, value);
+        }
+    }
+
+    // The high half (destination) is written first as writing the low half may unmask the input
+    fn set_redirection(&self, input: usize, entry: u64) {
+        let register = REGISTER_REDIRECTION + 2 * input as u32;
+
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+pub struct IoApics {
+    apics: [IoApic; MAX_IO_APICS],
+    count: usize,
+}
+
+impl IoApics {
+    pub const fn new() -> IoApics {
+        IoApics {
+            apics: [IoApic {
+                registers: 0,
+                gsi_base: 0,
+                inputs: 0,
+            }; MAX_IO_APICS],
+            count: 0,
+        }
+    }
+
+    /*
+        Maps every IO APIC listed in the MADT and masks all of their inputs
+        Returns false if there are none
+    */
+    pub fn init(&mut self) -> bool {
+        let madt = MADT.lock();
+
+        for io_apic in madt.io_apics() {
+            let mut apic = IoApic {
+                registers: paging::map_device_registers(io_apic.address),
+                gsi_base: io_apic.gsi_base,
+                inputs: 0,
+            };
+
+            // Bits 16-23 hold the index of the last redirection entry
+            apic.inputs = ((apic.read(REGISTER_VERSION) >> 16) & 0xFF) as usize + 1;
+
+            for input in 0..apic.inputs {
+                apic.set_redirection(input, MASKED);
+            }
+
+            self.apics[self.count] = apic;
+            self.count += 1;
+        }
+
+        MADT.free();
+
+        if self.count > 0 {
+            IS_ENABLED.store(true, Ordering::Release);
+        }
+
+        self.count > 0
+    }
+
+    /*
+        Sends an ISA IRQ to a CPU as the given vector
+        The MADT overrides give the input it is wired to along with its polarity and trigger mode
+    */
+    pub fn route(&self, irq: usize, vector: usize, apic_id: usize) {
+        let mut gsi = irq;
+        let mut flags = 0;
+
+        let madt = MADT.lock();
+        if let Some(irq_override) = madt.overrides().iter().find(|entry| entry.irq == irq) {
+            gsi = irq_override.gsi;
+            flags = irq_override.flags;
+        }
+        MADT.free();
+
+        let apic = match self.apics[..self.count]
+            .iter()
+            .find(|apic| apic.handles(gsi))
+        {
+            Some(apic) => apic,
+            None => {
+                print_serial!("IO APIC: No IO APIC handles IRQ {} (GSI {})\n", irq, gsi);
+                return;
+            }
+        };
+
+        let mut entry = vector as u64 | (apic_id as u64) << 56;
+
+        if flags & POLARITY_MASK == POLARITY_ACTIVE_LOW {
+            entry |= ACTIVE_LOW;
+        }
+
+        if flags & TRIGGER_MASK == TRIGGER_LEVEL {
+            entry |= LEVEL_TRIGGERED;
+        }
+
+        apic.set_redirection(gsi - apic.gsi_base, entry);
+    }
+}
+
+pub static IO_APICS: Lock<IoApics> = Lock::new(IoApics::new());
+
+// Set once the IO APICs are mapped as the legacy IRQs are then routed through them rather than the PIC
+static IS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_enabled() -> bool {
+    IS_ENABLED.load(Ordering::Acquire)
+}
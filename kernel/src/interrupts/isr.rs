@@ -1,11 +1,15 @@
 // Define all base isr's
 
 use super::{
-    exception_handler, exception_with_error_handler, interrupt_handler, pit_handler,
-    test_syscall_handler,
+    exception_handler, exception_with_error_handler, interrupt_handler, test_syscall_handler,
 };
 use core::arch::asm;
 
+/*
+    Exceptions save the same frame as the PIT handler so a faulting process can be switched away from
+    The handler returns the stack pointer of the frame to resume (the same one unless another process is picked)
+*/
+
 // Purely for exceptions with an error code eg page faults
 #[macro_export]
 macro_rules! setup_exception_with_e_handler {
@@ -14,21 +18,40 @@ macro_rules! setup_exception_with_e_handler {
         extern "C" fn wrapper() -> ! {
             unsafe {
                 asm!(
-                    "push rax",
+                    "xchg rax, [rsp]", // Save RAX in place of the error code which is loaded into RAX
                     "push rbx",
                     "push rcx",
                     "push rdx",
                     "push rbp",
                     "push rdi",
                     "push rsi",
-                    "mov rdx, [rsp + 7*8]", // Load error code
-                    "mov rdi, rsp", // Load ExceptionStackFrame
+                    "push r8",
+                    "push r9",
+                    "push r10",
+                    "push r11",
+                    "push r12",
+                    "push r13",
+                    "push r14",
+                    "push r15",
+                    "mov rdx, rax", // Load error code
+                    "mov rax, cr3",
+                    "push rax",
+                    "mov rdi, rsp", // Load frame
                     "mov rsi, {0}", // Load exception id
-                    "add rdi, 8*8",
-                    "sub rsp, 8", // Allign stack pointer
                     "cld",
                     "call {1}",
-                    "add rsp, 8", // Reallign stack pointer
+                    "mov rsp, rax",
+                    "mov rax, [rsp]",
+                    "mov cr3, rax",
+                    "add rsp, 0x08",
+                    "pop r15",
+                    "pop r14",
+                    "pop r13",
+                    "pop r12",
+                    "pop r11",
+                    "pop r10",
+                    "pop r9",
+                    "pop r8",
                     "pop rsi",
                     "pop rdi",
                     "pop rbp",
@@ -47,6 +70,65 @@ macro_rules! setup_exception_with_e_handler {
     }};
 }
 
+// Exceptions without an error code eg invalid opcode
+#[macro_export]
+macro_rules! setup_exception_handler {
+    ($exception_num: expr) => {{
+        #[naked]
+        extern "C" fn wrapper() -> ! {
+            unsafe {
+                asm!(
+                    "push rax",
+                    "push rbx",
+                    "push rcx",
+                    "push rdx",
+                    "push rbp",
+                    "push rdi",
+                    "push rsi",
+                    "push r8",
+                    "push r9",
+                    "push r10",
+                    "push r11",
+                    "push r12",
+                    "push r13",
+                    "push r14",
+                    "push r15",
+                    "mov rax, cr3",
+                    "push rax",
+                    "mov rdi, rsp", // Load frame
+                    "mov rsi, {0}", // Load exception id
+                    "cld",
+                    "call {1}",
+                    "mov rsp, rax",
+                    "mov rax, [rsp]",
+                    "mov cr3, rax",
+                    "add rsp, 0x08",
+                    "pop r15",
+                    "pop r14",
+                    "pop r13",
+                    "pop r12",
+                    "pop r11",
+                    "pop r10",
+                    "pop r9",
+                    "pop r8",
+                    "pop rsi",
+                    "pop rdi",
+                    "pop rbp",
+                    "pop rdx",
+                    "pop rcx",
+                    "pop rbx",
+                    "pop rax",
+                    "iretq",
+                    const $exception_num,
+                    sym exception_handler,
+                    options(noreturn)
+                );
+            }
+        }
+        wrapper
+    }};
+}
+
 // Includes exceptions and general interrupts
 #[macro_export]
 macro_rules! setup_interrupt_handler {
@@ -85,55 +167,64 @@ macro_rules! setup_interrupt_handler {
     }};
 }
 
-// Must save data in rax eg mov rsp, rax
-#[naked]
-pub extern "C" fn setup_pit_handler() -> ! {
-    unsafe {
-        asm!(
-            "push rax",
-            "push rbx",
-            "push rcx",
-            "push rdx",
-            "push rbp",
-            "push rdi",
-            "push rsi",
-            "push r8",
-            "push r9",
-            "push r10",
-            "push r11",
-            "push r12",
-            "push r13",
-            "push r14",
-            "push r15",
-            "mov rax, cr3",
-            "push rax",
-            "mov rdi, rsp",
-            "cld",
-            "call {0}",
-            "mov rsp, rax",
-            "mov rax, [rsp]",
-            "mov cr3, rax",
-            "add rsp, 0x08",
-            "pop r15",
-            "pop r14",
-            "pop r13",
-            "pop r12",
-            "pop r11",
-            "pop r10",
-            "pop r9",
-            "pop r8",
-            "pop rsi",
-            "pop rdi",
-            "pop rbp",
-            "pop rdx",
-            "pop rcx",
-            "pop rbx",
-            "pop rax",
-            "iretq",
-            sym pit_handler,
-            options(noreturn)
-        );
-    }
+/*
+    Saves the full frame (including CR3) so the handler can switch to another process
+    The handler returns the stack pointer of the frame to resume eg mov rsp, rax
+*/
+#[macro_export]
+macro_rules! setup_switching_handler {
+    ($func_name: ident) => {{
+        #[naked]
+        extern "C" fn wrapper() -> ! {
+            unsafe {
+                asm!(
+                    "push rax",
+                    "push rbx",
+                    "push rcx",
+                    "push rdx",
+                    "push rbp",
+                    "push rdi",
+                    "push rsi",
+                    "push r8",
+                    "push r9",
+                    "push r10",
+                    "push r11",
+                    "push r12",
+                    "push r13",
+                    "push r14",
+                    "push r15",
+                    "mov rax, cr3",
+                    "push rax",
+                    "mov rdi, rsp",
+                    "cld",
+                    "call {0}",
+                    "mov rsp, rax",
+                    "mov rax, [rsp]",
+                    "mov cr3, rax",
+                    "add rsp, 0x08",
+                    "pop r15",
+                    "pop r14",
+                    "pop r13",
+                    "pop r12",
+                    "pop r11",
+                    "pop r10",
+                    "pop r9",
+                    "pop r8",
+                    "pop rsi",
+                    "pop rdi",
+                    "pop rbp",
+                    "pop rdx",
+                    "pop rcx",
+                    "pop rbx",
+                    "pop rax",
+                    "iretq",
+                    sym $func_name,
+                    options(noreturn)
+                );
+            }
+        }
+        wrapper
+    }};
 }
 
 /*
@@ -0,0 +1,178 @@
+/*
+    Every CPU has its own local APIC (Advanced Programmable Interrupt Controller) which delivers interrupts to it
+    It is also used to send interprocessor interrupts (IPIs) such as those which start the other CPUs or ask them to flush their TLB
+    The registers are memory mapped at the same physical address for every CPU and each CPU only ever sees its own through it
+    Interrupts from the local APIC (unlike those from the PIC) must be acknowledged by writing to its EOI register
+    Each local APIC also has a timer which drives the scheduling ticks of its CPU
+*/
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::memory::paging;
+use crate::utils::msr;
+
+use super::pit;
+
+// Offsets of the registers
+const REGISTER_ID: usize = 0x20;
+const REGISTER_TASK_PRIORITY: usize = 0x80;
+const REGISTER_EOI: usize = 0xB0;
+const REGISTER_SPURIOUS: usize = 0xF0;
+const REGISTER_ICR_LOW: usize = 0x300; // Interrupt command register which sends an IPI once written
+const REGISTER_ICR_HIGH: usize = 0x310;
+const REGISTER_TIMER: usize = 0x320;
+const REGISTER_LINT0: usize = 0x350;
+const REGISTER_LINT1: usize = 0x360;
+const REGISTER_TIMER_INITIAL_COUNT: usize = 0x380;
+const REGISTER_TIMER_CURRENT_COUNT: usize = 0x390;
+const REGISTER_TIMER_DIVIDE: usize = 0x3E0;
+
+const CPUID_APIC: u32 = 1 << 9; // EDX of leaf 1
+const GLOBAL_ENABLE: u64 = 1 << 11; // Within the APIC base MSR
+const BASE_ADDRESS_MASK: u64 = 0xF_FFFF_F000;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// Delivery modes of the local vector table and the interrupt command register
+const DELIVERY_NMI: u32 = 0b100 << 8;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_EXTINT: u32 = 0b111 << 8;
+
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+pub const TIMER_VECTOR: usize = 0x40;
+pub const SPURIOUS_VECTOR: usize = 0xFF;
+
+// The timer is counted against the PIT for this long
+const CALIBRATION_MICROSECONDS: usize = 10_000;
+
+// Virtual address of the registers (0 until the local APIC is mapped)
+static LAPIC_ADDR: AtomicUsize = AtomicUsize::new(0);
+
+// Timer count between two ticks which is the same for every CPU as they share the bus clock
+static TIMER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn is_supported() -> bool {
+    unsafe { __cpuid(1).edx & CPUID_APIC != 0 }
+}
+
+/*
+    Maps the registers and enables the local APIC of the bootstrap processor before starting its timer
+    The registers are found through the APIC base MSR which also enables the local APIC if the firmware hadn't
+    The PIC stays in use through LINT0 (virtual wire mode) until the IO APIC takes over and NMIs arrive on LINT1
+    Must be called before any address space is cloned so every clone maps the registers
+*/
+pub fn init() {
+    let base = msr::read_msr(msr::APIC_BASE);
+    msr::write_msr(msr::APIC_BASE, base | GLOBAL_ENABLE);
+
+    let registers = paging::map_device_registers((base & BASE_ADDRESS_MASK) as usize);
+    LAPIC_ADDR.store(registers, Ordering::Release);
+
+    write(REGISTER_LINT0, DELIVERY_EXTINT);
+    write(REGISTER_LINT1, DELIVERY_NMI);
+
+    enable();
+    calibrate_timer();
+    start_timer();
+}
+
+// Called by every other CPU once it runs (only the bootstrap processor takes interrupts from the PIC)
+pub fn init_application_processor() {
+    write(REGISTER_LINT0, MASKED);
+    write(REGISTER_LINT1, DELIVERY_NMI);
+
+    enable();
+    start_timer();
+}
+
+// Stops the PIC reaching the bootstrap processor once the IO APIC delivers the legacy IRQs
+pub fn disable_virtual_wire() {
+    write(REGISTER_LINT0, MASKED);
+}
+
+/*
+    Counts how far the timer gets whilst the PIT waits for a known time
+    The timer runs at the speed of the bus which differs between machines
+*/
+fn calibrate_timer() {
+    write(REGISTER_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REGISTER_TIMER, MASKED);
+    write(REGISTER_TIMER_INITIAL_COUNT, u32::MAX);
+
+    pit::busy_wait(CALIBRATION_MICROSECONDS);
+
+    let elapsed = (u32::MAX - read(REGISTER_TIMER_CURRENT_COUNT)) as usize;
+    write(REGISTER_TIMER_INITIAL_COUNT, 0);
+
+    let count_per_second = elapsed * (1_000_000 / CALIBRATION_MICROSECONDS);
+    TIMER_COUNT.store(count_per_second / pit::FREQUENCY, Ordering::Release);
+}
+
+// Sends TIMER_VECTOR to the current CPU at the same frequency as the PIT
+fn start_timer() {
+    write(REGISTER_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REGISTER_TIMER, TIMER_PERIODIC | TIMER_VECTOR as u32);
+    write(
+        REGISTER_TIMER_INITIAL_COUNT,
+        TIMER_COUNT.load(Ordering::Acquire) as u32,
+    );
+}
+
+pub fn is_initialised() -> bool {
+    LAPIC_ADDR.load(Ordering::Acquire) != 0
+}
+
+// Accepts every interrupt and sends spurious interrupts to their own vector
+fn enable() {
+    write(REGISTER_TASK_PRIORITY, 0);
+    write(REGISTER_SPURIOUS, SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+pub fn id() -> usize {
+    (read(REGISTER_ID) >> 24) as usize
+}
+
+pub fn end_of_interrupt() {
+    write(REGISTER_EOI, 0);
+}
+
+pub fn send_ipi(apic_id: usize, vector: usize) {
+    send_command(apic_id, vector as u32);
+}
+
+// Resets a CPU so it waits for a startup IPI
+pub fn send_init(apic_id: usize) {
+    send_command(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+}
+
+// Starts a CPU in real mode at the start of the given page (below 1MB)
+pub fn send_startup(apic_id: usize, page: usize) {
+    send_command(apic_id, DELIVERY_STARTUP | LEVEL_ASSERT | page as u32);
+}
+
+// The destination must be written first as writing the low half sends the IPI
+fn send_command(apic_id: usize, command: u32) {
+    write(REGISTER_ICR_HIGH, (apic_id as u32) << 24);
+    write(REGISTER_ICR_LOW, command);
+
+    while read(REGISTER_ICR_LOW) & DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+fn read(register: usize) -> u32 {
+    let address = LAPIC_ADDR.load(Ordering::Acquire) + register;
+    unsafe { core::ptr::read_volatile(address as *const u32) }
+}
+
+fn write(register: usize, value: u32) {
+    let address = LAPIC_ADDR.load(Ordering::Acquire) + register;
+    unsafe { core::ptr::write_volatile(address as *mut u32, value) }
+}
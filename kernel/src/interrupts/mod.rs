@@ -22,22 +22,32 @@ use crate::interrupts::idt::PrivilegeLevel;
 use crate::interrupts::idt::IDT;
 use crate::interrupts::idt::IDTR;
 use crate::interrupts::idt::IDT_MAX_DESCRIPTIONS;
-use crate::memory::gdt::TSS;
+use crate::memory::gdt;
+use crate::memory::kernel_stack;
+use crate::multitask::signal;
 use crate::multitask::syscalls::syscall_handler;
-use crate::multitask::PROCESS_MANAGER;
+use crate::multitask::wait_queue::YIELD_INTERRUPT;
+use crate::multitask::{PROCESS_MANAGER, TIMER_WAITERS};
+use crate::output::uart;
 use crate::print_serial;
+use crate::setup_exception_handler;
 use crate::setup_exception_with_e_handler;
 use crate::setup_interrupt_handler;
+use crate::setup_switching_handler;
+use crate::smp::{kernel_lock, tlb, TLB_SHOOTDOWN_VECTOR};
 use crate::utils::multiboot2::MULTIBOOT2_BOOTLOADER_MAGIC;
+use crate::utils::event::EVENT_WAITERS;
 use crate::utils::ports::inb;
 use crate::CONSOLE;
 use core::arch::asm;
 use core::panic;
 
-use crate::interrupts::isr::setup_pit_handler;
+use self::pit::PIT;
 
 mod idt;
+pub mod ioapic;
 mod isr;
+pub mod lapic;
 pub mod pic;
 pub mod pit;
 
@@ -80,6 +90,15 @@ const EXCEPTION_MESSAGES: &'static [&'static str] = &[
 
 pub type InterruptHandlerFunc = extern "C" fn() -> !;
 
+// Legacy IRQs which have a handler (IRQ n arrives on vector 0x20 + n whether the PIC or the IO APIC delivers it)
+const IRQ_BASE_VECTOR: usize = 0x20;
+const HANDLED_IRQS: &[usize] = &[
+    0,  // Timer (PIT)
+    1,  // Keyboard
+    4,  // Serial (COM1)
+    12, // Mouse
+];
+
 #[derive(Debug)]
 #[repr(C)]
 struct StackFrame {
@@ -107,7 +126,7 @@ pub struct InterruptStackFrame {
     ss: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 #[repr(C)]
 pub struct SyscallStackFrame {
     pub r15: usize,
@@ -125,11 +144,11 @@ pub struct SyscallStackFrame {
     pub rcx: usize,
     pub rbx: usize,
     pub rax: usize,
-    rip: usize,
-    cs: usize,
-    rflags: usize,
-    rsp: usize,
-    ss: usize,
+    pub rip: usize,
+    pub cs: usize,
+    pub rflags: usize,
+    pub rsp: usize,
+    pub ss: usize,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -160,7 +179,91 @@ impl PageFaultFlags {
     }
 }
 
-pub extern "C" fn exception_handler(stack_frame: &StackFrame, exception_id: usize) {
+// The frame saved by the exception handlers holds CR3 and 15 registers before the interrupt frame
+fn get_exception_stack_frame(old_rsp: usize) -> &'static StackFrame {
+    unsafe { &*((old_rsp as *const usize).offset(16) as *const StackFrame) }
+}
+
+// The requested privilege level of the code segment shows which ring was interrupted
+fn is_from_user(stack_frame: &StackFrame) -> bool {
+    stack_frame.cs & 0x3 == 0x3
+}
+
+// Holds the address which caused the last page fault
+fn read_cr2() -> usize {
+    let cr2: usize;
+    unsafe {
+        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+    }
+    cr2
+}
+
+fn print_page_fault_flags(error_code: usize) {
+    print_serial!("Flags:");
+
+    for (index, flag) in PageFaultFlags::iter() {
+        if ((1 << index) & error_code) != 0 {
+            print_serial!(" {:?}", flag);
+        }
+    }
+
+    print_serial!("\n");
+}
+
+fn print_crash_report(pid: usize, stack_frame: &StackFrame, exception_id: usize, error_code: usize) {
+    print_serial!(
+        "Process {} crashed: {}\n",
+        pid,
+        EXCEPTION_MESSAGES[exception_id]
+    );
+    print_serial!("RIP: 0x{:x}\n", stack_frame.rip);
+    print_serial!("RSP: 0x{:x}\n", stack_frame.rsp);
+
+    if exception_id == 14 {
+        print_serial!("CR2: 0x{:x}\n", read_cr2());
+        print_page_fault_flags(error_code);
+    } else if error_code != 0 {
+        print_serial!("Error Code: 0x{:x}\n", error_code);
+    }
+}
+
+/*
+    Exceptions caused by user processes only take down that process rather than the whole kernel
+    Processes which catch the matching signal (eg SIGSEGV) run their handler instead
+    Returns the frame of the next process to run
+*/
+fn handle_user_fault(
+    stack_frame: &StackFrame,
+    old_rsp: usize,
+    exception_id: usize,
+    error_code: usize,
+) -> usize {
+    let pid = PROCESS_MANAGER.lock().get_current_process().pid;
+    PROCESS_MANAGER.free();
+
+    print_crash_report(pid, stack_frame, exception_id, error_code);
+
+    let signal = signal::from_exception(exception_id).unwrap_or(signal::SIGKILL);
+
+    let is_locked = kernel_lock::acquire_unless_held();
+    let process_manager = PROCESS_MANAGER.lock();
+    process_manager.fault_current_process(signal);
+    let rsp = process_manager.switch_process(old_rsp);
+    PROCESS_MANAGER.free();
+    if is_locked {
+        kernel_lock::release();
+    }
+
+    rsp
+}
+
+pub extern "C" fn exception_handler(old_rsp: usize, exception_id: usize) -> usize {
+    let stack_frame = get_exception_stack_frame(old_rsp);
+
+    if is_from_user(stack_frame) {
+        return handle_user_fault(stack_frame, old_rsp, exception_id, 0);
+    }
+
     match exception_id {
         0..32 => {
             print_serial!("{}\n", EXCEPTION_MESSAGES[exception_id]);
@@ -173,36 +276,101 @@ pub extern "C" fn exception_handler(stack_frame: &StackFrame, exception_id: usiz
     panic!("Unhandled exception: {}", exception_id);
 }
 
-pub extern "C" fn test_syscall_handler(stack_frame: &SyscallStackFrame) -> isize {
-    syscall_handler(stack_frame) as isize
+// Only one CPU runs a syscall at a time (see smp::kernel_lock)
+pub extern "C" fn test_syscall_handler(stack_frame: &mut SyscallStackFrame) -> isize {
+    kernel_lock::acquire();
+    let result = syscall_handler(stack_frame) as isize;
+    kernel_lock::release();
+
+    result
 }
 
 pub extern "C" fn interrupt_handler(stack_frame: &InterruptStackFrame, interrupt_id: usize) {
-    PICS.lock().acknowledge(interrupt_id as u8);
-    PICS.free();
+    end_of_interrupt(interrupt_id);
+
+    kernel_lock::acquire();
 
     match interrupt_id {
         0x21 => {
             KEYBOARD.lock().handle_keyboard();
             KEYBOARD.free();
         }
+        0x24 => uart::handle_serial_input(),
         0x2c => {
             MOUSE.lock().handle_mouse_interrupt();
             MOUSE.free();
         }
         _ => {}
     }
+
+    // Processes sleeping in get_event check whether the interrupt produced an event
+    PROCESS_MANAGER.lock().wake_all(EVENT_WAITERS.lock());
+    EVENT_WAITERS.free();
+    PROCESS_MANAGER.free();
+
+    kernel_lock::release();
+}
+
+// Interrupts delivered through the IO APIC are acknowledged at the local APIC instead of the PIC
+fn end_of_interrupt(interrupt_id: usize) {
+    if ioapic::is_enabled() {
+        lapic::end_of_interrupt();
+    } else {
+        PICS.lock().acknowledge(interrupt_id as u8);
+        PICS.free();
+    }
+}
+
+// Sent by another CPU which changed the mappings of the address space this CPU runs
+pub extern "C" fn tlb_shootdown_handler(stack_frame: &InterruptStackFrame, interrupt_id: usize) {
+    tlb::handle_shootdown();
+    lapic::end_of_interrupt();
 }
 
+// Spurious interrupts from the local APIC must not be acknowledged
+pub extern "C" fn spurious_handler(stack_frame: &InterruptStackFrame, interrupt_id: usize) {}
+
 pub extern "C" fn exception_with_error_handler(
-    stack_frame: &StackFrame,
+    old_rsp: usize,
     exception_id: usize,
     mut error_code: usize,
-) {
+) -> usize {
+    let stack_frame = get_exception_stack_frame(old_rsp);
+
+    // Demand paging and copy on write are resolved by the process before retrying the instruction
+    if exception_id == 14 {
+        let is_present = error_code & 0x1 != 0;
+        let is_write = error_code & 0x2 != 0;
+
+        // Faults within a syscall already hold the kernel lock
+        let is_locked = kernel_lock::acquire_unless_held();
+        let is_resolved = PROCESS_MANAGER
+            .lock()
+            .handle_page_fault(read_cr2(), is_present, is_write);
+        PROCESS_MANAGER.free();
+        if is_locked {
+            kernel_lock::release();
+        }
+
+        if is_resolved {
+            return old_rsp;
+        }
+    }
+
+    if is_from_user(stack_frame) {
+        return handle_user_fault(stack_frame, old_rsp, exception_id, error_code);
+    }
+
     print_serial!("{}\n", EXCEPTION_MESSAGES[exception_id]);
     print_serial!("{:?}\n", stack_frame);
 
     match exception_id {
+        8 => {
+            // Overflowing a kernel stack faults on its guard page whilst pushing the page fault frame
+            if kernel_stack::is_guard_page(read_cr2()) {
+                print_serial!("Kernel stack overflow at 0x{:x}\n", read_cr2());
+            }
+        }
         13 => {
             let external = error_code & 0x1 != 0;
             let idt = error_code & 0x2 != 0;
@@ -224,25 +392,35 @@ pub extern "C" fn exception_with_error_handler(
         }
         14 => {
             // Handle page fault by displaying which flags are set within error code
-            print_serial!("{}\n", EXCEPTION_MESSAGES[exception_id]);
-
-            for (index, flag) in PageFaultFlags::iter() {
-                if ((index << 1) & error_code) != 0 {
-                    print_serial!("{:?} is SET\n", flag);
-                }
-            }
+            print_serial!("CR2: 0x{:x}\n", read_cr2());
+            print_page_fault_flags(error_code);
         }
         _ => {}
     }
 
     panic!("dono");
-
-    loop {}
 }
 
+/*
+    The PIT keeps the time of the kernel whilst the local APIC timer of each CPU drives its scheduling
+    Without a local APIC the PIT also drives the scheduling of the only CPU
+*/
 pub extern "C" fn pit_handler(old_task_rsp: usize) -> usize {
-    PICS.lock().acknowledge(0x20 as u8);
-    PICS.free();
+    end_of_interrupt(0x20);
+
+    let pit = PIT.lock();
+    pit.handle_timer();
+    let ticks = pit.ticks();
+    PIT.free();
+
+    // Processes whose sleep has finished can be picked by the next switch
+    kernel_lock::acquire();
+    PROCESS_MANAGER
+        .lock()
+        .wake_expired_timers(ticks, TIMER_WAITERS.lock());
+    TIMER_WAITERS.free();
+    PROCESS_MANAGER.free();
+    kernel_lock::release();
 
     unsafe {
         let fb_addr = unsafe { FB_ADDR };
@@ -250,56 +428,149 @@ pub extern "C" fn pit_handler(old_task_rsp: usize) -> usize {
         TOP_BAR.free();
     }
 
+    if lapic::is_initialised() {
+        return old_task_rsp;
+    }
+
+    let is_locked = kernel_lock::acquire_unless_held();
+    let rsp = PROCESS_MANAGER.lock().timer_tick(old_task_rsp);
+    PROCESS_MANAGER.free();
+    if is_locked {
+        kernel_lock::release();
+    }
+
+    rsp
+}
+
+/*
+    Every CPU switches between processes on the ticks of its own local APIC timer
+    Switching delivers the signals of the next process which changes processes and memory that syscalls on other CPUs use
+    so it holds the kernel lock like they do (unless this CPU already holds it, eg a kernel thread was interrupted)
+*/
+pub extern "C" fn apic_timer_handler(old_task_rsp: usize) -> usize {
+    lapic::end_of_interrupt();
+
+    let is_locked = kernel_lock::acquire_unless_held();
+    let rsp = PROCESS_MANAGER.lock().timer_tick(old_task_rsp);
+    PROCESS_MANAGER.free();
+    if is_locked {
+        kernel_lock::release();
+    }
+
+    rsp
+}
+
+// Entered by a process which sleeps within a syscall so another process can run (it gave up the kernel lock beforehand)
+pub extern "C" fn yield_handler(old_task_rsp: usize) -> usize {
+    let is_locked = kernel_lock::acquire_unless_held();
     let rsp = PROCESS_MANAGER.lock().switch_process(old_task_rsp);
     PROCESS_MANAGER.free();
+    if is_locked {
+        kernel_lock::release();
+    }
 
     rsp
 }
 
 pub fn init() {
     unsafe {
-        // Setup exceptions
-        IDT[0] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 0));
-        IDT[1] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 1));
-        IDT[2] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 2));
-        IDT[3] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 3));
-        IDT[4] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 4));
-        IDT[5] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 5));
-        IDT[6] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 6));
-        IDT[7] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 7));
-        IDT[8] = IDTEntry::new_default_trap(setup_exception_with_e_handler!(8));
-        IDT[9] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 9));
-        IDT[10] = IDTEntry::new_default_trap(setup_exception_with_e_handler!(10));
-        IDT[11] = IDTEntry::new_default_trap(setup_exception_with_e_handler!(11));
-        IDT[12] = IDTEntry::new_default_trap(setup_exception_with_e_handler!(12));
-        IDT[13] = IDTEntry::new_default_trap(setup_exception_with_e_handler!(13));
-        IDT[14] = IDTEntry::new_default_trap(setup_exception_with_e_handler!(14));
-        IDT[16] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 16));
-        IDT[17] = IDTEntry::new_default_trap(setup_exception_with_e_handler!(17));
-        IDT[18] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 18));
-        IDT[19] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 19));
-        IDT[20] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 20));
-        IDT[21] = IDTEntry::new_default_trap(setup_exception_with_e_handler!(21));
-        IDT[28] = IDTEntry::new_default_trap(setup_interrupt_handler!(exception_handler, 28));
-        IDT[29] = IDTEntry::new_default_trap(setup_exception_with_e_handler!(29));
-        IDT[30] = IDTEntry::new_default_trap(setup_exception_with_e_handler!(30));
+        // Setup exceptions (interrupts stay disabled as a user fault may switch process)
+        IDT[0] = IDTEntry::new_default_interrupt(setup_exception_handler!(0));
+        IDT[1] = IDTEntry::new_default_interrupt(setup_exception_handler!(1));
+        IDT[2] = IDTEntry::new_default_interrupt(setup_exception_handler!(2));
+        IDT[3] = IDTEntry::new_default_interrupt(setup_exception_handler!(3));
+        IDT[4] = IDTEntry::new_default_interrupt(setup_exception_handler!(4));
+        IDT[5] = IDTEntry::new_default_interrupt(setup_exception_handler!(5));
+        IDT[6] = IDTEntry::new_default_interrupt(setup_exception_handler!(6));
+        IDT[7] = IDTEntry::new_default_interrupt(setup_exception_handler!(7));
+        IDT[8] = IDTEntry::new_default_interrupt(setup_exception_with_e_handler!(8))
+            .with_ist(gdt::DOUBLE_FAULT_IST_INDEX);
+        IDT[9] = IDTEntry::new_default_interrupt(setup_exception_handler!(9));
+        IDT[10] = IDTEntry::new_default_interrupt(setup_exception_with_e_handler!(10));
+        IDT[11] = IDTEntry::new_default_interrupt(setup_exception_with_e_handler!(11));
+        IDT[12] = IDTEntry::new_default_interrupt(setup_exception_with_e_handler!(12));
+        IDT[13] = IDTEntry::new_default_interrupt(setup_exception_with_e_handler!(13));
+        IDT[14] = IDTEntry::new_default_interrupt(setup_exception_with_e_handler!(14));
+        IDT[16] = IDTEntry::new_default_interrupt(setup_exception_handler!(16));
+        IDT[17] = IDTEntry::new_default_interrupt(setup_exception_with_e_handler!(17));
+        IDT[18] = IDTEntry::new_default_interrupt(setup_exception_handler!(18));
+        IDT[19] = IDTEntry::new_default_interrupt(setup_exception_handler!(19));
+        IDT[20] = IDTEntry::new_default_interrupt(setup_exception_handler!(20));
+        IDT[21] = IDTEntry::new_default_interrupt(setup_exception_with_e_handler!(21));
+        IDT[28] = IDTEntry::new_default_interrupt(setup_exception_handler!(28));
+        IDT[29] = IDTEntry::new_default_interrupt(setup_exception_with_e_handler!(29));
+        IDT[30] = IDTEntry::new_default_interrupt(setup_exception_with_e_handler!(30));
 
         // General interrupts
-        IDT[0x20] = IDTEntry::new_default_interrupt(setup_pit_handler); // Timer (PIT)
+        IDT[0x20] = IDTEntry::new_default_interrupt(setup_switching_handler!(pit_handler)); // Timer (PIT)
         IDT[0x21] =
             IDTEntry::new_default_interrupt(setup_interrupt_handler!(interrupt_handler, 0x21)); // Keyboard
+        IDT[0x24] =
+            IDTEntry::new_default_interrupt(setup_interrupt_handler!(interrupt_handler, 0x24)); // Serial
         IDT[0x2c] =
             IDTEntry::new_default_interrupt(setup_interrupt_handler!(interrupt_handler, 0x2c)); // Mouse
 
+        // Local APIC
+        IDT[lapic::TIMER_VECTOR] =
+            IDTEntry::new_default_interrupt(setup_switching_handler!(apic_timer_handler));
+        IDT[TLB_SHOOTDOWN_VECTOR] = IDTEntry::new_default_interrupt(setup_interrupt_handler!(
+            tlb_shootdown_handler,
+            TLB_SHOOTDOWN_VECTOR
+        ));
+        IDT[lapic::SPURIOUS_VECTOR] = IDTEntry::new_default_interrupt(setup_interrupt_handler!(
+            spurious_handler,
+            lapic::SPURIOUS_VECTOR
+        ));
+
         // Syscalls
         IDT[0x80] = IDTEntry::new_default_interrupt(setup_syscall_handler);
 
+        // Only the kernel may yield on behalf of a sleeping process
+        IDT[YIELD_INTERRUPT] = IDTEntry::new(
+            GateType::Interrupt,
+            PrivilegeLevel::Ring0,
+            setup_switching_handler!(yield_handler),
+        );
+
         // Actually set the IDTR values
         let idt_address = (&IDT[0] as *const IDTEntry) as u64;
         IDTR.limit = (core::mem::size_of::<IDTEntry>() as u16) * (IDT_MAX_DESCRIPTIONS as u16 - 1);
         IDTR.base = idt_address;
+    }
 
-        // Refresh
+    load_idt();
+}
+
+/*
+    Unmasks the legacy IRQs which have a handler at whichever controller delivers them
+    With an IO APIC the PIC is masked entirely and the IRQs are sent to the bootstrap processor
+*/
+pub fn enable_irqs() {
+    if ioapic::is_enabled() {
+        PICS.lock().disable();
+        PICS.free();
+
+        lapic::disable_virtual_wire();
+
+        let bsp_apic_id = lapic::id();
+
+        for &irq in HANDLED_IRQS {
+            ioapic::IO_APICS
+                .lock()
+                .route(irq, IRQ_BASE_VECTOR + irq, bsp_apic_id);
+            ioapic::IO_APICS.free();
+        }
+    } else {
+        for &irq in HANDLED_IRQS {
+            PICS.lock().clean_mask((IRQ_BASE_VECTOR + irq) as u8);
+            PICS.free();
+        }
+    }
+}
+
+// Every CPU loads the same IDT
+pub fn load_idt() {
+    unsafe {
         flush_idt();
     }
 }
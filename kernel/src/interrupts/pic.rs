@@ -85,13 +85,17 @@ impl ChainedPics {
         outb(self.slave.data, 1);
         io_wait();
 
-        // f9 - kbd, slave, f8 - kbd, slave, pit, fd - kbd
-        // ef - mouse, ff - completely disable
-
-        outb(self.master.data, 0xf8);
-        outb(self.slave.data, 0xef);
+        // Every IRQ starts masked apart from the slave PIC (IRQ 2) and is unmasked once it has a handler
+        outb(self.master.data, 0xfb);
+        outb(self.slave.data, 0xff);
         io_wait();
     }
+
+    // Masks every IRQ once the IO APIC delivers them instead
+    pub fn disable(&self) {
+        outb(self.master.data, 0xff);
+        outb(self.slave.data, 0xff);
+    }
 }
 
 impl PicFunctions for ChainedPics {
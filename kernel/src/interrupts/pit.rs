@@ -2,18 +2,20 @@
     Programmable interval timer is a chip which is used to implement a system clock as it sends interrupts on a regular basis
     Channel 0 (0x40) is connected to IRQ 0
     0x43 is command port
+    Every interrupt is counted which gives the kernel a monotonic clock since boot
 */
 
-use crate::utils::ports::outb;
+use crate::utils::ports::{inb, outb};
 use crate::utils::spinlock::Lock;
 
 pub struct Pit {
     divisor: usize,
-    ticks: usize,
+    ticks: usize, // Interrupts received since the timer was started
 }
 
 const INPUT_CLOCK: usize = 1193180;
-const FREQUENCY: usize = 100;
+pub const FREQUENCY: usize = 100;
+pub const NANOSECONDS_PER_TICK: usize = 1_000_000_000 / FREQUENCY;
 
 impl Pit {
     pub const fn new(frequency: usize) -> Pit {
@@ -33,6 +35,15 @@ impl Pit {
         self.ticks += 1;
     }
 
+    pub fn ticks(&self) -> usize {
+        self.ticks
+    }
+
+    // Time since the timer was started (only as precise as a single tick)
+    pub fn uptime_nanoseconds(&self) -> usize {
+        self.ticks * NANOSECONDS_PER_TICK
+    }
+
     fn set_frequency(&self) {
         // To set a frequency, a divisor is sent in bits
         outb(0x40, (self.divisor & 0xFF) as u8);
@@ -41,3 +52,33 @@ impl Pit {
 }
 
 pub static PIT: Lock<Pit> = Lock::new(Pit::new(FREQUENCY));
+
+/*
+    Waits for a number of microseconds without interrupts by counting down channel 2 (normally wired to the PC speaker)
+    Port 0x61 gates channel 2 (bit 0) and reads its output (bit 5) which goes high once the count reaches 0
+    The count is 16 bit so long waits are split into several countdowns
+*/
+pub fn busy_wait(microseconds: usize) {
+    let mut remaining = microseconds * INPUT_CLOCK / 1_000_000;
+
+    // Keep the speaker itself off (bit 1)
+    let gate = inb(0x61) & !0x2;
+
+    while remaining > 0 {
+        let count = remaining.min(0xFFFF);
+
+        outb(0x61, gate & !0x1);
+        outb(0x43, 0xB0); // Channel 2, low then high byte, mode 0 (interrupt on terminal count)
+        outb(0x42, (count & 0xFF) as u8);
+        outb(0x42, (count >> 8) as u8);
+        outb(0x61, gate | 0x1);
+
+        while inb(0x61) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+
+        remaining -= count;
+    }
+
+    outb(0x61, gate & !0x1);
+}
@@ -14,6 +14,7 @@ mod interrupts;
 mod memory;
 mod multitask;
 mod output;
+mod smp;
 mod utils;
 
 use utils::event::EVENT_MANAGER;
@@ -21,6 +22,7 @@ use utils::event::EVENT_MANAGER;
 use crate::dev::mouse;
 use crate::gfx::init;
 use crate::memory::allocator::{kfree, kmalloc, print_memory_list};
+use crate::memory::kernel_stack::KERNEL_STACKS;
 use crate::memory::page_frame_allocator::PAGE_FRAME_ALLOCATOR;
 use crate::memory::paging;
 use crate::multitask::PROCESS_MANAGER;
@@ -51,6 +53,9 @@ pub extern "C" fn rust_main(multiboot_info_addr: usize, magic: usize) {
     PAGE_FRAME_ALLOCATOR.lock().init(&multiboot_info);
     PAGE_FRAME_ALLOCATOR.free();
 
+    KERNEL_STACKS.lock().init();
+    KERNEL_STACKS.free();
+
     PROCESS_MANAGER.lock().init();
     PROCESS_MANAGER.free();
 
@@ -60,6 +65,9 @@ pub extern "C" fn rust_main(multiboot_info_addr: usize, magic: usize) {
     interrupts::pit::PIT.lock().init();
     interrupts::pit::PIT.free();
 
+    utils::time::CLOCK.lock().init();
+    utils::time::CLOCK.free();
+
     dev::init();
 
     interrupts::init();
@@ -67,6 +75,18 @@ pub extern "C" fn rust_main(multiboot_info_addr: usize, magic: usize) {
     interrupts::pic::PICS.lock().init();
     interrupts::pic::PICS.free();
 
+    // Other CPUs are only started when the firmware lists them (the registers must be mapped before userland clones the kernel)
+    if utils::acpi::init(&multiboot_info) && interrupts::lapic::is_supported() {
+        interrupts::lapic::init();
+
+        interrupts::ioapic::IO_APICS.lock().init();
+        interrupts::ioapic::IO_APICS.free();
+
+        smp::init();
+    }
+
+    interrupts::enable_irqs();
+
     grub::bga_set_video_mode();
     gfx::init(multiboot_info.get_framebuffer_tag().expect("Expected FB"));
 
@@ -32,12 +32,19 @@ impl MemoryBlock {
 
 static FREE_MEMORY_BLOCK_LIST: Lock<List<MemoryBlock>> = Lock::new(List::<MemoryBlock>::new());
 
+// Held for a whole allocation or free as finding and splitting a block takes the list lock several times
+static ALLOCATOR: Lock<()> = Lock::new(());
+
 /*
     Recives the size of data in bytes which is to be used
     Returns pointer to data region
 */
 pub fn kmalloc(mut size: usize) -> *mut usize {
-    _kmalloc(size, true)
+    ALLOCATOR.lock();
+    let data_addr = _kmalloc(size, true);
+    ALLOCATOR.free();
+
+    data_addr
 }
 
 fn _kmalloc(mut size: usize, should_update_size: bool) -> *mut usize {
@@ -97,6 +104,8 @@ fn _kmalloc(mut size: usize, should_update_size: bool) -> *mut usize {
     Frees a memory region which can later be allocated
 */
 pub fn kfree(data_addr: *mut usize) {
+    ALLOCATOR.lock();
+
     let header_addr = get_header_address(data_addr);
     let node = unsafe { &mut *(header_addr as *mut ListNode<MemoryBlock>) };
     let memory_block = node.payload.clone();
@@ -112,6 +121,8 @@ pub fn kfree(data_addr: *mut usize) {
         .push_back(memory_block, header_addr as usize);
     FREE_MEMORY_BLOCK_LIST.free();
 
+    ALLOCATOR.free();
+
     // let updated_node = unsafe { &mut *(header_addr as *mut ListNode<MemoryBlock>) };
 
     // Check next node to merge memory regions together to alleviate fragmentation
@@ -2,6 +2,7 @@
     Global descriptor table which contains 8 byte entries about memory segments
     Previousl setting the GDT within rust and calling the relevant assembly has proven futile
     The entries for the GDT will be generated here and the values will be copied into the boot file
+    Every CPU has its own GDT and TSS as the TSS holds the kernel stack of the process the CPU runs
 */
 
 /*
@@ -13,8 +14,9 @@ GDT Entry:
 +---------+------------+------------+-----------------+-----------+---------+---------+--------+--------+---------+
 */
 
-use crate::{output::uart::CONSOLE, print_serial};
+use crate::{either, output::uart::CONSOLE, print_serial, smp::MAX_CPUS};
 use core::arch::asm;
+use core::mem::size_of;
 
 #[derive(Debug, Clone)]
 #[repr(C)]
@@ -23,9 +25,19 @@ pub struct GlobalDescriptorTable {
     len: usize,
 }
 
+const EMPTY_GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
+const EMPTY_TSS: TaskStateSegment = TaskStateSegment::new();
+
+// Indexed by CPU
 #[no_mangle]
-pub static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
-pub static mut TSS: TaskStateSegment = TaskStateSegment::new();
+pub static mut GDTS: [GlobalDescriptorTable; MAX_CPUS] = [EMPTY_GDT; MAX_CPUS];
+pub static mut TSSS: [TaskStateSegment; MAX_CPUS] = [EMPTY_TSS; MAX_CPUS];
+
+// Double faults get their own stack (IST1) as they are usually caused by a kernel stack overflowing
+pub const DOUBLE_FAULT_IST_INDEX: u8 = 1;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 4;
+static mut DOUBLE_FAULT_STACKS: [[u8; DOUBLE_FAULT_STACK_SIZE]; MAX_CPUS] =
+    [[0; DOUBLE_FAULT_STACK_SIZE]; MAX_CPUS];
 
 // Load GDT
 fn lgdt(gdt: &GDTPointer) {
@@ -34,11 +46,44 @@ fn lgdt(gdt: &GDTPointer) {
     }
 }
 
+// Called by the bootstrap processor (CPU 0)
 pub fn init() {
+    init_cpu(0);
+}
+
+pub fn init_cpu(cpu: usize) {
     unsafe {
-        GDT.initalise();
-        GDT.load();
-        GDT.load_tss();
+        // The CPU aligns the stack pointer to 16 bytes when switching stacks
+        TSSS[cpu].interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize - 1] =
+            DOUBLE_FAULT_STACKS[cpu].as_ptr() as usize + DOUBLE_FAULT_STACK_SIZE;
+
+        GDTS[cpu].initalise(&TSSS[cpu]);
+        GDTS[cpu].load();
+        GDTS[cpu].load_tss();
+    }
+}
+
+/*
+    Finds which CPU is running from the address of the GDT it loaded
+    Anything else (eg the GDT of boot.asm before init) counts as the bootstrap processor
+*/
+pub fn cpu_index() -> usize {
+    let mut pointer = GDTPointer { limit: 0, base: 0 };
+
+    unsafe {
+        asm!("sgdt [{}]", in(reg) &mut pointer, options(nostack, preserves_flags));
+    }
+
+    let first = unsafe { core::ptr::addr_of!(GDTS) as usize };
+    let index = pointer.base.wrapping_sub(first) / size_of::<GlobalDescriptorTable>();
+
+    either!(index < MAX_CPUS => index; 0)
+}
+
+// Loaded into RSP0 of the TSS of the current CPU whenever it switches to a process
+pub fn set_kernel_stack(stack_top: usize) {
+    unsafe {
+        TSSS[cpu_index()].privilege_stack_table[0] = stack_top;
     }
 }
 
@@ -56,14 +101,12 @@ impl GlobalDescriptorTable {
         }
     }
 
-    pub fn initalise(&mut self) {
+    pub fn initalise(&mut self, tss: &TaskStateSegment) {
         self.add_entry(Descriptor::kernel_code_segment());
         self.add_entry(Descriptor::kernel_data_segment());
         self.add_entry(Descriptor::user_code_segment());
         self.add_entry(Descriptor::user_data_segment());
-        unsafe {
-            self.add_entry(Descriptor::task_state_segment(&TSS));
-        }
+        self.add_entry(Descriptor::task_state_segment(tss));
     }
 
     pub fn load(&self) {
@@ -77,7 +120,6 @@ impl GlobalDescriptorTable {
     }
 
     pub fn pointer(&self) -> GDTPointer {
-        use core::mem::size_of;
         GDTPointer {
             base: self.table.as_ptr() as usize,
             limit: (self.len * size_of::<usize>() - 1) as u16,
@@ -155,8 +197,6 @@ impl Descriptor {
     }
 
     pub fn task_state_segment(tss: &TaskStateSegment) -> Descriptor {
-        use core::mem::size_of;
-
         let mut descriptor: usize = 0;
         let base = tss as *const _ as usize;
         let limit: usize = (size_of::<TaskStateSegment>() - 1);
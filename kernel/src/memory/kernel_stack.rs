@@ -0,0 +1,147 @@
+/*
+    Every process has its own kernel stack which the CPU switches to (through RSP0 of the TSS) when an interrupt or syscall arrives from user mode
+    Frames saved by the scheduler and syscalls which sleep therefore never clobber the stack of another process
+    Stacks live within their own P4 entry which every address space shares rather than copies so a stack is reachable whichever address space is active
+    The page below each stack is left unmapped as a guard so an overflow faults instead of silently corrupting memory
+
+    +-------------+-------+-------+-------+-------+-------------+-----
+    | Guard page  | Stack | Stack | Stack | Stack | Guard page  | ...
+    +-------------+-------+-------+-------+-------+-------------+-----
+    ^ KERNEL_STACKS_START                         ^ Next slot
+*/
+
+use core::arch::asm;
+
+use crate::ds::vec::DynamicArray;
+use crate::smp::{self, tlb, MAX_CPUS};
+use crate::utils::spinlock::Lock;
+
+use super::page_frame_allocator::PAGE_FRAME_ALLOCATOR;
+use super::paging::{self, PAGE_SIZE};
+
+pub const KERNEL_STACKS_P4_INDEX: usize = 510;
+const KERNEL_STACKS_START: usize = 0xFFFF_FF00_0000_0000; // First address covered by the P4 entry
+
+const KERNEL_STACK_PAGES: usize = 4;
+const SLOT_SIZE: usize = (KERNEL_STACK_PAGES + 1) * PAGE_SIZE; // Includes the guard page
+
+pub static KERNEL_STACKS: Lock<KernelStackAllocator> = Lock::new(KernelStackAllocator::new());
+
+#[derive(Debug, Copy, Clone)]
+pub struct KernelStack {
+    slot: usize, // Address of the guard page
+}
+
+pub struct KernelStackAllocator {
+    next_slot: usize,
+    free_slots: DynamicArray<usize>,
+    retired: [Option<KernelStack>; MAX_CPUS], // Stack which was free'd whilst each CPU was still running on it
+}
+
+impl KernelStack {
+    // Stacks grow down so this is the initial stack pointer
+    pub fn top(&self) -> usize {
+        self.slot + SLOT_SIZE
+    }
+
+    fn contains(&self, address: usize) -> bool {
+        address >= self.slot && address < self.top()
+    }
+}
+
+impl KernelStackAllocator {
+    pub const fn new() -> KernelStackAllocator {
+        KernelStackAllocator {
+            next_slot: KERNEL_STACKS_START,
+            free_slots: DynamicArray::<usize>::new(),
+            retired: [None; MAX_CPUS],
+        }
+    }
+
+    // Must be called before any address space is cloned so that every clone shares the stacks
+    pub fn init(&mut self) {
+        self.free_slots.init();
+        paging::create_p4_entry(KERNEL_STACKS_P4_INDEX);
+    }
+
+    pub fn allocate(&mut self) -> KernelStack {
+        self.release_retired();
+
+        let slot = match self.free_slots.pop() {
+            Some(slot) => slot,
+            None => {
+                let slot = self.next_slot;
+                self.next_slot += SLOT_SIZE;
+                slot
+            }
+        };
+
+        let frames = PAGE_FRAME_ALLOCATOR
+            .lock()
+            .alloc_page_frames(KERNEL_STACK_PAGES);
+        PAGE_FRAME_ALLOCATOR.free();
+
+        paging::map_kernel_pages(KERNEL_STACK_PAGES, slot + PAGE_SIZE, frames as usize);
+
+        KernelStack { slot }
+    }
+
+    /*
+        A process may be cleaned up whilst the CPU is still running on its stack (eg after exiting from a syscall)
+        That stack is retired and only released once the kernel has moved onto another stack
+    */
+    pub fn free(&mut self, stack: KernelStack) {
+        self.release_retired();
+
+        if stack.contains(current_rsp()) {
+            self.retired[smp::current_cpu()] = Some(stack);
+        } else {
+            self.release(stack);
+        }
+    }
+
+    // Called on every context switch to return a retired stack once it is no longer in use
+    pub fn release_retired(&mut self) {
+        let cpu = smp::current_cpu();
+
+        if let Some(stack) = self.retired[cpu] {
+            if !stack.contains(current_rsp()) {
+                self.retired[cpu] = None;
+                self.release(stack);
+            }
+        }
+    }
+
+    fn release(&mut self, stack: KernelStack) {
+        let p4 = paging::get_current_p4();
+
+        for i in 1..=KERNEL_STACK_PAGES {
+            if let Some(frame) = paging::unmap_page(p4, stack.slot + i * PAGE_SIZE) {
+                unsafe {
+                    PAGE_FRAME_ALLOCATOR
+                        .lock()
+                        .free_page_frame(frame as *mut usize);
+                    PAGE_FRAME_ALLOCATOR.free();
+                }
+            }
+        }
+
+        // Every address space shares the stacks so no CPU may keep the old translations
+        tlb::shootdown_all();
+
+        self.free_slots.push(stack.slot);
+    }
+}
+
+// Faults on a guard page mean a kernel stack overflowed
+pub fn is_guard_page(address: usize) -> bool {
+    address >= KERNEL_STACKS_START && (address - KERNEL_STACKS_START) % SLOT_SIZE < PAGE_SIZE
+}
+
+fn current_rsp() -> usize {
+    let rsp: usize;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+    rsp
+}
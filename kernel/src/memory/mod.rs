@@ -1,4 +1,6 @@
 pub mod allocator;
 pub mod gdt;
+pub mod kernel_stack;
 pub mod page_frame_allocator;
 pub mod paging;
+pub mod vma;
@@ -1,8 +1,11 @@
 /*
 Physical memory is split into 4096 byte chunks called page frames
 To manage the frames, a stack of free pages along with a pointer to first page are used
+Each frame has a reference count as copy on write lets several address spaces share a frame
 */
 
+use core::mem::size_of;
+
 use crate::ds::stack;
 use crate::utils::multiboot2::MultibootBootInfo;
 use crate::{print_serial, utils::spinlock::Lock, CONSOLE};
@@ -26,6 +29,7 @@ pub struct PageFrameAllocator {
     memory_end: usize,
     pub free_page_frames: Option<&'static mut FreeStack>,
     pub current_page: usize,
+    reference_counts: *mut u16, // One for every frame between the start and end of memory
 }
 
 impl PageFrame {
@@ -41,6 +45,7 @@ impl PageFrameAllocator {
             memory_end: 0,
             free_page_frames: None,
             current_page: 0,
+            reference_counts: core::ptr::null_mut(),
         }
     }
 
@@ -50,7 +55,48 @@ impl PageFrameAllocator {
         self.memory_end = round_to_nearest_page(multiboot_info.end_of_useable_memory());
 
         self.free_page_frames = unsafe { Some(&mut *(self.memory_start as *mut FreeStack)) };
-        self.current_page = self.memory_start + paging::PAGE_SIZE;
+
+        // Reference counts are stored straight after the free stack
+        let frame_count = (self.memory_end - self.memory_start) / paging::PAGE_SIZE;
+        let table_size = round_to_nearest_page(frame_count * size_of::<u16>());
+
+        self.reference_counts = (self.memory_start + paging::PAGE_SIZE) as *mut u16;
+
+        unsafe {
+            core::ptr::write_bytes(self.reference_counts as *mut u8, 0, table_size);
+        }
+
+        self.current_page = self.memory_start + paging::PAGE_SIZE + table_size;
+    }
+
+    // Frames outside of the managed memory (eg the kernel itself) aren't counted
+    fn get_reference_count_mut(&mut self, frame_address: usize) -> Option<&mut u16> {
+        if frame_address < self.memory_start || frame_address >= self.memory_end {
+            return None;
+        }
+
+        let index = (frame_address - self.memory_start) / paging::PAGE_SIZE;
+        unsafe { Some(&mut *self.reference_counts.add(index)) }
+    }
+
+    pub fn get_reference_count(&mut self, frame_address: usize) -> usize {
+        match self.get_reference_count_mut(frame_address) {
+            Some(count) => *count as usize,
+            None => 1,
+        }
+    }
+
+    // Called when another address space starts sharing the frame
+    pub fn add_reference(&mut self, frame_address: usize) {
+        if let Some(count) = self.get_reference_count_mut(frame_address) {
+            *count += 1;
+        }
+    }
+
+    fn set_reference_count(&mut self, frame_address: usize, value: u16) {
+        if let Some(count) = self.get_reference_count_mut(frame_address) {
+            *count = value;
+        }
     }
 
     /*
@@ -61,10 +107,15 @@ impl PageFrameAllocator {
         if let Some(free_frames) = self.free_page_frames.as_mut() {
             if let Some(page_frame) = free_frames.pop() {
                 unsafe {
-                    return Some((*page_frame).get_address() as *mut usize);
+                    // The rest of the frame was zeroed when it was free'd
+                    core::ptr::write_bytes(page_frame as *mut u8, 0, size_of::<PageFrame>());
                 }
+
+                self.set_reference_count(page_frame as usize, 1);
+                return Some(page_frame as *mut usize);
             } else if (self.current_page < self.memory_end) {
                 self.current_page += paging::PAGE_SIZE;
+                self.set_reference_count(self.current_page, 1);
                 return Some(self.current_page as *mut usize);
             }
         }
@@ -72,8 +123,17 @@ impl PageFrameAllocator {
         None
     }
 
-    // Add the address of the free'd page to the stack
+    // Add the address of the free'd page to the stack once nothing else refers to it
     pub unsafe fn free_page_frame(&mut self, frame_address: *mut usize) {
+        if let Some(count) = self.get_reference_count_mut(frame_address as usize) {
+            if *count > 1 {
+                *count -= 1;
+                return;
+            }
+
+            *count = 0;
+        }
+
         // Need to zero out the page for safety
         unsafe {
             core::ptr::write_bytes(frame_address as *mut u8, 0, PAGE_SIZE);
@@ -92,14 +152,26 @@ impl PageFrameAllocator {
         let address = self.current_page + paging::PAGE_SIZE;
         for _i in 0..pages_required {
             self.current_page += paging::PAGE_SIZE;
+            self.set_reference_count(self.current_page, 1);
         }
         return address as *mut usize;
     }
 
+    // Bytes which can still be allocated (frames never handed out along with those which were free'd)
+    pub fn free_memory(&self) -> usize {
+        let freed_frames = self
+            .free_page_frames
+            .as_ref()
+            .map_or(0, |free_frames| free_frames.length);
+
+        self.memory_end.saturating_sub(self.current_page) + freed_frames * paging::PAGE_SIZE
+    }
+
     // Frees a continuous amount of memory
     pub fn free_page_frames(&mut self, frame_address: *mut usize, pages_required: usize) {
         for i in 0..pages_required {
-            unsafe { self.free_page_frame(frame_address.offset(i as isize)) }
+            let address = frame_address as usize + i * paging::PAGE_SIZE;
+            unsafe { self.free_page_frame(address as *mut usize) }
         }
     }
 }
@@ -119,6 +191,7 @@ impl FreeStack {
             unsafe {
                 self.top = (*cloned_top).next;
             }
+            self.length -= 1;
             return Some(cloned_top);
         }
 
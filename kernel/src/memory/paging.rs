@@ -17,19 +17,31 @@ Page table entries have a certain 64 bit format which looks like this:
 +---------+-----------+------------------+---------------+---------------+-------+-----------+--------+-----------+------------------+-----------+------------+
 | present | writable |  user accessible | write through | disable cache | dirty | huge page | global | available | physical address | available | no execute |
 +---------+-----------+------------------+---------------+---------------+-------+-----------+--------+-----------+------------------+-----------+------------+
+
+Bit 9 (available) marks user pages which are shared copy on write after a fork
+
+The P4 entry holding the kernel stacks is shared (not copied) between address spaces so every stack is mapped in all of them
 */
 
+use core::arch::asm;
 use core::{future::IntoFuture, num};
 
-use crate::{print_serial, CONSOLE};
+use crate::{either, multitask::process::USER_PROCESS_START_ADDRESS, print_serial, smp, CONSOLE};
 
-use super::{allocator::kmalloc, page_frame_allocator::PAGE_FRAME_ALLOCATOR};
+use super::kernel_stack::KERNEL_STACKS_P4_INDEX;
+use super::{allocator::kmalloc, page_frame_allocator::PAGE_FRAME_ALLOCATOR, vma};
 
 pub const PAGE_SIZE: usize = 4096;
 
 pub const P4: *mut PageTable = 0xffffffff_fffff000 as *mut _;
 
-enum PageFlags {
+const ADDRESS_MASK: usize = 0x000fffff_fffff000;
+const PRESENT: usize = 1 << 0;
+const WRITABLE: usize = 1 << 1;
+const USER_ACCESSIBLE: usize = 1 << 2;
+const COPY_ON_WRITE: usize = 1 << 9;
+
+pub enum PageFlags {
     Present,
     Writable,
     UserAccessible,
@@ -38,6 +50,7 @@ enum PageFlags {
     Dirty,
     Huge,
     Global,
+    NoExecute, // Requires NXE to be set in EFER
 }
 
 const FLAGS: [PageFlags; 3] = [
@@ -77,6 +90,7 @@ impl Page {
                 PageFlags::Dirty => (1 << 6) | entry_data,
                 PageFlags::Huge => (1 << 7) | entry_data,
                 PageFlags::Global => (1 << 8) | entry_data,
+                PageFlags::NoExecute => (1 << 63) | entry_data,
                 _ => entry_data,
             };
         }
@@ -87,15 +101,26 @@ impl Page {
     pub fn get_physical_address(&self) -> usize {
         return 0x000fffff_fffff000 & self.0;
     }
+
+    pub fn is_present(&self) -> bool {
+        self.0 & PRESENT != 0
+    }
+
+    pub fn is_copy_on_write(&self) -> bool {
+        self.0 & COPY_ON_WRITE != 0
+    }
 }
 
 impl PageTable {
-    // Map a virtual address to a physical address
-    fn map_recursive(&mut self, v_addr: usize, p_addr: usize, level: usize) {
+    /*
+        Map a virtual address to a physical address
+        Only the final entry gets the given flags as upper tables must allow every access
+    */
+    fn map_recursive(&mut self, v_addr: usize, p_addr: usize, level: usize, flags: &[PageFlags]) {
         if level == 0 {
             // Base case: Map the virtual address to the physical address in the P1 table
             let p1_index = (v_addr >> 12) & 0x1FF;
-            self.entries[p1_index] = Page::new(p_addr, &FLAGS);
+            self.entries[p1_index] = Page::new(p_addr, flags);
         } else {
             let index = (v_addr >> (level * 9 + 12)) & 0x1FF;
 
@@ -115,24 +140,43 @@ impl PageTable {
             let next_level_table =
                 unsafe { &mut *((self.entries[index].0 & 0xFFFF_FFFF_F000) as *mut PageTable) };
 
-            next_level_table.map_recursive(v_addr, p_addr, level - 1);
+            next_level_table.map_recursive(v_addr, p_addr, level - 1, flags);
         }
     }
 
+    /*
+        User pages (anything at or above the user process start address) are either skipped or shared copy on write
+        Shared pages become read only in both address spaces and the first write gives the writer its own copy
+        Everything below is the shared identity map of the kernel and keeps pointing at the same frames
+    */
     unsafe fn clone_page_table_recursive(
         &self,
         src_p: *mut PageTable,
         dst_p: *mut PageTable,
         level: usize,
+        v_addr: usize,
+        should_share_user_pages: bool,
     ) {
         match level {
             0..3 => {
                 for i in 0..(*src_p).entries.len() {
+                    // The recursive entry is set up seperately below
+                    if level == 0 && i == 511 {
+                        continue;
+                    }
+
+                    if level == 0 && i == KERNEL_STACKS_P4_INDEX {
+                        (*dst_p).entries[i] = Page((*src_p).entries[i].0);
+                        continue;
+                    }
+
                     if (*src_p).entries[i].0 != 0 {
                         let new_page_table: *mut PageTable =
                             PAGE_FRAME_ALLOCATOR.lock().alloc_page_frame().unwrap() as *mut _;
                         PAGE_FRAME_ALLOCATOR.free();
 
+                        core::ptr::write_bytes(new_page_table as *mut u8, 0, PAGE_SIZE);
+
                         let src_next_page_table =
                             ((*src_p).entries[i].0 & 0xFFFF_FFFF_F000) as *mut PageTable;
 
@@ -141,6 +185,8 @@ impl PageTable {
                             src_next_page_table,
                             new_page_table,
                             level + 1,
+                            v_addr | (i << ((3 - level) * 9 + 12)),
+                            should_share_user_pages,
                         );
 
                         // Create new Page object with cloned page table address
@@ -150,9 +196,28 @@ impl PageTable {
             }
             3 => {
                 for i in 0..(*src_p).entries.len() {
-                    if (*src_p).entries[i].0 != 0 {
-                        // Create new Page object with cloned page table address
-                        (*dst_p).entries[i] = Page::new((*src_p).entries[i].0 as usize, &FLAGS);
+                    if (*src_p).entries[i].0 == 0 {
+                        continue;
+                    }
+
+                    let page_v_addr = v_addr | (i << 12);
+
+                    if page_v_addr < USER_PROCESS_START_ADDRESS {
+                        // Kept as it is so device memory (eg the local APIC) stays uncached
+                        (*dst_p).entries[i] = Page((*src_p).entries[i].0);
+                    } else if should_share_user_pages {
+                        PAGE_FRAME_ALLOCATOR
+                            .lock()
+                            .add_reference((*src_p).entries[i].get_physical_address());
+                        PAGE_FRAME_ALLOCATOR.free();
+
+                        let entry = &mut (*src_p).entries[i];
+
+                        if entry.0 & WRITABLE != 0 {
+                            entry.0 = (entry.0 & !WRITABLE) | COPY_ON_WRITE;
+                        }
+
+                        (*dst_p).entries[i] = Page(entry.0);
                     }
                 }
             }
@@ -165,6 +230,38 @@ impl PageTable {
         }
     }
 
+    // Frees every table below this one along with the frames of user pages
+    unsafe fn free_recursive(&mut self, level: usize, v_addr: usize) {
+        for i in 0..self.entries.len() {
+            // The recursive entry points back to this table and the kernel stacks are shared
+            if level == 0 && (i == 511 || i == KERNEL_STACKS_P4_INDEX) {
+                continue;
+            }
+
+            if self.entries[i].is_unused() {
+                continue;
+            }
+
+            let p_addr = self.entries[i].get_physical_address();
+            let entry_v_addr = v_addr | (i << ((3 - level) * 9 + 12));
+
+            if level < 3 {
+                let next_level_table = &mut *(p_addr as *mut PageTable);
+                next_level_table.free_recursive(level + 1, entry_v_addr);
+            } else if entry_v_addr >= USER_PROCESS_START_ADDRESS {
+                PAGE_FRAME_ALLOCATOR
+                    .lock()
+                    .free_page_frame(p_addr as *mut usize);
+                PAGE_FRAME_ALLOCATOR.free();
+            }
+        }
+
+        PAGE_FRAME_ALLOCATOR
+            .lock()
+            .free_page_frame(self as *mut _ as *mut usize);
+        PAGE_FRAME_ALLOCATOR.free();
+    }
+
     fn unmap_recursive(&mut self, v_addr: usize, level: usize) {
         if (level == 0) {
             let p1_index = (v_addr >> 12) & 0x1FF;
@@ -207,32 +304,260 @@ impl PageTable {
     }
 
     fn map(&mut self, v_addr: usize, p_addr: usize) {
-        self.map_recursive(v_addr, p_addr, 3); // Level starts at 3 as 0..3
+        self.map_recursive(v_addr, p_addr, 3, &FLAGS); // Level starts at 3 as 0..3
     }
 
     fn map_pages(&mut self, number_of_pages: usize, v_addr: usize, p_addr: usize) {
+        self.map_pages_with_flags(number_of_pages, v_addr, p_addr, &FLAGS);
+    }
+
+    fn map_pages_with_flags(
+        &mut self,
+        number_of_pages: usize,
+        v_addr: usize,
+        p_addr: usize,
+        flags: &[PageFlags],
+    ) {
         for i in 0..number_of_pages {
             let p_addr_mod = p_addr + (i * PAGE_SIZE);
             let v_addr_mod = v_addr + (i * PAGE_SIZE);
-            self.map_recursive(v_addr_mod, p_addr_mod, 3);
+            self.map_recursive(v_addr_mod, p_addr_mod, 3, flags);
         }
     }
 }
 
-// Creates a deep clone of the paging system
+// Creates a deep clone of the kernel part of the paging system (user pages are left out)
 pub fn deep_clone() -> *mut PageTable {
+    clone_active_p4(false)
+}
+
+// Creates a deep clone of the paging system in which every user page is shared copy on write
+pub fn clone_copy_on_write() -> *mut PageTable {
+    let new_p4 = clone_active_p4(true);
+
+    // Writable pages of the active address space were made read only (including on CPUs running its other threads)
+    unsafe {
+        flush_tlb();
+    }
+    smp::tlb::shootdown(get_current_p4());
+
+    new_p4
+}
+
+fn clone_active_p4(should_share_user_pages: bool) -> *mut PageTable {
     unsafe {
         let p4 = &mut *P4;
         let new_p4: *mut PageTable =
             PAGE_FRAME_ALLOCATOR.lock().alloc_page_frame().unwrap() as *mut _;
         PAGE_FRAME_ALLOCATOR.free();
 
-        p4.clone_page_table_recursive(P4, new_p4, 0);
+        core::ptr::write_bytes(new_p4 as *mut u8, 0, PAGE_SIZE);
+
+        p4.clone_page_table_recursive(P4, new_p4, 0, 0, should_share_user_pages);
 
         new_p4
     }
 }
 
+/*
+    Returns the frames of a cloned address space to the page frame allocator
+    The kernel part of the address space is shared so only its page tables are free'd
+    Must not be called on the active address space
+*/
+pub fn free_address_space(p4: usize) {
+    unsafe {
+        (*(p4 as *mut PageTable)).free_recursive(0, 0);
+    }
+}
+
+// Walks the tables of an address space to find the entry within the P1 which maps a virtual address
+fn find_entry(p4: usize, v_addr: usize) -> Option<&'static mut Page> {
+    let mut table = p4 as *mut PageTable;
+
+    unsafe {
+        for level in (1..4).rev() {
+            let entry = &(*table).entries[(v_addr >> (level * 9 + 12)) & 0x1FF];
+
+            if !entry.is_present() {
+                return None;
+            }
+
+            table = entry.get_physical_address() as *mut PageTable;
+        }
+
+        let entry = &mut (*table).entries[(v_addr >> 12) & 0x1FF];
+        either!(entry.is_present() => Some(entry); None)
+    }
+}
+
+// Finds the physical address a virtual address maps to within an address space
+pub fn translate_address(p4: usize, v_addr: usize) -> Option<usize> {
+    let entry = find_entry(p4, v_addr)?;
+    Some(entry.get_physical_address() + (v_addr & 0xFFF))
+}
+
+// Other CPUs running the address space (eg another thread of the process) flush their TLB as well
+fn invalidate_page(p4: usize, v_addr: usize) {
+    if get_current_p4() == p4 {
+        unsafe {
+            asm!("invlpg [{}]", in(reg) v_addr, options(nostack, preserves_flags));
+        }
+    }
+
+    smp::tlb::shootdown(p4);
+}
+
+/*
+    Whether a user page is mapped writable
+    A write fault on such a page was already resolved by another CPU (eg two threads writing the same copy on write page)
+*/
+pub fn is_user_writable(p4: usize, v_addr: usize) -> bool {
+    match find_entry(p4, v_addr) {
+        Some(entry) => entry.0 & (WRITABLE | USER_ACCESSIBLE) == WRITABLE | USER_ACCESSIBLE,
+        None => false,
+    }
+}
+
+// Whether a page is still shared copy on write (eg a copy could not be made as memory ran out)
+pub fn is_copy_on_write(p4: usize, v_addr: usize) -> bool {
+    match find_entry(p4, v_addr) {
+        Some(entry) => entry.is_copy_on_write(),
+        None => false,
+    }
+}
+
+/*
+    Gives an address space its own writable copy of a page which is shared copy on write
+    The last address space using the frame takes it over without copying
+    Returns false if the page isn't copy on write (so the fault is a real protection violation)
+    or no frame is left for the copy (the page stays shared and read only)
+*/
+pub fn resolve_copy_on_write(p4: usize, v_addr: usize) -> bool {
+    let entry = match find_entry(p4, v_addr) {
+        Some(entry) => entry,
+        None => return false,
+    };
+
+    if !entry.is_copy_on_write() {
+        return false;
+    }
+
+    let frame = entry.get_physical_address();
+    let flags = ((entry.0 & !ADDRESS_MASK) & !COPY_ON_WRITE) | WRITABLE;
+
+    let reference_count = PAGE_FRAME_ALLOCATOR.lock().get_reference_count(frame);
+    PAGE_FRAME_ALLOCATOR.free();
+
+    if reference_count > 1 {
+        let new_frame = PAGE_FRAME_ALLOCATOR.lock().alloc_page_frame();
+        PAGE_FRAME_ALLOCATOR.free();
+
+        let new_frame = match new_frame {
+            Some(new_frame) => new_frame as usize,
+            None => return false,
+        };
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame as *const u8, new_frame as *mut u8, PAGE_SIZE);
+
+            // Only drops the reference of this address space
+            PAGE_FRAME_ALLOCATOR
+                .lock()
+                .free_page_frame(frame as *mut usize);
+            PAGE_FRAME_ALLOCATOR.free();
+        }
+
+        entry.0 = new_frame | flags;
+    } else {
+        entry.0 = frame | flags;
+    }
+
+    invalidate_page(p4, v_addr);
+
+    true
+}
+
+/*
+    Copies bytes into an address space which may not be the active one
+    Each page is translated seperately as contiguous virtual pages need not be contiguous frames
+    Returns false if part of the destination is not mapped or a shared page could not be copied
+*/
+pub fn copy_to_address_space(p4: usize, v_addr: usize, src: *const u8, length: usize) -> bool {
+    let mut copied = 0;
+
+    while copied < length {
+        let current = v_addr + copied;
+        let amount = core::cmp::min(PAGE_SIZE - (current & 0xFFF), length - copied);
+
+        // Writing through the physical address would bypass copy on write
+        if !resolve_copy_on_write(p4, current) && is_copy_on_write(p4, current) {
+            return false;
+        }
+
+        match translate_address(p4, current) {
+            Some(p_addr) => unsafe {
+                core::ptr::copy_nonoverlapping(src.add(copied), p_addr as *mut u8, amount);
+            },
+            None => return false,
+        }
+
+        copied += amount;
+    }
+
+    true
+}
+
+// Copies bytes out of an address space which may not be the active one
+pub fn copy_from_address_space(p4: usize, v_addr: usize, dst: *mut u8, length: usize) -> bool {
+    let mut copied = 0;
+
+    while copied < length {
+        let current = v_addr + copied;
+        let amount = core::cmp::min(PAGE_SIZE - (current & 0xFFF), length - copied);
+
+        match translate_address(p4, current) {
+            Some(p_addr) => unsafe {
+                core::ptr::copy_nonoverlapping(p_addr as *const u8, dst.add(copied), amount);
+            },
+            None => return false,
+        }
+
+        copied += amount;
+    }
+
+    true
+}
+
+/*
+    Removes the mapping of a page and returns the frame it pointed to
+    Page tables are left in place until the whole address space is free'd
+*/
+pub fn unmap_page(p4: usize, v_addr: usize) -> Option<usize> {
+    let entry = find_entry(p4, v_addr)?;
+
+    let frame = entry.get_physical_address();
+    entry.set_to_unused();
+
+    invalidate_page(p4, v_addr);
+
+    Some(frame)
+}
+
+pub fn get_current_p4() -> usize {
+    let p4: usize;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) p4, options(nomem, nostack, preserves_flags));
+    }
+    p4
+}
+
+// Loads a new P4 into CR3 which also flushes the TLB
+pub fn switch_page_table(p4: usize) {
+    unsafe {
+        asm!("mov cr3, {}", in(reg) p4, options(nostack, preserves_flags));
+    }
+}
+
 pub fn map_pages(number_of_pages: usize, v_addr: usize, p_addr: usize) {
     unsafe {
         (*P4).map_pages(number_of_pages, v_addr, p_addr);
@@ -247,6 +572,105 @@ pub fn map_pages_custom_p4(number_of_pages: usize, v_addr: usize, p_addr: usize,
     }
 }
 
+// Creates an empty P3 for an entry of the active P4 so that address spaces cloned from it can share the entry
+pub fn create_p4_entry(index: usize) {
+    let p3 = PAGE_FRAME_ALLOCATOR
+        .lock()
+        .alloc_page_frame()
+        .expect("PFA Ran out of memory");
+    PAGE_FRAME_ALLOCATOR.free();
+
+    unsafe {
+        core::ptr::write_bytes(p3 as *mut u8, 0, PAGE_SIZE);
+        (*(get_current_p4() as *mut PageTable)).entries[index] = Page::new(p3 as usize, &FLAGS);
+    }
+}
+
+// Maps pages which only the kernel can access and never executes
+pub fn map_kernel_pages(number_of_pages: usize, v_addr: usize, p_addr: usize) {
+    unsafe {
+        (*(get_current_p4() as *mut PageTable)).map_pages_with_flags(
+            number_of_pages,
+            v_addr,
+            p_addr,
+            &[PageFlags::Present, PageFlags::Writable, PageFlags::NoExecute],
+        );
+    }
+}
+
+/*
+    Maps user pages with the permissions of the area they belong to
+    Pages are read only unless writable and never executable unless they hold code
+*/
+pub fn map_user_pages(number_of_pages: usize, v_addr: usize, p_addr: usize, p4: usize, prot: usize) {
+    let mut flags = [
+        PageFlags::Present,
+        PageFlags::UserAccessible,
+        PageFlags::Present,
+        PageFlags::Present,
+    ];
+    let mut flag_count = 2;
+
+    if prot & vma::PROT_WRITE != 0 {
+        flags[flag_count] = PageFlags::Writable;
+        flag_count += 1;
+    }
+
+    if prot & vma::PROT_EXEC == 0 {
+        flags[flag_count] = PageFlags::NoExecute;
+        flag_count += 1;
+    }
+
+    unsafe {
+        (*(p4 as *mut PageTable)).map_pages_with_flags(
+            number_of_pages,
+            v_addr,
+            p_addr,
+            &flags[..flag_count],
+        );
+        flush_tlb();
+    }
+}
+
+// Maps memory mapped registers of a device which must never be cached
+pub fn map_device_pages(number_of_pages: usize, v_addr: usize, p_addr: usize) {
+    unsafe {
+        (*P4).map_pages_with_flags(
+            number_of_pages,
+            v_addr,
+            p_addr,
+            &[
+                PageFlags::Present,
+                PageFlags::Writable,
+                PageFlags::WriteThrough,
+                PageFlags::DisableCache,
+                PageFlags::NoExecute,
+            ],
+        );
+        flush_tlb();
+    }
+}
+
+/*
+    Maps the page of registers of a device (eg an APIC) and returns the virtual address of the registers
+    The page takes the place of the identity mapping of a spare frame from the page frame allocator
+*/
+pub fn map_device_registers(p_addr: usize) -> usize {
+    map_device_memory(p_addr, 1)
+}
+
+// Same as map_device_registers for devices whose registers span several pages (eg an AHCI controller)
+pub fn map_device_memory(p_addr: usize, number_of_pages: usize) -> usize {
+    let window = PAGE_FRAME_ALLOCATOR
+        .lock()
+        .alloc_page_frames(number_of_pages) as usize;
+    PAGE_FRAME_ALLOCATOR.free();
+
+    map_device_pages(number_of_pages, window, p_addr & !(PAGE_SIZE - 1));
+
+    window + (p_addr & (PAGE_SIZE - 1))
+}
+
 pub fn map_page(v_addr: usize, p_addr: usize, is_user: bool) {
     map_pages(1, v_addr, p_addr);
 }
@@ -0,0 +1,203 @@
+/*
+    Virtual memory areas (VMAs) describe which ranges of the user address space a process is allowed to use
+    The heap and anonymous mappings are only reserved when created and a zeroed frame is mapped when a page is first touched (demand paging)
+    Touching memory outside of every area is a segmentation fault
+*/
+
+use crate::ds::vec::DynamicArray;
+use crate::multitask::process::USER_PROCESS_START_ADDRESS;
+
+use super::page_frame_allocator::PAGE_FRAME_ALLOCATOR;
+use super::paging::{self, PAGE_SIZE};
+
+// Anonymous mappings are placed downwards from here which leaves room for the stack above
+pub const MMAP_TOP: usize = 0x7F00_0000_0000;
+
+pub const PROT_NONE: usize = 0x00;
+pub const PROT_READ: usize = 0x01;
+pub const PROT_WRITE: usize = 0x02;
+pub const PROT_EXEC: usize = 0x04;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum VmaKind {
+    Program, // Segments loaded from the ELF file
+    Heap,    // Grown and shrunk by brk
+    Anonymous,
+    Stack,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize, // Exclusive
+    pub prot: usize,
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.start && address < self.end
+    }
+
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        start < self.end && end > self.start
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct VmaList {
+    areas: DynamicArray<Vma>,
+}
+
+pub fn round_up_to_page(address: usize) -> usize {
+    (address + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/*
+    Rounds a length given by a process up to whole pages and returns the end of the range starting at start
+    Returns None unless the whole range lies within the part of the address space processes can map and unmap
+*/
+pub fn user_range(start: usize, length: usize) -> Option<usize> {
+    let length = length.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+    let end = start.checked_add(length)?;
+
+    if length == 0 || start < USER_PROCESS_START_ADDRESS || end > MMAP_TOP {
+        return None;
+    }
+
+    Some(end)
+}
+
+impl VmaList {
+    pub fn new() -> VmaList {
+        let mut areas = DynamicArray::<Vma>::new();
+        areas.init();
+        VmaList { areas }
+    }
+
+    // Copies every area into a new list for a forked process
+    pub fn duplicate(&self) -> VmaList {
+        let mut list = VmaList::new();
+
+        for area in self.areas.iter() {
+            list.areas.push(*area);
+        }
+
+        list
+    }
+
+    pub fn add(&mut self, start: usize, end: usize, prot: usize, kind: VmaKind) {
+        self.areas.push(Vma {
+            start,
+            end,
+            prot,
+            kind,
+        });
+    }
+
+    pub fn find(&self, address: usize) -> Option<Vma> {
+        self.areas.iter().find(|area| area.contains(address)).copied()
+    }
+
+    pub fn find_kind_mut(&mut self, kind: VmaKind) -> Option<&mut Vma> {
+        for i in 0..self.areas.length() {
+            let area = self.areas.get_mut(i).expect("VMA not found");
+            if area.kind == kind {
+                return Some(area);
+            }
+        }
+
+        None
+    }
+
+    pub fn is_free(&self, start: usize, end: usize) -> bool {
+        start >= USER_PROCESS_START_ADDRESS
+            && end <= MMAP_TOP
+            && !self.areas.iter().any(|area| area.overlaps(start, end))
+    }
+
+    /*
+        Finds the highest gap below MMAP_TOP which can hold the given (page aligned) length
+        Each area in the way moves the search below it
+    */
+    pub fn find_free(&self, length: usize) -> Option<usize> {
+        let mut end = MMAP_TOP;
+
+        loop {
+            let start = end.checked_sub(length)?;
+
+            if start < USER_PROCESS_START_ADDRESS {
+                return None;
+            }
+
+            match self.areas.iter().find(|area| area.overlaps(start, end)) {
+                Some(area) => end = area.start,
+                None => return Some(start),
+            }
+        }
+    }
+
+    // Calls the function with each part of the range which lies within an area
+    pub fn for_each_covered<F>(&self, start: usize, end: usize, mut func: F)
+    where
+        F: FnMut(usize, usize),
+    {
+        for area in self.areas.iter() {
+            if area.overlaps(start, end) {
+                func(area.start.max(start), area.end.min(end));
+            }
+        }
+    }
+
+    /*
+        Removes a range from every area which overlaps it
+        Areas which only partly overlap are trimmed or split in two
+    */
+    pub fn remove_range(&mut self, start: usize, end: usize) {
+        let mut i = 0;
+
+        while i < self.areas.length() {
+            let area = *self.areas.get_mut(i).expect("VMA not found");
+
+            if !area.overlaps(start, end) {
+                i += 1;
+                continue;
+            }
+
+            self.areas.remove(i);
+
+            if area.start < start {
+                self.add(area.start, start, area.prot, area.kind);
+            }
+
+            if area.end > end {
+                self.add(end, area.end, area.prot, area.kind);
+            }
+        }
+    }
+
+    pub fn free(&mut self) {
+        self.areas.free();
+    }
+}
+
+/*
+    Unmaps every page within a range of an address space and returns the frames behind them
+    Pages which were never touched have no frame to free
+*/
+pub fn release_range(p4: usize, start: usize, end: usize) {
+    let mut address = start;
+
+    while address < end {
+        if let Some(frame) = paging::unmap_page(p4, address) {
+            unsafe {
+                PAGE_FRAME_ALLOCATOR
+                    .lock()
+                    .free_page_frame(frame as *mut usize);
+                PAGE_FRAME_ALLOCATOR.free();
+            }
+        }
+
+        address += PAGE_SIZE;
+    }
+}
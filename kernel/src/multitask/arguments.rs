@@ -0,0 +1,248 @@
+/*
+    Programs are started with their arguments, environment and auxiliary vector on the user stack (System V ABI)
+    The auxiliary vector passes information about the loaded image which libc and the dynamic linker need
+
+    +-----------------------------+ <- USER_STACK_TOP
+    | argument and env strings    |
+    +-----------------------------+
+    | padding (16 byte alignment) |
+    | AT_NULL                     |
+    | auxv (type, value) pairs    |
+    | NULL                        |
+    | envp[envc - 1] .. envp[0]   |
+    | NULL                        |
+    | argv[argc - 1] .. argv[0]   |
+    | argc                        | <- RSP
+    +-----------------------------+
+*/
+
+use crate::ds::vec::DynamicArray;
+use crate::memory::allocator::{kfree, kmalloc};
+use crate::memory::paging::{self, PAGE_SIZE};
+
+use core::mem::size_of;
+
+// Types of auxiliary vector entries
+pub const AT_NULL: usize = 0;
+pub const AT_PHDR: usize = 3; // Address of the program headers
+pub const AT_PHENT: usize = 4; // Size of a program header
+pub const AT_PHNUM: usize = 5; // Number of program headers
+pub const AT_PAGESZ: usize = 6;
+pub const AT_BASE: usize = 7; // Where the interpreter was loaded
+pub const AT_ENTRY: usize = 9; // Entry point of the program
+
+// Always leave a page of the eagerly mapped stack for the program itself
+const ARGUMENTS_LIMIT: usize = 3 * PAGE_SIZE;
+
+// Every string is copied into the kernel heap so the arguments outlive whatever they were taken from
+// They are kept as bytes as programs may be passed strings which aren't UTF-8
+pub struct Arguments {
+    argv: DynamicArray<&'static [u8]>,
+    envp: DynamicArray<&'static [u8]>,
+}
+
+// Where the kernel placed everything which is also passed in RDI, RSI and RDX
+pub struct InitialStack {
+    pub rsp: usize,
+    pub argc: usize,
+    pub argv: usize,
+    pub envp: usize,
+}
+
+impl Arguments {
+    pub fn new(argv: &[&str], envp: &[&str]) -> Arguments {
+        let mut arguments = Arguments::empty();
+
+        for arg in argv {
+            arguments.argv.push(copy_string(arg.as_bytes()));
+        }
+
+        for env in envp {
+            arguments.envp.push(copy_string(env.as_bytes()));
+        }
+
+        arguments
+    }
+
+    // Splits a command line on whitespace where the first word is the path of the program
+    pub fn from_command(command: &str, envp: &[&str]) -> Arguments {
+        let mut arguments = Arguments::new(&[], envp);
+
+        for arg in command.split_whitespace() {
+            arguments.argv.push(copy_string(arg.as_bytes()));
+        }
+
+        arguments
+    }
+
+    /*
+        Collects the NULL terminated string arrays passed to execve
+        The strings still live in the caller so this must be called before its address space is free'd
+        Returns None as soon as they would not fit on the stack so the rest are never copied
+    */
+    pub fn from_user(argv: *const *const u8, envp: *const *const u8) -> Option<Arguments> {
+        let mut arguments = Arguments::empty();
+
+        // argc and the NULLs ending argv and envp
+        let mut size = 3 * size_of::<usize>();
+
+        if !Arguments::collect_user_strings(argv, &mut arguments.argv, &mut size)
+            || !Arguments::collect_user_strings(envp, &mut arguments.envp, &mut size)
+        {
+            arguments.free();
+            return None;
+        }
+
+        Some(arguments)
+    }
+
+    fn empty() -> Arguments {
+        let mut argv = DynamicArray::<&'static [u8]>::new();
+        argv.init();
+        let mut envp = DynamicArray::<&'static [u8]>::new();
+        envp.init();
+
+        Arguments { argv, envp }
+    }
+
+    // Adds each string and its pointer to the running size and returns false once it goes past ARGUMENTS_LIMIT
+    fn collect_user_strings(
+        array: *const *const u8,
+        strings: &mut DynamicArray<&'static [u8]>,
+        size: &mut usize,
+    ) -> bool {
+        if array.is_null() {
+            return true;
+        }
+
+        let mut i = 0;
+
+        loop {
+            let string_ptr = unsafe { *array.add(i) };
+
+            if string_ptr.is_null() {
+                return true;
+            }
+
+            // Stops scanning once the string can't fit rather than walking the whole of a huge one
+            let mut length = 0;
+            loop {
+                if *size + length + 1 + size_of::<usize>() > ARGUMENTS_LIMIT {
+                    return false;
+                }
+
+                if unsafe { *string_ptr.add(length) } == 0 {
+                    break;
+                }
+
+                length += 1;
+            }
+
+            *size += length + 1 + size_of::<usize>();
+
+            let string = unsafe { core::slice::from_raw_parts(string_ptr, length) };
+            strings.push(copy_string(string));
+            i += 1;
+        }
+    }
+
+    // Bytes taken by the strings and pointer arrays (excluding the auxiliary vector)
+    fn size(&self) -> usize {
+        let strings: usize = self.argv.iter().chain(self.envp.iter()).map(|s| s.len() + 1).sum();
+        let pointers = 1 + self.argv.length() + 1 + self.envp.length() + 1;
+
+        strings + pointers * size_of::<usize>()
+    }
+
+    pub fn fits_on_stack(&self) -> bool {
+        self.size() <= ARGUMENTS_LIMIT
+    }
+
+    /*
+        Writes the initial stack below stack_top within the given address space
+        The layout is built in a kernel buffer first so it can be copied across in one go
+    */
+    pub fn push_to_stack(&self, p4: usize, stack_top: usize, auxv: &[(usize, usize)]) -> InitialStack {
+        assert!(self.fits_on_stack(), "Arguments do not fit on the user stack");
+
+        // Copy the strings to the very top of the stack and remember where each one went
+        let mut string_addr = stack_top;
+        let mut pointers = DynamicArray::<usize>::new();
+        pointers.init();
+
+        for arg in self.argv.iter().chain(self.envp.iter()) {
+            string_addr -= arg.len() + 1;
+
+            paging::copy_to_address_space(p4, string_addr, arg.as_ptr(), arg.len());
+            paging::copy_to_address_space(p4, string_addr + arg.len(), [0u8].as_ptr(), 1);
+
+            pointers.push(string_addr);
+        }
+
+        let argc = self.argv.length();
+        let envc = self.envp.length();
+
+        // argc, argv and envp with their NULLs then the auxiliary vector terminated by AT_NULL
+        let words = 1 + argc + 1 + envc + 1 + (auxv.len() + 1) * 2;
+
+        // RSP must be 16 byte aligned when the program starts
+        let rsp = (string_addr - words * size_of::<usize>()) & !0xF;
+
+        let buffer = kmalloc(words * size_of::<usize>());
+        let mut index = 0;
+
+        let mut push = |value: usize| {
+            unsafe { *buffer.add(index) = value };
+            index += 1;
+        };
+
+        push(argc);
+        pointers.iter().take(argc).for_each(|addr| push(*addr));
+        push(0);
+        pointers.iter().skip(argc).for_each(|addr| push(*addr));
+        push(0);
+
+        for (entry_type, value) in auxv {
+            push(*entry_type);
+            push(*value);
+        }
+        push(AT_NULL);
+        push(0);
+
+        paging::copy_to_address_space(
+            p4,
+            rsp,
+            buffer as *const u8,
+            words * size_of::<usize>(),
+        );
+
+        kfree(buffer);
+        pointers.free();
+
+        InitialStack {
+            rsp,
+            argc,
+            argv: rsp + size_of::<usize>(),
+            envp: rsp + (argc + 2) * size_of::<usize>(),
+        }
+    }
+
+    pub fn free(&mut self) {
+        for string in self.argv.iter().chain(self.envp.iter()) {
+            kfree(string.as_ptr() as *mut usize);
+        }
+
+        self.argv.free();
+        self.envp.free();
+    }
+}
+
+// The copy lives until it is kfree'd
+fn copy_string(string: &[u8]) -> &'static [u8] {
+    let buffer = kmalloc(string.len().max(1)) as *mut u8;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(string.as_ptr(), buffer, string.len());
+        core::slice::from_raw_parts(buffer, string.len())
+    }
+}
@@ -18,10 +18,13 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
-use crate::memory::allocator::kmalloc;
+use crate::memory::allocator::{kfree, kmalloc};
 use crate::memory::page_frame_allocator::PAGE_FRAME_ALLOCATOR;
+use crate::memory::vma::{self, VmaKind, VmaList};
 use crate::memory::{page_frame_allocator, paging};
-use crate::{print_serial, CONSOLE};
+use crate::{either, print_serial, CONSOLE};
+
+use super::process::USER_PROCESS_START_ADDRESS;
 use core::{mem, num, panic};
 
 type Elf64Half = u16;
@@ -73,6 +76,7 @@ enum ElfType {
     EtNone = 0, // Unknown
     EtRel = 1,  // Relocatable
     EtExec = 2, // Executable
+    EtDyn = 3,  // Shared object or position independent executable
 }
 
 #[repr(C, packed)]
@@ -88,107 +92,591 @@ struct ElfProgramHeader {
     p_align: Elf64Xword,  // Alignment in memory and file
 }
 
+// Permission bits within p_flags
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
+
 #[derive(PartialEq, Copy, Clone)]
 #[repr(u32)]
 enum ProgramHeaderType {
-    PtNull = 0, // Unused
-    PtLoad = 1, // Loadable segment
+    PtNull = 0,    // Unused
+    PtLoad = 1,    // Loadable segment
+    PtDynamic = 2, // Dynamic linking information
+    PtInterp = 3,  // Path of the interpreter (dynamic linker)
+    PtPhdr = 6,    // Location of the program headers themselves
+}
+
+// Entry of the dynamic section
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+struct ElfDynamic {
+    d_tag: Elf64Xword,
+    d_val: Elf64Xword,
+}
+
+// Relocation with an explicit addend
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+struct ElfRela {
+    r_offset: Elf64Addr, // Where to apply the relocation
+    r_info: Elf64Xword,  // Symbol (upper 32 bits) and type (lower 32 bits)
+    r_addend: i64,
 }
 
-pub fn parse(file_start: usize, p4: usize) {
+// Tags of dynamic section entries
+const DT_NULL: usize = 0;
+const DT_RELA: usize = 7; // Address of the relocation table
+const DT_RELASZ: usize = 8; // Size of the relocation table
+const DT_RELAENT: usize = 9; // Size of a relocation
+
+// Relocation types
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8; // Load bias plus addend
+
+// Position independent executables are linked to run at zero and are moved up to here
+const DYNAMIC_LOAD_BIAS: usize = 0x5555_5555_4000;
+
+// Interpreters are position independent and placed well above the program and its heap
+const INTERPRETER_LOAD_BIAS: usize = 0x7E00_0000_0000;
+
+/*
+    A program read from the VFS along with the interpreter it asks for (if any)
+    Both are read up front as programs are loaded with the process manager locked which mustn't wait on the disk
+*/
+pub struct ProgramFiles {
+    pub start: usize,
+    pub size: usize,
+    is_owned: bool, // Whether the program is in a buffer which has to be free'd
+    interpreter: Option<(usize, usize)>,
+}
+
+impl ProgramFiles {
+    // Returns None if either file can't be found or the program isn't a valid ELF file
+    pub fn read(filepath: &str) -> Option<ProgramFiles> {
+        let (buffer, size) = super::read_program(filepath)?;
+
+        ProgramFiles::read_interpreter(ProgramFiles {
+            start: buffer as usize,
+            size,
+            is_owned: true,
+            interpreter: None,
+        })
+    }
+
+    // Programs which are already in memory (eg grub modules) are left where they are when free'd
+    pub fn from_memory(start: usize, size: usize) -> Option<ProgramFiles> {
+        ProgramFiles::read_interpreter(ProgramFiles {
+            start,
+            size,
+            is_owned: false,
+            interpreter: None,
+        })
+    }
+
+    fn read_interpreter(mut files: ProgramFiles) -> Option<ProgramFiles> {
+        let interpreter = match find_interpreter_path(files.start, files.size) {
+            Ok(interpreter) => interpreter,
+            Err(()) => {
+                files.free();
+                return None;
+            }
+        };
+
+        if let Some(path) = interpreter {
+            match super::read_program(path) {
+                Some((buffer, size)) => files.interpreter = Some((buffer as usize, size)),
+                None => {
+                    print_serial!("Error: Could not find the interpreter {}\n", path);
+                    files.free();
+                    return None;
+                }
+            }
+        }
+
+        Some(files)
+    }
+
+    pub fn free(&mut self) {
+        if self.is_owned {
+            kfree(self.start as *mut usize);
+        }
+
+        if let Some((start, _)) = self.interpreter.take() {
+            kfree(start as *mut usize);
+        }
+    }
+}
+
+// Describes a program once it has been loaded which is passed on to it in the auxiliary vector
+pub struct LoadedImage {
+    pub entry: usize,            // Entry point of the program itself
+    pub start: usize,            // Where execution begins which is the interpreter if there is one
+    pub bias: usize,             // Amount the file was moved by when loaded
+    pub interpreter_base: usize, // Load bias of the interpreter (zero without one)
+    pub program_headers: usize,  // Virtual address of the program headers (zero if they aren't loaded)
+    pub program_header_count: usize,
+    pub program_header_size: usize,
+    pub end: usize, // End of the highest segment of the program
+}
+
+/*
+    Loads the program into the address space and records its segments as VMAs
+    Dynamically linked programs name an interpreter (read alongside them) which is loaded and started first
+    The interpreter is then responsible for loading shared libraries and relocating the program
+    Returns None if either file is malformed or its segments can't be placed
+    The address space may hold part of the program by then so must be free'd by the caller
+*/
+pub fn parse(files: &ProgramFiles, p4: usize, vmas: &mut VmaList) -> Option<LoadedImage> {
+    let (mut image, interpreter) =
+        load_file(files.start, files.size, p4, vmas, DYNAMIC_LOAD_BIAS, false)?;
+
+    if interpreter.is_some() {
+        let (start, size) = files.interpreter?;
+        let (interpreter_image, _) = load_file(start, size, p4, vmas, INTERPRETER_LOAD_BIAS, true)?;
+
+        image.start = interpreter_image.entry;
+        image.interpreter_base = interpreter_image.bias;
+    }
+
+    Some(image)
+}
+
+/*
+    Loads a single ELF file and returns it along with the path of the interpreter it asks for
+    Position independent files are moved by the given bias
+    Relocations are only applied to statically linked programs as interpreters relocate themselves
+*/
+fn load_file(
+    file_start: usize,
+    file_size: usize,
+    p4: usize,
+    vmas: &mut VmaList,
+    dynamic_bias: usize,
+    is_interpreter: bool,
+) -> Option<(LoadedImage, Option<&'static str>)> {
+    if file_size < mem::size_of::<ElfHeader>() {
+        print_serial!("Error: File is too small to be an ELF file\n");
+        return None;
+    }
+
+    let elf_header = unsafe { &*(file_start as *const ElfHeader) };
+
+    if !validate_file(elf_header, file_size) {
+        return None;
+    }
+
+    let bias = either!(elf_header.e_type == ElfType::EtDyn as u16 => dynamic_bias; 0);
+
+    let interpreter = match find_program_header(file_start, elf_header, ProgramHeaderType::PtInterp) {
+        Some(header) => Some(read_interpreter_path(file_start, file_size, &header)?),
+        None => None,
+    };
+
+    if !check(!is_interpreter || interpreter.is_none(), "ELF interpreter asks for an interpreter") {
+        return None;
+    }
+
+    let (end, program_headers) =
+        parse_program_headers(file_start, file_size, elf_header, p4, vmas, bias)?;
+
+    if interpreter.is_none()
+        && !is_interpreter
+        && !apply_relocations(file_start, file_size, elf_header, p4, vmas, bias)
+    {
+        return None;
+    }
+
+    let entry = relocate(elf_header.e_entry, bias)?;
+
+    // Jumping anywhere else would fault straight away
+    match vmas.find(entry) {
+        Some(area) if area.prot & vma::PROT_EXEC != 0 => {}
+        _ => {
+            print_serial!("Error: ELF entry point {:#x} is not within an executable segment\n", entry);
+            return None;
+        }
+    }
+
+    let image = LoadedImage {
+        entry,
+        start: entry,
+        bias,
+        interpreter_base: 0,
+        program_headers,
+        program_header_count: elf_header.e_phnum as usize,
+        program_header_size: elf_header.e_phentsize as usize,
+        end,
+    };
+
+    Some((image, interpreter))
+}
+
+// Only valid files are searched for an interpreter so Err is returned (and logged) for the rest
+fn find_interpreter_path(file_start: usize, file_size: usize) -> Result<Option<&'static str>, ()> {
+    if !check(file_size >= mem::size_of::<ElfHeader>(), "File is too small to be an ELF file") {
+        return Err(());
+    }
+
     let elf_header = unsafe { &*(file_start as *const ElfHeader) };
-    validate_file(elf_header);
-    parse_program_headers(file_start, elf_header, p4);
+
+    if !validate_file(elf_header, file_size) {
+        return Err(());
+    }
+
+    match find_program_header(file_start, elf_header, ProgramHeaderType::PtInterp) {
+        Some(header) => read_interpreter_path(file_start, file_size, &header).map(Some).ok_or(()),
+        None => Ok(None),
+    }
+}
+
+// Logs why a file was rejected
+fn check(condition: bool, error: &str) -> bool {
+    if !condition {
+        print_serial!("Error: {}\n", error);
+    }
+
+    condition
+}
+
+// Moves an address within the file by the load bias (addresses which overflow reject the file)
+fn relocate(address: usize, bias: usize) -> Option<usize> {
+    let address = address.checked_add(bias);
+    check(address.is_some(), "ELF address overflows when moved by the load bias");
+    address
 }
 
 // Verify file starts with ELF Magic number and is built for the correct system
-fn validate_file(elf_header: &ElfHeader) -> bool {
-    assert!(
-        elf_header.e_ident[ElfIdent::EiMag0 as usize] == ELF_FLAG_MAG0,
-        "ELF Header EI_MAG0 incorrect\n"
-    );
-
-    assert!(
-        elf_header.e_ident[ElfIdent::EiMag1 as usize] == ('E' as u8),
-        "ELF header EI_MAG1 incorrect\n"
-    );
-
-    assert!(
-        elf_header.e_ident[ElfIdent::EiMag2 as usize] == ('L' as u8),
-        "ELF header EI_MAG2 incorrect\n"
-    );
-
-    assert!(
-        elf_header.e_ident[ElfIdent::EiMag3 as usize] == ('F' as u8),
-        "ELF header EI_MAG3 incorrect\n"
-    );
-
-    assert!(
-        elf_header.e_ident[ElfIdent::EiClass as usize] == ELF_CLASS,
-        "Unsupported ELF File class\n"
-    );
-
-    assert!(
-        elf_header.e_ident[ElfIdent::EiData as usize] == ELF_DATA,
-        "Unsupported ELF File byte order\n"
-    );
-
-    assert!(
-        elf_header.e_ident[ElfIdent::EiVersion as usize] == ELF_VERSION,
-        "Unsupported ELF version\n"
-    );
-
-    assert!(
+fn validate_file(elf_header: &ElfHeader, file_size: usize) -> bool {
+    let ident = elf_header.e_ident;
+    let e_type = elf_header.e_type;
+    let program_headers_size = elf_header.e_phnum as usize * mem::size_of::<ElfProgramHeader>();
+
+    check(
+        ident[ElfIdent::EiMag0 as usize] == ELF_FLAG_MAG0
+            && ident[ElfIdent::EiMag1 as usize] == ('E' as u8)
+            && ident[ElfIdent::EiMag2 as usize] == ('L' as u8)
+            && ident[ElfIdent::EiMag3 as usize] == ('F' as u8),
+        "ELF header magic number incorrect",
+    ) && check(
+        ident[ElfIdent::EiClass as usize] == ELF_CLASS,
+        "Unsupported ELF File class",
+    ) && check(
+        ident[ElfIdent::EiData as usize] == ELF_DATA,
+        "Unsupported ELF File byte order",
+    ) && check(
+        ident[ElfIdent::EiVersion as usize] == ELF_VERSION,
+        "Unsupported ELF version",
+    ) && check(
         elf_header.e_machine == ELF_MACHINE,
-        "Unsupported ELF file target\n"
-    );
+        "Unsupported ELF file target",
+    ) && check(
+        e_type == ElfType::EtExec as u16 || e_type == ElfType::EtDyn as u16,
+        "Unsupported ELF file type",
+    ) && check(
+        elf_header.e_phentsize as usize == mem::size_of::<ElfProgramHeader>(),
+        "Unsupported ELF program header size",
+    ) && check(
+        elf_header
+            .e_phoff
+            .checked_add(program_headers_size)
+            .map_or(false, |end| end <= file_size),
+        "ELF program headers lie outside of the file",
+    )
+}
 
-    assert!(
-        elf_header.e_type == (ElfType::EtExec as u16),
-        "Unsupported ELF file type"
-    );
+fn get_program_header(file_start: usize, elf_header: &ElfHeader, index: usize) -> ElfProgramHeader {
+    let address = file_start + elf_header.e_phoff + mem::size_of::<ElfProgramHeader>() * index;
+    unsafe { *(address as *const ElfProgramHeader) }
+}
 
-    return true;
+// Finds the first program header of a type
+fn find_program_header(
+    file_start: usize,
+    elf_header: &ElfHeader,
+    p_type: ProgramHeaderType,
+) -> Option<ElfProgramHeader> {
+    (0..elf_header.e_phnum as usize)
+        .map(|i| get_program_header(file_start, elf_header, i))
+        .find(|header| header.p_type == p_type as u32)
+}
+
+// The interpreter segment holds a null terminated path within the VFS
+fn read_interpreter_path(
+    file_start: usize,
+    file_size: usize,
+    program_header: &ElfProgramHeader,
+) -> Option<&'static str> {
+    let file_end = program_header.p_offset.checked_add(program_header.p_filesz);
+
+    if !check(
+        program_header.p_filesz > 1 && file_end.map_or(false, |end| end <= file_size),
+        "ELF interpreter path lies outside of the file",
+    ) {
+        return None;
+    }
+
+    let path = unsafe {
+        core::slice::from_raw_parts(
+            (file_start + program_header.p_offset) as *const u8,
+            program_header.p_filesz - 1,
+        )
+    };
+
+    let path = core::str::from_utf8(path).ok();
+    check(path.is_some(), "ELF interpreter path is not valid UTF-8");
+    path
 }
 
 /*
     Elf program headers specify where segments are located and point to them
     Segments which contain multiple sections
     These are utilised whilst executing
+    Returns the end of the highest segment and the address the program headers were loaded at
 */
-fn parse_program_headers(file_start: usize, elf_header: &ElfHeader, p4: usize) {
+fn parse_program_headers(
+    file_start: usize,
+    file_size: usize,
+    elf_header: &ElfHeader,
+    p4: usize,
+    vmas: &mut VmaList,
+    bias: usize,
+) -> Option<(usize, usize)> {
+    let mut image_end = 0;
+    let mut program_headers = 0;
+
     // Loop through the headers and load each loadable segment into memory
-    for i in 0..elf_header.e_phnum {
-        let address =
-            file_start + elf_header.e_phoff + (mem::size_of::<ElfProgramHeader>()) * (i as usize);
-        let program_header = unsafe { &*(address as *const ElfProgramHeader) };
+    for i in 0..elf_header.e_phnum as usize {
+        let program_header = get_program_header(file_start, elf_header, i);
 
         match program_header.p_type {
             1 => {
                 // LOAD
+                if program_header.p_memsz == 0 {
+                    continue;
+                }
+
+                let address = relocate(program_header.p_vaddr, bias)?;
+
+                if !validate_segment(&program_header, address, file_size, vmas) {
+                    return None;
+                }
+
                 let source = file_start + program_header.p_offset as usize;
-                load_segment_into_memory(
+                let prot = convert_flags(program_header.p_flags);
+
+                let segment_end = load_segment_into_memory(
                     source,
                     program_header.p_filesz,
                     program_header.p_memsz,
-                    program_header.p_vaddr,
+                    address,
                     p4,
+                    prot,
                 );
+
+                vmas.add(
+                    address & !(paging::PAGE_SIZE - 1),
+                    segment_end,
+                    prot,
+                    VmaKind::Program,
+                );
+
+                image_end = core::cmp::max(image_end, segment_end);
+
+                // The headers are usually loaded as part of the first segment (which was checked to fit in the file and user space)
+                let phoff = elf_header.e_phoff;
+                if program_headers == 0
+                    && phoff >= program_header.p_offset
+                    && phoff - program_header.p_offset < program_header.p_filesz
+                {
+                    program_headers = address + (phoff - program_header.p_offset);
+                }
+            }
+            6 => {
+                // PHDR
+                program_headers = relocate(program_header.p_vaddr, bias)?;
             }
             _ => {}
         }
     }
+
+    Some((image_end, program_headers))
+}
+
+/*
+    A segment must come from within the file and be placed within user space without overlapping another segment
+    Segments may share a page in the file but not in memory as each page only has one set of permissions
+*/
+fn validate_segment(program_header: &ElfProgramHeader, address: usize, file_size: usize, vmas: &VmaList) -> bool {
+    let file_end = program_header.p_offset.checked_add(program_header.p_filesz);
+    let start = address & !(paging::PAGE_SIZE - 1);
+    let end = address.checked_add(program_header.p_memsz);
+
+    check(
+        program_header.p_filesz <= program_header.p_memsz,
+        "ELF segment is larger in the file than in memory",
+    ) && check(
+        file_end.map_or(false, |file_end| file_end <= file_size),
+        "ELF segment lies outside of the file",
+    ) && check(
+        end.map_or(false, |end| start >= USER_PROCESS_START_ADDRESS && end <= vma::MMAP_TOP),
+        "ELF segment lies outside of user space",
+    ) && check(
+        vmas.is_free(start, vma::round_up_to_page(end.unwrap_or(0))),
+        "ELF segments overlap",
+    )
+}
+
+/*
+    Position independent executables contain absolute addresses which assume the program was loaded at zero
+    These are listed in the dynamic section as relocations which have to be moved by the load bias
+    Only relative relocations are supported as there is no dynamic linker to resolve symbols
+*/
+fn apply_relocations(
+    file_start: usize,
+    file_size: usize,
+    elf_header: &ElfHeader,
+    p4: usize,
+    vmas: &VmaList,
+    bias: usize,
+) -> bool {
+    if bias == 0 {
+        return true;
+    }
+
+    let dynamic = match find_program_header(file_start, elf_header, ProgramHeaderType::PtDynamic) {
+        Some(header) => header,
+        None => return true,
+    };
+
+    let dynamic_end = dynamic.p_offset.checked_add(dynamic.p_filesz);
+    if !check(
+        dynamic_end.map_or(false, |end| end <= file_size),
+        "ELF dynamic section lies outside of the file",
+    ) {
+        return false;
+    }
+
+    let mut table = 0;
+    let mut table_size = 0;
+    let mut entry_size = mem::size_of::<ElfRela>();
+
+    let entries = (file_start + dynamic.p_offset) as *const ElfDynamic;
+
+    for i in 0..dynamic.p_filesz / mem::size_of::<ElfDynamic>() {
+        let entry = unsafe { *entries.add(i) };
+
+        match entry.d_tag {
+            DT_NULL => break,
+            DT_RELA => table = entry.d_val,
+            DT_RELASZ => table_size = entry.d_val,
+            DT_RELAENT => entry_size = entry.d_val,
+            _ => {}
+        }
+    }
+
+    if !check(entry_size == mem::size_of::<ElfRela>(), "Unsupported ELF relocation size") {
+        return false;
+    }
+
+    for i in 0..table_size / entry_size {
+        let mut relocation = unsafe { mem::zeroed::<ElfRela>() };
+
+        let address = i
+            .checked_mul(entry_size)
+            .and_then(|offset| table.checked_add(offset))
+            .and_then(|address| address.checked_add(bias));
+
+        if !check(
+            address.map_or(false, |address| {
+                paging::copy_from_address_space(
+                    p4,
+                    address,
+                    &mut relocation as *mut ElfRela as *mut u8,
+                    entry_size,
+                )
+            }),
+            "ELF relocation table is not loaded",
+        ) {
+            return false;
+        }
+
+        let relocation_type = relocation.r_info as u32;
+
+        match relocation_type {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                let target = match relocate(relocation.r_offset, bias) {
+                    Some(target) => target,
+                    None => return false,
+                };
+                let value = bias.wrapping_add(relocation.r_addend as usize);
+
+                let is_within_program =
+                    matches!(vmas.find(target), Some(area) if area.kind == VmaKind::Program);
+
+                if !check(
+                    is_within_program
+                        && paging::copy_to_address_space(
+                            p4,
+                            target,
+                            &value as *const usize as *const u8,
+                            mem::size_of::<usize>(),
+                        ),
+                    "ELF relocation lies outside of the program",
+                ) {
+                    return false;
+                }
+            }
+            _ => {
+                print_serial!("Error: Unsupported ELF relocation type {}\n", relocation_type);
+                return false;
+            }
+        }
+    }
+
+    true
 }
 
+/*
+    Converts the permissions of a segment into the protection of its pages
+    .text is read only and executable, .rodata is read only and data/bss are writable but never executable
+*/
+fn convert_flags(p_flags: u32) -> usize {
+    let mut prot = vma::PROT_NONE;
+
+    if p_flags & PF_R != 0 {
+        prot |= vma::PROT_READ;
+    }
+
+    if p_flags & PF_W != 0 {
+        prot |= vma::PROT_WRITE;
+    }
+
+    if p_flags & PF_X != 0 {
+        prot |= vma::PROT_EXEC;
+    }
+
+    prot
+}
+
+/*
+    Copies a segment into freshly allocated frames and maps them at its virtual address
+    Segments don't have to start on a page boundary so the data keeps its offset within the first page
+    Returns the (page aligned) end of the segment
+*/
 fn load_segment_into_memory(
     source_raw: usize,
     filesz: usize,
     memsz: usize,
     v_address: usize,
     p4: usize,
+    prot: usize,
 ) -> usize {
+    let page_offset = v_address & (paging::PAGE_SIZE - 1);
+
     // Allocate appropriate amount of memory
-    let rounded_size = page_frame_allocator::round_to_nearest_page(memsz);
+    let rounded_size = page_frame_allocator::round_to_nearest_page(page_offset + memsz);
     let number_of_pages = page_frame_allocator::get_number_of_pages(rounded_size);
 
     let dest: *mut usize = PAGE_FRAME_ALLOCATOR
@@ -197,11 +685,16 @@ fn load_segment_into_memory(
     PAGE_FRAME_ALLOCATOR.free();
 
     unsafe {
-        core::ptr::write_bytes(dest as *mut u8, 0, memsz as usize);
-        core::ptr::copy_nonoverlapping(source_raw as *mut u8, dest as *mut u8, filesz as usize);
+        core::ptr::write_bytes(dest as *mut u8, 0, rounded_size);
+        core::ptr::copy_nonoverlapping(
+            source_raw as *mut u8,
+            (dest as *mut u8).add(page_offset),
+            filesz as usize,
+        );
     }
 
-    paging::map_pages_custom_p4(number_of_pages, v_address, dest as usize, p4);
+    let page_start = v_address - page_offset;
+    paging::map_user_pages(number_of_pages, page_start, dest as usize, p4, prot);
 
-    v_address + (rounded_size)
+    page_start + rounded_size
 }
@@ -0,0 +1,145 @@
+/*
+    Futexes (fast userspace mutexes) let threads wait for a 32 bit word in memory to change without spinning
+    Userland takes its locks with atomic instructions and only enters the kernel when it has to sleep or wake a sleeper
+    Sleepers are keyed on the physical address of the word so threads and processes sharing the memory find each other
+*/
+
+use crate::ds::queue::Queue;
+use crate::memory::allocator::kfree;
+use crate::memory::paging;
+use crate::utils::spinlock::Lock;
+
+use super::wait_queue::{self, WaitQueue};
+use super::{PROCESS_MANAGER, TIMER_WAITERS};
+
+// Operations of the futex syscall (same as Linux)
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+
+// Every futex is keyed on its physical address so the private flag is accepted but changes nothing
+pub const FUTEX_PRIVATE_FLAG: usize = 128;
+
+pub struct FutexTable {
+    sleepers: Queue<(usize, usize)>, // Physical address of the word and pid of the sleeper
+}
+
+impl FutexTable {
+    pub const fn new() -> FutexTable {
+        FutexTable {
+            sleepers: Queue::new(),
+        }
+    }
+
+    pub fn add(&mut self, address: usize, pid: usize) {
+        self.sleepers.enqueue((address, pid));
+    }
+
+    // Returns false if the process was not sleeping on a futex
+    pub fn remove(&mut self, pid: usize) -> bool {
+        match self
+            .sleepers
+            .find_where(&|sleeper: &(usize, usize), pid| sleeper.1 == pid, pid)
+        {
+            Some(index) => {
+                if let Some((_, node)) = self.sleepers.remove(index) {
+                    kfree(node);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Removes the process which has slept longest on the futex at the address
+    pub fn take(&mut self, address: usize) -> Option<usize> {
+        let index = self.sleepers.find_where(
+            &|sleeper: &(usize, usize), address| sleeper.0 == address,
+            address,
+        )?;
+
+        let ((_, pid), node) = self.sleepers.remove(index)?;
+        kfree(node);
+
+        Some(pid)
+    }
+}
+
+pub static FUTEXES: Lock<FutexTable> = Lock::new(FutexTable::new());
+
+// Processes sleeping on any futex (the futex table says which one)
+pub static FUTEX_WAITERS: Lock<WaitQueue> = Lock::new(WaitQueue::new());
+
+/*
+    Finds the physical address of a word in the current address space (None if it isn't mapped or can't be copied)
+    A page shared copy on write gets its own frame first so the sleeper and the waker agree on the address
+*/
+pub fn physical_address(address: usize) -> Option<usize> {
+    let p4 = paging::get_current_p4();
+
+    if !paging::resolve_copy_on_write(p4, address) && paging::is_copy_on_write(p4, address) {
+        return None;
+    }
+
+    paging::translate_address(p4, address)
+}
+
+/*
+    Sleeps on the futex at the physical address until it is woken, the timer reaches wake_tick or a signal arrives
+    The caller checks the value of the word first which can't race with a wake as syscalls run with interrupts disabled
+    Returns true only if it was woken by wake
+*/
+pub fn wait(address: usize, wake_tick: Option<usize>) -> bool {
+    let pid = PROCESS_MANAGER.lock().get_current_process().pid;
+    PROCESS_MANAGER.free();
+
+    FUTEXES.lock().add(address, pid);
+    FUTEXES.free();
+
+    // The timer wakes the process through its own queue whilst it sleeps on the futex queue
+    if let Some(wake_tick) = wake_tick {
+        PROCESS_MANAGER.lock().add_timer(pid, wake_tick);
+        PROCESS_MANAGER.free();
+
+        TIMER_WAITERS.lock().add(pid);
+        TIMER_WAITERS.free();
+    }
+
+    let is_uninterrupted = wait_queue::sleep_on(&FUTEX_WAITERS);
+
+    // Still in the table if the sleep ended because of a signal or the timeout
+    let is_still_waiting = FUTEXES.lock().remove(pid);
+    FUTEXES.free();
+
+    if wake_tick.is_some() {
+        TIMER_WAITERS.lock().remove(pid);
+        TIMER_WAITERS.free();
+    }
+
+    is_uninterrupted && !is_still_waiting
+}
+
+// Wakes at most count processes sleeping on the futex at the physical address and returns how many were woken
+pub fn wake(address: usize, count: usize) -> usize {
+    let mut woken = 0;
+
+    while woken < count {
+        let pid = FUTEXES.lock().take(address);
+        FUTEXES.free();
+
+        let pid = match pid {
+            Some(pid) => pid,
+            None => break,
+        };
+
+        // Sleepers which have since exited are skipped
+        let is_woken = PROCESS_MANAGER.lock().wake(FUTEX_WAITERS.lock(), pid);
+        FUTEX_WAITERS.free();
+        PROCESS_MANAGER.free();
+
+        if is_woken {
+            woken += 1;
+        }
+    }
+
+    woken
+}
@@ -6,12 +6,161 @@
     Communication link must exist between 2 processes like buffering, synchronisation,
 */
 
+pub mod arguments;
 mod elf;
+pub mod futex;
 pub mod process;
 mod process_manager;
+pub mod resources;
+pub mod scheduler;
+pub mod signal;
 pub mod syscalls;
+pub mod wait_queue;
 
+use crate::fs::vfs::VFS;
+use crate::memory::allocator::{kfree, kmalloc};
+use crate::print_serial;
+use crate::smp::kernel_lock;
 use crate::utils::spinlock::Lock;
+use arguments::Arguments;
+pub use elf::ProgramFiles;
 use process_manager::ProcessManager;
+use wait_queue::WaitQueue;
 
 pub static PROCESS_MANAGER: Lock<ProcessManager> = Lock::new(ProcessManager::new());
+
+// Processes sleeping in receive_message until a message is sent to them
+pub static MESSAGE_WAITERS: Lock<WaitQueue> = Lock::new(WaitQueue::new());
+
+// Processes sleeping in nanosleep which are woken by the timer list of the process manager
+pub static TIMER_WAITERS: Lock<WaitQueue> = Lock::new(WaitQueue::new());
+
+// Processes sleeping in waitpid until one of their children exits
+pub static CHILD_WAITERS: Lock<WaitQueue> = Lock::new(WaitQueue::new());
+
+// Environment given to the programs started by the kernel
+pub const DEFAULT_ENVIRONMENT: &[&str] = &["PATH=/", "HOME=/"];
+
+/*
+    Reads an entire file from the VFS into a kernel buffer along with its size
+    The buffer must be free'd by the caller with kfree
+*/
+pub fn read_program(filepath: &str) -> Option<(*mut u8, usize)> {
+    let file = VFS.lock().find_file(filepath);
+    VFS.free();
+
+    let file = file?;
+
+    let buffer = kmalloc(file.size) as *mut u8;
+
+    let result = VFS.lock().read_file(&file, buffer, file.size, 0);
+    VFS.free();
+
+    if result.is_err() {
+        kfree(buffer as *mut usize);
+        return None;
+    }
+
+    Some((buffer, file.size))
+}
+
+/*
+    Creates a new user process from a command line and returns its pid (None if the program can't be loaded)
+    The first word is the path of an ELF file within the VFS and every word (including the path) is passed as an argument
+*/
+pub fn spawn(command: &str) -> Option<usize> {
+    let mut arguments = Arguments::from_command(command, DEFAULT_ENVIRONMENT);
+    let filepath = command.split_whitespace().next()?;
+
+    if !arguments.fits_on_stack() {
+        arguments.free();
+        return None;
+    }
+
+    let mut files = match ProgramFiles::read(filepath) {
+        Some(files) => files,
+        None => {
+            arguments.free();
+            return None;
+        }
+    };
+
+    let pid = PROCESS_MANAGER.lock().add_process(true, &files, &arguments);
+    PROCESS_MANAGER.free();
+
+    files.free();
+    arguments.free();
+
+    let pid = pid?;
+
+    print_serial!("Spawned {} with pid {}\n", filepath, pid);
+
+    Some(pid)
+}
+
+/*
+    Starts a thread which runs a function within the kernel (eg flushing the filesystem in the background) and returns its pid
+    The function runs with interrupts disabled so it must sleep (eg with wait_queue::sleep_until) to let everything else run
+    It must never return and calls exit_kernel_thread once it is done
+*/
+pub fn spawn_kernel_thread(entry: extern "C" fn() -> !) -> usize {
+    let pid = PROCESS_MANAGER.lock().add_kernel_thread(entry as usize);
+    PROCESS_MANAGER.free();
+
+    pid
+}
+
+// Kernel threads start here so they hold the kernel lock like a syscall would (it is given up whenever they sleep)
+pub extern "C" fn kernel_thread_start(entry: extern "C" fn() -> !) -> ! {
+    kernel_lock::acquire();
+    entry()
+}
+
+// Ends the kernel thread which calls it
+pub fn exit_kernel_thread() -> ! {
+    PROCESS_MANAGER.lock().remove_process(0);
+    PROCESS_MANAGER.free();
+
+    // The thread is cleaned up by the scheduler once it has switched away
+    loop {
+        wait_queue::yield_process();
+    }
+}
+
+/*
+    The config file lists the command line of each program to start at boot on its own line
+    Empty lines and lines starting with # are ignored
+    Returns false if the config file does not exist or can't be read as text
+*/
+pub fn spawn_from_config(config_path: &str) -> bool {
+    let (buffer, size) = match read_program(config_path) {
+        Some(result) => result,
+        None => return false,
+    };
+
+    let contents = unsafe { core::slice::from_raw_parts(buffer, size) };
+    let contents = match core::str::from_utf8(contents) {
+        Ok(contents) => contents,
+        Err(_) => {
+            print_serial!("Error: {} is not valid UTF-8\n", config_path);
+            kfree(buffer as *mut usize);
+            return false;
+        }
+    };
+
+    for line in contents.lines() {
+        let command = line.trim();
+
+        if command.is_empty() || command.starts_with("#") {
+            continue;
+        }
+
+        if spawn(command).is_none() {
+            print_serial!("Error: Could not start {} listed in {}\n", command, config_path);
+        }
+    }
+
+    kfree(buffer as *mut usize);
+
+    true
+}
@@ -2,14 +2,33 @@ use crate::{
     ds::{hashmap::HashMap, queue::Queue},
     either,
     fs::vfs::File,
-    memory::{page_frame_allocator::PAGE_FRAME_ALLOCATOR, paging},
+    interrupts::SyscallStackFrame,
+    memory::{
+        allocator::kfree,
+        kernel_stack::{KernelStack, KERNEL_STACKS},
+        page_frame_allocator::PAGE_FRAME_ALLOCATOR,
+        paging,
+        vma::{self, VmaKind, VmaList},
+    },
     multitask::elf,
     print_serial,
 };
 
-// The entrypoint for each user mode process
+use super::arguments::{self, Arguments, InitialStack};
+use super::elf::{LoadedImage, ProgramFiles};
+use super::resources::Resources;
+use super::scheduler::{self, CpuTimes};
+use super::signal::{self, SignalAction, SignalFrame, SIGNAL_COUNT, SIG_DFL, SIG_IGN};
+use core::mem::size_of;
+
+// The lowest address user programs can be loaded at which keeps them clear of the kernel
 pub static USER_PROCESS_START_ADDRESS: usize = 0x8000000;
 
+// Each process has its own user stack which grows down from here
+pub static USER_STACK_TOP: usize = 0x7FFF_FFFF_F000;
+const USER_STACK_PAGES: usize = 4; // Mapped upfront
+const USER_STACK_LIMIT: usize = 256; // The rest are mapped on demand
+
 #[derive(Debug, Copy, Clone)]
 pub struct Message {
     pub sender_pid: usize,
@@ -24,75 +43,605 @@ pub struct Message {
 pub enum ProcessState {
     Running,
     Blocked,
+    Stopped, // Halted by a signal until SIGCONT is sent
+    Zombie,  // Exited but the exit status has not been collected by the parent yet
     Terminated,
 }
 
 /*
     Processes are running programs with an individual address space, stack and data which run in userspace
-    Processes will be selected based on  priority
+    Processes will be selected based on their level and nice value (see scheduler)
     Procesess are mapped into a specific address space
+    A process can have several threads which are scheduled on their own but share the resources of the process
+    Each thread is its own entry in the run queue with its own pid (thread id) and the pid of the first thread as its tgid
 */
 #[derive(Copy, Clone, Debug)]
 pub struct Process {
     pub pid: usize,
+    pub tgid: usize,       // Pid of the first thread of the process (its own pid unless it is a thread)
+    pub parent_pid: usize, // Zero when the process has no parent
+    pub exit_status: usize, // Encoded in the same way waitpid reports it
     pub rsp: *const usize,
-    pub priority: ProcessPriority,
-    p4: usize,
-    pub fdt: HashMap<*mut File>,
+    kernel_stack: KernelStack, // Used whenever the process enters the kernel
+    pub nice: isize,
+    level: usize,      // Level of the feedback queue (0 runs first)
+    time_slice: usize, // Ticks left before the process is preempted
+    pub cpu_times: CpuTimes,
+    resources: *mut Resources, // Shared with every other thread of the process
+    fs_base: usize,            // Thread local storage pointer which is loaded into FS_BASE
     pub state: ProcessState,
     pub messages: Queue<Message>,
+    pub pending_signals: u64,
+    pub blocked_signals: u64,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-#[allow(dead_code)]
-pub enum ProcessPriority {
-    High,
-    Low,
-}
+// multiboot data defines the address of the process followed by its size
+impl Process {
+    // Returns None if the ELF file can't be loaded
+    pub fn init(
+        is_user: bool,
+        pid: usize,
+        files: &ProgramFiles,
+        arguments: &Arguments,
+    ) -> Option<Process> {
+        let mut p4 = paging::deep_clone() as usize;
+        let mut vmas = VmaList::new();
+
+        let image = match elf::parse(files, p4, &mut vmas) {
+            Some(image) => image,
+            None => {
+                paging::free_address_space(p4);
+                vmas.free();
+                return None;
+            }
+        };
+        let heap_start = image.end;
+
+        print_serial!("Parsed process successfully\n");
+
+        let stack_top = Process::map_user_stack(p4, &mut vmas);
+        vmas.add(heap_start, heap_start, vma::PROT_READ | vma::PROT_WRITE, VmaKind::Heap);
+
+        let stack = arguments.push_to_stack(p4, stack_top, &Process::auxiliary_vector(&image));
+
+        let kernel_stack = KERNEL_STACKS.lock().allocate();
+        KERNEL_STACKS.free();
+
+        let rsp = Process::create_initial_frame(is_user, &kernel_stack, image.start, &stack, p4);
+
+        Some(Process {
+            pid,
+            tgid: pid,
+            parent_pid: 0,
+            exit_status: 0,
+            rsp,
+            kernel_stack,
+            nice: either!(is_user => 0; scheduler::KERNEL_NICE),
+            level: 0,
+            time_slice: 0,
+            cpu_times: CpuTimes::new(),
+            resources: Resources::create(p4, vmas, heap_start),
+            fs_base: 0,
+            state: ProcessState::Running,
+            messages: Queue::<Message>::new(),
+            pending_signals: 0,
+            blocked_signals: 0,
+        })
+    }
+
+    /*
+        Creates a thread which runs a function within the kernel (eg a background worker)
+        Kernel threads run with interrupts disabled so they are never preempted whilst holding a lock and must sleep to let other processes run
+        The kernel stack of the thread is the only stack it uses
+    */
+    pub fn kernel_thread(pid: usize, entry: usize, kernel_p4: usize) -> Process {
+        let kernel_stack = KERNEL_STACKS.lock().allocate();
+        KERNEL_STACKS.free();
+
+        let mut rsp = kernel_stack.top() as *mut usize;
+
+        unsafe {
+            *rsp.offset(-1) = 0x10; // SS
+            *rsp.offset(-2) = kernel_stack.top(); // RSP
+            *rsp.offset(-3) = 0x002; // RFLAGS with interrupts disabled
+            *rsp.offset(-4) = 0x08; // CS
+            *rsp.offset(-5) = super::kernel_thread_start as usize; // RIP
+
+            // General purpose registers start zeroed
+            for i in 6..=20 {
+                *rsp.offset(-i) = 0;
+            }
+
+            *rsp.offset(-11) = entry; // RDI is the first argument of kernel_thread_start
+
+            *rsp.offset(-21) = kernel_p4; // CR3
+            rsp = rsp.offset(-21);
+        }
 
-impl ProcessPriority {
-    pub fn convert(process_priority: ProcessPriority) -> usize {
-        match process_priority {
-            Self::High => 5,
-            Self::Low => 10,
+        Process {
+            pid,
+            tgid: pid,
+            parent_pid: 0,
+            exit_status: 0,
+            rsp,
+            kernel_stack,
+            nice: scheduler::KERNEL_NICE,
+            level: 0,
+            time_slice: 0,
+            cpu_times: CpuTimes::new(),
+            resources: Resources::kernel(kernel_p4),
+            fs_base: 0,
+            state: ProcessState::Running,
+            messages: Queue::<Message>::new(),
+            pending_signals: 0,
+            blocked_signals: 0,
         }
     }
-}
 
-// multiboot data defines the address of the process followed by its size
-impl Process {
-    pub fn init(is_user: bool, pid: usize, start_addr: usize) -> Process {
-        // Allocate a page of memory for the stack
-        // Use PFA for safety
-        let mut rsp = PAGE_FRAME_ALLOCATOR.lock().alloc_page_frame().unwrap();
+    /*
+        Creates a copy of this process which shares every user page copy on write
+        The child resumes from the same syscall as the parent but with a return value of 0
+        Signal actions and the blocked mask are inherited but pending signals are not
+    */
+    pub fn fork(&self, pid: usize, registers: &SyscallStackFrame) -> Process {
+        let p4 = paging::clone_copy_on_write() as usize;
+
+        let kernel_stack = KERNEL_STACKS.lock().allocate();
+        KERNEL_STACKS.free();
+
+        let rsp = Process::copy_frame(&kernel_stack, registers, registers.rsp, p4);
+
+        let parent_resources = self.resources();
+        let resources = Resources::create(
+            p4,
+            parent_resources.vmas.duplicate(),
+            parent_resources.heap_start,
+        );
+        let resources_ref = unsafe { &mut *resources };
+
+        // Both processes point to the same open files but need seperate tables
+        parent_resources
+            .fdt
+            .for_each(|fd, file| resources_ref.fdt.set(fd, file));
+
+        resources_ref.signal_actions = parent_resources.signal_actions;
+        resources_ref.program_break = parent_resources.program_break;
+
+        Process {
+            pid,
+            tgid: pid,
+            parent_pid: self.tgid,
+            exit_status: 0,
+            rsp,
+            kernel_stack,
+            nice: self.nice,
+            level: self.level,
+            time_slice: 0,
+            cpu_times: CpuTimes::new(),
+            resources,
+            fs_base: self.fs_base,
+            state: ProcessState::Running,
+            messages: Queue::<Message>::new(),
+            pending_signals: 0,
+            blocked_signals: self.blocked_signals,
+        }
+    }
+
+    /*
+        Creates a new thread of this process which starts on its own user stack
+        The thread resumes from the same syscall as the caller but with a return value of 0
+        Threads aren't children of anyone so they are cleaned up as soon as they exit
+    */
+    pub fn create_thread(
+        &self,
+        pid: usize,
+        registers: &SyscallStackFrame,
+        stack: usize,
+        tls: Option<usize>,
+    ) -> Process {
+        self.resources().acquire();
+
+        let kernel_stack = KERNEL_STACKS.lock().allocate();
+        KERNEL_STACKS.free();
+
+        let rsp = Process::copy_frame(&kernel_stack, registers, stack, self.p4());
+
+        Process {
+            pid,
+            tgid: self.tgid,
+            parent_pid: 0,
+            exit_status: 0,
+            rsp,
+            kernel_stack,
+            nice: self.nice,
+            level: self.level,
+            time_slice: 0,
+            cpu_times: CpuTimes::new(),
+            resources: self.resources,
+            fs_base: tls.unwrap_or(self.fs_base),
+            state: ProcessState::Running,
+            messages: Queue::<Message>::new(),
+            pending_signals: 0,
+            blocked_signals: self.blocked_signals,
+        }
+    }
+
+    // Builds a frame on a new kernel stack which resumes from a syscall with a return value of 0
+    fn copy_frame(
+        kernel_stack: &KernelStack,
+        registers: &SyscallStackFrame,
+        user_rsp: usize,
+        p4: usize,
+    ) -> *const usize {
+        let mut rsp = kernel_stack.top() as *mut usize;
+
+        unsafe {
+            *rsp.offset(-1) = registers.ss; // SS
+            *rsp.offset(-2) = user_rsp; // RSP
+            *rsp.offset(-3) = registers.rflags; // RFLAGS
+            *rsp.offset(-4) = registers.cs; // CS
+            *rsp.offset(-5) = registers.rip; // RIP
+            *rsp.offset(-6) = 0x00; // RAX (return value within the new process or thread)
+            *rsp.offset(-7) = registers.rbx; // RBX
+            *rsp.offset(-8) = registers.rcx; // RCX
+            *rsp.offset(-9) = registers.rdx; // RDX
+            *rsp.offset(-10) = registers.rbp; // RBP
+            *rsp.offset(-11) = registers.rdi; // RDI
+            *rsp.offset(-12) = registers.rsi; // RSI
+            *rsp.offset(-13) = registers.r8; // R8
+            *rsp.offset(-14) = registers.r9; // R9
+            *rsp.offset(-15) = registers.r10; // R10
+            *rsp.offset(-16) = registers.r11; // R11
+            *rsp.offset(-17) = registers.r12; // R12
+            *rsp.offset(-18) = registers.r13; // R13
+            *rsp.offset(-19) = registers.r14; // R14
+            *rsp.offset(-20) = registers.r15; // R15
+            *rsp.offset(-21) = p4; // CR3
+            rsp = rsp.offset(-21);
+        }
+
+        rsp
+    }
+
+    /*
+        Replaces the program running within this process with the given ELF file
+        The syscall frame is rewritten so returning from the syscall jumps to the start of the new program
+        The arguments may point into the old program so are copied across before its address space is free'd
+        Returns false (leaving the old program untouched) if the ELF file can't be loaded
+    */
+    pub fn exec(
+        &mut self,
+        files: &ProgramFiles,
+        arguments: &Arguments,
+        registers: &mut SyscallStackFrame,
+    ) -> bool {
+        let p4 = paging::deep_clone() as usize;
+        let mut vmas = VmaList::new();
+
+        let image = match elf::parse(files, p4, &mut vmas) {
+            Some(image) => image,
+            None => {
+                paging::free_address_space(p4);
+                vmas.free();
+                return false;
+            }
+        };
+        let heap_start = image.end;
+
+        let stack_top = Process::map_user_stack(p4, &mut vmas);
+        vmas.add(heap_start, heap_start, vma::PROT_READ | vma::PROT_WRITE, VmaKind::Heap);
+
+        let stack = arguments.push_to_stack(p4, stack_top, &Process::auxiliary_vector(&image));
+
+        self.fs_base = 0;
+
+        let resources = self.resources();
+
+        resources.vmas.free();
+        resources.vmas = vmas;
+        resources.heap_start = heap_start;
+        resources.program_break = heap_start;
+
+        registers.rip = image.start;
+        registers.rsp = stack.rsp;
+        registers.rbx = 0;
+        registers.rcx = 0;
+        registers.rdx = stack.envp;
+        registers.rbp = 0;
+        registers.rdi = stack.argc;
+        registers.rsi = stack.argv;
+        registers.r8 = 0;
+        registers.r9 = 0;
+        registers.r10 = 0;
+        registers.r11 = 0;
+        registers.r12 = 0;
+        registers.r13 = 0;
+        registers.r14 = 0;
+        registers.r15 = 0;
+
+        // Handlers belong to the old program so caught signals go back to their default action
+        for action in resources.signal_actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SignalAction::new();
+            }
+        }
+
+        let old_p4 = resources.p4;
+
+        resources.p4 = p4;
+        paging::switch_page_table(p4);
+
+        paging::free_address_space(old_p4);
+
+        true
+    }
+
+    /*
+        Returns the kernel stack and kernel side structures of an exited process or thread
+        The shared resources (including the address space) are only free'd along with the last thread
+        Must not be called whilst the address space of the process is active unless other threads still use it
+    */
+    pub fn destroy(&mut self) {
+        if self.resources.is_null() {
+            return;
+        }
+
+        Resources::release(self.resources);
+        self.resources = core::ptr::null_mut();
+
+        KERNEL_STACKS.lock().free(self.kernel_stack);
+        KERNEL_STACKS.free();
+
+        // Payloads of messages which were never received
+        while let Some(message) = self.messages.dequeue() {
+            kfree(message.message as *mut usize);
+        }
+    }
+
+    fn resources(&self) -> &mut Resources {
+        unsafe { &mut *self.resources }
+    }
+
+    pub fn p4(&self) -> usize {
+        self.resources().p4
+    }
+
+    pub fn fdt(&self) -> &mut HashMap<*mut File> {
+        &mut self.resources().fdt
+    }
+
+    pub fn signal_actions(&self) -> &mut [SignalAction; SIGNAL_COUNT] {
+        &mut self.resources().signal_actions
+    }
+
+    pub fn is_thread(&self) -> bool {
+        self.pid != self.tgid
+    }
+
+    pub fn is_kernel_thread(&self) -> bool {
+        !self.resources.is_null() && self.resources().is_kernel()
+    }
+
+    // Number of threads (including this one) within the process
+    pub fn thread_count(&self) -> usize {
+        self.resources().thread_count()
+    }
+
+    pub fn fs_base(&self) -> usize {
+        self.fs_base
+    }
+
+    pub fn set_fs_base(&mut self, address: usize) {
+        self.fs_base = address;
+    }
+
+    // Maps a zeroed stack just under USER_STACK_TOP within the given address space
+    fn map_user_stack(p4: usize, vmas: &mut VmaList) -> usize {
+        let stack_bottom = PAGE_FRAME_ALLOCATOR
+            .lock()
+            .alloc_page_frames(USER_STACK_PAGES);
         PAGE_FRAME_ALLOCATOR.free();
 
-        let mut p4 = paging::deep_clone() as usize;
+        unsafe {
+            core::ptr::write_bytes(stack_bottom as *mut u8, 0, USER_STACK_PAGES * paging::PAGE_SIZE);
+        }
 
-        elf::parse(start_addr, p4);
+        paging::map_user_pages(
+            USER_STACK_PAGES,
+            USER_STACK_TOP - USER_STACK_PAGES * paging::PAGE_SIZE,
+            stack_bottom as usize,
+            p4,
+            vma::PROT_READ | vma::PROT_WRITE,
+        );
 
-        print_serial!("Parsed process successfully\n");
+        vmas.add(
+            USER_STACK_TOP - USER_STACK_LIMIT * paging::PAGE_SIZE,
+            USER_STACK_TOP,
+            vma::PROT_READ | vma::PROT_WRITE,
+            VmaKind::Stack,
+        );
+
+        USER_STACK_TOP
+    }
+
+    /*
+        Moves the end of the heap (the program break) and returns the new break
+        Pages above the old break are only mapped when touched whilst pages given back are unmapped straight away
+        The break is left unchanged if it can't be moved
+    */
+    pub fn set_program_break(&mut self, address: usize) -> usize {
+        let resources = self.resources();
+
+        if address < resources.heap_start || address > vma::MMAP_TOP {
+            return resources.program_break;
+        }
+
+        let new_end = vma::round_up_to_page(address);
+        let old_end = vma::round_up_to_page(resources.program_break);
+
+        if new_end > old_end && !resources.vmas.is_free(old_end, new_end) {
+            return resources.program_break;
+        }
+
+        if new_end < old_end {
+            vma::release_range(resources.p4, new_end, old_end);
+        }
+
+        let heap = resources.vmas.find_kind_mut(VmaKind::Heap).expect("Heap not found");
+        heap.end = new_end;
+
+        resources.program_break = address;
+        address
+    }
+
+    // Reserves a range for an anonymous mapping which is placed at the hint if it is free
+    pub fn map_anonymous(&mut self, hint: usize, length: usize, prot: usize) -> Option<usize> {
+        let length = length.checked_add(paging::PAGE_SIZE - 1)? & !(paging::PAGE_SIZE - 1);
+
+        if length == 0 {
+            return None;
+        }
+
+        let vmas = &mut self.resources().vmas;
+
+        let is_hint_free = hint % paging::PAGE_SIZE == 0
+            && vma::user_range(hint, length).is_some_and(|end| vmas.is_free(hint, end));
+
+        let start = if is_hint_free {
+            hint
+        } else {
+            vmas.find_free(length)?
+        };
+
+        vmas.add(start, start + length, prot, VmaKind::Anonymous);
+
+        Some(start)
+    }
+
+    /*
+        Removes a range from the address space along with any frames behind it
+        Only pages within the process's own areas are released as the kernel is mapped into every address space
+    */
+    pub fn unmap(&mut self, start: usize, length: usize) -> bool {
+        if start % paging::PAGE_SIZE != 0 {
+            return false;
+        }
+
+        let end = match vma::user_range(start, length) {
+            Some(end) => end,
+            None => return false,
+        };
+
+        let p4 = self.p4();
+        let vmas = &mut self.resources().vmas;
+
+        vmas.for_each_covered(start, end, |area_start, area_end| {
+            vma::release_range(p4, area_start, area_end)
+        });
+        vmas.remove_range(start, end);
+
+        true
+    }
+
+    /*
+        Resolves a page fault caused by the process (or the kernel touching its memory)
+        Writes to pages shared after fork are given a private copy
+        Pages which lie within an area but were never touched are given a zeroed frame
+        Returns false if the access is invalid or no memory is left to resolve it
+    */
+    pub fn handle_page_fault(&mut self, address: usize, is_present: bool, is_write: bool) -> bool {
+        // Another thread may have resolved the fault on another CPU before this one got here
+        if is_present {
+            return is_write
+                && (paging::resolve_copy_on_write(self.p4(), address)
+                    || paging::is_user_writable(self.p4(), address));
+        }
+
+        self.map_on_demand(address)
+    }
+
+    fn map_on_demand(&mut self, address: usize) -> bool {
+        let area = match self.resources().vmas.find(address) {
+            Some(area) => area,
+            None => return false,
+        };
+
+        if area.prot == vma::PROT_NONE {
+            return false;
+        }
+
+        // Another thread mapped the page since the fault
+        if paging::translate_address(self.p4(), address).is_some() {
+            return true;
+        }
+
+        // Running out of memory only takes down the process which faulted
+        let frame = PAGE_FRAME_ALLOCATOR.lock().alloc_page_frame();
+        PAGE_FRAME_ALLOCATOR.free();
+
+        let frame = match frame {
+            Some(frame) => frame,
+            None => return false,
+        };
+
+        unsafe {
+            core::ptr::write_bytes(frame as *mut u8, 0, paging::PAGE_SIZE);
+        }
+
+        paging::map_user_pages(
+            1,
+            address & !(paging::PAGE_SIZE - 1),
+            frame as usize,
+            self.p4(),
+            area.prot,
+        );
+
+        true
+    }
+
+    // Tells the program (and libc or the interpreter) where everything was loaded
+    fn auxiliary_vector(image: &LoadedImage) -> [(usize, usize); 6] {
+        [
+            (arguments::AT_PHDR, image.program_headers),
+            (arguments::AT_PHENT, image.program_header_size),
+            (arguments::AT_PHNUM, image.program_header_count),
+            (arguments::AT_PAGESZ, paging::PAGE_SIZE),
+            (arguments::AT_BASE, image.interpreter_base),
+            (arguments::AT_ENTRY, image.entry),
+        ]
+    }
+
+    /*
+        Builds the frame which the PIT handler pops off when the process is first scheduled
+        argc, argv and envp are passed in registers as well as on the stack
+    */
+    fn create_initial_frame(
+        is_user: bool,
+        kernel_stack: &KernelStack,
+        rip: usize,
+        stack: &InitialStack,
+        p4: usize,
+    ) -> *const usize {
+        let mut rsp = kernel_stack.top() as *mut usize;
 
         unsafe {
-            rsp = rsp.offset(511);
-            let stack_top: usize = rsp as usize;
 
             /*
                When interrupt is called the following registers are pushed as follows: SS -> RSP -> RFLAGS -> CS -> RIP
                These registers are then pushed: RAX -> RBX -> RBC -> RDX -> RSI -> RDI -> R8..R15
             */
             *rsp.offset(-1) = either!(is_user => 0x20 | 0x3; 0x10); // SS
-            *rsp.offset(-2) = stack_top; // RSP
+            *rsp.offset(-2) = stack.rsp; // RSP
             *rsp.offset(-3) = 0x202; // RFLAGS which enable interrupts
             *rsp.offset(-4) = either!(is_user=> 0x18 | 0x3; 0x08); // CS
-            *rsp.offset(-5) = USER_PROCESS_START_ADDRESS; // RIP
+            *rsp.offset(-5) = rip; // RIP
             *rsp.offset(-6) = 0x00; // RAX
             *rsp.offset(-7) = 0x00; // RBX
             *rsp.offset(-8) = 0x00; // RCX
-            *rsp.offset(-9) = 0x00; // RDX
+            *rsp.offset(-9) = stack.envp; // RDX
             *rsp.offset(-10) = 0; // RBP
-            *rsp.offset(-11) = 0; // RDI (argv)
-            *rsp.offset(-12) = 0; // RSI (argc)
+            *rsp.offset(-11) = stack.argc; // RDI
+            *rsp.offset(-12) = stack.argv; // RSI
             *rsp.offset(-13) = 0; // R8
             *rsp.offset(-14) = 0; // R9
             *rsp.offset(-15) = 0; // R10
@@ -105,24 +654,177 @@ impl Process {
             rsp = rsp.offset(-21);
         }
 
-        let fdt = HashMap::<*mut File>::new();
+        rsp
+    }
+
+    // Loaded into RSP0 of the TSS whenever the process is switched to
+    pub fn kernel_stack_top(&self) -> usize {
+        self.kernel_stack.top()
+    }
+
+    // Registers saved by the PIT handler when the process was last switched out
+    pub fn saved_registers(&self) -> *mut SyscallStackFrame {
+        unsafe { (self.rsp as *mut usize).offset(1) as *mut SyscallStackFrame }
+    }
+
+    // Removes the lowest pending signal which isn't blocked
+    pub fn take_pending_signal(&mut self) -> Option<usize> {
+        let deliverable = self.pending_signals & !self.blocked_signals;
+
+        if deliverable == 0 {
+            return None;
+        }
+
+        let signal = deliverable.trailing_zeros() as usize;
+        self.pending_signals &= !signal::mask(signal);
+        Some(signal)
+    }
 
-        Process {
-            pid,
-            rsp,
-            priority: either!(is_user => ProcessPriority::Low; ProcessPriority::High),
-            p4,
-            fdt,
-            state: ProcessState::Running,
-            messages: Queue::<Message>::new(),
+    pub fn has_deliverable_signal(&self) -> bool {
+        self.pending_signals & !self.blocked_signals != 0
+    }
+
+    /*
+        Makes the process run the handler for a signal the next time it is scheduled
+        The saved registers are pushed onto the user stack as a signal frame and the handler returns into the restorer
+        Returns false if the user stack can't hold the frame
+    */
+    pub fn enter_signal_handler(&mut self, signal: usize) -> bool {
+        let action = self.signal_actions()[signal];
+        let registers = unsafe { &mut *self.saved_registers() };
+
+        // Skip the red zone of the interrupted function and keep the frame 16 byte aligned
+        let frame_addr = (registers.rsp - 128 - size_of::<SignalFrame>()) & !0xF;
+        let return_addr = frame_addr - size_of::<usize>();
+
+        let frame = SignalFrame {
+            registers: *registers,
+            blocked_signals: self.blocked_signals,
+        };
+
+        let is_frame_written = paging::copy_to_address_space(
+            self.p4(),
+            frame_addr,
+            &frame as *const SignalFrame as *const u8,
+            size_of::<SignalFrame>(),
+        ) && paging::copy_to_address_space(
+            self.p4(),
+            return_addr,
+            &action.restorer as *const usize as *const u8,
+            size_of::<usize>(),
+        );
+
+        if !is_frame_written {
+            return false;
+        }
+
+        registers.rip = action.handler;
+        registers.rsp = return_addr;
+        registers.rdi = signal;
+
+        self.blocked_signals |= action.mask;
+        if action.flags & signal::SA_NODEFER == 0 {
+            self.blocked_signals |= signal::mask(signal);
+        }
+        self.blocked_signals &= !signal::UNBLOCKABLE_SIGNALS;
+
+        if action.flags & signal::SA_RESETHAND != 0 {
+            self.signal_actions()[signal].handler = SIG_DFL;
+        }
+
+        true
+    }
+
+    /*
+        Restores the registers and blocked mask saved in the signal frame at the top of the user stack
+        The code and stack segments are kept and only harmless flags may be changed
+        Returns false if the frame isn't within user memory
+    */
+    pub fn return_from_signal(&mut self, registers: &mut SyscallStackFrame) -> bool {
+        let frame_addr = registers.rsp;
+
+        if frame_addr < USER_PROCESS_START_ADDRESS {
+            return false;
         }
+
+        let mut frame = unsafe { core::mem::zeroed::<SignalFrame>() };
+
+        if !paging::copy_from_address_space(
+            self.p4(),
+            frame_addr,
+            &mut frame as *mut SignalFrame as *mut u8,
+            size_of::<SignalFrame>(),
+        ) {
+            return false;
+        }
+
+        let cs = registers.cs;
+        let ss = registers.ss;
+
+        *registers = frame.registers;
+        registers.cs = cs;
+        registers.ss = ss;
+        registers.rflags = (frame.registers.rflags & 0xCD5) | 0x202; // Status flags and interrupts enabled
+
+        self.blocked_signals = frame.blocked_signals & !signal::UNBLOCKABLE_SIGNALS;
+
+        true
     }
 
+    pub fn is_address_space_active(&self) -> bool {
+        !self.resources.is_null() && paging::get_current_p4() == self.p4()
+    }
+
+    // A thread which another CPU ended whilst it entered a syscall stays ended
     pub fn block(&mut self) {
-        self.state = ProcessState::Blocked;
+        if self.state == ProcessState::Running {
+            self.state = ProcessState::Blocked;
+        }
     }
 
     pub fn unblock(&mut self) {
         self.state = ProcessState::Running;
     }
+
+    // Key of the process within the run queue (only read when it is enqueued)
+    pub fn schedule_priority(&self) -> usize {
+        scheduler::queue_priority(self.level, self.nice)
+    }
+
+    pub fn set_nice(&mut self, nice: isize) {
+        self.nice = scheduler::clamp_nice(nice);
+    }
+
+    // Called when the process is picked to run
+    pub fn start_time_slice(&mut self) {
+        self.time_slice = scheduler::time_slice(self.level, self.nice);
+    }
+
+    // Accounts a tick to the process and returns false once its time slice is used up
+    pub fn charge_tick(&mut self, is_user: bool) -> bool {
+        if is_user {
+            self.cpu_times.user += 1;
+        } else {
+            self.cpu_times.system += 1;
+        }
+
+        self.time_slice = self.time_slice.saturating_sub(1);
+        self.time_slice > 0
+    }
+
+    /*
+        Called when the process is switched out to move it between levels
+        Using up the time slice moves it down whilst sleeping early moves it up
+    */
+    pub fn update_level(&mut self) {
+        if self.time_slice == 0 {
+            self.level = scheduler::lower_level(self.level);
+        } else if self.state == ProcessState::Blocked {
+            self.level = scheduler::raise_level(self.level);
+        }
+    }
+
+    pub fn boost(&mut self) {
+        self.level = 0;
+    }
 }
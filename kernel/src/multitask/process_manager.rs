@@ -1,132 +1,792 @@
 use crate::{
-    ds::queue::{PriorityQueue, PriorityWrapper},
-    memory::{allocator::kmalloc, gdt::TSS},
+    ds::{
+        queue::{PriorityQueue, PriorityWrapper},
+        vec::DynamicArray,
+    },
+    interrupts::SyscallStackFrame,
+    either,
+    memory::{
+        allocator::{kfree, kmalloc},
+        gdt,
+        kernel_stack::KERNEL_STACKS,
+        paging,
+    },
     print_serial,
+    smp::{self, tlb, MAX_CPUS},
+    utils::msr,
 };
 
 use core::mem::size_of;
 
-use super::process::{Message, Process, ProcessPriority, ProcessState};
+use super::arguments::Arguments;
+use super::elf::ProgramFiles;
+use super::process::{Message, Process, ProcessState};
+use super::scheduler::{self, BOOST_INTERVAL};
+use super::wait_queue::WaitQueue;
+use super::CHILD_WAITERS;
+use super::signal::{self, DefaultAction, SIGCHLD, SIGCONT, SIGKILL, SIGSEGV, SIG_DFL, SIG_IGN};
 
+/*
+    Every process lives on the kernel heap so it stays at the same address whilst it moves between run queues
+    Each CPU has its own run queue and takes a process from another queue once it has nothing left to run
+    A process which isn't running sits in exactly one run queue (even whilst it sleeps) until it exits
+*/
 pub struct ProcessManager {
-    pub tasks: PriorityQueue<Process>,
-    pub current_process_id: usize,
-    pub is_from_kernel: bool,
+    processes: DynamicArray<*mut Process>,
+    cpus: [Cpu; MAX_CPUS],
+    next_pid: usize,
+    kernel_p4: usize, // Address space used once a process has exited
+    timers: PriorityQueue<(usize, usize)>, // Tick and pid of sleeping processes (earliest first)
+    ticks_since_boost: usize,
 }
 
-fn find_process(node: &PriorityWrapper<Process>, pid: usize) -> bool {
-    return node.value.pid == pid;
+struct Cpu {
+    queue: PriorityQueue<*mut Process>,
+    current: Option<*mut Process>,
+    previous: Option<*mut Process>, // Switched out by the last switch which still ran on its kernel stack
+    idle_rsp: usize,                // Frame of the kernel which is resumed when no process can run
+}
+
+const IDLE_CPU: Cpu = Cpu {
+    queue: PriorityQueue::<*mut Process>::new(),
+    current: None,
+    previous: None,
+    idle_rsp: 0,
+};
+
+pub enum WaitResult {
+    Exited(usize, usize), // Pid and exit status of the child which was reaped
+    Running,              // Matching children exist but none have exited yet
+    NoChildren,
+}
+
+fn find_process(process: &*mut Process, pid: usize) -> bool {
+    return unsafe { (**process).pid == pid };
+}
+
+fn find_queued(node: &PriorityWrapper<*mut Process>, address: usize) -> bool {
+    return node.value as usize == address;
 }
 
 impl ProcessManager {
     pub const fn new() -> ProcessManager {
         ProcessManager {
-            tasks: PriorityQueue::<Process>::new(),
-            current_process_id: 0,
-            is_from_kernel: true,
+            processes: DynamicArray::<*mut Process>::new(),
+            cpus: [IDLE_CPU; MAX_CPUS],
+            next_pid: 1,
+            kernel_p4: 0,
+            timers: PriorityQueue::<(usize, usize)>::new(),
+            ticks_since_boost: 0,
         }
     }
 
     pub fn init(&mut self) {
-        self.tasks.init();
+        self.processes.init();
+
+        for cpu in self.cpus.iter_mut() {
+            cpu.queue.init();
+        }
+
+        self.timers.init();
+        self.kernel_p4 = paging::get_current_p4();
     }
 
-    pub fn add_process(&mut self, is_user: bool, pid: usize, multiboot_start_addr: usize) {
-        let process = Process::init(is_user, pid, multiboot_start_addr);
-        let converted_priority = ProcessPriority::convert(process.priority);
-        self.tasks.enqueue(process, converted_priority);
+    // Returns None if the ELF file is invalid
+    pub fn add_process(
+        &mut self,
+        is_user: bool,
+        files: &ProgramFiles,
+        arguments: &Arguments,
+    ) -> Option<usize> {
+        let pid = self.allocate_pid();
+        let process = Process::init(is_user, pid, files, arguments)?;
+        self.add(process);
+        Some(pid)
     }
 
-    pub fn get_current_process(&mut self) -> &mut Process {
-        self.tasks.peek()
+    // Duplicates the current process and returns the pid of the child
+    pub fn fork(&mut self, registers: &SyscallStackFrame) -> usize {
+        let pid = self.allocate_pid();
+        let child = self.get_current_process().fork(pid, registers);
+        self.add(child);
+        pid
     }
 
-    pub fn remove_process(&mut self) {
-        // Mark process for termination
-        let current_process = self.tasks.peek();
-        current_process.state = ProcessState::Terminated;
+    // Starts a new thread within the current process and returns its pid
+    pub fn create_thread(
+        &mut self,
+        registers: &SyscallStackFrame,
+        stack: usize,
+        tls: Option<usize>,
+    ) -> usize {
+        let pid = self.allocate_pid();
+        let thread = self
+            .get_current_process()
+            .create_thread(pid, registers, stack, tls);
+        self.add(thread);
+        pid
     }
 
-    pub fn send_message(&mut self, message: *mut Message) {
-        let message_ref = unsafe { &mut *message };
+    // Starts a thread which runs the given function within the kernel and returns its pid
+    pub fn add_kernel_thread(&mut self, entry: usize) -> usize {
+        let pid = self.allocate_pid();
+        let thread = Process::kernel_thread(pid, entry, self.kernel_p4);
+        self.add(thread);
+        pid
+    }
+
+    fn add(&mut self, process: Process) {
+        let address = kmalloc(size_of::<Process>()) as *mut Process;
 
-        let current_process = self.tasks.peek();
+        unsafe {
+            core::ptr::write(address, process);
+        }
+
+        self.processes.push(address);
+        self.enqueue(address);
+    }
+
+    // New processes go to the CPU with the fewest processes queued
+    fn enqueue(&mut self, process: *mut Process) {
+        let cpu = (0..smp::cpu_count())
+            .min_by_key(|&cpu| self.cpus[cpu].queue.len())
+            .unwrap_or(0);
+
+        self.schedule(process, cpu);
+    }
+
+    fn schedule(&mut self, process: *mut Process, cpu: usize) {
+        let priority = unsafe { (*process).schedule_priority() };
+        self.cpus[cpu].queue.enqueue(process, priority);
+    }
 
-        message_ref.sender_pid = current_process.pid;
+    fn allocate_pid(&mut self) -> usize {
+        let pid = self.next_pid;
+        self.next_pid += 1;
+        pid
+    }
 
-        let receiver_process = self
-            .tasks
-            .nodes
-            .find_where(&find_process, message_ref.receiver_pid);
+    // Processes never move in memory so they can be referred to after the process manager is free'd
+    fn process(&self, index: usize) -> &'static mut Process {
+        unsafe { &mut **self.processes.get_mut(index).expect("Process not found") }
+    }
 
-        if let Some(process_index) = receiver_process {
-            let receiver_process = self
-                .tasks
-                .nodes
-                .get_mut(process_index)
-                .expect("Process not found");
+    fn find(&self, pid: usize) -> Option<&'static mut Process> {
+        let index = self.processes.find_where(&find_process, pid)?;
+        Some(self.process(index))
+    }
 
-            receiver_process.value.messages.enqueue(*message_ref);
+    // The process running on the CPU which calls it
+    pub fn get_current_process(&mut self) -> &'static mut Process {
+        let process = self.cpus[smp::current_cpu()]
+            .current
+            .expect("No process is running");
 
-            if receiver_process.value.state == ProcessState::Blocked {
-                receiver_process.value.unblock();
+        unsafe { &mut *process }
+    }
+
+    // A pid of 0 refers to the current process
+    pub fn get_process(&mut self, pid: usize) -> Option<&mut Process> {
+        if pid == 0 {
+            return Some(self.get_current_process());
+        }
+
+        self.find(pid)
+    }
+
+    // A process is in use by a CPU until that CPU has switched to another process after it
+    fn is_running(&self, process: *mut Process) -> bool {
+        self.cpus
+            .iter()
+            .any(|cpu| cpu.current == Some(process) || cpu.previous == Some(process))
+    }
+
+    // The process switched out last on a CPU is only safe to resume on that same CPU
+    fn is_running_elsewhere(&self, process: *mut Process, cpu: usize) -> bool {
+        self.cpus.iter().enumerate().any(|(other, other_cpu)| {
+            other != cpu
+                && (other_cpu.current == Some(process) || other_cpu.previous == Some(process))
+        })
+    }
+
+    // Whether another thread of the current process is still running (eg before exec replaces the address space)
+    pub fn is_other_thread_running(&self) -> bool {
+        let current = self.cpus[smp::current_cpu()]
+            .current
+            .expect("No process is running");
+        let tgid = unsafe { (*current).tgid };
+
+        self.processes.iter().any(|&process| {
+            process != current && unsafe { (*process).tgid } == tgid && self.is_running(process)
+        })
+    }
+
+    // Ends the current process (and all of its threads) which is cleaned up once the scheduler switches away from it
+    pub fn remove_process(&mut self, exit_status: usize) {
+        // Leave the address space of the process so it can be free'd
+        paging::switch_page_table(self.kernel_p4);
+
+        let pid = self.get_current_process().pid;
+        self.terminate_group(pid, exit_status);
+    }
+
+    /*
+        Ends only the current thread whilst the rest of the process keeps running
+        The whole process exits if this is its first or last thread
+    */
+    pub fn remove_thread(&mut self, exit_status: usize) {
+        let current_thread = self.get_current_process();
+
+        if !current_thread.is_thread() || current_thread.thread_count() == 1 {
+            self.remove_process(exit_status);
+            return;
+        }
+
+        let pid = current_thread.pid;
+        self.terminate(pid, exit_status);
+    }
+
+    /*
+        Ends every other thread of the current process (eg before exec replaces the program)
+        Threads running on other CPUs stop on their next tick
+        Returns false if the current thread isn't the first thread of the process
+    */
+    pub fn terminate_other_threads(&mut self) -> bool {
+        let current_process = self.get_current_process();
+
+        if current_process.is_thread() {
+            return false;
+        }
+
+        let tgid = current_process.tgid;
+        self.terminate_threads(tgid);
+
+        true
+    }
+
+    // Threads are never zombies so they are simply marked for termination
+    fn terminate_threads(&mut self, tgid: usize) {
+        for i in 0..self.processes.length() {
+            let process = self.process(i);
+
+            if process.tgid == tgid
+                && process.is_thread()
+                && process.state != ProcessState::Terminated
+            {
+                process.state = ProcessState::Terminated;
             }
         }
     }
 
-    pub fn receive_message(&mut self) -> Option<*mut Message> {
-        let process = self.tasks.peek();
+    /*
+        Ends every thread of the process which the given thread belongs to
+        The first thread reports the exit status to the parent
+    */
+    fn terminate_group(&mut self, pid: usize, exit_status: usize) {
+        let tgid = self.find(pid).expect("Process not found").tgid;
 
-        if let Some(message) = process.messages.dequeue() {
-            let message_addr = kmalloc(size_of::<Message>()) as *mut Message;
+        self.terminate_threads(tgid);
 
-            unsafe {
-                core::ptr::write(message_addr, message);
+        if let Some(leader) = self.find(tgid) {
+            if leader.state != ProcessState::Zombie && leader.state != ProcessState::Terminated {
+                self.terminate(tgid, exit_status);
             }
+        }
+    }
 
-            return Some(message_addr as *mut Message);
+    /*
+        Processes with a parent become zombies until the parent collects the exit status with waitpid
+        Otherwise the process is marked for termination
+    */
+    fn terminate(&mut self, pid: usize, exit_status: usize) {
+        self.orphan_children(pid);
+
+        let process = self.find(pid).expect("Process not found");
+
+        process.exit_status = exit_status;
+        let parent_pid = process.parent_pid;
+
+        match self.find(parent_pid) {
+            Some(parent) => {
+                process.state = ProcessState::Zombie;
+
+                self.wake(CHILD_WAITERS.lock(), parent_pid);
+                CHILD_WAITERS.free();
+
+                self.send_signal(parent_pid, SIGCHLD);
+            }
+            None => {
+                process.state = ProcessState::Terminated;
+            }
         }
+    }
 
-        process.block();
-        None
+    // Children of an exiting process lose their parent and any which already exited are left for the scheduler to reap
+    fn orphan_children(&mut self, pid: usize) {
+        for i in 0..self.processes.length() {
+            let child = self.process(i);
+
+            if child.parent_pid != pid {
+                continue;
+            }
+
+            child.parent_pid = 0;
+
+            if child.state == ProcessState::Zombie {
+                child.state = ProcessState::Terminated;
+            }
+        }
     }
 
-    fn is_all_process_blocked(&self) -> bool {
-        for process in self.tasks.nodes.iter() {
-            if process.value.state == ProcessState::Running {
-                return false;
+    /*
+        Looks for a child of the current process which has exited and collects its exit status
+        The child itself is released by the scheduler as it may still be switching out on another CPU
+        A pid of -1 matches any child
+    */
+    pub fn wait_for_child(&mut self, pid: isize) -> WaitResult {
+        let parent = self.get_current_process();
+        let parent_pid = parent.tgid;
+        let mut has_children = false;
+
+        for index in 0..self.processes.length() {
+            let child = self.process(index);
+
+            if child.parent_pid != parent_pid || (pid != -1 && child.pid != pid as usize) {
+                continue;
+            }
+
+            if child.state == ProcessState::Zombie {
+                parent.cpu_times.add_child(&child.cpu_times);
+
+                child.parent_pid = 0;
+                child.state = ProcessState::Terminated;
+
+                return WaitResult::Exited(child.pid, child.exit_status);
             }
+
+            has_children = true;
         }
-        return true;
+
+        either!(has_children => WaitResult::Running; WaitResult::NoChildren)
     }
 
-    pub fn switch_process(&mut self, old_rsp: usize) -> usize {
-        if self.is_from_kernel {
-            self.is_from_kernel = false;
-            unsafe {
-                TSS.privilege_stack_table[0] = old_rsp;
+    /*
+        Marks a signal as pending for a process which acts upon it the next time it is scheduled
+        Blocked processes are woken to handle the signal and SIGKILL or SIGCONT resume stopped ones
+        A signal of 0 only checks the process exists
+    */
+    pub fn send_signal(&mut self, pid: usize, signal: usize) -> bool {
+        let process = match self.find(pid) {
+            Some(process) => process,
+            None => return false,
+        };
+
+        // Kernel threads can't be interrupted
+        if process.is_kernel_thread() {
+            return false;
+        }
+
+        if signal == 0
+            || process.state == ProcessState::Zombie
+            || process.state == ProcessState::Terminated
+        {
+            return true;
+        }
+
+        if signal == SIGCONT {
+            process.pending_signals = signal::clear_stop_signals(process.pending_signals);
+        } else if signal::is_stop_signal(signal) {
+            process.pending_signals &= !signal::mask(SIGCONT);
+        }
+
+        if (signal == SIGCONT || signal == SIGKILL) && process.state == ProcessState::Stopped {
+            process.unblock();
+        }
+
+        // Ignored signals are discarded straight away
+        let action = process.signal_actions()[signal];
+        let is_ignored = action.handler == SIG_IGN
+            || (action.handler == SIG_DFL
+                && (signal::default_action(signal) == DefaultAction::Ignore
+                    || signal::default_action(signal) == DefaultAction::Continue));
+
+        if is_ignored {
+            return true;
+        }
+
+        process.pending_signals |= signal::mask(signal);
+
+        if process.state == ProcessState::Blocked && process.has_deliverable_signal() {
+            process.unblock();
+        }
+
+        true
+    }
+
+    /*
+        Sends a signal caused by the process itself (eg a fault) which can't be blocked or ignored
+        Otherwise the process would return to the faulting instruction
+    */
+    pub fn force_signal(&mut self, pid: usize, signal: usize) {
+        if let Some(process) = self.find(pid) {
+            process.blocked_signals &= !signal::mask(signal);
+
+            if process.signal_actions()[signal].handler == SIG_IGN {
+                process.signal_actions()[signal].handler = SIG_DFL;
             }
+        }
+
+        self.send_signal(pid, signal);
+    }
+
+    /*
+        Sends the signal raised by a fault to the current process
+        Unless the process has a handler for it the process is terminated straight away
+    */
+    pub fn fault_current_process(&mut self, signal: usize) {
+        let current_process = self.get_current_process();
+        let pid = current_process.pid;
+
+        if current_process.signal_actions()[signal].handler > SIG_IGN {
+            self.force_signal(pid, signal);
         } else {
-            if let Some(mut process) = self.tasks.dequeue() {
-                // If the process is not terminated, then remove
-                if process.state != ProcessState::Terminated {
-                    let converted_priority = ProcessPriority::convert(process.priority);
-                    process.rsp = old_rsp as *const usize;
-                    self.tasks.enqueue(process, converted_priority);
+            self.remove_process(signal);
+        }
+    }
+
+    /*
+        Acts upon the pending signals of the process which is about to be resumed
+        Returns false if a signal stopped or terminated it
+    */
+    fn handle_signals(&mut self, process: &mut Process) -> bool {
+        loop {
+            let signal = match process.take_pending_signal() {
+                Some(signal) => signal,
+                None => return true,
+            };
+
+            let action = process.signal_actions()[signal];
+
+            match action.handler {
+                SIG_IGN => continue,
+                SIG_DFL => match signal::default_action(signal) {
+                    DefaultAction::Terminate => {
+                        self.terminate_group(process.pid, signal);
+                        return false;
+                    }
+                    DefaultAction::Stop => {
+                        process.state = ProcessState::Stopped;
+                        return false;
+                    }
+                    DefaultAction::Ignore | DefaultAction::Continue => continue,
+                },
+                _ => {
+                    // Handlers can only be entered from a frame which returns to user mode
+                    if unsafe { (*process.saved_registers()).cs } & 0x3 != 0x3 {
+                        process.pending_signals |= signal::mask(signal);
+                        return true;
+                    }
+
+                    if !process.enter_signal_handler(signal) {
+                        self.terminate_group(process.pid, SIGSEGV);
+                        return false;
+                    }
+
+                    return true;
                 }
             }
         }
+    }
 
-        if self.tasks.is_empty() {
-            self.is_from_kernel = true;
-            return old_rsp;
-        } else if self.is_all_process_blocked() {
-            self.is_from_kernel = true;
+    // Faults whilst the kernel runs on its own (no process) are never resolved
+    pub fn handle_page_fault(&mut self, address: usize, is_present: bool, is_write: bool) -> bool {
+        match self.cpus[smp::current_cpu()].current {
+            Some(process) => unsafe { (*process).handle_page_fault(address, is_present, is_write) },
+            None => false,
+        }
+    }
+
+    // Returns false if the receiver doesn't exist or has exited (its messages would never be freed)
+    pub fn send_message(&mut self, message: *mut Message) -> bool {
+        let message_ref = unsafe { &mut *message };
+
+        message_ref.sender_pid = self.get_current_process().pid;
+
+        match self.find(message_ref.receiver_pid) {
+            Some(receiver_process)
+                if !matches!(receiver_process.state, ProcessState::Zombie | ProcessState::Terminated) =>
+            {
+                receiver_process.messages.enqueue(*message_ref);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // The receiver takes ownership of the payload
+    pub fn receive_message(&mut self) -> Option<Message> {
+        self.get_current_process().messages.dequeue()
+    }
+
+    // Wakes every process sleeping on a queue
+    pub fn wake_all(&mut self, queue: &mut WaitQueue) {
+        while let Some(pid) = queue.pop() {
+            self.unblock_sleeper(pid);
+        }
+    }
+
+    /*
+        Wakes a single process if it is sleeping on a queue
+        Returns false if it wasn't on the queue or no longer exists
+    */
+    pub fn wake(&mut self, queue: &mut WaitQueue, pid: usize) -> bool {
+        queue.remove(pid) && self.unblock_sleeper(pid)
+    }
+
+    // Wakes the process which has waited longest on a queue (skipping any which have since exited)
+    pub fn wake_one(&mut self, queue: &mut WaitQueue) -> bool {
+        while let Some(pid) = queue.pop() {
+            if self.unblock_sleeper(pid) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    // The process is woken from the queue on the first tick at or after wake_tick
+    pub fn add_timer(&mut self, pid: usize, wake_tick: usize) {
+        self.timers.enqueue((wake_tick, pid), wake_tick);
+    }
+
+    // Called on every tick to wake the processes whose timers have expired
+    pub fn wake_expired_timers(&mut self, current_tick: usize, queue: &mut WaitQueue) {
+        while !self.timers.is_empty() && self.timers.peek().0 <= current_tick {
+            let (_, pid) = self.timers.dequeue().expect("Priority Queue is empty");
+            self.wake(queue, pid);
+        }
+    }
+
+    /*
+        Stopped processes stay stopped until SIGCONT even if what they waited for happened
+        Returns false if the process no longer exists
+    */
+    fn unblock_sleeper(&mut self, pid: usize) -> bool {
+        let process = match self.find(pid) {
+            Some(process) => process,
+            None => return false,
+        };
+
+        if process.state == ProcessState::Blocked {
+            process.unblock();
+        }
+
+        true
+    }
+
+    /*
+        Called on every tick of the timer with the frame of whatever it interrupted
+        The current process keeps running until its time slice is used up (or it can no longer run)
+    */
+    pub fn timer_tick(&mut self, old_rsp: usize) -> usize {
+        let cpu = smp::current_cpu();
+
+        // Every CPU is ticked at the same time so only the bootstrap processor counts towards the boost
+        if cpu == 0 {
+            self.ticks_since_boost += 1;
+        }
+
+        let current_process = match self.cpus[cpu].current {
+            Some(process) => unsafe { &mut *process },
+            None => return self.switch_process(old_rsp),
+        };
+
+        // The frame starts with CR3 which is followed by the registers pushed for the interrupt
+        let registers = unsafe { &*((old_rsp as *const usize).offset(1) as *const SyscallStackFrame) };
+        let is_user = registers.cs & 0x3 == 0x3;
+
+        let has_time_left = current_process.charge_tick(is_user);
+
+        if has_time_left
+            && current_process.state == ProcessState::Running
+            && self.ticks_since_boost < BOOST_INTERVAL
+        {
             return old_rsp;
-        } else {
-            let next_process = self.tasks.peek();
+        }
+
+        self.switch_process(old_rsp)
+    }
+
+    // Takes a process out of whichever run queue it is in
+    fn unqueue(&mut self, process: *mut Process) {
+        for cpu in self.cpus.iter_mut() {
+            if let Some(index) = cpu.queue.nodes.find_where(&find_queued, process as usize) {
+                cpu.queue.remove(index);
+                return;
+            }
+        }
+    }
+
+    fn release(&mut self, index: usize) {
+        let process = self.processes.remove(index).expect("Process not found");
+        self.unqueue(process);
+
+        unsafe {
+            (*process).destroy();
+        }
+
+        kfree(process as *mut usize);
+    }
+
+    /*
+        Cleans up processes and threads which were ended whilst running elsewhere
+        Processes are left until no CPU runs on them anymore (including the one being switched out)
+    */
+    fn reap_terminated(&mut self) {
+        let mut index = 0;
+
+        while index < self.processes.length() {
+            let process = *self.processes.get_mut(index).expect("Process not found");
+            let state = unsafe { (*process).state };
+
+            if self.is_running(process) {
+                index += 1;
+                continue;
+            }
+
+            match state {
+                ProcessState::Terminated => {
+                    self.release(index);
+                    continue;
+                }
+                // Zombies only keep their exit status (destroying one twice does nothing)
+                ProcessState::Zombie => {
+                    self.unqueue(process);
+
+                    unsafe {
+                        (*process).destroy();
+                    }
+                }
+                _ => {}
+            }
+
+            index += 1;
+        }
+    }
+
+    // Moves every process back to the top level so processes stuck in the lower levels get to run
+    fn boost_priorities(&mut self) {
+        self.ticks_since_boost = 0;
+
+        for i in 0..self.processes.length() {
+            self.process(i).boost();
+        }
+
+        for cpu in self.cpus.iter_mut() {
+            cpu.queue
+                .reprioritise(|&process| unsafe { (*process).schedule_priority() });
+        }
+    }
+
+    // Index of the process which should run next on a CPU within the run queue of the given CPU
+    fn find_runnable(&self, queue_cpu: usize, cpu: usize) -> Option<usize> {
+        let nodes = &self.cpus[queue_cpu].queue.nodes;
+        let mut best: Option<usize> = None;
+
+        for (index, node) in nodes.iter().enumerate() {
+            let is_runnable = unsafe { (*node.value).state } == ProcessState::Running
+                && !self.is_running_elsewhere(node.value, cpu);
+
+            if !is_runnable {
+                continue;
+            }
+
+            let is_better =
+                best.map_or(true, |best| node > nodes.get_mut(best).expect("Process not found"));
+
+            if is_better {
+                best = Some(index);
+            }
+        }
+
+        best
+    }
+
+    // Takes a process from the run queue of the CPU or else from another CPU which has one waiting
+    fn pick_next(&mut self, cpu: usize) -> Option<*mut Process> {
+        if let Some(index) = self.find_runnable(cpu, cpu) {
+            return self.cpus[cpu].queue.remove(index);
+        }
+
+        for other in (0..smp::cpu_count()).filter(|&other| other != cpu) {
+            if let Some(index) = self.find_runnable(other, cpu) {
+                return self.cpus[other].queue.remove(index);
+            }
+        }
+
+        None
+    }
+
+    /*
+        Saves the frame of the interrupted process and picks the next one to run on this CPU
+        RSP0 is pointed at the kernel stack of the new process so its next interrupt or syscall lands on its own stack
+    */
+    pub fn switch_process(&mut self, old_rsp: usize) -> usize {
+        let cpu = smp::current_cpu();
+
+        // The stack of a process which exited can be released now that the switch has moved off it
+        KERNEL_STACKS.lock().release_retired();
+        KERNEL_STACKS.free();
+
+        // The old process can't run elsewhere until this CPU is off its kernel stack
+        self.cpus[cpu].previous = self.cpus[cpu].current.take();
+
+        match self.cpus[cpu].previous {
+            None => self.cpus[cpu].idle_rsp = old_rsp,
+            Some(process) => {
+                let process_ref = unsafe { &mut *process };
+
+                // Processes which exited are left for reap_terminated
+                if process_ref.state != ProcessState::Zombie
+                    && process_ref.state != ProcessState::Terminated
+                {
+                    process_ref.rsp = old_rsp as *const usize;
+                    process_ref.update_level();
+                    self.schedule(process, cpu);
+                }
+            }
+        }
+
+        self.reap_terminated();
+
+        if cpu == 0 && self.ticks_since_boost >= BOOST_INTERVAL {
+            self.boost_priorities();
+        }
+
+        while let Some(process) = self.pick_next(cpu) {
+            let next_process = unsafe { &mut *process };
+
+            // Pick another process if a signal stopped or terminated this one
+            if !self.handle_signals(next_process) {
+                if next_process.state == ProcessState::Stopped {
+                    self.schedule(process, cpu);
+                }
+
+                continue;
+            }
+
+            next_process.start_time_slice();
+            gdt::set_kernel_stack(next_process.kernel_stack_top());
+            msr::write_msr(msr::FS_BASE, next_process.fs_base() as u64);
+            tlb::set_active_p4(next_process.p4());
+
+            self.cpus[cpu].current = Some(process);
 
             return next_process.rsp as usize;
         }
+
+        // Return to the kernel until the next tick as nothing can run
+        tlb::set_active_p4(self.kernel_p4);
+        self.cpus[cpu].idle_rsp
     }
 }
@@ -0,0 +1,101 @@
+/*
+    Resources which every thread of a process shares (address space, memory areas, open files and signal actions)
+    They live on the kernel heap and are counted so they are only free'd once the last thread using them is destroyed
+    Kernel threads share the address space of the kernel which is never free'd
+*/
+
+use core::mem::size_of;
+
+use crate::ds::hashmap::HashMap;
+use crate::fs::vfs::File;
+use crate::memory::allocator::{kfree, kmalloc};
+use crate::memory::paging;
+use crate::memory::vma::VmaList;
+
+use super::signal::{SignalAction, SIGNAL_COUNT};
+
+pub struct Resources {
+    pub p4: usize,
+    pub vmas: VmaList,
+    pub fdt: HashMap<*mut File>,
+    pub signal_actions: [SignalAction; SIGNAL_COUNT],
+    pub heap_start: usize,
+    pub program_break: usize,
+    owns_address_space: bool, // False for kernel threads
+    threads: usize,           // Threads which refer to the resources
+}
+
+impl Resources {
+    pub fn create(p4: usize, vmas: VmaList, heap_start: usize) -> *mut Resources {
+        Resources::allocate(Resources {
+            p4,
+            vmas,
+            fdt: HashMap::<*mut File>::new(),
+            signal_actions: [SignalAction::new(); SIGNAL_COUNT],
+            heap_start,
+            program_break: heap_start,
+            owns_address_space: true,
+            threads: 1,
+        })
+    }
+
+    // Kernel threads have no user memory or files and run within the address space of the kernel
+    pub fn kernel(kernel_p4: usize) -> *mut Resources {
+        Resources::allocate(Resources {
+            p4: kernel_p4,
+            vmas: VmaList::new(),
+            fdt: HashMap::<*mut File>::new(),
+            signal_actions: [SignalAction::new(); SIGNAL_COUNT],
+            heap_start: 0,
+            program_break: 0,
+            owns_address_space: false,
+            threads: 1,
+        })
+    }
+
+    fn allocate(resources: Resources) -> *mut Resources {
+        let address = kmalloc(size_of::<Resources>()) as *mut Resources;
+
+        unsafe {
+            core::ptr::write(address, resources);
+        }
+
+        address
+    }
+
+    // Called when a new thread starts using the resources
+    pub fn acquire(&mut self) {
+        self.threads += 1;
+    }
+
+    /*
+        Called when a thread using the resources is destroyed
+        The last thread frees everything (which must not happen whilst its address space is active)
+    */
+    pub fn release(resources: *mut Resources) {
+        let resources_ref = unsafe { &mut *resources };
+
+        resources_ref.threads -= 1;
+
+        if resources_ref.threads > 0 {
+            return;
+        }
+
+        if resources_ref.owns_address_space {
+            paging::free_address_space(resources_ref.p4);
+        }
+
+        resources_ref.fdt.free();
+        resources_ref.vmas.free();
+
+        kfree(resources as *mut usize);
+    }
+
+    pub fn thread_count(&self) -> usize {
+        self.threads
+    }
+
+    pub fn is_kernel(&self) -> bool {
+        !self.owns_address_space
+    }
+}
@@ -0,0 +1,75 @@
+/*
+    Scheduling policy used by the process manager (multilevel feedback queue)
+    Every process sits in a level and lower levels always run first with a short time slice
+    A process which uses up its whole time slice is moved down a level as it is likely busy with the CPU
+    A process which sleeps before its time slice ends (eg waiting for a message or event) is moved up a level so interactive programs respond quickly
+    Every process is moved back to the top level at a regular interval so nothing waits forever
+    The nice value orders processes within a level and scales how long their time slices are
+*/
+
+pub const LEVEL_COUNT: usize = 4;
+
+// Ticks a process runs for in each level before it is preempted
+const TIME_SLICES: [usize; LEVEL_COUNT] = [2, 4, 8, 16];
+
+// Ticks between moving every process back to the top level
+pub const BOOST_INTERVAL: usize = 100;
+
+// Same range as Unix where a lower value means a higher priority
+pub const NICE_MIN: isize = -20;
+pub const NICE_MAX: isize = 19;
+pub const KERNEL_NICE: isize = -10;
+const NICE_RANGE: usize = (NICE_MAX - NICE_MIN + 1) as usize;
+
+/*
+    CPU time used by a process and its reaped children measured in ticks
+    Same layout as struct tms which is filled in by times
+*/
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct CpuTimes {
+    pub user: usize,
+    pub system: usize,
+    pub children_user: usize,
+    pub children_system: usize,
+}
+
+impl CpuTimes {
+    pub const fn new() -> CpuTimes {
+        CpuTimes {
+            user: 0,
+            system: 0,
+            children_user: 0,
+            children_system: 0,
+        }
+    }
+
+    // Called when a child is reaped which includes the children it reaped itself
+    pub fn add_child(&mut self, child: &CpuTimes) {
+        self.children_user += child.user + child.children_user;
+        self.children_system += child.system + child.children_system;
+    }
+}
+
+pub fn clamp_nice(nice: isize) -> isize {
+    nice.clamp(NICE_MIN, NICE_MAX)
+}
+
+// Priority of the process within the run queue where the lowest value runs first
+pub fn queue_priority(level: usize, nice: isize) -> usize {
+    level * NICE_RANGE + (nice - NICE_MIN) as usize
+}
+
+// A nice value of 0 gets the normal time slice which is doubled at -20 and shrinks towards a single tick at 19
+pub fn time_slice(level: usize, nice: isize) -> usize {
+    let scale = (NICE_MAX + 1 - nice) as usize;
+    (TIME_SLICES[level] * scale / (NICE_MAX + 1) as usize).max(1)
+}
+
+pub fn lower_level(level: usize) -> usize {
+    (level + 1).min(LEVEL_COUNT - 1)
+}
+
+pub fn raise_level(level: usize) -> usize {
+    level.saturating_sub(1)
+}
@@ -0,0 +1,129 @@
+/*
+    Signals are software interrupts which notify a process that an event has occured (eg a child exiting or a bad memory access)
+    A process can catch a signal with its own handler, ignore it or leave the kernel to carry out the default action
+    Signals are only marked as pending when sent and are acted upon just before the process is next scheduled
+    Numbering follows Linux on x86_64
+*/
+
+use crate::interrupts::SyscallStackFrame;
+
+pub const SIGNAL_COUNT: usize = 32;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+// Special values for the handler of an action
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+// Flags of an action
+pub const SA_NODEFER: usize = 0x40000000; // Don't block the signal whilst its handler runs
+pub const SA_RESETHAND: usize = 0x80000000; // Restore the default action once the handler is entered
+
+// Operations for sigprocmask
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+// SIGKILL and SIGSTOP can never be caught, ignored or blocked
+pub const UNBLOCKABLE_SIGNALS: u64 = mask(SIGKILL) | mask(SIGSTOP);
+
+const STOP_SIGNALS: u64 = mask(SIGSTOP) | mask(SIGTSTP) | mask(SIGTTIN) | mask(SIGTTOU);
+
+// Same layout as the sigaction structure passed to the kernel by userland
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct SignalAction {
+    pub handler: usize,
+    pub flags: usize,
+    pub restorer: usize, // Where the handler returns to which must call sigreturn
+    pub mask: u64,       // Extra signals blocked whilst the handler runs
+}
+
+impl SignalAction {
+    pub const fn new() -> SignalAction {
+        SignalAction {
+            handler: SIG_DFL,
+            flags: 0,
+            restorer: 0,
+            mask: 0,
+        }
+    }
+}
+
+/*
+    Pushed onto the user stack before a handler is entered
+    sigreturn uses it to resume the process as if the signal never happened
+*/
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SignalFrame {
+    pub registers: SyscallStackFrame,
+    pub blocked_signals: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+pub const fn mask(signal: usize) -> u64 {
+    1 << signal
+}
+
+pub fn is_valid(signal: usize) -> bool {
+    signal > 0 && signal < SIGNAL_COUNT
+}
+
+pub fn is_stop_signal(signal: usize) -> bool {
+    mask(signal) & STOP_SIGNALS != 0
+}
+
+pub fn clear_stop_signals(signals: u64) -> u64 {
+    signals & !STOP_SIGNALS
+}
+
+pub fn default_action(signal: usize) -> DefaultAction {
+    match signal {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGCONT => DefaultAction::Continue,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+// Finds the signal sent to a user process which caused a CPU exception
+pub fn from_exception(exception_id: usize) -> Option<usize> {
+    match exception_id {
+        0 | 16 | 19 => Some(SIGFPE),
+        1 | 3 => Some(SIGTRAP),
+        6 => Some(SIGILL),
+        11 | 12 | 13 | 14 => Some(SIGSEGV),
+        17 => Some(SIGBUS),
+        _ => None,
+    }
+}
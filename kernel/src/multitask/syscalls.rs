@@ -4,24 +4,63 @@
     They are invoked with software interrupts and the design is inspired by postfix
 */
 
+use core::mem::size_of;
 use core::panic;
 
-use crate::fs::vfs::{File, Vfs, VFS};
+use crate::fs::cache;
+use crate::fs::vfs::{File, FileType, Stat, Vfs, VFS};
 use crate::gfx::window::{self, SimpleWindow, Window};
 use crate::gfx::wm::WM;
 use crate::gfx::FB_ADDR;
 use crate::interrupts::{InterruptStackFrame, SyscallStackFrame};
-use crate::memory::allocator::kmalloc;
+use crate::memory::allocator::{kfree, kmalloc};
 use crate::memory::page_frame_allocator::PAGE_FRAME_ALLOCATOR;
-use crate::utils::event::EVENT_MANAGER;
-use crate::utils::{bitwise, string};
+use crate::utils::event::{EVENT_MANAGER, EVENT_WAITERS};
+use crate::utils::time::{Timespec, Timeval, CLOCK, CLOCK_REALTIME};
+use crate::utils::{bitwise, msr, string};
+use crate::interrupts::pit::{NANOSECONDS_PER_TICK, PIT};
 use crate::{either, print_serial};
 
-use super::process::Message;
-use super::PROCESS_MANAGER;
+use super::arguments::Arguments;
+use super::elf::ProgramFiles;
+use super::futex::{self, FUTEX_PRIVATE_FLAG, FUTEX_WAIT, FUTEX_WAKE};
+use super::process::{Message, USER_PROCESS_START_ADDRESS};
+use super::process_manager::WaitResult;
+use super::scheduler::CpuTimes;
+use super::signal::{self, SignalAction, SIGSEGV, SIG_DFL, SIG_IGN};
+use crate::memory::paging::{self, PAGE_SIZE};
+use crate::memory::vma;
+use super::wait_queue;
+use super::{CHILD_WAITERS, MESSAGE_WAITERS, PROCESS_MANAGER};
 
 pub static mut FILE_TABLE_COUNTER: usize = 5;
 
+const WAIT_NO_HANG: usize = 0x01;
+
+// Priorities can only be set per process (not per process group or user)
+const PRIO_PROCESS: usize = 0;
+
+// Flags of clone (same as Linux)
+const CLONE_VM: usize = 0x100;
+const CLONE_FS: usize = 0x200;
+const CLONE_FILES: usize = 0x400;
+const CLONE_SIGHAND: usize = 0x800;
+const CLONE_THREAD: usize = 0x10000;
+const CLONE_SETTLS: usize = 0x80000;
+
+// Threads share everything so these must always be given together
+const CLONE_THREAD_FLAGS: usize = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD;
+
+// Codes of arch_prctl
+const ARCH_SET_FS: usize = 0x1002;
+const ARCH_GET_FS: usize = 0x1003;
+
+// Payloads are copied into the kernel until they are received so their size is capped
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+// Addresses at or above this are either kernel addresses or non canonical
+const USER_ADDRESS_LIMIT: usize = 0x8000_0000_0000;
+
 #[repr(usize)]
 enum MemoryProtectionAttributes {
     None = 0x00,
@@ -46,13 +85,25 @@ enum OpenFlags {
     Append = 0b10000000000,  // 0x400
 }
 
+// Types of file given by getdents
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+
+// Start of each entry given by getdents (same layout as struct linux_dirent) which is followed by the name and then the type
+#[repr(C, packed)]
+struct DirectoryEntry {
+    inode: u64,
+    offset: u64, // Position of the next entry
+    length: u16, // Of the whole entry including the name and type
+}
+
 #[repr(C)]
 pub struct Iovec {
     pub base: *mut u8,
     pub len: usize,
 }
 
-pub fn syscall_handler(registers: &SyscallStackFrame) -> i64 {
+pub fn syscall_handler(registers: &mut SyscallStackFrame) -> i64 {
     let syscall_id = registers.rax;
 
     // print_serial!("syscall id: {}\n", syscall_id);
@@ -64,14 +115,56 @@ pub fn syscall_handler(registers: &SyscallStackFrame) -> i64 {
         1 => write(registers.rbx, registers.rcx as *mut u8, registers.rdx),
         2 => open(registers.rbx as *mut u8, registers.rcx),
         3 => close(registers.rbx),
+        4 => stat(registers.rbx as *const u8, registers.rcx as *mut Stat),
+        5 => fstat(registers.rbx, registers.rcx as *mut Stat),
         8 => allocate_pages(registers.rbx),
         9 => lseek(registers.rdx, registers.rcx as isize, registers.rbx),
+        11 => munmap(registers.rbx, registers.rcx),
+        12 => brk(registers.rbx),
+        13 => sigaction(
+            registers.rbx,
+            registers.rcx as *const SignalAction,
+            registers.rdx as *mut SignalAction,
+        ),
+        14 => sigprocmask(registers.rbx, registers.rcx as *const u64, registers.rdx as *mut u64),
+        15 => sigreturn(registers),
         19 => free_pages(registers.rbx, registers.rcx),
-        56 => exit(),
+        35 => nanosleep(registers.rbx as *const Timespec, registers.rcx as *mut Timespec),
+        56 => exit(registers.rbx),
+        57 => fork(registers),
+        59 => execve(
+            registers.rbx as *const u8,
+            registers.rcx as *const *const u8,
+            registers.rdx as *const *const u8,
+            registers,
+        ),
+        61 => waitpid(registers.rbx as isize, registers.rcx as *mut i32, registers.rdx),
+        62 => kill(registers.rbx, registers.rcx),
+        74 => fsync(registers.rbx),
+        77 => ftruncate(registers.rbx, registers.rcx),
+        78 => getdents(registers.rbx, registers.rcx as *mut u8, registers.rdx),
+        82 => rename(registers.rbx as *const u8, registers.rcx as *const u8),
+        83 => mkdir(registers.rbx as *const u8),
+        84 => rmdir(registers.rbx as *const u8),
+        87 => unlink(registers.rbx as *const u8),
+        96 => gettimeofday(registers.rbx as *mut Timeval),
+        100 => times(registers.rbx as *mut CpuTimes),
+        140 => getpriority(registers.rbx, registers.rcx),
+        141 => setpriority(registers.rbx, registers.rcx, registers.rdx as isize),
+        158 => arch_prctl(registers.rbx, registers.rcx),
+        162 => sync(),
+        186 => gettid(),
+        202 => futex(
+            registers.rbx,
+            registers.rcx,
+            registers.rdx,
+            registers.rsi as *const Timespec,
+        ),
+        228 => clock_gettime(registers.rbx, registers.rcx as *mut Timespec),
         350 => getpid(),
         351 => isatty(registers.rbx),
         352 => send_message(registers.rbx as *mut Message),
-        353 => receive_message(),
+        353 => receive_message(registers.rbx as *mut Message, registers.rcx as *mut u8, registers.rdx as usize),
         354 => create_window(registers.rbx as *mut SimpleWindow),
         355 => get_event(),
         356 => paint_string(
@@ -81,6 +174,16 @@ pub fn syscall_handler(registers: &SyscallStackFrame) -> i64 {
             registers.rdi,
         ),
         357 => copy_to_win_buffer(registers.rbx, registers.rcx as *const u32),
+        358 => mmap(
+            registers.rbx,
+            registers.rcx,
+            registers.rdx,
+            registers.rsi,
+            registers.rdi as i32,
+            registers.r8,
+        ),
+        359 => clone(registers.rbx, registers.rcx, registers.rdx, registers),
+        360 => exit_thread(registers.rbx),
         _ => {
             panic!("Unknown syscall? {}\n", syscall_id);
             return 0;
@@ -100,16 +203,21 @@ fn read(file: usize, buffer: *mut u8, length: usize) -> i64 {
     let current_proc = PROCESS_MANAGER.lock().get_current_process();
     PROCESS_MANAGER.free();
 
-    let file = current_proc.fdt.get(file);
+    let file = current_proc.fdt().get(file);
 
     match file {
         Some(file) => {
             let file_ref = unsafe { &(*file) };
 
-            VFS.lock()
+            let result = VFS
+                .lock()
                 .read_file(file_ref, buffer, length, file_ref.get_offset());
             VFS.free();
 
+            if result.is_err() {
+                return -1;
+            }
+
             return either!(length == file_ref.size => 0; length as i64);
         }
         None => panic!("Error: File not found"),
@@ -144,16 +252,24 @@ fn write(file: usize, buffer: *mut u8, length: usize) -> i64 {
             let current_proc = PROCESS_MANAGER.lock().get_current_process();
             PROCESS_MANAGER.free();
 
-            let file = current_proc.fdt.get(file);
+            let file = current_proc.fdt().get(file);
 
             match file {
                 // Need references rather then files need to fix
                 Some(mut file) => {
                     let file_mut_ref = unsafe { &mut (*file) };
-                    VFS.lock()
-                        .write_file(file_mut_ref, buffer, length, file_mut_ref.get_offset());
+                    let result = VFS.lock().write_file(
+                        file_mut_ref,
+                        buffer,
+                        length,
+                        file_mut_ref.get_offset(),
+                    );
                     VFS.free();
 
+                    if result.is_err() {
+                        return -1;
+                    }
+
                     return either!(length == file_mut_ref.size => 0; length as i64);
                 }
                 None => panic!("Error: File not found"),
@@ -167,15 +283,27 @@ fn write(file: usize, buffer: *mut u8, length: usize) -> i64 {
 fn open(file: *const u8, flags: usize) -> i64 {
     let filepath = string::get_string_from_ptr(file);
 
-    if (bitwise::contains_bit(flags as u8, OpenFlags::Create as u8)) {
-        panic!("Not implemented")
-    }
-
     print_serial!("open\n");
 
+    // Missing files are only created if asked to
+    let should_create = bitwise::contains_bit(flags as u8, OpenFlags::Create as u8);
     let file = VFS.lock().open_addr(filepath);
     VFS.free();
 
+    let file = match file {
+        Some(file) => file,
+        None if should_create => {
+            let file = VFS.lock().create_file(filepath, FileType::File);
+            VFS.free();
+
+            match file {
+                Some(file) => file,
+                None => return -1,
+            }
+        }
+        None => return -1,
+    };
+
     let current_proc = PROCESS_MANAGER.lock().get_current_process();
     PROCESS_MANAGER.free();
 
@@ -184,7 +312,7 @@ fn open(file: *const u8, flags: usize) -> i64 {
         print_serial!("file: {:?}\n", file_mut_ref);
 
         FILE_TABLE_COUNTER += 1;
-        current_proc.fdt.set(FILE_TABLE_COUNTER, file);
+        current_proc.fdt().set(FILE_TABLE_COUNTER, file);
 
         // print_serial!("Opening with {}\n", FILE_TABLE_COUNTER);
 
@@ -196,7 +324,7 @@ fn close(file: usize) -> i64 {
     let current_proc = PROCESS_MANAGER.lock().get_current_process();
     PROCESS_MANAGER.free();
 
-    current_proc.fdt.delete(file);
+    current_proc.fdt().delete(file);
     0
 }
 
@@ -218,7 +346,7 @@ fn lseek(fd: usize, new_offset: isize, whence: usize) -> i64 {
     let current_proc = PROCESS_MANAGER.lock().get_current_process();
     PROCESS_MANAGER.free();
 
-    let file = current_proc.fdt.get(fd);
+    let file = current_proc.fdt().get(fd);
 
     match file {
         // Need references rather then files need to fix
@@ -244,33 +372,263 @@ fn lseek(fd: usize, new_offset: isize, whence: usize) -> i64 {
     new_offset as i64
 }
 
+// Writes back the cached data of a file to the disk
+fn fsync(fd: usize) -> i64 {
+    let current_proc = PROCESS_MANAGER.lock().get_current_process();
+    PROCESS_MANAGER.free();
+
+    let file = match current_proc.fdt().get(fd) {
+        Some(file) => file,
+        None => return -1,
+    };
+
+    let result = VFS.lock().sync_file(unsafe { &*file });
+    VFS.free();
+
+    either!(result.is_ok() => 0; -1)
+}
+
+// Writes back every cached change to the disk
+fn sync() -> i64 {
+    either!(cache::sync().is_ok() => 0; -1)
+}
+
+fn stat(filepath: *const u8, status: *mut Stat) -> i64 {
+    if filepath.is_null() || status.is_null() {
+        return -1;
+    }
+
+    let filepath = string::get_string_from_ptr(filepath);
+
+    let file = VFS.lock().find_file(filepath);
+    VFS.free();
+
+    let file = match file {
+        Some(file) => file,
+        None => return -1,
+    };
+
+    let result = VFS.lock().stat(&file);
+    VFS.free();
+
+    match result {
+        Ok(file_status) => {
+            unsafe {
+                *status = file_status;
+            }
+            0
+        }
+        Err(_) => -1,
+    }
+}
+
+fn fstat(fd: usize, status: *mut Stat) -> i64 {
+    if status.is_null() {
+        return -1;
+    }
+
+    let current_proc = PROCESS_MANAGER.lock().get_current_process();
+    PROCESS_MANAGER.free();
+
+    let file = match current_proc.fdt().get(fd) {
+        Some(file) => file,
+        None => return -1,
+    };
+
+    let result = VFS.lock().stat(unsafe { &*file });
+    VFS.free();
+
+    match result {
+        Ok(file_status) => {
+            unsafe {
+                *status = file_status;
+            }
+            0
+        }
+        Err(_) => -1,
+    }
+}
+
+// Changes the size of a file, with the clusters past the end freed or new space filled with zeros
+fn ftruncate(fd: usize, length: usize) -> i64 {
+    let current_proc = PROCESS_MANAGER.lock().get_current_process();
+    PROCESS_MANAGER.free();
+
+    let file = match current_proc.fdt().get(fd) {
+        Some(file) => file,
+        None => return -1,
+    };
+
+    let file_mut_ref = unsafe { &mut (*file) };
+    if file_mut_ref.get_type() == FileType::Directory {
+        return -1;
+    }
+
+    let result = VFS.lock().truncate_file(file_mut_ref, length);
+    VFS.free();
+
+    either!(result.is_ok() => 0; -1)
+}
+
+/*
+    Fills the buffer with as many entries of an open directory as fit and returns the number of bytes used (0 once every entry has been given)
+    The offset of the directory is the position of the next entry to give
+*/
+fn getdents(fd: usize, buffer: *mut u8, length: usize) -> i64 {
+    if buffer.is_null() {
+        return -1;
+    }
+
+    let current_proc = PROCESS_MANAGER.lock().get_current_process();
+    PROCESS_MANAGER.free();
+
+    let directory = match current_proc.fdt().get(fd) {
+        Some(directory) => directory,
+        None => return -1,
+    };
+
+    let directory_mut_ref = unsafe { &mut (*directory) };
+    if directory_mut_ref.get_type() != FileType::Directory {
+        return -1;
+    }
+
+    let mut bytes_used = 0;
+    loop {
+        let index = directory_mut_ref.get_offset();
+
+        let child = VFS.lock().get_child(directory, index);
+        VFS.free();
+
+        let child = match child {
+            Some(child) => child,
+            None => break,
+        };
+
+        // Entries are 8 byte aligned and hold the name with a null terminator followed by the type
+        let name = child.get_name();
+        let entry_length = (size_of::<DirectoryEntry>() + name.len() + 2).next_multiple_of(8);
+
+        if bytes_used + entry_length > length {
+            // The buffer must hold at least one entry
+            if bytes_used == 0 {
+                return -1;
+            }
+
+            break;
+        }
+
+        unsafe {
+            let entry = buffer.add(bytes_used);
+            core::ptr::write_bytes(entry, 0, entry_length);
+            core::ptr::write_unaligned(
+                entry as *mut DirectoryEntry,
+                DirectoryEntry {
+                    inode: (index + 1) as u64,
+                    offset: (index + 1) as u64,
+                    length: entry_length as u16,
+                },
+            );
+
+            let name_ptr = entry.add(size_of::<DirectoryEntry>());
+            core::ptr::copy_nonoverlapping(name.as_ptr(), name_ptr, name.len());
+
+            *entry.add(entry_length - 1) =
+                either!(child.get_type() == FileType::Directory => DT_DIR; DT_REG);
+        }
+
+        bytes_used += entry_length;
+        directory_mut_ref.set_offset(index + 1);
+    }
+
+    bytes_used as i64
+}
+
+fn rename(old_filepath: *const u8, new_filepath: *const u8) -> i64 {
+    if old_filepath.is_null() || new_filepath.is_null() {
+        return -1;
+    }
+
+    let old_filepath = string::get_string_from_ptr(old_filepath);
+    let new_filepath = string::get_string_from_ptr(new_filepath);
+
+    let is_renamed = VFS.lock().rename(old_filepath, new_filepath);
+    VFS.free();
+
+    either!(is_renamed => 0; -1)
+}
+
+// Permissions are not kept by FAT so no mode is taken
+fn mkdir(filepath: *const u8) -> i64 {
+    if filepath.is_null() {
+        return -1;
+    }
+
+    let filepath = string::get_string_from_ptr(filepath);
+
+    let directory = VFS.lock().create_file(filepath, FileType::Directory);
+    VFS.free();
+
+    either!(directory.is_some() => 0; -1)
+}
+
+// The directory must be empty
+fn rmdir(filepath: *const u8) -> i64 {
+    if filepath.is_null() {
+        return -1;
+    }
+
+    let filepath = string::get_string_from_ptr(filepath);
+
+    let is_deleted = VFS.lock().delete_file(filepath, FileType::Directory);
+    VFS.free();
+
+    either!(is_deleted => 0; -1)
+}
+
+fn unlink(filepath: *const u8) -> i64 {
+    if filepath.is_null() {
+        return -1;
+    }
+
+    let filepath = string::get_string_from_ptr(filepath);
+
+    let is_deleted = VFS.lock().delete_file(filepath, FileType::File);
+    VFS.free();
+
+    either!(is_deleted => 0; -1)
+}
+
+/*
+    Reserves a range of anonymous memory which is mapped page by page as it is touched
+    Mapping files is not supported
+*/
 fn mmap(addr: usize, length: usize, prot: usize, flags: usize, fd: i32, offset: usize) -> i64 {
-    assert!(addr == 0, "Error: Cannot set addr");
-    assert!(fd == -1, "Error: Cannot currently set fd");
-
-    assert!(
-        prot == (MemoryProtectionAttributes::Read as usize)
-            || prot
-                == (MemoryProtectionAttributes::Read as usize
-                    | MemoryProtectionAttributes::Write as usize)
-            || prot == (MemoryProtectionAttributes::None as usize)
-    );
+    if flags & (MemoryMappingFlags::MapAnonymous as usize) == 0 || fd != -1 {
+        return -1;
+    }
 
-    assert!(
-        flags
-            == (MemoryMappingFlags::MapAnonymous as usize
-                | MemoryMappingFlags::MapPrivate as usize)
-    );
+    let current_proc = PROCESS_MANAGER.lock().get_current_process();
+    PROCESS_MANAGER.free();
 
-    print_serial!("{} {} {} {} {} {}\n", addr, length, prot, flags, fd, offset);
+    match current_proc.map_anonymous(addr, length, prot) {
+        Some(address) => address as i64,
+        None => -1,
+    }
+}
 
-    let adddr = kmalloc(length);
+fn munmap(addr: usize, length: usize) -> i64 {
+    let current_proc = PROCESS_MANAGER.lock().get_current_process();
+    PROCESS_MANAGER.free();
 
-    return adddr as i64;
+    either!(current_proc.unmap(addr, length) => 0; -1)
 }
 
+// Returns the new program break (or the current one if it could not be moved)
 fn brk(addr: usize) -> i64 {
-    -1
+    let current_proc = PROCESS_MANAGER.lock().get_current_process();
+    PROCESS_MANAGER.free();
+
+    current_proc.set_program_break(addr) as i64
 }
 
 fn ioctl(cmd: usize, arg: usize) -> i64 {
@@ -301,13 +659,308 @@ fn writev(fd: usize, iovec: *const Iovec, count: usize) -> i64 {
     total_length as i64
 }
 
-fn exit() -> i64 {
-    PROCESS_MANAGER.lock().remove_process();
+// Ends every thread of the current process
+fn exit(status: usize) -> i64 {
+    // Same layout as WEXITSTATUS expects
+    PROCESS_MANAGER.lock().remove_process((status & 0xFF) << 8);
+    PROCESS_MANAGER.free();
+
+    // The process is never scheduled again so switch away straight away
+    loop {
+        wait_queue::yield_process();
+    }
+}
+
+// Ends only the current thread (which ends the whole process if it is the first or last thread)
+fn exit_thread(status: usize) -> i64 {
+    PROCESS_MANAGER.lock().remove_thread((status & 0xFF) << 8);
+    PROCESS_MANAGER.free();
+
+    loop {
+        wait_queue::yield_process();
+    }
+}
+
+/*
+    Starts a new thread within the current process on the given user stack and returns its pid
+    The thread returns from the syscall with 0 like fork but shares the address space, files and signal actions
+    Only the flags which create a thread are supported where CLONE_SETTLS gives the thread its own FS base
+*/
+fn clone(flags: usize, stack: usize, tls: usize, registers: &SyscallStackFrame) -> i64 {
+    if flags & CLONE_THREAD_FLAGS != CLONE_THREAD_FLAGS
+        || flags & !(CLONE_THREAD_FLAGS | CLONE_SETTLS) != 0
+    {
+        return -1;
+    }
+
+    if stack == 0 || stack >= USER_ADDRESS_LIMIT || tls >= USER_ADDRESS_LIMIT {
+        return -1;
+    }
+
+    let tls = either!(flags & CLONE_SETTLS != 0 => Some(tls); None);
+
+    let pid = PROCESS_MANAGER.lock().create_thread(registers, stack, tls);
+    PROCESS_MANAGER.free();
+
+    pid as i64
+}
+
+// Sets or gets the FS base of the current thread which libc uses to find its thread local storage
+fn arch_prctl(code: usize, address: usize) -> i64 {
+    let current_thread = PROCESS_MANAGER.lock().get_current_process();
+    PROCESS_MANAGER.free();
+
+    match code {
+        ARCH_SET_FS => {
+            if address >= USER_ADDRESS_LIMIT {
+                return -1;
+            }
+
+            current_thread.set_fs_base(address);
+            msr::write_msr(msr::FS_BASE, address as u64);
+            0
+        }
+        ARCH_GET_FS => {
+            // Written through the thread's page tables so it can only land in user memory
+            let end = address.checked_add(size_of::<usize>());
+            if address < USER_PROCESS_START_ADDRESS
+                || end.map_or(true, |end| end > USER_ADDRESS_LIMIT)
+            {
+                return -1;
+            }
+
+            let fs_base = current_thread.fs_base();
+            let is_copied = paging::copy_to_address_space(
+                current_thread.p4(),
+                address,
+                &fs_base as *const usize as *const u8,
+                size_of::<usize>(),
+            );
+
+            either!(is_copied => 0; -1)
+        }
+        _ => -1,
+    }
+}
+
+/*
+    Collects the exit status of a child which has exited
+    Sleeps until a child exits unless WNOHANG is set, in which case 0 is returned if none has yet
+    Returns -1 if there are no matching children or a signal interrupted the sleep
+*/
+fn waitpid(pid: isize, status: *mut i32, options: usize) -> i64 {
+    loop {
+        // Checking and sleeping under the same lock means a child exiting on another CPU can't be missed in between
+        let process_manager = PROCESS_MANAGER.lock();
+        let result = process_manager.wait_for_child(pid);
+
+        let should_sleep = matches!(result, WaitResult::Running) && options & WAIT_NO_HANG == 0;
+        let current_process = process_manager.get_current_process();
+        let current_pid = current_process.pid;
+        let is_interrupted = current_process.has_deliverable_signal();
+
+        if should_sleep && !is_interrupted {
+            CHILD_WAITERS.lock().add(current_pid);
+            CHILD_WAITERS.free();
+            current_process.block();
+        }
+
+        PROCESS_MANAGER.free();
+
+        match result {
+            WaitResult::Exited(child_pid, exit_status) => {
+                if !status.is_null() {
+                    unsafe {
+                        *status = exit_status as i32;
+                    }
+                }
+
+                return child_pid as i64;
+            }
+            WaitResult::Running if !should_sleep => return 0,
+            WaitResult::Running if is_interrupted => return -1,
+            WaitResult::Running => {
+                wait_queue::yield_process();
+
+                // Woken by something other than a child exiting (eg a signal)
+                CHILD_WAITERS.lock().remove(current_pid);
+                CHILD_WAITERS.free();
+            }
+            WaitResult::NoChildren => return -1,
+        }
+    }
+}
+
+/*
+    Sends a signal to the process with the given pid
+    A signal of 0 only checks whether the process exists
+*/
+fn kill(pid: usize, signal: usize) -> i64 {
+    if signal != 0 && !signal::is_valid(signal) {
+        return -1;
+    }
+
+    let is_sent = PROCESS_MANAGER.lock().send_signal(pid, signal);
+    PROCESS_MANAGER.free();
+
+    either!(is_sent => 0; -1)
+}
+
+// Changes what the current process does when it receives a signal and returns the previous action
+fn sigaction(signal: usize, action: *const SignalAction, old_action: *mut SignalAction) -> i64 {
+    if !signal::is_valid(signal) {
+        return -1;
+    }
+
+    let current_proc = PROCESS_MANAGER.lock().get_current_process();
+    PROCESS_MANAGER.free();
+
+    if !old_action.is_null() {
+        unsafe {
+            *old_action = current_proc.signal_actions()[signal];
+        }
+    }
+
+    if !action.is_null() {
+        if signal::mask(signal) & signal::UNBLOCKABLE_SIGNALS != 0 {
+            return -1;
+        }
+
+        let action = unsafe { *action };
+        current_proc.signal_actions()[signal] = action;
+
+        // Ignoring a signal discards any which are pending
+        if action.handler == SIG_IGN
+            || (action.handler == SIG_DFL
+                && signal::default_action(signal) == signal::DefaultAction::Ignore)
+        {
+            current_proc.pending_signals &= !signal::mask(signal);
+        }
+    }
+
+    0
+}
+
+// Adds to, removes from or replaces the set of signals blocked by the current process
+fn sigprocmask(how: usize, set: *const u64, old_set: *mut u64) -> i64 {
+    let current_proc = PROCESS_MANAGER.lock().get_current_process();
     PROCESS_MANAGER.free();
 
+    if !old_set.is_null() {
+        unsafe {
+            *old_set = current_proc.blocked_signals;
+        }
+    }
+
+    if set.is_null() {
+        return 0;
+    }
+
+    let set = unsafe { *set };
+
+    current_proc.blocked_signals = match how {
+        signal::SIG_BLOCK => current_proc.blocked_signals | set,
+        signal::SIG_UNBLOCK => current_proc.blocked_signals & !set,
+        signal::SIG_SETMASK => set,
+        _ => return -1,
+    } & !signal::UNBLOCKABLE_SIGNALS;
+
     0
 }
 
+/*
+    Called by the restorer once a signal handler returns
+    Every register is restored from the signal frame so the saved RAX is returned
+*/
+fn sigreturn(registers: &mut SyscallStackFrame) -> i64 {
+    let current_proc = PROCESS_MANAGER.lock().get_current_process();
+    PROCESS_MANAGER.free();
+
+    if !current_proc.return_from_signal(registers) {
+        let pid = current_proc.pid;
+        PROCESS_MANAGER.lock().force_signal(pid, SIGSEGV);
+        PROCESS_MANAGER.free();
+        return -1;
+    }
+
+    registers.rax as i64
+}
+
+fn fork(registers: &SyscallStackFrame) -> i64 {
+    let pid = PROCESS_MANAGER.lock().fork(registers);
+    PROCESS_MANAGER.free();
+
+    pid as i64
+}
+
+/*
+    Loads the ELF file at the given path into a fresh address space for the current process
+    argv and envp are NULL terminated arrays of strings which are copied onto the stack of the new program
+    Every other thread of the process is ended and only the first thread may call it
+    Only returns to the caller if the file could not be loaded or the arguments are too large
+*/
+fn execve(
+    path: *const u8,
+    argv: *const *const u8,
+    envp: *const *const u8,
+    registers: &mut SyscallStackFrame,
+) -> i64 {
+    let is_thread = PROCESS_MANAGER.lock().get_current_process().is_thread();
+    PROCESS_MANAGER.free();
+
+    if is_thread {
+        return -1;
+    }
+
+    let filepath = string::get_string_from_ptr(path);
+    let mut arguments = match Arguments::from_user(argv, envp) {
+        Some(arguments) => arguments,
+        None => return -1,
+    };
+
+    let mut files = match ProgramFiles::read(filepath) {
+        Some(files) => files,
+        None => {
+            arguments.free();
+            return -1;
+        }
+    };
+
+    // The old address space is free'd by exec so no other thread may still be running in it on another CPU
+    PROCESS_MANAGER.lock().terminate_other_threads();
+    PROCESS_MANAGER.free();
+
+    wait_for_other_threads();
+
+    let current_proc = PROCESS_MANAGER.lock().get_current_process();
+    PROCESS_MANAGER.free();
+
+    let is_loaded = current_proc.exec(&files, &arguments, registers);
+
+    files.free();
+    arguments.free();
+
+    if is_loaded {
+        msr::write_msr(msr::FS_BASE, 0);
+    }
+
+    either!(is_loaded => 0; -1)
+}
+
+// Threads which were ended stop on their next tick and are out of the way once their CPU has switched twice
+fn wait_for_other_threads() {
+    loop {
+        let is_running = PROCESS_MANAGER.lock().is_other_thread_running();
+        PROCESS_MANAGER.free();
+
+        if !is_running {
+            return;
+        }
+
+        wait_queue::yield_process();
+    }
+}
+
 fn isatty(file: usize) -> i64 {
     if file == 0 || file == 1 || file == 2 {
         return 1;
@@ -315,47 +968,287 @@ fn isatty(file: usize) -> i64 {
     return -1;
 }
 
+// Every thread of a process shares the pid of its first thread
 fn getpid() -> i64 {
-    let pid = PROCESS_MANAGER.lock().get_current_process().pid as i64;
+    let pid = PROCESS_MANAGER.lock().get_current_process().tgid as i64;
     PROCESS_MANAGER.free();
     pid
 }
 
-fn allocate_pages(pages_required: usize) -> i64 {
-    // print_serial!("allocating {}\n", pages_required);
+fn gettid() -> i64 {
+    let tid = PROCESS_MANAGER.lock().get_current_process().pid as i64;
+    PROCESS_MANAGER.free();
+    tid
+}
 
-    let address = PAGE_FRAME_ALLOCATOR
-        .lock()
-        .alloc_page_frames(pages_required);
-    PAGE_FRAME_ALLOCATOR.free();
-    address as i64
+/*
+    Sleeps for at least the requested time which is rounded up to whole ticks
+    If a signal interrupts the sleep the time left is written to remaining and -1 is returned
+*/
+fn nanosleep(requested: *const Timespec, remaining: *mut Timespec) -> i64 {
+    if requested.is_null() {
+        return -1;
+    }
+
+    let duration = match unsafe { *requested }.to_nanoseconds() {
+        Some(duration) => duration,
+        None => return -1,
+    };
+
+    let start_tick = PIT.lock().ticks();
+    PIT.free();
+
+    let wake_tick = start_tick + (duration + NANOSECONDS_PER_TICK - 1) / NANOSECONDS_PER_TICK;
+
+    if wait_queue::sleep_until(wake_tick) {
+        return 0;
+    }
+
+    if !remaining.is_null() {
+        let current_tick = PIT.lock().ticks();
+        PIT.free();
+
+        let ticks_left = wake_tick.saturating_sub(current_tick);
+
+        unsafe {
+            *remaining = Timespec::from_nanoseconds(ticks_left * NANOSECONDS_PER_TICK);
+        }
+    }
+
+    -1
 }
 
-fn free_pages(memory_address: usize, pages_required: usize) -> i64 {
-    PAGE_FRAME_ALLOCATOR
+/*
+    FUTEX_WAIT sleeps as long as the word at address holds value (until the optional relative timeout runs out)
+    It returns 0 once woken and -1 if the word has changed, the timeout ran out or a signal arrived
+    FUTEX_WAKE wakes at most value threads sleeping on the word and returns how many were woken
+*/
+fn futex(address: usize, operation: usize, value: usize, timeout: *const Timespec) -> i64 {
+    if address == 0 || address >= USER_ADDRESS_LIMIT || address % 4 != 0 {
+        return -1;
+    }
+
+    let physical_address = match futex::physical_address(address) {
+        Some(physical_address) => physical_address,
+        None => return -1,
+    };
+
+    match operation & !FUTEX_PRIVATE_FLAG {
+        FUTEX_WAIT => {
+            if unsafe { *(address as *const u32) } != value as u32 {
+                return -1;
+            }
+
+            let wake_tick = if timeout.is_null() {
+                None
+            } else {
+                let duration = match unsafe { *timeout }.to_nanoseconds() {
+                    Some(duration) => duration,
+                    None => return -1,
+                };
+
+                let current_tick = PIT.lock().ticks();
+                PIT.free();
+
+                Some(current_tick + (duration + NANOSECONDS_PER_TICK - 1) / NANOSECONDS_PER_TICK)
+            };
+
+            either!(futex::wait(physical_address, wake_tick) => 0; -1)
+        }
+        FUTEX_WAKE => futex::wake(physical_address, value) as i64,
+        _ => -1,
+    }
+}
+
+// Clocks are either CLOCK_MONOTONIC (time since boot) or CLOCK_REALTIME (unix time)
+fn clock_gettime(clock_id: usize, time: *mut Timespec) -> i64 {
+    if time.is_null() {
+        return -1;
+    }
+
+    let clock_time = CLOCK.lock().get_time(clock_id);
+    CLOCK.free();
+
+    match clock_time {
+        Some(clock_time) => {
+            unsafe {
+                *time = clock_time;
+            }
+            0
+        }
+        None => -1,
+    }
+}
+
+// Same as the realtime clock but in microseconds (the timezone argument is obsolete and ignored)
+fn gettimeofday(time: *mut Timeval) -> i64 {
+    if time.is_null() {
+        return -1;
+    }
+
+    let clock_time = CLOCK
         .lock()
-        .free_page_frames(memory_address as *mut usize, pages_required);
-    PAGE_FRAME_ALLOCATOR.free();
+        .get_time(CLOCK_REALTIME)
+        .expect("Realtime clock is always available");
+    CLOCK.free();
 
-    1
+    unsafe {
+        *time = clock_time.to_timeval();
+    }
+
+    0
+}
+
+/*
+    Only PRIO_PROCESS is supported where a pid of 0 is the current process
+    The nice value is returned as 20 - nice (like Linux) so that it is never negative and can't be mistaken for an error
+*/
+fn getpriority(which: usize, pid: usize) -> i64 {
+    if which != PRIO_PROCESS {
+        return -1;
+    }
+
+    let nice = PROCESS_MANAGER.lock().get_process(pid).map(|process| process.nice);
+    PROCESS_MANAGER.free();
+
+    match nice {
+        Some(nice) => 20 - nice as i64,
+        None => -1,
+    }
 }
 
+// Values outside of -20 to 19 are clamped and take effect the next time the process is switched out
+fn setpriority(which: usize, pid: usize, nice: isize) -> i64 {
+    if which != PRIO_PROCESS {
+        return -1;
+    }
+
+    let is_set = match PROCESS_MANAGER.lock().get_process(pid) {
+        Some(process) => {
+            process.set_nice(nice);
+            true
+        }
+        None => false,
+    };
+    PROCESS_MANAGER.free();
+
+    either!(is_set => 0; -1)
+}
+
+// Fills in the CPU time of the current process and its children and returns the ticks since boot
+fn times(buffer: *mut CpuTimes) -> i64 {
+    if !buffer.is_null() {
+        let cpu_times = PROCESS_MANAGER.lock().get_current_process().cpu_times;
+        PROCESS_MANAGER.free();
+
+        unsafe {
+            *buffer = cpu_times;
+        }
+    }
+
+    let ticks = PIT.lock().ticks();
+    PIT.free();
+
+    ticks as i64
+}
+
+// Older interface for allocating memory which is now backed by an anonymous mapping
+fn allocate_pages(pages_required: usize) -> i64 {
+    let length = match pages_required.checked_mul(PAGE_SIZE) {
+        Some(length) => length,
+        None => return -1,
+    };
+
+    mmap(
+        0,
+        length,
+        vma::PROT_READ | vma::PROT_WRITE,
+        MemoryMappingFlags::MapAnonymous as usize | MemoryMappingFlags::MapPrivate as usize,
+        -1,
+        0,
+    )
+}
+
+fn free_pages(memory_address: usize, pages_required: usize) -> i64 {
+    match pages_required.checked_mul(PAGE_SIZE) {
+        Some(length) => either!(munmap(memory_address, length) == 0 => 1; -1),
+        None => -1,
+    }
+}
+
+/*
+    The message and its payload are copied into the kernel before taking the lock as touching them may fault in a page
+    The payload lives in the private memory of the sender so the receiver gets the copy, which is freed once received
+    Returns -1 if the payload is too long or there is no process to receive it
+*/
 fn send_message(message: *mut Message) -> i64 {
-    PROCESS_MANAGER.lock().send_message(message);
+    if message.is_null() {
+        return -1;
+    }
+
+    let mut message = unsafe { *message };
+
+    if message.length > MAX_MESSAGE_LENGTH || (message.length > 0 && message.message.is_null()) {
+        return -1;
+    }
+
+    let payload = kmalloc(message.length.max(1)) as *mut u8;
+    unsafe {
+        core::ptr::copy_nonoverlapping(message.message, payload, message.length);
+    }
+    message.message = payload;
+
+    let process_manager = PROCESS_MANAGER.lock();
+    let is_sent = process_manager.send_message(&mut message);
+
+    if is_sent {
+        process_manager.wake(MESSAGE_WAITERS.lock(), message.receiver_pid);
+        MESSAGE_WAITERS.free();
+    }
     PROCESS_MANAGER.free();
 
+    if !is_sent {
+        kfree(payload as *mut usize);
+        return -1;
+    }
+
     1
 }
 
-fn receive_message() -> i64 {
-    let message = PROCESS_MANAGER.lock().receive_message();
-    PROCESS_MANAGER.free();
+/*
+    Sleeps until a message arrives and returns -1 if a signal arrives first
+    The message is written to the given one with its payload copied into the buffer (cut short if it doesn't fit)
+*/
+fn receive_message(message: *mut Message, buffer: *mut u8, size: usize) -> i64 {
+    if message.is_null() || (size > 0 && buffer.is_null()) {
+        return -1;
+    }
+
+    let mut received = loop {
+        let received = PROCESS_MANAGER.lock().receive_message();
+        PROCESS_MANAGER.free();
 
-    if let Some(message_addr) = message {
-        return message_addr as i64;
+        if let Some(received) = received {
+            break received;
+        }
+
+        if !wait_queue::sleep_on(&MESSAGE_WAITERS) {
+            return -1;
+        }
+    };
+
+    // Copied without any lock held as the buffer may not be mapped yet
+    let payload = received.message;
+    received.length = received.length.min(size);
+    received.message = buffer;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(payload, buffer, received.length);
+        *message = received;
     }
+    kfree(payload as *mut usize);
 
-    -1
+    1
 }
 
 fn create_window(new_window: *mut SimpleWindow) -> i64 {
@@ -374,7 +1267,17 @@ fn create_window(new_window: *mut SimpleWindow) -> i64 {
     wid as i64
 }
 
+// Sleeps until a key or mouse event happens (a signal returns an empty event)
 fn get_event() -> i64 {
+    loop {
+        let has_event = EVENT_MANAGER.lock().has_event();
+        EVENT_MANAGER.free();
+
+        if has_event || !wait_queue::sleep_on(&EVENT_WAITERS) {
+            break;
+        }
+    }
+
     let event = EVENT_MANAGER.lock().get_event();
     EVENT_MANAGER.free();
 
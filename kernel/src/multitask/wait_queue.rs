@@ -0,0 +1,168 @@
+/*
+    Wait queues hold the processes which are sleeping until something happens (eg a key press or a message arriving)
+    A process sleeps from within its syscall by blocking itself and yielding which leaves its kernel context on its own kernel stack
+    Waking a queue unblocks the processes on it which then carry on from where they slept
+    Woken processes must check what they were waiting for again as another process may have taken it first
+*/
+
+use core::arch::asm;
+
+use crate::ds::queue::Queue;
+use crate::interrupts::pit::PIT;
+use crate::memory::allocator::kfree;
+use crate::smp::kernel_lock;
+use crate::utils::spinlock::Lock;
+
+use super::{PROCESS_MANAGER, TIMER_WAITERS};
+
+// Vector of the interrupt used to switch away from a process which is sleeping within the kernel
+pub const YIELD_INTERRUPT: usize = 0x81;
+
+pub struct WaitQueue {
+    pids: Queue<usize>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue { pids: Queue::new() }
+    }
+
+    pub fn add(&mut self, pid: usize) {
+        if !self.contains(pid) {
+            self.pids.enqueue(pid);
+        }
+    }
+
+    // Returns false if the process was not waiting
+    pub fn remove(&mut self, pid: usize) -> bool {
+        match self.pids.find_where(&|waiting_pid: &usize, pid| *waiting_pid == pid, pid) {
+            Some(index) => {
+                if let Some((_, node)) = self.pids.remove(index) {
+                    kfree(node);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn contains(&self, pid: usize) -> bool {
+        self.pids.iter().any(|waiting_pid| *waiting_pid == pid)
+    }
+
+    pub fn pop(&mut self) -> Option<usize> {
+        self.pids.dequeue()
+    }
+}
+
+/*
+    Puts the current process to sleep on a wait queue until it is woken
+    Must be called from a syscall whilst holding no locks as other processes run in the meantime
+    Returns false if a signal interrupted the sleep
+*/
+pub fn sleep_on(queue: &Lock<WaitQueue>) -> bool {
+    let pid = PROCESS_MANAGER.lock().get_current_process().pid;
+    PROCESS_MANAGER.free();
+
+    queue.lock().add(pid);
+    queue.free();
+
+    // A signal which arrived on another CPU since the caller last checked would otherwise be slept through
+    let process = PROCESS_MANAGER.lock().get_current_process();
+    let is_interrupted = process.has_deliverable_signal();
+    if !is_interrupted {
+        process.block();
+    }
+    PROCESS_MANAGER.free();
+
+    if !is_interrupted {
+        yield_process();
+    }
+
+    // The process may have been woken by something else (eg a signal) whilst still on the queue
+    queue.lock().remove(pid);
+    queue.free();
+
+    let is_interrupted = PROCESS_MANAGER
+        .lock()
+        .get_current_process()
+        .has_deliverable_signal();
+    PROCESS_MANAGER.free();
+
+    !is_interrupted
+}
+
+/*
+    Puts the current process to sleep on a wait queue until it is woken even if a signal arrives
+    The condition is checked once the process is on the queue so a wake just before it blocks isn't missed
+    Only for waits which end quickly (eg a kernel mutex) as the process can't be interrupted
+*/
+pub fn sleep_on_uninterruptible<F>(queue: &Lock<WaitQueue>, should_sleep: F)
+where
+    F: Fn() -> bool,
+{
+    let pid = PROCESS_MANAGER.lock().get_current_process().pid;
+    PROCESS_MANAGER.free();
+
+    queue.lock().add(pid);
+    queue.free();
+
+    // Wakes take PROCESS_MANAGER first so either the condition already changed or the wake sees the process blocked
+    let process_manager = PROCESS_MANAGER.lock();
+    let is_sleeping = should_sleep();
+    if is_sleeping {
+        process_manager.get_current_process().block();
+    }
+    PROCESS_MANAGER.free();
+
+    if is_sleeping {
+        yield_process();
+    }
+
+    queue.lock().remove(pid);
+    queue.free();
+}
+
+/*
+    Puts the current process to sleep until the timer reaches the given tick
+    Returns false if a signal interrupted the sleep
+*/
+pub fn sleep_until(wake_tick: usize) -> bool {
+    loop {
+        let current_tick = PIT.lock().ticks();
+        PIT.free();
+
+        if current_tick >= wake_tick {
+            return true;
+        }
+
+        let process_manager = PROCESS_MANAGER.lock();
+        let pid = process_manager.get_current_process().pid;
+        process_manager.add_timer(pid, wake_tick);
+        PROCESS_MANAGER.free();
+
+        if !sleep_on(&TIMER_WAITERS) {
+            return false;
+        }
+    }
+}
+
+/*
+    Switches to another process from within the kernel
+    The frame saved by the interrupt resumes the kernel straight after it once the process is picked again
+    The kernel lock is given up in the meantime as the process may be resumed on another CPU
+*/
+pub fn yield_process() {
+    let is_locked = kernel_lock::is_held();
+    if is_locked {
+        kernel_lock::release();
+    }
+
+    unsafe {
+        asm!("int {}", const YIELD_INTERRUPT);
+    }
+
+    if is_locked {
+        kernel_lock::acquire();
+    }
+}
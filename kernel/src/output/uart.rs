@@ -7,6 +7,7 @@
 const PORT: u16 = 0x3F8; // COM1
 
 use crate::output::output::Output;
+use crate::utils::event::EVENT_MANAGER;
 use crate::utils::ports::{inb, outb};
 use crate::utils::spinlock::Lock;
 use core::fmt;
@@ -33,6 +34,8 @@ impl Console {
         // If serial is not faulty set it in normal operation mode
         // (not-loopback with IRQs enabled and OUT#1 and OUT#2 bits enabled)
         outb(PORT + 4, 0x0F);
+
+        outb(PORT + 1, 0x01); // Enable the received data interrupt (IRQ 4)
     }
 
     fn read_serial(&self) -> char {
@@ -40,6 +43,15 @@ impl Console {
         return inb(self.port) as char;
     }
 
+    // Returns the next received byte without waiting for one
+    pub fn read_received(&self) -> Option<char> {
+        if self.has_serial_received() == 0 {
+            return None;
+        }
+
+        Some(inb(self.port) as char)
+    }
+
     fn has_serial_received(&self) -> u8 {
         return inb(self.port + 5) & 1;
     }
@@ -65,6 +77,23 @@ impl Output for Console {
 
 pub static CONSOLE: Lock<Console> = Lock::new(Console { port: PORT });
 
+// Characters typed on the serial line are passed on like key presses (enter is sent as a carriage return)
+pub fn handle_serial_input() {
+    loop {
+        let character = CONSOLE.lock().read_received();
+        CONSOLE.free();
+
+        let (scancode, character) = match character {
+            Some('\r') => (0x1c, 0x1c as char),
+            Some(character) => (0, character),
+            None => break,
+        };
+
+        EVENT_MANAGER.lock().update_key_event(scancode, character);
+        EVENT_MANAGER.free();
+    }
+}
+
 impl fmt::Write for Console {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.write_string(s);
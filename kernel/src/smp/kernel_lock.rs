@@ -0,0 +1,48 @@
+/*
+    The kernel lock is held whilst a CPU runs a syscall, a kernel thread or an interrupt handler which wakes processes
+    Much of the kernel checks for something (eg a message) and then sleeps until it arrives which relied on interrupts being disabled
+    With several CPUs that only holds if a single CPU runs that code at a time
+    A process gives up the lock whilst it sleeps and takes it again once it is resumed (which may be on another CPU)
+*/
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{current_cpu, tlb};
+
+const NO_OWNER: usize = usize::MAX;
+
+// CPU holding the lock
+static OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+
+pub fn acquire() {
+    let cpu = current_cpu();
+
+    while let Err(owner) =
+        OWNER.compare_exchange(NO_OWNER, cpu, Ordering::Acquire, Ordering::Relaxed)
+    {
+        if owner == cpu {
+            panic!("kernel lock already held by CPU {}", cpu);
+        }
+
+        tlb::handle_shootdown();
+        core::hint::spin_loop();
+    }
+}
+
+pub fn release() {
+    OWNER.store(NO_OWNER, Ordering::Release);
+}
+
+pub fn is_held() -> bool {
+    OWNER.load(Ordering::Relaxed) == current_cpu()
+}
+
+// Takes the lock unless this CPU already holds it (eg a page fault within a syscall) and returns whether it was taken
+pub fn acquire_unless_held() -> bool {
+    if is_held() {
+        return false;
+    }
+
+    acquire();
+    true
+}
@@ -0,0 +1,201 @@
+/*
+    Symmetric multiprocessing lets every CPU of the machine run processes at the same time
+    The CPU which booted the kernel (bootstrap processor) finds the others (application processors) in the MADT and starts each of them
+    Application processors start in real mode so they first run a trampoline which takes them to long mode like boot.asm does
+    Every CPU has its own GDT, TSS, local APIC and run queue whilst memory and everything else is shared
+
+    The bootstrap processor is always CPU 0 and the others are numbered in the order they are started
+*/
+
+pub mod kernel_lock;
+pub mod tlb;
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::interrupts::{self, lapic, pit};
+use crate::memory::allocator::{kfree, kmalloc};
+use crate::memory::gdt;
+use crate::memory::kernel_stack::KERNEL_STACKS;
+use crate::memory::paging::{self, PAGE_SIZE};
+use crate::print_serial;
+use crate::utils::acpi::MADT;
+
+pub const MAX_CPUS: usize = 16;
+
+// Vector of the interprocessor interrupt which asks a CPU to flush its TLB
+pub const TLB_SHOOTDOWN_VECTOR: usize = 0x41;
+
+// Application processors start at this page which must be below 1MB and is identity mapped
+const TRAMPOLINE_ADDRESS: usize = 0x8000;
+
+// Milliseconds to wait for a CPU to come online after it was sent the startup IPIs
+const STARTUP_TIMEOUT: usize = 100;
+
+// Read by the trampoline (the layout matches ap_trampoline_parameters)
+#[repr(C)]
+struct TrampolineParameters {
+    p4: usize,
+    stack: usize,
+    entry: usize,
+    cpu: usize,
+}
+
+const OFFLINE: AtomicBool = AtomicBool::new(false);
+static ONLINE: [AtomicBool; MAX_CPUS] = [OFFLINE; MAX_CPUS];
+
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+// Local APIC of each CPU which IPIs are addressed to
+const NO_APIC: AtomicUsize = AtomicUsize::new(0);
+static APIC_IDS: [AtomicUsize; MAX_CPUS] = [NO_APIC; MAX_CPUS];
+
+// Index of the CPU running the caller
+pub fn current_cpu() -> usize {
+    gdt::cpu_index()
+}
+
+// CPUs which are online (their indexes are below this)
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/*
+    Starts every other CPU listed in the MADT with the INIT-SIPI-SIPI sequence
+    Each one gets a kernel stack and runs ap_main before idling until its timer first ticks
+    The memory under the trampoline is put back afterwards as it may hold something of the bootloader
+*/
+pub fn init() {
+    ONLINE[0].store(true, Ordering::Release);
+
+    let bsp_apic_id = lapic::id();
+    APIC_IDS[0].store(bsp_apic_id, Ordering::Release);
+
+    let mut apic_ids = [0; MAX_CPUS];
+    let madt = MADT.lock();
+    let listed = madt.apic_ids().len();
+    apic_ids[..listed].copy_from_slice(madt.apic_ids());
+    MADT.free();
+
+    let trampoline_size = unsafe {
+        &ap_trampoline_end as *const u8 as usize - &ap_trampoline_start as *const u8 as usize
+    };
+    assert!(
+        trampoline_size <= PAGE_SIZE,
+        "AP trampoline is larger than a page"
+    );
+
+    let saved_memory = kmalloc(trampoline_size) as *mut u8;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            TRAMPOLINE_ADDRESS as *const u8,
+            saved_memory,
+            trampoline_size,
+        );
+        core::ptr::copy_nonoverlapping(
+            &ap_trampoline_start as *const u8,
+            TRAMPOLINE_ADDRESS as *mut u8,
+            trampoline_size,
+        );
+    }
+
+    for &apic_id in apic_ids[..listed].iter().filter(|&&id| id != bsp_apic_id) {
+        let cpu = cpu_count();
+
+        if !start_cpu(cpu, apic_id) {
+            // A CPU which comes online late would take the index of the next one
+            print_serial!("SMP: CPU with APIC id {} did not start\n", apic_id);
+            break;
+        }
+
+        CPU_COUNT.store(cpu + 1, Ordering::Release);
+    }
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            saved_memory,
+            TRAMPOLINE_ADDRESS as *mut u8,
+            trampoline_size,
+        );
+    }
+    kfree(saved_memory as *mut usize);
+
+    print_serial!("SMP: {} CPUs online\n", cpu_count());
+}
+
+// Returns false if the CPU didn't come online in time
+fn start_cpu(cpu: usize, apic_id: usize) -> bool {
+    let stack = KERNEL_STACKS.lock().allocate();
+    KERNEL_STACKS.free();
+
+    let parameters = TRAMPOLINE_ADDRESS
+        + unsafe {
+            &ap_trampoline_parameters as *const u8 as usize
+                - &ap_trampoline_start as *const u8 as usize
+        };
+
+    unsafe {
+        core::ptr::write_volatile(
+            parameters as *mut TrampolineParameters,
+            TrampolineParameters {
+                p4: paging::get_current_p4(),
+                stack: stack.top(),
+                entry: ap_main as usize,
+                cpu,
+            },
+        );
+    }
+
+    lapic::send_init(apic_id);
+    pit::busy_wait(10_000);
+
+    // The second startup IPI is only needed if the first was missed
+    for _ in 0..2 {
+        lapic::send_startup(apic_id, TRAMPOLINE_ADDRESS / PAGE_SIZE);
+        pit::busy_wait(200);
+
+        if ONLINE[cpu].load(Ordering::Acquire) {
+            return true;
+        }
+    }
+
+    for _ in 0..STARTUP_TIMEOUT {
+        if ONLINE[cpu].load(Ordering::Acquire) {
+            return true;
+        }
+
+        pit::busy_wait(1000);
+    }
+
+    false
+}
+
+/*
+    Entered from the trampoline in long mode on the kernel stack it was given
+    Interrupts are only enabled once everything is set up and the CPU idles until the first tick switches it to a process
+*/
+extern "C" fn ap_main(cpu: usize) -> ! {
+    gdt::init_cpu(cpu);
+    interrupts::load_idt();
+    lapic::init_application_processor();
+
+    APIC_IDS[cpu].store(lapic::id(), Ordering::Release);
+    ONLINE[cpu].store(true, Ordering::Release);
+
+    loop {
+        unsafe {
+            asm!("sti", "hlt");
+        }
+    }
+}
+
+pub fn apic_id(cpu: usize) -> usize {
+    APIC_IDS[cpu].load(Ordering::Acquire)
+}
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_parameters: u8;
+}
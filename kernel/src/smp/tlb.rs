@@ -0,0 +1,83 @@
+/*
+    Every CPU caches translations in its own TLB which is only flushed by that CPU
+    Changing or removing a mapping of an address space which runs on other CPUs (eg copy on write between threads) leaves them with stale entries
+    Those CPUs are sent an IPI asking them to reload CR3 and the sender waits until they all have
+
+    Kernel code runs with interrupts disabled so a CPU spinning on a lock also checks whether it was asked to flush
+    Otherwise the CPU waiting for the flush whilst holding the lock and the CPU waiting for the lock would wait on each other
+*/
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::interrupts::lapic;
+
+use super::{apic_id, cpu_count, current_cpu, MAX_CPUS, TLB_SHOOTDOWN_VECTOR};
+
+const NO_ADDRESS_SPACE: AtomicUsize = AtomicUsize::new(0);
+const NOT_PENDING: AtomicBool = AtomicBool::new(false);
+
+// P4 each CPU has switched to
+static ACTIVE_P4: [AtomicUsize; MAX_CPUS] = [NO_ADDRESS_SPACE; MAX_CPUS];
+
+// Set for a CPU which was asked to flush its TLB and cleared once it has
+static PENDING: [AtomicBool; MAX_CPUS] = [NOT_PENDING; MAX_CPUS];
+
+// Called whenever the scheduler picks the address space the CPU runs next
+pub fn set_active_p4(p4: usize) {
+    ACTIVE_P4[current_cpu()].store(p4, Ordering::SeqCst);
+}
+
+/*
+    Makes every other CPU running the address space flush its TLB after a mapping of it was changed
+    The caller has already flushed its own TLB
+*/
+pub fn shootdown(p4: usize) {
+    flush_other_cpus(|other| ACTIVE_P4[other].load(Ordering::SeqCst) == p4);
+}
+
+// Used for mappings which every address space shares (eg kernel stacks)
+pub fn shootdown_all() {
+    flush_other_cpus(|_| true);
+}
+
+fn flush_other_cpus<F>(is_affected: F)
+where
+    F: Fn(usize) -> bool,
+{
+    let count = cpu_count();
+
+    if count == 1 || !lapic::is_initialised() {
+        return;
+    }
+
+    let cpu = current_cpu();
+
+    for other in 0..count {
+        if other != cpu && is_affected(other) {
+            PENDING[other].store(true, Ordering::SeqCst);
+            lapic::send_ipi(apic_id(other), TLB_SHOOTDOWN_VECTOR);
+        }
+    }
+
+    for other in 0..count {
+        while PENDING[other].load(Ordering::SeqCst) {
+            // Another CPU may be waiting on this one at the same time
+            handle_shootdown();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// Flushes the TLB of the current CPU if another CPU asked it to
+pub fn handle_shootdown() {
+    let cpu = current_cpu();
+
+    if PENDING[cpu].load(Ordering::SeqCst) {
+        unsafe {
+            asm!("mov {0}, cr3", "mov cr3, {0}", out(reg) _, options(nostack, preserves_flags));
+        }
+
+        PENDING[cpu].store(false, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,372 @@
+/*
+    ACPI (Advanced Configuration and Power Interface) tables describe hardware which can't be probed (eg how many CPUs there are)
+    The RSDP points to the RSDT (or the XSDT from ACPI 2.0) which lists the physical address of every other table
+    Only the MADT (signature APIC) is used which lists the local APIC of every CPU along with the IO APICs
+    Tables can be anywhere in physical memory so they are copied out through a temporary mapping
+*/
+
+use core::mem::size_of;
+
+use crate::memory::allocator::{kfree, kmalloc};
+use crate::memory::page_frame_allocator::PAGE_FRAME_ALLOCATOR;
+use crate::memory::paging::{self, PAGE_SIZE};
+use crate::print_serial;
+use crate::smp::MAX_CPUS;
+
+use super::multiboot2::MultibootBootInfo;
+use super::spinlock::Lock;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+// Areas the BIOS may leave the RSDP in when the bootloader doesn't pass a copy
+const EBDA_POINTER: usize = 0x40E;
+const BIOS_AREA_START: usize = 0xE0000;
+const BIOS_AREA_END: usize = 0x100000;
+
+pub const MAX_IO_APICS: usize = 8;
+const MAX_OVERRIDES: usize = 16;
+
+// Types of the entries which follow the MADT header
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+const PCAT_COMPAT: u32 = 1 << 0; // The machine also has the two 8259 PICs
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8, // 0 for ACPI 1.0 which only has the fields up to the RSDT address
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+// Every table starts with this header
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32, // Includes the header
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MadtHeader {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: usize,
+    pub address: usize,  // Physical address of its registers
+    pub gsi_base: usize, // First global system interrupt it handles
+}
+
+// ISA interrupts which are not wired to the IO APIC input with the same number
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: usize,
+    pub gsi: usize,
+    pub flags: usize, // Polarity (bits 0-1) and trigger mode (bits 2-3)
+}
+
+pub struct Madt {
+    pub local_apic_address: usize,
+    pub has_legacy_pics: bool,
+    apic_ids: [usize; MAX_CPUS], // Local APIC of every usable CPU in the order the firmware lists them
+    cpu_count: usize,
+    io_apics: [IoApic; MAX_IO_APICS],
+    io_apic_count: usize,
+    overrides: [InterruptOverride; MAX_OVERRIDES],
+    override_count: usize,
+}
+
+impl Madt {
+    pub const fn new() -> Madt {
+        Madt {
+            local_apic_address: 0,
+            has_legacy_pics: false,
+            apic_ids: [0; MAX_CPUS],
+            cpu_count: 0,
+            io_apics: [IoApic {
+                id: 0,
+                address: 0,
+                gsi_base: 0,
+            }; MAX_IO_APICS],
+            io_apic_count: 0,
+            overrides: [InterruptOverride {
+                irq: 0,
+                gsi: 0,
+                flags: 0,
+            }; MAX_OVERRIDES],
+            override_count: 0,
+        }
+    }
+
+    pub fn apic_ids(&self) -> &[usize] {
+        &self.apic_ids[..self.cpu_count]
+    }
+
+    pub fn io_apics(&self) -> &[IoApic] {
+        &self.io_apics[..self.io_apic_count]
+    }
+
+    pub fn overrides(&self) -> &[InterruptOverride] {
+        &self.overrides[..self.override_count]
+    }
+
+    // CPUs beyond MAX_CPUS are left unused
+    fn add_cpu(&mut self, apic_id: usize) {
+        if self.cpu_count < MAX_CPUS {
+            self.apic_ids[self.cpu_count] = apic_id;
+            self.cpu_count += 1;
+        }
+    }
+
+    fn add_io_apic(&mut self, io_apic: IoApic) {
+        if self.io_apic_count < MAX_IO_APICS {
+            self.io_apics[self.io_apic_count] = io_apic;
+            self.io_apic_count += 1;
+        }
+    }
+
+    fn add_override(&mut self, interrupt_override: InterruptOverride) {
+        if self.override_count < MAX_OVERRIDES {
+            self.overrides[self.override_count] = interrupt_override;
+            self.override_count += 1;
+        }
+    }
+
+    // Entries are a type and length followed by fields which depend on the type
+    fn parse_entries(&mut self, entries: *const u8, length: usize) {
+        let mut offset = 0;
+
+        while offset + 2 <= length {
+            let entry = unsafe { entries.add(offset) };
+            let entry_type = unsafe { *entry };
+            let entry_length = unsafe { *entry.add(1) } as usize;
+
+            if entry_length < 2 || offset + entry_length > length {
+                break;
+            }
+
+            match entry_type {
+                ENTRY_LOCAL_APIC => {
+                    let apic_id = unsafe { *entry.add(3) } as usize;
+                    let flags = unsafe { read_unaligned::<u32>(entry.add(4)) };
+
+                    if flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                        self.add_cpu(apic_id);
+                    }
+                }
+                ENTRY_IO_APIC => self.add_io_apic(IoApic {
+                    id: unsafe { *entry.add(2) } as usize,
+                    address: unsafe { read_unaligned::<u32>(entry.add(4)) } as usize,
+                    gsi_base: unsafe { read_unaligned::<u32>(entry.add(8)) } as usize,
+                }),
+                ENTRY_INTERRUPT_OVERRIDE => self.add_override(InterruptOverride {
+                    irq: unsafe { *entry.add(3) } as usize,
+                    gsi: unsafe { read_unaligned::<u32>(entry.add(4)) } as usize,
+                    flags: unsafe { read_unaligned::<u16>(entry.add(8)) } as usize,
+                }),
+                ENTRY_LOCAL_APIC_ADDRESS => {
+                    self.local_apic_address =
+                        unsafe { read_unaligned::<u64>(entry.add(4)) } as usize;
+                }
+                _ => {}
+            }
+
+            offset += entry_length;
+        }
+    }
+}
+
+pub static MADT: Lock<Madt> = Lock::new(Madt::new());
+
+/*
+    Finds and parses the MADT into MADT
+    Returns false if the machine has no ACPI tables or no MADT (so only the bootstrap processor can be used)
+*/
+pub fn init(multiboot_info: &MultibootBootInfo) -> bool {
+    let rsdp = match find_rsdp(multiboot_info) {
+        Some(rsdp) => rsdp,
+        None => {
+            print_serial!("ACPI: No RSDP found\n");
+            return false;
+        }
+    };
+
+    let madt_address = match find_table(&rsdp, MADT_SIGNATURE) {
+        Some(address) => address,
+        None => {
+            print_serial!("ACPI: No MADT found\n");
+            return false;
+        }
+    };
+
+    let header = read_physical::<SdtHeader>(madt_address);
+    let length = header.length as usize;
+
+    if length < size_of::<MadtHeader>() {
+        return false;
+    }
+
+    let table = kmalloc(length) as *mut u8;
+    copy_from_physical(madt_address, table, length);
+
+    let is_valid = is_checksum_valid(table, length);
+
+    if is_valid {
+        let madt_header = unsafe { read_unaligned::<MadtHeader>(table) };
+
+        let madt = MADT.lock();
+        madt.local_apic_address = madt_header.local_apic_address as usize;
+        madt.has_legacy_pics = madt_header.flags & PCAT_COMPAT != 0;
+        madt.parse_entries(
+            unsafe { table.add(size_of::<MadtHeader>()) },
+            length - size_of::<MadtHeader>(),
+        );
+
+        print_serial!(
+            "ACPI: {} CPUs and {} IO APICs\n",
+            madt.apic_ids().len(),
+            madt.io_apics().len()
+        );
+        MADT.free();
+    }
+
+    kfree(table as *mut usize);
+
+    is_valid
+}
+
+// GRUB passes a copy of the RSDP but older bootloaders leave it to be found in the BIOS areas
+fn find_rsdp(multiboot_info: &MultibootBootInfo) -> Option<Rsdp> {
+    if let Some(address) = multiboot_info.get_acpi_rsdp_address() {
+        return Some(unsafe { read_unaligned::<Rsdp>(address as *const u8) });
+    }
+
+    let ebda = (unsafe { *(EBDA_POINTER as *const u16) } as usize) << 4;
+
+    search_rsdp(ebda, ebda + 1024).or_else(|| search_rsdp(BIOS_AREA_START, BIOS_AREA_END))
+}
+
+// The RSDP sits on a 16 byte boundary (the first MB is identity mapped so it can be read directly)
+fn search_rsdp(start: usize, end: usize) -> Option<Rsdp> {
+    (start..end).step_by(16).find_map(|address| {
+        let signature = unsafe { core::slice::from_raw_parts(address as *const u8, 8) };
+
+        if signature == RSDP_SIGNATURE && is_checksum_valid(address as *const u8, 20) {
+            Some(unsafe { read_unaligned::<Rsdp>(address as *const u8) })
+        } else {
+            None
+        }
+    })
+}
+
+// Looks through the XSDT (or RSDT for ACPI 1.0) for the physical address of a table
+fn find_table(rsdp: &Rsdp, signature: &[u8; 4]) -> Option<usize> {
+    let (root_address, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address as usize, size_of::<u64>())
+    } else {
+        (rsdp.rsdt_address as usize, size_of::<u32>())
+    };
+
+    let root = read_physical::<SdtHeader>(root_address);
+    let entry_count = (root.length as usize).saturating_sub(size_of::<SdtHeader>()) / entry_size;
+
+    for i in 0..entry_count {
+        let entry_address = root_address + size_of::<SdtHeader>() + i * entry_size;
+
+        let table_address = read_table_address(entry_address, entry_size);
+        let header = read_physical::<SdtHeader>(table_address);
+
+        if &header.signature == signature {
+            return Some(table_address);
+        }
+    }
+
+    None
+}
+
+// Entries of the RSDT are 32 bit whilst those of the XSDT are 64 bit
+fn read_table_address(address: usize, entry_size: usize) -> usize {
+    if entry_size == size_of::<u64>() {
+        read_physical::<u64>(address) as usize
+    } else {
+        read_physical::<u32>(address) as usize
+    }
+}
+
+// The bytes of a table (including its header) add up to 0
+fn is_checksum_valid(table: *const u8, length: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(table, length) };
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+fn read_physical<T: Copy>(p_addr: usize) -> T {
+    let mut value = core::mem::MaybeUninit::<T>::uninit();
+    copy_from_physical(p_addr, value.as_mut_ptr() as *mut u8, size_of::<T>());
+    unsafe { value.assume_init() }
+}
+
+unsafe fn read_unaligned<T: Copy>(address: *const u8) -> T {
+    core::ptr::read_unaligned(address as *const T)
+}
+
+/*
+    Copies physical memory which may not be mapped into a buffer
+    A frame from the page frame allocator is used as the window which is pointed at each page in turn
+    The window is mapped back onto its own frame afterwards so the frame can be free'd
+*/
+fn copy_from_physical(p_addr: usize, dst: *mut u8, length: usize) {
+    let window = PAGE_FRAME_ALLOCATOR
+        .lock()
+        .alloc_page_frame()
+        .expect("PFA Ran out of memory") as usize;
+    PAGE_FRAME_ALLOCATOR.free();
+
+    let mut copied = 0;
+
+    while copied < length {
+        let current = p_addr + copied;
+        let offset = current & (PAGE_SIZE - 1);
+        let amount = core::cmp::min(PAGE_SIZE - offset, length - copied);
+
+        paging::map_pages(1, window, current - offset);
+
+        unsafe {
+            core::ptr::copy_nonoverlapping((window + offset) as *const u8, dst.add(copied), amount);
+        }
+
+        copied += amount;
+    }
+
+    paging::map_pages(1, window, window);
+
+    unsafe {
+        PAGE_FRAME_ALLOCATOR
+            .lock()
+            .free_page_frame(window as *mut usize);
+        PAGE_FRAME_ALLOCATOR.free();
+    }
+}
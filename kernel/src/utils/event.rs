@@ -1,4 +1,5 @@
 use super::{bitwise, spinlock::Lock};
+use crate::multitask::wait_queue::WaitQueue;
 use crate::{memory::allocator::kmalloc, print_serial};
 use core::mem::size_of;
 
@@ -32,6 +33,7 @@ impl Event {
 pub struct EventManager {
     event_addr: *mut Event,
     event_rtn_addr: *mut Event,
+    is_pending: bool, // Set by the keyboard and mouse until the event is taken
 }
 
 impl EventManager {
@@ -52,6 +54,7 @@ impl EventManager {
         event_ref.scancode = scancode;
         event_ref.character = character as u8;
         event_ref.flags = bitwise::set_bit(event_ref.flags, EventFlags::KeyPressed as u8);
+        self.is_pending = true;
     }
 
     pub fn update_mouse_event(
@@ -73,6 +76,12 @@ impl EventManager {
             event_ref.flags =
                 bitwise::set_bit(event_ref.flags, EventFlags::MouseRightClicked as u8);
         }
+
+        self.is_pending = true;
+    }
+
+    pub fn has_event(&self) -> bool {
+        self.is_pending
     }
 
     pub fn get_event(&mut self) -> *mut Event {
@@ -87,6 +96,7 @@ impl EventManager {
         }
 
         event_ref.clear();
+        self.is_pending = false;
 
         self.event_rtn_addr
     }
@@ -95,4 +105,8 @@ impl EventManager {
 pub static EVENT_MANAGER: Lock<EventManager> = Lock::new(EventManager {
     event_addr: core::ptr::null_mut(),
     event_rtn_addr: core::ptr::null_mut(),
+    is_pending: false,
 });
+
+// Processes sleeping in get_event until the keyboard or mouse produces an event
+pub static EVENT_WAITERS: Lock<WaitQueue> = Lock::new(WaitQueue::new());
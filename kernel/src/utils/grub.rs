@@ -10,7 +10,7 @@ use core::panic;
 
 use crate::{
     fs, gfx,
-    multitask::{self, PROCESS_MANAGER},
+    multitask::{self, arguments::Arguments, ProgramFiles, PROCESS_MANAGER},
     print_serial,
 };
 
@@ -33,24 +33,55 @@ const VBE_DISPI_INDEX_X_OFFSET: u16 = 8;
 const VBE_DISPI_INDEX_Y_OFFSET: u16 = 9;
 const VBE_DISPI_LFB_ENABLED: u16 = 0x40;
 
+// Lists the programs to start at boot within the filesystem
+const INIT_CONFIG_PATH: &str = "/init.cfg";
+
 /*
-    All usermode processes are grub modules
+    The first grub module is the filesystem which holds the usermode programs listed in the init config
+    Without the config file, every other module is loaded as a usermode process instead
 */
 pub fn initalise_userland(multiboot_info: &MultibootBootInfo) {
-    for (i, tag) in multiboot_info.get_module_tags().enumerate() {
-        if i == 0 {
-            fs::init(tag.mod_start as usize);
-        } else {
-            print_serial!("Loading module {}\n", i);
-            PROCESS_MANAGER
-                .lock()
-                .add_process(true, i, tag.mod_start as usize);
-            PROCESS_MANAGER.free();
+    let mut module_tags = multiboot_info.get_module_tags();
+
+    let fs_tag = module_tags.next().expect("Expected filesystem module");
+    fs::init(
+        fs_tag.mod_start as usize,
+        (fs_tag.mod_end - fs_tag.mod_start) as usize,
+    );
+
+    if multitask::spawn_from_config(INIT_CONFIG_PATH) {
+        return;
+    }
+
+    for (i, tag) in module_tags.enumerate() {
+        print_serial!("Loading module {}\n", i + 1);
+
+        let files = ProgramFiles::from_memory(
+            tag.mod_start as usize,
+            (tag.mod_end - tag.mod_start) as usize,
+        );
+
+        let mut files = match files {
+            Some(files) => files,
+            None => {
+                print_serial!("Error: Module {} is not a valid program\n", i + 1);
+                continue;
+            }
+        };
+
+        let mut arguments = Arguments::new(&[], multitask::DEFAULT_ENVIRONMENT);
+
+        let pid = PROCESS_MANAGER.lock().add_process(true, &files, &arguments);
+        PROCESS_MANAGER.free();
+
+        if pid.is_none() {
+            print_serial!("Error: Module {} is not a valid program\n", i + 1);
         }
 
-        // gfx::display_image(tag.mod_start as *const u8, tag.size as usize);
+        files.free();
+        arguments.free();
 
-        // fs::init(tag.mod_start as usize);
+        // gfx::display_image(tag.mod_start as *const u8, tag.size as usize);
     }
 }
 
@@ -1,11 +1,16 @@
+pub mod acpi;
 pub mod bitwise;
 pub mod event;
 pub mod grub;
+pub mod msr;
 pub mod multiboot2;
+pub mod mutex;
 pub mod ports;
 pub mod rtc;
+pub mod semaphore;
 pub mod spinlock;
 pub mod string;
+pub mod time;
 pub mod wrapping_zero;
 
 #[macro_export]
@@ -0,0 +1,28 @@
+/*
+    Model specific registers configure features of the CPU which don't have their own instructions
+    Each register is selected by its number in ECX and the 64 bit value is split between EDX (high) and EAX (low)
+*/
+
+use core::arch::asm;
+
+pub const APIC_BASE: u32 = 0x1B; // Physical address of the local APIC registers along with its enable bit
+pub const FS_BASE: u32 = 0xC000_0100; // Base of the FS segment which userland uses for thread local storage
+
+pub fn read_msr(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
+
+    unsafe {
+        asm!("rdmsr", in("ecx") msr, out("edx") high, out("eax") low, options(nomem, nostack, preserves_flags));
+    }
+
+    ((high as u64) << 32) | low as u64
+}
+
+pub fn write_msr(msr: u32, value: u64) {
+    let high = (value >> 32) as u32;
+    let low = value as u32;
+
+    unsafe {
+        asm!("wrmsr", in("ecx") msr, in("edx") high, in("eax") low, options(nostack, preserves_flags));
+    }
+}
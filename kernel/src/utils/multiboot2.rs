@@ -88,6 +88,14 @@ impl MultibootBootInfo {
             .map(|tag| unsafe { &*(tag as *const Tag as *const FramebufferTag) })
     }
 
+    // Address of the copy of the RSDP which the bootloader placed after the tag header (the ACPI 2.0 one if both exist)
+    pub fn get_acpi_rsdp_address(&self) -> Option<usize> {
+        self.tags()
+            .find(|&tag| tag.typ == TagType::AcpiNew as u32)
+            .or_else(|| self.tags().find(|&tag| tag.typ == TagType::AcpiOld as u32))
+            .map(|tag| tag as *const Tag as usize + core::mem::size_of::<Tag>())
+    }
+
     pub fn tags(&self) -> TagIter {
         TagIter::new(self.get_tag_address())
     }
@@ -96,6 +104,7 @@ impl MultibootBootInfo {
 #[repr(u32)]
 pub enum TagType {
     End = 0,            // Marks end of tags
+    CommandLine = 1,    // Boot command line tag
     BootLoaderName = 2, // Bootloader name tag
     Module = 3,         // Module tag
     BasicMemInfo = 4,   // Basic memory information tag
@@ -103,23 +112,19 @@ pub enum TagType {
     MemoryMap = 6,      // Memory map tag
     Vbe = 7,            // VBE (Video Display Information) tag
     Framebuffer = 8,    // Framebuffer tag
-    AcpiOld = 9,        // ACPI (Advanced Configuration and Power Interface) tag (old)
-    AcpiNew = 10,       // ACPI (Advanced Configuration and Power Interface) tag (new)
-    Network = 11,       // Network configuration tag
-    Efi32 = 12,         // EFI 32-bit tag
-    Efi64 = 13,         // EFI 64-bit tag
-    Smbios = 14,        // SMBIOS (System Management BIOS) tag
-    ElfSections = 15,   // ELF sections tag
-    Apm = 16,           // APM (Advanced Power Management) BIOS tag
-    Efi32Ih = 17,       // EFI 32-bit real-time clock tag
-    Efi64Ih = 18,       // EFI 64-bit real-time clock tag
-    Scsi = 19,          // SCSI (Small Computer System Interface) tag
-    Edid = 20,          // EDID (Extended Display Identification Data) tag
-    EfiMmap = 21,       // EFI memory map tag
-    EfiBs = 22,         // EFI boot services tag
-    Efi32St = 23,       // EFI 32-bit system table tag
-    Efi64St = 24,       // EFI 64-bit system table tag
-    LoadBaseAddr = 25,  // Load base address tag
+    ElfSections = 9,    // ELF sections tag
+    Apm = 10,           // APM (Advanced Power Management) BIOS tag
+    Efi32 = 11,         // EFI 32-bit system table tag
+    Efi64 = 12,         // EFI 64-bit system table tag
+    Smbios = 13,        // SMBIOS (System Management BIOS) tag
+    AcpiOld = 14,       // Copy of the ACPI 1.0 RSDP (Advanced Configuration and Power Interface)
+    AcpiNew = 15,       // Copy of the ACPI 2.0 RSDP
+    Network = 16,       // Network configuration tag
+    EfiMmap = 17,       // EFI memory map tag
+    EfiBs = 18,         // EFI boot services tag
+    Efi32Ih = 19,       // EFI 32-bit image handle tag
+    Efi64Ih = 20,       // EFI 64-bit image handle tag
+    LoadBaseAddr = 21,  // Load base address tag
 }
 
 #[repr(u32)]
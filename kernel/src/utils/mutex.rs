@@ -0,0 +1,63 @@
+/*
+    A mutex which puts the process trying to take it to sleep instead of panicking like Lock
+    It is for data held across something slow (eg reading a disk) and can only be used from a syscall or kernel thread
+    Processes holding it must not hold PROCESS_MANAGER as taking or freeing it may switch process
+*/
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::either;
+use crate::multitask::wait_queue::{self, WaitQueue};
+use crate::multitask::PROCESS_MANAGER;
+
+use super::spinlock::Lock;
+
+pub struct Mutex<T> {
+    is_locked: AtomicBool,
+    waiters: Lock<WaitQueue>,
+    data: UnsafeCell<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex {
+            is_locked: AtomicBool::new(false),
+            waiters: Lock::new(WaitQueue::new()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Sleeps until the mutex is free (signals don't interrupt the wait)
+    pub fn lock(&self) -> &mut T {
+        while !self.try_take() {
+            wait_queue::sleep_on_uninterruptible(&self.waiters, || {
+                self.is_locked.load(Ordering::Acquire)
+            });
+        }
+
+        unsafe { &mut *self.data.get() }
+    }
+
+    // Returns None instead of sleeping if the mutex is taken
+    pub fn try_lock(&self) -> Option<&mut T> {
+        either!(self.try_take() => Some(unsafe { &mut *self.data.get() }); None)
+    }
+
+    pub fn free(&self) {
+        self.is_locked.store(false, Ordering::Release);
+
+        // The woken process takes the mutex again when it runs as another may have taken it in the meantime
+        PROCESS_MANAGER.lock().wake_one(self.waiters.lock());
+        self.waiters.free();
+        PROCESS_MANAGER.free();
+    }
+
+    fn try_take(&self) -> bool {
+        self.is_locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+unsafe impl<T> Sync for Mutex<T> {}
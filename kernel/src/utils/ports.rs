@@ -15,6 +15,13 @@ pub fn inpw(port: u16) -> u16 {
     unsafe { inpw_raw(port) }
 }
 
+pub fn outpl(port: u16, value: u32) {
+    unsafe { outpl_raw(port, value) };
+}
+pub fn inpl(port: u16) -> u32 {
+    unsafe { inpl_raw(port) }
+}
+
 pub fn io_wait() {
     outb(0x80, 0);
 }
@@ -25,5 +32,8 @@ extern "C" {
 
     fn outpw_raw(port: u16, value: u16);
     fn inpw_raw(port: u16) -> u16;
+
+    fn outpl_raw(port: u16, value: u32);
+    fn inpl_raw(port: u16) -> u32;
 }
 
@@ -1,5 +1,5 @@
 use crate::{
-    print_serial,
+    either, print_serial,
     utils::{bitwise, ports},
 };
 
@@ -7,6 +7,8 @@ const CMOS_PORT_ADDR: u16 = 0x70;
 const CMOS_PORT_DATA: u16 = 0x71;
 const CURRENT_YEAR: u16 = 2024;
 const CENTURY_REGISTER: u16 = 0x00;
+const BST_OFFSET: u64 = 60 * 60; // Added by convert_to_bst
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
 
 #[derive(Copy, Clone, PartialEq)]
 pub struct DateTime {
@@ -93,6 +95,31 @@ impl DateTime {
         (self.day, self.month, self.year)
     }
 
+    /*
+        Seconds since 00:00:00 01/01/1970 (UTC)
+        The days before the current year and month are counted and then the time of day is added
+    */
+    pub fn to_unix_time(&self) -> u64 {
+        let mut days: u64 = 0;
+
+        for year in 1970..self.year {
+            days += either!(is_leap_year(year) => 366; 365);
+        }
+
+        for month in 1..self.month {
+            days += days_in_month(month, self.year);
+        }
+
+        days += self.day.saturating_sub(1) as u64;
+
+        let seconds = days * SECONDS_PER_DAY
+            + self.hour as u64 * 60 * 60
+            + self.minute as u64 * 60
+            + self.second as u64;
+
+        seconds.saturating_sub(BST_OFFSET)
+    }
+
     pub fn calculate_year(&mut self) {
         self.year += ((CURRENT_YEAR / 100) * 100) as u16;
         if self.year < CURRENT_YEAR as u16 {
@@ -132,6 +159,18 @@ impl DateTime {
     }
 }
 
+fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(month: u8, year: u16) -> u64 {
+    match month {
+        2 => either!(is_leap_year(year) => 29; 28),
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
 fn get_update_in_progress_flag() -> bool {
     ports::outb(CMOS_PORT_ADDR, 0x0A);
     bitwise::contains_bit(ports::inb(CMOS_PORT_DATA), 0x80)
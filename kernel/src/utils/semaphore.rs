@@ -0,0 +1,54 @@
+/*
+    A counting semaphore where processes sleep until one of a limited number of units is available
+    Like Mutex it can only be used from a syscall or kernel thread whilst not holding PROCESS_MANAGER
+*/
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::multitask::wait_queue::{self, WaitQueue};
+use crate::multitask::PROCESS_MANAGER;
+
+use super::spinlock::Lock;
+
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: Lock<WaitQueue>,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Semaphore {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: Lock::new(WaitQueue::new()),
+        }
+    }
+
+    // Sleeps until a unit is available and takes it (signals don't interrupt the wait)
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            wait_queue::sleep_on_uninterruptible(&self.waiters, || self.count() == 0);
+        }
+    }
+
+    // Returns false instead of sleeping if no unit is available
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    // Gives back a unit and wakes a process waiting for one
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+
+        PROCESS_MANAGER.lock().wake_one(self.waiters.lock());
+        self.waiters.free();
+        PROCESS_MANAGER.free();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+}
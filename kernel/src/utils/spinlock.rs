@@ -1,35 +1,53 @@
 use core::cell::UnsafeCell;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicUsize;
 use core::sync::atomic::Ordering;
 
+use crate::smp::{self, tlb};
+
+const NO_OWNER: usize = usize::MAX;
+
+/*
+    Kernel code runs with interrupts disabled so a CPU never has to wait for itself to free a lock
+    Taking a lock the same CPU already holds is a bug whilst another CPU holding it is waited for
+*/
 pub struct Lock<T> {
-    active: AtomicBool,
+    owner: AtomicUsize, // CPU holding the lock
     data: UnsafeCell<T>,
 }
 
 impl<T> Lock<T> {
     pub const fn new(data: T) -> Lock<T> {
         Lock {
-            active: AtomicBool::new(false),
+            owner: AtomicUsize::new(NO_OWNER),
             data: UnsafeCell::new(data),
         }
     }
 
     pub fn lock(&self) -> &mut T {
-        if self.active.load(Ordering::Acquire) {
-            panic!(
-                "spinlock already locked of type {}",
-                core::any::type_name::<T>()
-            );
+        let cpu = smp::current_cpu();
+
+        while let Err(owner) =
+            self.owner
+                .compare_exchange(NO_OWNER, cpu, Ordering::Acquire, Ordering::Relaxed)
+        {
+            if owner == cpu {
+                panic!(
+                    "spinlock already locked of type {}",
+                    core::any::type_name::<T>()
+                );
+            }
+
+            // The CPU holding the lock may be waiting for this one to flush its TLB
+            tlb::handle_shootdown();
+            core::hint::spin_loop();
         }
-        self.active.store(true, Ordering::Release);
 
         unsafe { &mut *self.data.get() }
     }
 
     pub fn free(&self) {
-        self.active.store(false, Ordering::Release);
+        self.owner.store(NO_OWNER, Ordering::Release);
     }
 }
 
-unsafe impl<T> Sync for Lock<T> {}
\ No newline at end of file
+unsafe impl<T> Sync for Lock<T> {}
@@ -43,6 +43,12 @@ pub fn concatenate_filename_ext<'a>(
     ext: &str,
     buffer: &'a mut [u8],
 ) -> Result<&'a str, &'static str> {
+    // Files without an extension have no period
+    if ext.is_empty() {
+        let lower_filename = to_lowercase(filename, buffer)?;
+        return Ok(lower_filename);
+    }
+
     // Calculate the total length needed (filename + '.' + extension)
     let total_length = filename.len() + 1 + ext.len();
 
@@ -69,3 +75,28 @@ pub fn concatenate_filename_ext<'a>(
     // Convert the buffer to &str
     core::str::from_utf8(&buffer[..total_length]).map_err(|_| "Invalid UTF-8")
 }
+
+// Long FAT names are UTF-16 and end with a 0 (or run to the end of the array)
+pub fn convert_utf16_to_string<'a>(
+    characters: &[u16],
+    buffer: &'a mut [u8],
+) -> Result<&'a str, &'static str> {
+    let length = characters
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(characters.len());
+
+    let mut total_length = 0;
+    for c in char::decode_utf16(characters[..length].iter().copied()) {
+        let c = c.map_err(|_| "Invalid UTF-16")?;
+
+        if total_length + c.len_utf8() > buffer.len() {
+            return Err("Buffer size is too small");
+        }
+
+        c.encode_utf8(&mut buffer[total_length..]);
+        total_length += c.len_utf8();
+    }
+
+    core::str::from_utf8(&buffer[..total_length]).map_err(|_| "Invalid UTF-8")
+}
@@ -0,0 +1,100 @@
+/*
+    Clocks given to userland built from the PIT and RTC
+    The monotonic clock counts PIT ticks since boot and never goes backwards
+    Wall clock (realtime) time is the time read from the RTC at boot plus the monotonic time since
+*/
+
+use crate::interrupts::pit::PIT;
+
+use super::rtc;
+use super::spinlock::Lock;
+
+// Same clock ids as Linux
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+
+const NANOSECONDS_PER_SECOND: usize = 1_000_000_000;
+
+// Same layout as struct timespec
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct Timespec {
+    pub seconds: i64,
+    pub nanoseconds: i64,
+}
+
+// Same layout as struct timeval
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct Timeval {
+    pub seconds: i64,
+    pub microseconds: i64,
+}
+
+pub struct Clock {
+    boot_time: usize, // Unix time (in seconds) when the clock was seeded
+}
+
+impl Timespec {
+    pub fn from_nanoseconds(nanoseconds: usize) -> Timespec {
+        Timespec {
+            seconds: (nanoseconds / NANOSECONDS_PER_SECOND) as i64,
+            nanoseconds: (nanoseconds % NANOSECONDS_PER_SECOND) as i64,
+        }
+    }
+
+    // None if the nanoseconds are out of range or the time is negative
+    pub fn to_nanoseconds(&self) -> Option<usize> {
+        if self.seconds < 0
+            || self.nanoseconds < 0
+            || self.nanoseconds >= NANOSECONDS_PER_SECOND as i64
+        {
+            return None;
+        }
+
+        (self.seconds as usize)
+            .checked_mul(NANOSECONDS_PER_SECOND)?
+            .checked_add(self.nanoseconds as usize)
+    }
+
+    pub fn to_timeval(&self) -> Timeval {
+        Timeval {
+            seconds: self.seconds,
+            microseconds: self.nanoseconds / 1000,
+        }
+    }
+}
+
+impl Clock {
+    pub const fn new() -> Clock {
+        Clock { boot_time: 0 }
+    }
+
+    // Must be called once the PIT has been started
+    pub fn init(&mut self) {
+        let datetime = rtc::get_current_datetime();
+        self.boot_time = datetime.to_unix_time() as usize;
+    }
+
+    pub fn get_time(&self, clock_id: usize) -> Option<Timespec> {
+        let uptime = Timespec::from_nanoseconds(uptime_nanoseconds());
+
+        match clock_id {
+            CLOCK_MONOTONIC => Some(uptime),
+            CLOCK_REALTIME => Some(Timespec {
+                seconds: uptime.seconds + self.boot_time as i64,
+                nanoseconds: uptime.nanoseconds,
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub fn uptime_nanoseconds() -> usize {
+    let uptime = PIT.lock().uptime_nanoseconds();
+    PIT.free();
+
+    uptime
+}
+
+pub static CLOCK: Lock<Clock> = Lock::new(Clock::new());
@@ -6,8 +6,39 @@
 
 #include "../syscalls/syscalls.h"
 
+#define ROW_HEIGHT 20
+
 static Window *new_window;
 
+// Paints the name of every entry of a directory on its own row
+static void paint_directory(int wid, const char *path)
+{
+    int directory = open(path, 0);
+    if (directory < 0)
+    {
+        paint_string("Cannot open directory", wid, 5, ROW_HEIGHT);
+        return;
+    }
+
+    char buffer[1024];
+    int y = ROW_HEIGHT;
+    int length;
+
+    while ((length = getdents(directory, (DirectoryEntry *)buffer, sizeof(buffer))) > 0)
+    {
+        for (int offset = 0; offset < length;)
+        {
+            DirectoryEntry *entry = (DirectoryEntry *)(buffer + offset);
+
+            paint_string(entry->name, wid, 5, y);
+            y += ROW_HEIGHT;
+            offset += entry->length;
+        }
+    }
+
+    close(directory);
+}
+
 int main()
 {
     new_window = malloc(sizeof(Window));
@@ -22,8 +53,7 @@ int main()
 
     printf("program 2 with new window with wid of %d\n", wid);
 
-    paint_string("a.txt", wid, 5, 20);
-    paint_string("b.txt", wid, 5, 40);
+    paint_directory(wid, "/");
 
     for (;;)
     {
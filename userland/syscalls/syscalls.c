@@ -54,6 +54,152 @@ int close(int file)
     return (int)result;
 }
 
+int fsync(int file)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[file], %%rbx \n\t"
+        "mov $74, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [file] "r"((int64_t)file)
+        : "rax", "rbx");
+    return (int)result;
+}
+
+void sync()
+{
+    asm volatile(
+        "mov $162, %%rax \n\t"
+        "int $0x80 \n\t"
+        :
+        :
+        : "rax");
+}
+
+int stat(const char *name, Stat *status)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[name], %%rbx \n\t"
+        "mov %[status], %%rcx \n\t"
+        "mov $4, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [name] "r"(name), [status] "r"(status)
+        : "rax", "rbx", "rcx", "memory");
+    return (int)result;
+}
+
+int fstat(int file, Stat *status)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[file], %%rbx \n\t"
+        "mov %[status], %%rcx \n\t"
+        "mov $5, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [file] "r"((int64_t)file), [status] "r"(status)
+        : "rax", "rbx", "rcx", "memory");
+    return (int)result;
+}
+
+int ftruncate(int file, long length)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[file], %%rbx \n\t"
+        "mov %[length], %%rcx \n\t"
+        "mov $77, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [file] "r"((int64_t)file), [length] "r"((int64_t)length)
+        : "rax", "rbx", "rcx");
+    return (int)result;
+}
+
+// Returns the number of bytes of entries read (0 once every entry has been read)
+int getdents(int file, DirectoryEntry *entries, int count)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[file], %%rbx \n\t"
+        "mov %[entries], %%rcx \n\t"
+        "mov %[count], %%rdx \n\t"
+        "mov $78, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [file] "r"((int64_t)file), [entries] "r"(entries), [count] "r"((int64_t)count)
+        : "rax", "rbx", "rcx", "rdx", "memory");
+    return (int)result;
+}
+
+int rename(const char *old, const char *new)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[old], %%rbx \n\t"
+        "mov %[new], %%rcx \n\t"
+        "mov $82, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [old] "r"(old), [new] "r"(new)
+        : "rax", "rbx", "rcx");
+    return (int)result;
+}
+
+// FAT has no permissions so the mode is ignored
+int mkdir(const char *name, uint32_t mode)
+{
+    (void)mode;
+
+    int64_t result;
+    asm volatile(
+        "mov %[name], %%rbx \n\t"
+        "mov $83, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [name] "r"(name)
+        : "rax", "rbx");
+    return (int)result;
+}
+
+int rmdir(const char *name)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[name], %%rbx \n\t"
+        "mov $84, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [name] "r"(name)
+        : "rax", "rbx");
+    return (int)result;
+}
+
+int unlink(const char *name)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[name], %%rbx \n\t"
+        "mov $87, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [name] "r"(name)
+        : "rax", "rbx");
+    return (int)result;
+}
+
 int lseek(int file, int ptr, int dir)
 {
     uint64_t result;
@@ -68,11 +214,313 @@ int lseek(int file, int ptr, int dir)
     return (int)result;
 }
 
-void _exit()
+void _exit(int status)
 {
-    asm volatile("mov $56, %rax \n\t\
-        int $0x80 \n\t\
-        ");
+    asm volatile(
+        "mov %[status], %%rbx \n\t"
+        "mov $56, %%rax \n\t"
+        "int $0x80 \n\t"
+        :
+        : [status] "r"((int64_t)status)
+        : "rax", "rbx");
+
+    for (;;)
+    {
+    }
+}
+
+// Sleeps in the kernel until a child exits unless WNOHANG (1) is set
+int waitpid(int pid, int *status, int options)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[pid], %%rbx \n\t"
+        "mov %[status], %%rcx \n\t"
+        "mov %[options], %%rdx \n\t"
+        "mov $61, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [pid] "r"((int64_t)pid), [status] "r"(status), [options] "r"((int64_t)options)
+        : "rax", "rbx", "rcx", "rdx", "memory");
+    return (int)result;
+}
+
+int wait(int *status)
+{
+    return waitpid(-1, status, 0);
+}
+
+int kill(int pid, int sig)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[pid], %%rbx \n\t"
+        "mov %[sig], %%rcx \n\t"
+        "mov $62, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [pid] "r"((int64_t)pid), [sig] "r"((int64_t)sig)
+        : "rax", "rbx", "rcx");
+    return (int)result;
+}
+
+int nanosleep(const Timespec *requested, Timespec *remaining)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[requested], %%rbx \n\t"
+        "mov %[remaining], %%rcx \n\t"
+        "mov $35, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [requested] "r"(requested), [remaining] "r"(remaining)
+        : "rax", "rbx", "rcx", "memory");
+    return (int)result;
+}
+
+int clock_gettime(int clock_id, Timespec *time)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[clock_id], %%rbx \n\t"
+        "mov %[time], %%rcx \n\t"
+        "mov $228, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [clock_id] "r"((int64_t)clock_id), [time] "r"(time)
+        : "rax", "rbx", "rcx", "memory");
+    return (int)result;
+}
+
+// Returns the ticks since boot
+int64_t times(CpuTimes *buffer)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[buffer], %%rbx \n\t"
+        "mov $100, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [buffer] "r"(buffer)
+        : "rax", "rbx", "memory");
+    return result;
+}
+
+// The kernel returns 20 - nice so a valid nice value is never mistaken for an error
+int getpriority(int which, int who)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[which], %%rbx \n\t"
+        "mov %[who], %%rcx \n\t"
+        "mov $140, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [which] "r"((int64_t)which), [who] "r"((int64_t)who)
+        : "rax", "rbx", "rcx");
+
+    if (result == -1)
+    {
+        return -1;
+    }
+
+    return 20 - (int)result;
+}
+
+int setpriority(int which, int who, int nice)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[which], %%rbx \n\t"
+        "mov %[who], %%rcx \n\t"
+        "mov %[nice], %%rdx \n\t"
+        "mov $141, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [which] "r"((int64_t)which), [who] "r"((int64_t)who), [nice] "r"((int64_t)nice)
+        : "rax", "rbx", "rcx", "rdx");
+    return (int)result;
+}
+
+// Returns the new nice value of the current process
+int nice(int increment)
+{
+    int current = getpriority(PRIO_PROCESS, 0);
+
+    if (setpriority(PRIO_PROCESS, 0, current + increment) == -1)
+    {
+        return -1;
+    }
+
+    return getpriority(PRIO_PROCESS, 0);
+}
+
+// The timezone is obsolete and always ignored by the kernel
+int gettimeofday(Timeval *time, void *timezone)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[time], %%rbx \n\t"
+        "mov $96, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [time] "r"(time)
+        : "rax", "rbx", "memory");
+    return (int)result;
+}
+
+// Handlers return here which asks the kernel to restore the state from before the signal
+void __restore_signal(void);
+asm(".global __restore_signal \n\t"
+    "__restore_signal: \n\t"
+    "mov $15, %rax \n\t"
+    "int $0x80 \n\t");
+
+int sys_sigaction(int sig, const SignalAction *action, SignalAction *old_action)
+{
+    SignalAction copy;
+    const SignalAction *kernel_action = 0;
+
+    if (action)
+    {
+        copy = *action;
+        copy.restorer = __restore_signal;
+        kernel_action = &copy;
+    }
+
+    int64_t result;
+    asm volatile(
+        "mov %[sig], %%rbx \n\t"
+        "mov %[action], %%rcx \n\t"
+        "mov %[old_action], %%rdx \n\t"
+        "mov $13, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [sig] "r"((int64_t)sig), [action] "r"(kernel_action), [old_action] "r"(old_action)
+        : "rax", "rbx", "rcx", "rdx", "memory");
+    return (int)result;
+}
+
+int sys_sigprocmask(int how, const uint64_t *set, uint64_t *old_set)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[how], %%rbx \n\t"
+        "mov %[set], %%rcx \n\t"
+        "mov %[old_set], %%rdx \n\t"
+        "mov $14, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [how] "r"((int64_t)how), [set] "r"(set), [old_set] "r"(old_set)
+        : "rax", "rbx", "rcx", "rdx", "memory");
+    return (int)result;
+}
+
+int fork()
+{
+    int64_t result;
+    asm volatile(
+        "mov $57, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        :
+        : "rax");
+    return (int)result;
+}
+
+int execve(char *name, char **argv, char **env)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[name], %%rbx \n\t"
+        "mov %[argv], %%rcx \n\t"
+        "mov %[env], %%rdx \n\t"
+        "mov $59, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [name] "r"(name), [argv] "r"(argv), [env] "r"(env)
+        : "rax", "rbx", "rcx", "rdx");
+    return (int)result;
+}
+
+// The kernel returns the new break or the current one if it could not be moved
+static void *set_break(void *addr)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[addr], %%rbx \n\t"
+        "mov $12, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [addr] "r"(addr)
+        : "rax", "rbx");
+    return (void *)result;
+}
+
+int brk(void *addr)
+{
+    return set_break(addr) == addr ? 0 : -1;
+}
+
+void *sbrk(intptr_t increment)
+{
+    char *current = set_break(0);
+
+    if (increment == 0)
+        return current;
+
+    if (set_break(current + increment) != current + increment)
+        return (void *)-1;
+
+    return current;
+}
+
+void *mmap(void *addr, uint64_t length, int prot, int flags, int fd, uint64_t offset)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[addr], %%rbx \n\t"
+        "mov %[length], %%rcx \n\t"
+        "mov %[prot], %%rdx \n\t"
+        "mov %[flags], %%rsi \n\t"
+        "mov %[fd], %%rdi \n\t"
+        "mov %[offset], %%r8 \n\t"
+        "mov $358, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [addr] "r"(addr), [length] "r"(length), [prot] "r"((int64_t)prot),
+          [flags] "r"((int64_t)flags), [fd] "r"((int64_t)fd), [offset] "r"(offset)
+        : "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "r8");
+    return (void *)result;
+}
+
+int munmap(void *addr, uint64_t length)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[addr], %%rbx \n\t"
+        "mov %[length], %%rcx \n\t"
+        "mov $11, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [addr] "r"(addr), [length] "r"(length)
+        : "rax", "rbx", "rcx");
+    return (int)result;
 }
 
 int getpid()
@@ -104,23 +552,27 @@ int send_message(Message *message)
         "mov %[msg_addr], %%rbx \n\t"
         "mov $352, %%rax \n\t"
         "int $0x80 \n\t"
-        : "=r"(result)
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
         : [msg_addr] "r"(message)
         : "rax", "rbx");
     return (int)result;
 }
 
-Message *receive_message()
+int receive_message(Message *message, unsigned char *buffer, uint64_t size)
 {
-    Message *result;
+    int64_t result;
     asm volatile(
+        "mov %[msg_addr], %%rbx \n\t"
+        "mov %[buffer], %%rcx \n\t"
+        "mov %[size], %%rdx \n\t"
         "mov $353, %%rax \n\t"
         "int $0x80 \n\t"
         "mov %%rax, %[result] \n\t"
         : [result] "=r"(result)
-        :
-        : "rax");
-    return result;
+        : [msg_addr] "r"(message), [buffer] "r"(buffer), [size] "r"(size)
+        : "rax", "rbx", "rcx", "rdx", "memory");
+    return (int)result;
 }
 
 int create_window(Window *new_window, bool should_repaint)
@@ -172,4 +624,106 @@ int copy_to_win_buffer(int wid, uint32_t *buffer)
                  : "=r"(result)
                  : "r"(wid), "m"(buffer));
     return (int)result;
-}
\ No newline at end of file
+}
+int arch_prctl(int code, uint64_t address)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[code], %%rbx \n\t"
+        "mov %[address], %%rcx \n\t"
+        "mov $158, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [code] "r"((int64_t)code), [address] "r"(address)
+        : "rax", "rbx", "rcx", "memory");
+    return (int)result;
+}
+
+int gettid()
+{
+    int64_t result;
+    asm volatile(
+        "mov $186, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        :
+        : "rax");
+    return (int)result;
+}
+
+/*
+    Runs start(argument) on a new thread of this process using the given stack
+    stack_top must be 16 byte aligned and tls (if not NULL) becomes the FS base of the new thread
+    The thread exits with status 0 once start returns
+    Returns the id of the new thread or -1
+*/
+int thread_create(void (*start)(void *), void *argument, void *stack_top, void *tls)
+{
+    int64_t flags = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD;
+    if (tls)
+    {
+        flags |= CLONE_SETTLS;
+    }
+
+    int64_t result;
+    asm volatile(
+        "mov %[flags], %%rbx \n\t"
+        "mov %[stack], %%rcx \n\t"
+        "mov %[tls], %%rdx \n\t"
+        "mov %[start], %%r12 \n\t"
+        "mov %[argument], %%r13 \n\t"
+        "mov $359, %%rax \n\t"
+        "int $0x80 \n\t"
+        "test %%rax, %%rax \n\t"
+        "jnz 1f \n\t"
+        // The new thread starts here on its own stack with the registers of the caller
+        "mov %%r13, %%rdi \n\t"
+        "call *%%r12 \n\t"
+        "xor %%rbx, %%rbx \n\t"
+        "mov $360, %%rax \n\t"
+        "int $0x80 \n\t"
+        "1: \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [flags] "r"(flags), [stack] "r"(stack_top), [tls] "r"(tls), [start] "r"(start), [argument] "r"(argument)
+        : "rax", "rbx", "rcx", "rdx", "rdi", "r12", "r13", "memory");
+    return (int)result;
+}
+
+void thread_exit(int status)
+{
+    asm volatile(
+        "mov %[status], %%rbx \n\t"
+        "mov $360, %%rax \n\t"
+        "int $0x80 \n\t"
+        :
+        : [status] "r"((int64_t)status)
+        : "rax", "rbx");
+
+    for (;;)
+    {
+    }
+}
+
+/*
+    FUTEX_WAIT sleeps whilst *address == value (timeout is relative and may be NULL) and returns 0 once woken
+    FUTEX_WAKE wakes at most value threads sleeping on address and returns how many were woken
+*/
+int futex(uint32_t *address, int operation, uint32_t value, const Timespec *timeout)
+{
+    int64_t result;
+    asm volatile(
+        "mov %[address], %%rbx \n\t"
+        "mov %[operation], %%rcx \n\t"
+        "mov %[value], %%rdx \n\t"
+        "mov %[timeout], %%rsi \n\t"
+        "mov $202, %%rax \n\t"
+        "int $0x80 \n\t"
+        "mov %%rax, %[result] \n\t"
+        : [result] "=r"(result)
+        : [address] "r"(address), [operation] "r"((int64_t)operation), [value] "r"((uint64_t)value), [timeout] "r"(timeout)
+        : "rax", "rbx", "rcx", "rdx", "rsi", "memory");
+    return (int)result;
+}
@@ -40,26 +40,183 @@ typedef struct Event
     uint16_t mouse_y;
 } Event;
 
-void _exit();
+// Signal numbers used by the kernel (same as Linux)
+// These and the rest of the signal interface are prefixed as newlib's <signal.h> numbers signals differently
+#define SYS_SIGHUP 1
+#define SYS_SIGINT 2
+#define SYS_SIGQUIT 3
+#define SYS_SIGILL 4
+#define SYS_SIGTRAP 5
+#define SYS_SIGABRT 6
+#define SYS_SIGBUS 7
+#define SYS_SIGFPE 8
+#define SYS_SIGKILL 9
+#define SYS_SIGUSR1 10
+#define SYS_SIGSEGV 11
+#define SYS_SIGUSR2 12
+#define SYS_SIGPIPE 13
+#define SYS_SIGALRM 14
+#define SYS_SIGTERM 15
+#define SYS_SIGCHLD 17
+#define SYS_SIGCONT 18
+#define SYS_SIGSTOP 19
+#define SYS_SIGTSTP 20
+
+#define SYS_SIG_DFL ((SignalHandler)0)
+#define SYS_SIG_IGN ((SignalHandler)1)
+
+#define SYS_SA_NODEFER 0x40000000
+#define SYS_SA_RESETHAND 0x80000000
+
+#define SYS_SIG_BLOCK 0
+#define SYS_SIG_UNBLOCK 1
+#define SYS_SIG_SETMASK 2
+
+typedef void (*SignalHandler)(int);
+
+typedef struct SignalAction
+{
+    SignalHandler handler;
+    uint64_t flags;
+    void (*restorer)(void); // Filled in by sys_sigaction
+    uint64_t mask;          // Bit n blocks signal n whilst the handler runs
+} SignalAction;
+
+#define PROT_NONE 0x0
+#define PROT_READ 0x1
+#define PROT_WRITE 0x2
+#define PROT_EXEC 0x4
+
+#define MAP_PRIVATE 0x02
+#define MAP_ANONYMOUS 0x20
+#define MAP_FAILED ((void *)-1)
+
+#define CLOCK_REALTIME 0  // Seconds since 01/01/1970
+#define CLOCK_MONOTONIC 1 // Time since boot
+
+typedef struct Timespec
+{
+    int64_t seconds;
+    int64_t nanoseconds;
+} Timespec;
+
+typedef struct Timeval
+{
+    int64_t seconds;
+    int64_t microseconds;
+} Timeval;
+
+#define CLOCKS_PER_TICK 100 // Ticks of the timer in a second
+
+// CPU time measured in ticks
+typedef struct CpuTimes
+{
+    uint64_t user;
+    uint64_t system;
+    uint64_t children_user;   // Children which have been waited for
+    uint64_t children_system;
+} CpuTimes;
+
+#define PRIO_PROCESS 0
+
+// Same layout as struct stat on x86_64 Linux (FAT has no owners, permissions or times so these are 0)
+typedef struct Stat
+{
+    uint64_t device;
+    uint64_t inode;
+    uint64_t links;
+    uint32_t mode;
+    uint32_t user_id;
+    uint32_t group_id;
+    uint32_t padding;
+    uint64_t special_device;
+    int64_t size;
+    int64_t block_size;
+    int64_t blocks; // Of 512 bytes
+    Timespec access_time;
+    Timespec modification_time;
+    Timespec status_change_time;
+    int64_t reserved[3];
+} Stat;
+
+#define S_IFMT 0170000
+#define S_IFDIR 0040000
+#define S_IFREG 0100000
+
+// Entries given by getdents (same layout as struct linux_dirent) where the type is the last byte of each entry
+typedef struct __attribute__((packed)) DirectoryEntry
+{
+    uint64_t inode;
+    uint64_t offset; // Position of the next entry
+    uint16_t length; // Of the whole entry
+    char name[];     // Null terminated
+} DirectoryEntry;
+
+#define DT_DIR 4
+#define DT_REG 8
+
+// Flags for clone (same as Linux), thread_create passes all of them
+#define CLONE_VM 0x00000100
+#define CLONE_FS 0x00000200
+#define CLONE_FILES 0x00000400
+#define CLONE_SIGHAND 0x00000800
+#define CLONE_THREAD 0x00010000
+#define CLONE_SETTLS 0x00080000
+
+// Operations of futex (FUTEX_PRIVATE_FLAG is accepted but every futex is keyed on its physical address)
+#define FUTEX_WAIT 0
+#define FUTEX_WAKE 1
+#define FUTEX_PRIVATE_FLAG 128
+
+// Codes for arch_prctl
+#define ARCH_SET_FS 0x1002
+#define ARCH_GET_FS 0x1003
+
+void _exit(int status);
 int close(int file);
-// int execve(char *name, char **argv, char **env);
-// int fork();
-// int fstat(int file, struct stat *st);
+int fsync(int file);
+void sync();
+int execve(char *name, char **argv, char **env);
+int fork();
+int fstat(int file, Stat *status);
+int ftruncate(int file, long length);
+int getdents(int file, DirectoryEntry *entries, int count);
 int getpid();
+int gettid();
+int thread_create(void (*start)(void *), void *argument, void *stack_top, void *tls);
+void thread_exit(int status);
+int arch_prctl(int code, uint64_t address);
+int futex(uint32_t *address, int operation, uint32_t value, const Timespec *timeout);
+int brk(void *addr);
+void *sbrk(intptr_t increment);
+void *mmap(void *addr, uint64_t length, int prot, int flags, int fd, uint64_t offset);
+int munmap(void *addr, uint64_t length);
 int isatty(int file);
-// int kill(int pid, int sig);
+int kill(int pid, int sig); // Takes the SYS_ signal numbers
+int nanosleep(const Timespec *requested, Timespec *remaining);
+int clock_gettime(int clock_id, Timespec *time);
+int getpriority(int which, int who);
+int setpriority(int which, int who, int nice);
+int nice(int increment);
 // int link(char *old, char *new);
 int open(const char *name, int flags, ...);
 // int read(int file, char *ptr, int len);
-// int stat(const char *file, struct stat *st);
-// clock_t times(struct tms *buf);
-// int unlink(char *name);
-// int wait(int *status);
+int stat(const char *name, Stat *status);
+int mkdir(const char *name, uint32_t mode);
+int rmdir(const char *name);
+int rename(const char *old, const char *new);
+int64_t times(CpuTimes *buffer);
+int unlink(const char *name);
+int sys_sigaction(int sig, const SignalAction *action, SignalAction *old_action);
+int sys_sigprocmask(int how, const uint64_t *set, uint64_t *old_set);
+int wait(int *status);
+int waitpid(int pid, int *status, int options);
 // int lseek(int file, long int ptr, int dir);
 // int write(int file, char *ptr, int len);
-// int gettimeofday(struct timeval *p, void *restrict);
+int gettimeofday(Timeval *time, void *timezone);
 int send_message(Message *message);
-Message *receive_message();
+// Sleeps until a message arrives and copies its payload into buffer (cut short to size)
+int receive_message(Message *message, unsigned char *buffer, uint64_t size);
 int create_window(Window *new_window, bool should_repaint);
 Event *get_event();
 int paint_string(char *ptr, int wid, int x, int y);
@@ -3,9 +3,14 @@
 #include <stdlib.h>
 #include <stdint.h>
 #include <string.h>
+#include <sys/wait.h>
 
 #include "../syscalls/syscalls.h"
 
+#define MAX_ARGUMENTS 16
+
+extern char **environ;
+
 static Window *new_window;
 static int x_base = 5;
 static int y_base = 20;
@@ -89,7 +94,51 @@ int evaluate_command(char command[255], int wid)
     }
     else
     {
-        paint_string("Unknown command", wid, x_base, y_base);
+        // Anything else is a program on the filesystem
+        int pid = fork();
+
+        if (pid == 0)
+        {
+            // Split the command into its arguments where the first is the program
+            char *argv[MAX_ARGUMENTS + 1];
+            int argc = 0;
+
+            char *token = strtok(new_command, " ");
+            while (token != NULL && argc < MAX_ARGUMENTS)
+            {
+                argv[argc++] = token;
+                token = strtok(NULL, " ");
+            }
+            argv[argc] = NULL;
+
+            char path[256] = "/";
+            if (argc > 0)
+            {
+                strcat(path, argv[0]);
+            }
+            execve(path, argv, environ);
+            _exit(127); // Only reached if the program could not be found
+        }
+
+        int status = 0;
+        waitpid(pid, &status, 0);
+
+        if (WIFSIGNALED(status))
+        {
+            char result[64];
+            sprintf(result, "Killed by signal %d", WTERMSIG(status));
+            paint_string(result, wid, x_base, y_base);
+        }
+        else if (WEXITSTATUS(status) == 127)
+        {
+            paint_string("Unknown command", wid, x_base, y_base);
+        }
+        else
+        {
+            char result[64];
+            sprintf(result, "Exited with status %d", WEXITSTATUS(status));
+            paint_string(result, wid, x_base, y_base);
+        }
     }
     y_base += 20;
 }
\ No newline at end of file